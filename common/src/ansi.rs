@@ -0,0 +1,18 @@
+//! ANSI escape sequences
+//!
+//! Colours shared by the kernel and user programs, such as for the `[ok]` printed by each test.
+
+/// Start green text
+pub const GREEN: &str = "\x1b[32m";
+/// Start red text
+pub const RED: &str = "\x1b[31m";
+/// Return to the default colour
+pub const RESET: &str = "\x1b[0m";
+
+/// Print a green `[ok]` and end the line, as each test does when it passes
+#[macro_export]
+macro_rules! ok {
+    () => {
+        $crate::println!("[{}ok{}]", $crate::ansi::GREEN, $crate::ansi::RESET)
+    };
+}
@@ -0,0 +1,94 @@
+//! Command-line arguments for user processes
+//!
+//! The kernel maps one read-only page at `ARGS_VADDR` into every user process. It holds `argc: usize`
+//! followed by `argc` length-prefixed arguments: each a `len: usize` then `len` bytes of UTF-8, padded
+//! to `usize` alignment.
+
+pub use crate::layout::ARGS_VADDR;
+/// Size of the arguments page
+pub const ARGS_SIZE: usize = 4096;
+
+const WORD: usize = size_of::<usize>();
+
+const fn align_word(n: usize) -> usize {
+    (n + WORD - 1) & !(WORD - 1)
+}
+
+fn read_word(buf: &[u8], off: usize) -> Option<usize> {
+    let bytes = buf.get(off..off + WORD)?;
+    Some(usize::from_ne_bytes(bytes.try_into().ok()?))
+}
+
+/// Encode `args` into `buf` in the arguments page layout
+///
+/// Returns the number of bytes used, or `None` if the arguments do not fit.
+pub fn encode<'s>(args: impl IntoIterator<Item = &'s str>, buf: &mut [u8]) -> Option<usize> {
+    let mut argc = 0;
+    let mut off = WORD;
+    for arg in args {
+        let data = off + WORD;
+        let end = data + arg.len();
+        buf.get_mut(off..data)?.copy_from_slice(&arg.len().to_ne_bytes());
+        buf.get_mut(data..end)?.copy_from_slice(arg.as_bytes());
+        off = align_word(end).min(buf.len());
+        argc += 1;
+    }
+    buf.get_mut(..WORD)?.copy_from_slice(&usize::to_ne_bytes(argc));
+    Some(off)
+}
+
+/// Iterator over the arguments in an arguments page
+///
+/// Stops early at the first argument that is truncated or not valid UTF-8.
+#[derive(Clone, Debug)]
+pub struct Args<'a> {
+    buf: &'a [u8],
+    off: usize,
+    remaining: usize,
+}
+
+impl<'a> Args<'a> {
+    pub fn parse(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            off: WORD,
+            remaining: read_word(buf, 0).unwrap_or(0),
+        }
+    }
+
+    /// The `argc` arguments starting at `argv`, as the user `start` passes them to `main`
+    ///
+    /// # Safety
+    /// `argv` must point at the first argument in a mapped arguments page of `ARGS_SIZE` bytes, as
+    /// `ARGS_VADDR + size_of::<usize>()` does in a user process.
+    pub unsafe fn from_argv(argc: usize, argv: *const u8) -> Args<'static> {
+        // Safety: The caller guarantees the page starts one word before argv
+        let page = unsafe { core::slice::from_raw_parts(argv.wrapping_sub(WORD), ARGS_SIZE) };
+        Args {
+            buf: page,
+            off: WORD,
+            remaining: argc,
+        }
+    }
+
+    /// Check that all `argc` arguments are present and valid
+    pub fn is_valid(&self) -> bool {
+        self.clone().count() == self.remaining
+    }
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let len = read_word(self.buf, self.off)?;
+        let data = self.off + WORD;
+        let arg = str::from_utf8(self.buf.get(data..data.checked_add(len)?)?).ok()?;
+        self.off = align_word(data + len);
+        self.remaining -= 1;
+        Some(arg)
+    }
+}
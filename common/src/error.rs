@@ -0,0 +1,45 @@
+//! System call error codes
+//!
+//! System calls return a non-negative value on success or a negative `ErrorCode` on failure. The kernel
+//! writes the code to `a0` as a `usize`, so `NotFound` reads as `usize::MAX`.
+
+/// Reason a system call failed
+#[repr(isize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCode {
+    NotFound = -1,          // No such file, program, process or pipe
+    NoSpace = -2,           // A fixed size table or file is full
+    BadArg = -3,            // An argument is out of range or malformed
+    WouldBlock = -4,        // Nothing is ready and the call does not block
+    IoError = -5,           // A device or the SBI reported a failure
+    PermissionDenied = -6,  // The operation is not allowed on this object
+    NoSys = -7,             // No system call has this number
+}
+
+impl ErrorCode {
+    const ALL: [Self; 7] = [
+        Self::NotFound,
+        Self::NoSpace,
+        Self::BadArg,
+        Self::WouldBlock,
+        Self::IoError,
+        Self::PermissionDenied,
+        Self::NoSys,
+    ];
+
+    /// The value the kernel returns in `a0`
+    pub const fn as_usize(self) -> usize {
+        self as isize as usize
+    }
+}
+
+impl TryFrom<isize> for ErrorCode {
+    type Error = isize;
+
+    /// Decode a system call result, returning it unchanged if it is not an error code
+    fn try_from(result: isize) -> Result<Self, Self::Error> {
+        Self::ALL.into_iter()
+            .find(|&code| code as isize == result)
+            .ok_or(result)
+    }
+}
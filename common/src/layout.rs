@@ -0,0 +1,8 @@
+// Where user programs live in memory. The user build script includes this file too, to pass the same
+// addresses to `user.ld`, so it holds only constants.
+
+/// Virtual address user programs are linked at
+pub const USER_BASE: usize = 0x1000000;
+
+/// User virtual address of the arguments page. Just above the largest image `user.ld` allows.
+pub const ARGS_VADDR: usize = 0x1800000;
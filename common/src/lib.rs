@@ -2,10 +2,195 @@
 
 #![no_std]
 
+pub mod ansi;
+pub mod args;
+pub mod error;
+mod layout;
 pub mod print;
+pub mod syscall;
+pub mod trap;
 
-pub const SYS_PUTBYTE: usize = 1;
-pub const SYS_GETCHAR: usize = 2;
-pub const SYS_EXIT: usize = 3;
-pub const SYS_READFILE: usize = 4;
-pub const SYS_WRITEFILE: usize = 5;
+pub use error::ErrorCode;
+pub use layout::USER_BASE;
+pub use syscall::Syscall;
+
+// Old names for the system call numbers
+#[deprecated(note = "use Syscall::PutByte")]
+pub const SYS_PUTBYTE: usize = Syscall::PutByte as usize;
+#[deprecated(note = "use Syscall::GetChar")]
+pub const SYS_GETCHAR: usize = Syscall::GetChar as usize;
+#[deprecated(note = "use Syscall::Exit")]
+pub const SYS_EXIT: usize = Syscall::Exit as usize;
+#[deprecated(note = "use Syscall::ReadFile")]
+pub const SYS_READFILE: usize = Syscall::ReadFile as usize;
+#[deprecated(note = "use Syscall::WriteFile")]
+pub const SYS_WRITEFILE: usize = Syscall::WriteFile as usize;
+#[deprecated(note = "use Syscall::Stat")]
+pub const SYS_STAT: usize = Syscall::Stat as usize;
+#[deprecated(note = "use Syscall::SchedLatencyHist")]
+pub const SYS_SCHED_LATENCY_HIST: usize = Syscall::SchedLatencyHist as usize;
+#[deprecated(note = "use Syscall::GetPid")]
+pub const SYS_GETPID: usize = Syscall::GetPid as usize;
+#[deprecated(note = "use Syscall::InjectFault")]
+pub const SYS_INJECT_FAULT: usize = Syscall::InjectFault as usize;
+#[deprecated(note = "use Syscall::Shutdown")]
+pub const SYS_SHUTDOWN: usize = Syscall::Shutdown as usize;
+#[deprecated(note = "use Syscall::PollChar")]
+pub const SYS_POLLCHAR: usize = Syscall::PollChar as usize;
+#[deprecated(note = "use Syscall::Spawn")]
+pub const SYS_SPAWN: usize = Syscall::Spawn as usize;
+#[deprecated(note = "use Syscall::Yield")]
+pub const SYS_YIELD: usize = Syscall::Yield as usize;
+#[deprecated(note = "use Syscall::Uptime")]
+pub const SYS_UPTIME: usize = Syscall::Uptime as usize;
+#[deprecated(note = "use Syscall::Kill")]
+pub const SYS_KILL: usize = Syscall::Kill as usize;
+#[deprecated(note = "use Syscall::PipeCreate")]
+pub const SYS_PIPE_CREATE: usize = Syscall::PipeCreate as usize;
+#[deprecated(note = "use Syscall::PipeWrite")]
+pub const SYS_PIPE_WRITE: usize = Syscall::PipeWrite as usize;
+#[deprecated(note = "use Syscall::PipeRead")]
+pub const SYS_PIPE_READ: usize = Syscall::PipeRead as usize;
+#[deprecated(note = "use Syscall::ProcStat")]
+pub const SYS_PROC_STAT: usize = Syscall::ProcStat as usize;
+#[deprecated(note = "use Syscall::SetQuantum")]
+pub const SYS_SETQUANTUM: usize = Syscall::SetQuantum as usize;
+#[deprecated(note = "use Syscall::Open")]
+pub const SYS_OPEN: usize = Syscall::Open as usize;
+#[deprecated(note = "use Syscall::Close")]
+pub const SYS_CLOSE: usize = Syscall::Close as usize;
+#[deprecated(note = "use Syscall::Read")]
+pub const SYS_READ: usize = Syscall::Read as usize;
+#[deprecated(note = "use Syscall::Write")]
+pub const SYS_WRITE: usize = Syscall::Write as usize;
+#[deprecated(note = "use Syscall::Mmap")]
+pub const SYS_MMAP: usize = Syscall::Mmap as usize;
+#[deprecated(note = "use Syscall::Munmap")]
+pub const SYS_MUNMAP: usize = Syscall::Munmap as usize;
+#[deprecated(note = "use Syscall::MemInfo")]
+pub const SYS_MEMINFO: usize = Syscall::MemInfo as usize;
+#[deprecated(note = "use Syscall::SigAction")]
+pub const SYS_SIGACTION: usize = Syscall::SigAction as usize;
+#[deprecated(note = "use Syscall::Signal")]
+pub const SYS_SIGNAL: usize = Syscall::Signal as usize;
+#[deprecated(note = "use Syscall::SigReturn")]
+pub const SYS_SIGRETURN: usize = Syscall::SigReturn as usize;
+#[deprecated(note = "use Syscall::SchedStat")]
+pub const SYS_SCHEDSTAT: usize = Syscall::SchedStat as usize;
+#[deprecated(note = "use Syscall::Exec")]
+pub const SYS_EXEC: usize = Syscall::Exec as usize;
+
+// Status values for QEMU's `sifive_test` exit device; a failure carries its exit status in the upper 16 bits
+pub const QEMU_EXIT_PASS: u32 = 0x5555;
+pub const QEMU_EXIT_FAIL: u32 = 0x3333;
+
+// Fault kinds for `SYS_INJECT_FAULT`
+pub const FAULT_ALLOC: usize = 0;           // Allocation of a page of user memory
+pub const FAULT_FILE_WRITE: usize = 1;      // File write
+pub const FAULT_VIRTIO: usize = 2;          // Virtio block read or write
+pub const FAULT_PROCESS_CREATE: usize = 3;  // Process creation
+pub const FAULT_CONSOLE_WRITE: usize = 4;   // Console write, cut short after half of its bytes
+pub const FAULT_KINDS: usize = 5;
+
+// Signal numbers for `SYS_SIGNAL`
+pub const SIGNAL_TERM: usize = 1;           // Asks a process to clean up and exit
+pub const SIGNAL_KINDS: usize = 2;
+
+// Exit codes of processes the kernel terminates, above those processes usually exit with themselves
+pub const EXIT_SIGNALLED: usize = 128;              // Plus the number of a signal with no handler
+pub const EXIT_KILLED: usize = EXIT_SIGNALLED + 9;  // `SYS_KILL`, reported as Unix shells report SIGKILL
+pub const EXIT_FAULTED: usize = 256;                // Plus the `scause` of a fault that ended the process
+
+/// Number of log-scale buckets in the scheduling latency histogram
+///
+/// Bucket 0 counts zero-tick delays; bucket `i` counts delays in `[2^(i-1), 2^i)` ticks.
+/// The last bucket also counts anything larger.
+pub const SCHED_LATENCY_BUCKETS: usize = 32;
+
+/// File metadata filled in by `SYS_STAT`
+///
+/// Shared by kernel and user so both sides agree on the layout.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileStat {
+    pub size: usize,        // File size in bytes
+    pub name_len: usize,    // Length of the file name in bytes, excluding the nul terminator
+}
+
+/// File system usage filled in by `SYS_FSINFO`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FsInfo {
+    pub files_used: usize,  // Slots in the file table holding a file
+    pub files_max: usize,   // Slots in the file table
+    pub bytes_used: usize,  // Total size of the files
+    pub bytes_free: usize,  // Room left for file data, in existing files and free slots
+    pub file_size_max: usize,   // Largest size of one file
+}
+
+/// Page mapping filled in by `SYS_V2P`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PageInfo {
+    pub paddr: usize,       // Physical address the virtual address maps to
+    pub flags: usize,       // `PAGE_INFO_*` bits of the page table entry
+}
+
+// Bits of `PageInfo::flags`, as in an Sv32 page table entry
+pub const PAGE_INFO_VALID: usize = 1 << 0;
+pub const PAGE_INFO_READ: usize = 1 << 1;
+pub const PAGE_INFO_WRITE: usize = 1 << 2;
+pub const PAGE_INFO_EXEC: usize = 1 << 3;
+pub const PAGE_INFO_USER: usize = 1 << 4;
+
+// Flags for `SYS_OPEN`
+pub const OPEN_READ: u32 = 1 << 0;
+pub const OPEN_WRITE: u32 = 1 << 1;
+pub const OPEN_APPEND: u32 = 1 << 2;    // Every write goes to the end of the file; needs OPEN_WRITE
+
+// Flags for `SYS_SPAWN`
+pub const SPAWN_RESTART_ON_FAULT: u32 = 1 << 0;    // Restart the process from its entry point if it faults
+
+// Protection flags for `SYS_MMAP`; memory is never both writable and executable
+pub const MAP_READ: u32 = 1 << 0;
+pub const MAP_WRITE: u32 = 1 << 1;
+pub const MAP_EXEC: u32 = 1 << 2;
+
+/// Maximum number of files each process may have open at once
+pub const OPEN_FILES_MAX: usize = 8;
+
+/// Maximum number of processes; process IDs are at most this value
+pub const PROCS_MAX: usize = 64;
+
+/// Bytes in a process name; longer names are cut short
+pub const PROC_NAME_MAX: usize = 16;
+
+// Process states reported in `ProcStat::state`
+pub const PROC_STATE_RUNNABLE: usize = 1;
+pub const PROC_STATE_SLEEPING: usize = 2;
+pub const PROC_STATE_EXITED: usize = 3;
+
+/// `ProcStat::hart` of a process that is not running
+pub const PROC_HART_NONE: usize = usize::MAX;
+
+/// Descriptors `SYS_SELECT` waits on at most
+pub const SELECT_MAX: usize = 8;
+
+/// Console input among the descriptors passed to `SYS_SELECT`; other descriptors are pipe ids. The call
+/// returns the index of the ready descriptor.
+pub const SELECT_CONSOLE: usize = usize::MAX;
+
+/// `SYS_SELECT` timeout that waits until a descriptor is ready, however long that takes
+pub const SELECT_FOREVER: u64 = u64::MAX;
+
+/// Process information filled in by `SYS_PROC_STAT`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProcStat {
+    pub pid: usize,
+    pub state: usize,       // One of the PROC_STATE_* constants
+    pub cpu_ticks: u64,     // Timer ticks spent running
+    pub exit_code: usize,   // Status the process exited with; 0 while it runs
+    pub name: [u8; PROC_NAME_MAX],  // Process name, padded with nul bytes
+    pub hart: usize,        // Hart running the process, or PROC_HART_NONE
+}
@@ -4,15 +4,14 @@ use core::fmt;
 
 pub struct DebugConsole;
 
+// The kernel writes straight to the SBI console. User processes buffer lines and write them with a system call.
 unsafe extern "Rust" {
-    pub fn put_byte(b: u8) -> Result<isize, isize>;
+    pub fn console_write(buf: &[u8]) -> Result<usize, isize>;
 }
 
 impl fmt::Write for DebugConsole {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for b in s.bytes() {
-            unsafe { put_byte(b).map_err(|_| fmt::Error)?; }
-        }
+        unsafe { console_write(s.as_bytes()).map_err(|_| fmt::Error)?; }
         Ok(())
     }
 }
@@ -0,0 +1,116 @@
+//! System call numbers
+//!
+//! User code puts the number in `a7` before `ecall`. The kernel decodes it with `Syscall::try_from`, so a
+//! number it does not know fails with `ErrorCode::NoSys` rather than being mistaken for another call.
+
+/// A system call, numbered as passed in `a7`
+#[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syscall {
+    PutByte = 1,
+    GetChar = 2,
+    Exit = 3,
+    ReadFile = 4,
+    WriteFile = 5,
+    Stat = 6,
+    SchedLatencyHist = 7,
+    GetPid = 8,
+    InjectFault = 9,
+    Shutdown = 10,
+    PollChar = 11,
+    Spawn = 12,
+    Yield = 13,
+    Uptime = 14,
+    Kill = 15,
+    PipeCreate = 16,
+    PipeWrite = 17,
+    PipeRead = 18,
+    ProcStat = 19,
+    SetQuantum = 20,
+    Open = 21,
+    Close = 22,
+    Read = 23,
+    Write = 24,
+    Mmap = 25,
+    Munmap = 26,
+    MemInfo = 27,
+    SigAction = 28,
+    Signal = 29,
+    SigReturn = 30,
+    SchedStat = 31,
+    Exec = 32,
+    Truncate = 33,
+    Sync = 34,
+    ListDir = 35,
+    GetCharTimeout = 36,
+    V2P = 37,
+    FsInfo = 38,
+    SleepUntil = 39,
+    PutBytes = 40,
+    Select = 41,
+    SetTrace = 42,
+    Random = 43,
+    Wait = 44,
+    PipeClose = 45,
+}
+
+impl Syscall {
+    const ALL: [Self; 45] = [
+        Self::PutByte,
+        Self::GetChar,
+        Self::Exit,
+        Self::ReadFile,
+        Self::WriteFile,
+        Self::Stat,
+        Self::SchedLatencyHist,
+        Self::GetPid,
+        Self::InjectFault,
+        Self::Shutdown,
+        Self::PollChar,
+        Self::Spawn,
+        Self::Yield,
+        Self::Uptime,
+        Self::Kill,
+        Self::PipeCreate,
+        Self::PipeWrite,
+        Self::PipeRead,
+        Self::ProcStat,
+        Self::SetQuantum,
+        Self::Open,
+        Self::Close,
+        Self::Read,
+        Self::Write,
+        Self::Mmap,
+        Self::Munmap,
+        Self::MemInfo,
+        Self::SigAction,
+        Self::Signal,
+        Self::SigReturn,
+        Self::SchedStat,
+        Self::Exec,
+        Self::Truncate,
+        Self::Sync,
+        Self::ListDir,
+        Self::GetCharTimeout,
+        Self::V2P,
+        Self::FsInfo,
+        Self::SleepUntil,
+        Self::PutBytes,
+        Self::Select,
+        Self::SetTrace,
+        Self::Random,
+        Self::Wait,
+        Self::PipeClose,
+    ];
+}
+
+impl TryFrom<usize> for Syscall {
+    type Error = usize;
+
+    /// Decode a system call number, returning it unchanged if there is no such call
+    fn try_from(sysno: usize) -> Result<Self, Self::Error> {
+        Self::ALL.into_iter()
+            .find(|&syscall| syscall as usize == sysno)
+            .ok_or(sysno)
+    }
+}
@@ -0,0 +1,63 @@
+//! Trap frame
+//!
+//! Registers saved by the kernel's trap entry assembly, which addresses the fields by offset. The layout
+//! is defined once here and the offsets the assembly relies on are checked when this crate compiles.
+
+use core::mem::{offset_of, size_of};
+
+/// Registers saved on a trap, in the order `kernel_entry` stores them
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct TrapFrame {
+    pub ra: usize,      // 0
+    pub gp: usize,
+    pub tp: usize,
+    pub t0: usize,
+    pub t1: usize,
+    pub t2: usize,
+    pub t3: usize,
+    pub t4: usize,
+    pub t5: usize,
+    pub t6: usize,
+    pub a0: usize,
+    pub a1: usize,
+    pub a2: usize,
+    pub a3: usize,
+    pub a4: usize,
+    pub a5: usize,
+    pub a6: usize,
+    pub a7: usize,
+    pub s0: usize,
+    pub s1: usize,
+    pub s2: usize,
+    pub s3: usize,
+    pub s4: usize,
+    pub s5: usize,
+    pub s6: usize,
+    pub s7: usize,
+    pub s8: usize,
+    pub s9: usize,
+    pub s10: usize,
+    pub s11: usize,
+    pub sp: usize,          // 30
+    pub sscratch: usize,    // 31
+}
+
+impl TrapFrame {
+    pub const ZERO: Self = Self { ra: 0, gp: 0, tp: 0, t0: 0, t1: 0, t2: 0, t3: 0, t4: 0, t5: 0, t6: 0, a0: 0, a1: 0, a2: 0, a3: 0, a4: 0, a5: 0, a6: 0, a7: 0, s0: 0, s1: 0, s2: 0, s3: 0, s4: 0, s5: 0, s6: 0, s7: 0, s8: 0, s9: 0, s10: 0, s11: 0, sp: 0, sscratch: 0 };
+}
+
+/// Bytes reserved on the kernel stack for a trap frame
+pub const TRAP_FRAME_SIZE: usize = size_of::<TrapFrame>();
+/// Offset of the saved stack pointer
+pub const TRAP_FRAME_SP: usize = offset_of!(TrapFrame, sp);
+/// Offset of the saved `sscratch`, the kernel stack top of a user process
+pub const TRAP_FRAME_SSCRATCH: usize = offset_of!(TrapFrame, sscratch);
+
+// The entry assembly stores register n at 4 * n
+const _: () = assert!(TRAP_FRAME_SIZE == 32 * 4);
+const _: () = assert!(offset_of!(TrapFrame, ra) == 0);
+const _: () = assert!(offset_of!(TrapFrame, a0) == 10 * 4);
+const _: () = assert!(offset_of!(TrapFrame, s11) == 29 * 4);
+const _: () = assert!(TRAP_FRAME_SP == 30 * 4);
+const _: () = assert!(TRAP_FRAME_SSCRATCH == 31 * 4);
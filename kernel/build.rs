@@ -1,3 +1,10 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Slots in the file table unless OS1K_FILES_MAX says otherwise
+const DEFAULT_FILES_MAX: usize = 3;
+
 fn main() {
     // Add rustc linker arguments
     println!("cargo:rustc-link-arg=--Map=kernel/kernel.map");
@@ -8,4 +15,19 @@ fn main() {
 
     // Link the shell binary
     println!("cargo:rustc-link-arg=shell.bin.o");
+
+    // Size the file table, for tar.rs to include
+    let files_max = match env::var("OS1K_FILES_MAX") {
+        Ok(value) => value.parse::<usize>()
+            .ok()
+            .filter(|&files_max| files_max > 0)
+            .expect("OS1K_FILES_MAX should be a number of files above zero"),
+        Err(_) => DEFAULT_FILES_MAX,
+    };
+    let out_dir = env::var("OUT_DIR").expect("cargo should set OUT_DIR");
+    fs::write(
+        Path::new(&out_dir).join("files_max.rs"),
+        format!("pub const FILES_MAX: usize = {};\n", files_max),
+    ).expect("should write files_max.rs");
+    println!("cargo:rerun-if-env-changed=OS1K_FILES_MAX");
 }
@@ -1,5 +1,9 @@
 //! Address functions for os1k
 
+use core::ops::Add;
+
+use crate::page::PAGE_SIZE;
+
 // Physical Address
 #[derive(Clone, Copy, Debug)]
 #[repr(transparent)]
@@ -21,6 +25,18 @@ impl PAddr {
     pub const fn as_ptr_mut(&mut self) -> *mut usize {
          self.0 as *mut usize
     }
+
+    /// The start of the page holding this address
+    pub const fn align_down(self) -> Self {
+        Self(align_down(self.0, PAGE_SIZE))
+    }
+}
+
+impl Add<usize> for PAddr {
+    type Output = Self;
+    fn add(self, offset: usize) -> Self {
+        Self(self.0 + offset)
+    }
 }
 
 // Virtual Address
@@ -37,9 +53,34 @@ impl VAddr {
         self.0
     }
 
+    /// A raw pointer to the address itself, for assembly that stores an address in place
+    ///
+    /// The pointer is only valid while `self` is neither moved nor dropped, and it carries no borrow, so
+    /// nothing stops it outliving either. Prefer a reference, such as from `Process::sp_slot`.
     pub const fn field_raw_ptr(&mut self) -> *mut usize {
         &raw mut self.0
     }
+
+    /// The start of the page holding this address
+    pub const fn align_down(self) -> Self {
+        Self(align_down(self.0, PAGE_SIZE))
+    }
+}
+
+impl Add<usize> for VAddr {
+    type Output = Self;
+    fn add(self, offset: usize) -> Self {
+        Self(self.0 + offset)
+    }
+}
+
+/// Each page from `start` up to `end`, identity mapped, as a virtual and physical address pair
+///
+/// `start` is rounded down and `end` up to whole pages, so a partial page at either end is included.
+pub fn page_range(start: usize, end: usize) -> impl Iterator<Item = (VAddr, PAddr)> {
+    (align_down(start, PAGE_SIZE)..align_up(end, PAGE_SIZE))
+        .step_by(PAGE_SIZE)
+        .map(|addr| (VAddr::new(addr), PAddr::new(addr)))
 }
 
 pub const fn align_up(value: usize, align: usize) -> usize {
@@ -48,6 +89,12 @@ pub const fn align_up(value: usize, align: usize) -> usize {
     (value + (align - 1)) & !(align - 1)
 }
 
+pub const fn align_down(value: usize, align: usize) -> usize {
+    debug_assert!(align.is_power_of_two());
+
+    value & !(align - 1)
+}
+
 pub const fn is_aligned(value: usize, align: usize) -> bool {
     assert!(align.is_power_of_two(), "align must be a power of 2");
     let align_mask = align - 1;
@@ -57,7 +104,7 @@ pub const fn is_aligned(value: usize, align: usize) -> bool {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{print, println};
+    use crate::print;
 
     #[test_case]
     fn is_address_aligned() {
@@ -66,7 +113,7 @@ mod test {
         assert!(is_aligned(0x10000000, 512) == true);
         assert!(is_aligned(0x10000001, 1024) == false);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -75,6 +122,45 @@ mod test {
 
         assert!(align_up(0x10000001, 512) == 0x10000200);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
+    }
+
+    #[test_case]
+    fn align_address_down() {
+        print!("address: align address down...");
+
+        assert!(align_down(0x10000201, 512) == 0x10000200);
+        assert!(align_down(0x10000200, 512) == 0x10000200);
+        assert!(VAddr::new(0x10000fff).align_down().as_usize() == 0x10000000);
+        assert!(VAddr::new(0x10001000).align_down().as_usize() == 0x10001000);
+        assert!(PAddr::new(0x80200001).align_down().as_usize() == 0x80200000);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn add_to_address() {
+        print!("address: add to address...");
+
+        assert!((VAddr::new(0x10000000) + PAGE_SIZE).as_usize() == 0x10001000);
+        assert!((PAddr::new(0x80200ffc) + 4).as_usize() == 0x80201000);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn iterate_page_range() {
+        print!("address: iterate page range...");
+
+        // Whole pages from the start up to but not including the end
+        let pages = || page_range(0x80200000, 0x80203000).map(|(v, p)| (v.as_usize(), p.as_usize()));
+        assert!(pages().count() == 3);
+        assert!(pages().eq([(0x80200000, 0x80200000), (0x80201000, 0x80201000), (0x80202000, 0x80202000)]));
+
+        // Partial pages at either end are included
+        assert!(page_range(0x80200fff, 0x80201001).count() == 2);
+        assert!(page_range(0x80201000, 0x80201000).count() == 0);
+
+        common::ok!();
     }
 }
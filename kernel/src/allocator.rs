@@ -1,9 +1,14 @@
 //! Allocate memory pages
 
 use core::alloc::{GlobalAlloc, Layout};
-use core::ptr::write_bytes;
+use core::ops::Range;
+use core::ptr::{null_mut, write_bytes};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use common::{ErrorCode, FAULT_ALLOC};
 
 use crate::address::{align_up, PAddr};
+use crate::fault::should_fail;
 use crate::page::PAGE_SIZE;
 use crate::spinlock::SpinLock;
 
@@ -13,50 +18,191 @@ unsafe extern "C" {
     static __free_ram_end: u8;
 }
 
+/// Physical address range handed out by the allocator
+///
+/// The last page before `__free_ram_end` is a guard page that is never allocated or mapped,
+/// so running off the end of the heap faults rather than reaching whatever follows it.
+pub fn heap_range() -> Range<usize> {
+    let free_ram = &raw const __free_ram as usize;
+    let free_ram_end = &raw const __free_ram_end as usize;
+    free_ram..free_ram_end - PAGE_SIZE
+}
+
+/// Bytes of heap not yet allocated, counting freed pages waiting to be reused
+pub fn bytes_available() -> usize {
+    let heap = ALLOCATOR.0.lock();
+    let range = heap_range();
+    range.end - heap.next_paddr.map_or(range.start, |paddr| paddr.as_usize()) + heap.free_count * PAGE_SIZE
+}
+
+/// Allocation work since boot, to measure what zeroed allocations save
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AllocStats {
+    pub allocs: usize,          // Allocations made
+    pub bytes_filled: usize,    // Bytes written to poison or zero new allocations
+    pub bytes_zeroed: usize,    // Of those, bytes zeroed by `alloc_zeroed`, which would otherwise be filled twice
+}
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static BYTES_FILLED: AtomicUsize = AtomicUsize::new(0);
+static BYTES_ZEROED: AtomicUsize = AtomicUsize::new(0);
+
+pub fn alloc_stats() -> AllocStats {
+    AllocStats {
+        allocs: ALLOCS.load(Ordering::Relaxed),
+        bytes_filled: BYTES_FILLED.load(Ordering::Relaxed),
+        bytes_zeroed: BYTES_ZEROED.load(Ordering::Relaxed),
+    }
+}
+
+// Count an allocation of `size` bytes, filled once
+fn count_alloc(size: usize) {
+    ALLOCS.fetch_add(1, Ordering::Relaxed);
+    BYTES_FILLED.fetch_add(size, Ordering::Relaxed);
+}
+
+/// Heap kept back from user frames, so that the kernel's own allocations such as page tables still
+/// succeed once user processes have taken the rest
+const KERNEL_RESERVE: usize = 64 * PAGE_SIZE;
+
+// Layout of a frame, as `Box<[u8; PAGE_SIZE]>` allocates it
+const FRAME_LAYOUT: Layout = Layout::new::<[u8; PAGE_SIZE]>();
+
+/// Allocate a zeroed page for user memory
+///
+/// Returns `Err(NoSpace)` rather than taking the heap kept for the kernel, or if an allocation failure was
+/// injected. Free it with `free_frame`.
+pub fn alloc_frame() -> Result<&'static mut [u8; PAGE_SIZE], ErrorCode> {
+    if should_fail(FAULT_ALLOC) || bytes_available() < KERNEL_RESERVE + PAGE_SIZE {
+        return Err(ErrorCode::NoSpace);
+    }
+    // Safety: FRAME_LAYOUT has a non-zero size
+    let frame = unsafe { ALLOCATOR.alloc_zeroed(FRAME_LAYOUT) };
+    if frame.is_null() {
+        return Err(ErrorCode::NoSpace);
+    }
+    // Safety: The allocation is a whole page, zeroed, and owned by the caller from here
+    Ok(unsafe { &mut *(frame as *mut [u8; PAGE_SIZE]) })
+}
+
+/// Free a page allocated by `alloc_frame`
+///
+/// # Safety
+/// `paddr` must be a frame from `alloc_frame` that is no longer mapped or otherwise used.
+pub unsafe fn free_frame(paddr: PAddr) {
+    // Safety: The caller passes a frame allocated with FRAME_LAYOUT
+    unsafe { ALLOCATOR.dealloc(paddr.as_usize() as *mut u8, FRAME_LAYOUT) };
+}
+
 #[derive(Debug)]
-struct BumpAllocator(SpinLock<Option<PAddr>>);
+struct Heap {
+    next_paddr: Option<PAddr>,  // Start of the heap not yet handed out; `None` until first use
+    free_pages: Option<PAddr>,  // Freed single pages, each holding the address of the next in its first word
+    free_count: usize,
+}
+
+/// Hands out whole pages from the heap in order
+///
+/// Freed allocations of a single page, which user frames and page tables are, go on a free list for the
+/// next single page allocation. Larger allocations are never reused.
+#[derive(Debug)]
+struct BumpAllocator(SpinLock<Heap>);
 
 #[global_allocator]
 static ALLOCATOR: BumpAllocator = BumpAllocator(
-    SpinLock::new(None),
+    SpinLock::new(Heap { next_paddr: None, free_pages: None, free_count: 0 }),
 );
 
-unsafe impl GlobalAlloc for BumpAllocator {
-    // Safety: Caller must ensure that Layout has a non-zero size
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl BumpAllocator {
+    // Reserve whole pages for `layout`, returning the start address without initialising them,
+    // or `None` if the heap is used up
+    fn bump(&self, layout: Layout) -> Option<(PAddr, usize)> {
         debug_assert!(layout.size() > 0, "allocation size must be non-zero");
 
-        let mut next_paddr = self.0.lock();
+        let mut heap = self.0.lock();
+        let aligned_size = align_up(layout.size(), PAGE_SIZE);
+
+        if aligned_size == PAGE_SIZE
+            && let Some(page) = heap.free_pages
+        {
+            // Safety: Pages on the free list are unused, and their first word was written by `dealloc`
+            heap.free_pages = match unsafe { *page.as_ptr() } {
+                0 => None,
+                next => Some(PAddr::new(next)),
+            };
+            heap.free_count -= 1;
+            return Some((page, aligned_size));
+        }
 
         // Initialise on first use
-        let mut paddr = *next_paddr.get_or_insert_with(|| {
-            PAddr::new(&raw const __free_ram as usize)
+        let paddr = *heap.next_paddr.get_or_insert_with(|| {
+            PAddr::new(heap_range().start)
         });
 
-        let aligned_size = align_up(layout.size(), PAGE_SIZE);
-
-        let new_paddr = paddr.as_usize() + aligned_size;
-        if new_paddr > &raw const __free_ram_end as usize {
-            panic!("out of memory");
+        let new_paddr = paddr + aligned_size;
+        if new_paddr.as_usize() > heap_range().end {
+            return None;
         }
 
-        *next_paddr = Some(PAddr::new(new_paddr));
+        heap.next_paddr = Some(new_paddr);
+
+        Some((paddr, aligned_size))
+    }
+}
+
+unsafe impl GlobalAlloc for BumpAllocator {
+    // Safety: Caller must ensure that Layout has a non-zero size
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((mut paddr, aligned_size)) = self.bump(layout) else {
+            return null_mut();
+        };
 
         unsafe{
             // Safety: paddr.as_ptr_mut() is aligned and not null; entire aligned_size of bytes is available for write
+            // Poison with 0x55 so reads of uninitialised memory stand out
             write_bytes(paddr.as_ptr_mut() as *mut u8, 0x55, aligned_size)
         };
+        count_alloc(aligned_size);
+
+        paddr.as_ptr() as *mut u8
+    }
+
+    // Safety: Caller must ensure that Layout has a non-zero size
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let Some((mut paddr, aligned_size)) = self.bump(layout) else {
+            return null_mut();
+        };
+
+        unsafe{
+            // Safety: paddr.as_ptr_mut() is aligned and not null; entire aligned_size of bytes is available for write
+            // Zero directly rather than poisoning first; write_bytes lowers to a single memset call
+            write_bytes(paddr.as_ptr_mut() as *mut u8, 0, aligned_size)
+        };
+        count_alloc(aligned_size);
+        BYTES_ZEROED.fetch_add(aligned_size, Ordering::Relaxed);
 
         paddr.as_ptr() as *mut u8
     }
 
-    unsafe fn dealloc(&self, _: *mut u8, _: Layout) {}
+    // Safety: Caller must pass a pointer allocated by this allocator with the same layout
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if align_up(layout.size(), PAGE_SIZE) != PAGE_SIZE {
+            return;
+        }
+        let mut heap = self.0.lock();
+        let next = heap.free_pages.map_or(0, |page| page.as_usize());
+        // Safety: The page is no longer used by its owner, so its first word can link the free list
+        unsafe { *(ptr as *mut usize) = next };
+        heap.free_pages = Some(PAddr::new(ptr as usize));
+        heap.free_count += 1;
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use super::*;
     use alloc::vec;
-    use crate::{print, println};
+    use crate::print;
 
     #[test_case]
     fn allocate_a_vec() {
@@ -65,6 +211,90 @@ mod test {
         let v = vec![1, 2, 3];
         assert!(v == [1, 2, 3]);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
+    }
+
+    #[test_case]
+    fn heap_stops_before_guard_page() {
+        print!("allocator: heap stops before guard page...");
+
+        let heap = heap_range();
+        assert!(heap.end == &raw const __free_ram_end as usize - PAGE_SIZE);
+
+        let v = vec![0u8; 16];
+        assert!(heap.contains(&(v.as_ptr() as usize)));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn bytes_available_shrinks() {
+        print!("allocator: bytes available shrinks...");
+
+        let before = bytes_available();
+        let v = vec![0u8; 4 * PAGE_SIZE];
+        assert!(bytes_available() == before - v.len());
+        assert!(bytes_available() < heap_range().len());
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn allocate_zeroed_vec() {
+        print!("allocator: allocate zeroed vec...");
+
+        // vec! of zeroes uses alloc_zeroed, which must not leave the 0x55 poison behind
+        let v = vec![0u8; 3 * 4096 + 1];
+        assert!(v.iter().all(|&b| b == 0));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn zeroed_allocation_is_filled_once() {
+        print!("allocator: zeroed allocation is filled once...");
+
+        let before = alloc_stats();
+        let v = vec![0u8; 2 * PAGE_SIZE];
+        let after = alloc_stats();
+        assert!(after.allocs == before.allocs + 1);
+        assert!(after.bytes_filled == before.bytes_filled + v.len());
+        assert!(after.bytes_zeroed == before.bytes_zeroed + v.len());
+
+        // Other allocations are poisoned instead
+        let v = vec![1u8; PAGE_SIZE];
+        let last = alloc_stats();
+        assert!(last.bytes_filled == after.bytes_filled + v.len());
+        assert!(last.bytes_zeroed == after.bytes_zeroed);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn freed_frame_is_reused() {
+        print!("allocator: freed frame is reused...");
+
+        let frame = alloc_frame()
+            .expect("should allocate a frame");
+        frame[0] = 1;
+        let paddr = PAddr::new(frame.as_ptr() as usize);
+        let before = bytes_available();
+
+        // Safety: The frame is not used again
+        unsafe { free_frame(paddr) };
+        assert!(bytes_available() == before + PAGE_SIZE);
+
+        // Allocating and freeing in a loop, as mmap and munmap do, takes no more of the heap
+        for _ in 0..16 {
+            let frame = alloc_frame()
+                .expect("should allocate a frame");
+            assert!(frame.as_ptr() as usize == paddr.as_usize());
+            assert!(frame.iter().all(|&b| b == 0));
+            // Safety: The frame is not used again
+            unsafe { free_frame(PAddr::new(frame.as_ptr() as usize)) };
+        }
+        assert!(bytes_available() == before + PAGE_SIZE);
+
+        common::ok!();
     }
 }
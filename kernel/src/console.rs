@@ -0,0 +1,250 @@
+//! Console input
+//!
+//! The SBI console raises no interrupt when a byte arrives, so the timer interrupt polls it each tick and
+//! buffers what it reads, so keystrokes are kept while no process is reading. A process reading the console
+//! sleeps until then instead of spinning on the SBI.
+
+use crate::sbi::sbi;
+use crate::scheduler::{sleep_until, sleep_while, wake_sleeping};
+use crate::spinlock::SpinLock;
+use crate::timer::{get_timer, millisecs_to_ticks};
+
+const INPUT_MAX: usize = 64;    // Bytes buffered between reads; beyond this the oldest are dropped
+
+struct Input {
+    bytes: [u8; INPUT_MAX],
+    start: usize,   // Index of the oldest byte
+    len: usize,
+}
+
+impl Input {
+    const fn new() -> Self {
+        Self { bytes: [0; INPUT_MAX], start: 0, len: 0 }
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == INPUT_MAX
+    }
+
+    // Add a byte, dropping the oldest if full. Returns whether one was dropped.
+    fn push(&mut self, b: u8) -> bool {
+        let dropped = self.is_full();
+        if dropped {
+            self.start = (self.start + 1) % INPUT_MAX;
+            self.len -= 1;
+        }
+        self.bytes[(self.start + self.len) % INPUT_MAX] = b;
+        self.len += 1;
+        dropped
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let b = self.bytes[self.start];
+        self.start = (self.start + 1) % INPUT_MAX;
+        self.len -= 1;
+        Some(b)
+    }
+}
+
+static INPUT: SpinLock<Input> = SpinLock::new(Input::new());
+
+/// Buffer any bytes waiting at the SBI console and wake sleeping processes if one arrived
+///
+/// Called from the timer interrupt. If it interrupted a reader holding the buffer, this tick is skipped.
+pub fn poll_console() {
+    let Some(mut input) = INPUT.try_lock() else {
+        return;
+    };
+    let (mut arrived, mut dropped) = (false, 0);
+    while let Ok(ch) = sbi().get_char() {
+        if input.push(ch as u8) {
+            dropped += 1;
+        }
+        arrived = true;
+    }
+    drop(input);
+
+    if dropped > 0 {
+        klog!(WARN, "console: input full, dropped {} oldest bytes", dropped);
+    }
+
+    if arrived {
+        wake_sleeping();
+    }
+}
+
+/// Take the next byte from the console without waiting
+///
+/// Returns the SBI error, -1 if no byte is ready, when nothing is buffered.
+pub fn read_byte() -> Result<u8, isize> {
+    if let Some(b) = INPUT.lock().pop() {
+        return Ok(b);
+    }
+    sbi().get_char().map(|ch| ch as u8)
+}
+
+/// Whether a byte from the console is waiting to be read, buffering one from the SBI if need be
+pub fn has_input() -> bool {
+    let mut input = INPUT.lock();
+    if input.len == 0 {
+        let _ = sbi().get_char().map(|ch| input.push(ch as u8));
+    }
+    input.len > 0
+}
+
+/// Wait for the next byte from the console, sleeping until `poll_console` finds one
+pub fn get_byte() -> u8 {
+    let mut byte = None;
+    sleep_while(|| {
+        byte = read_byte().ok();
+        byte.is_none()
+    });
+    byte.expect("should have a byte once awake")
+}
+
+/// Wait up to `millisecs` for the next byte from the console, or `None` if none arrives in time
+///
+/// A zero timeout only polls. The timer interrupt ends the wait, so it may run over by up to a time slice.
+/// Returns the SBI error if the console fails.
+pub fn get_byte_timeout(millisecs: u64) -> Result<Option<u8>, isize> {
+    let deadline = get_timer().saturating_add(millisecs_to_ticks(millisecs));
+    let mut result = Ok(None);
+    sleep_until(deadline, || {
+        result = match read_byte() {
+            Ok(b) => Ok(Some(b)),
+            Err(-1) => Ok(None),    // No byte ready yet
+            Err(e) => Err(e),
+        };
+        result == Ok(None) && get_timer() < deadline
+    });
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+    use crate::process::State;
+    use crate::sbi::{use_mock_sbi, MOCK_SBI};
+    use crate::scheduler::{PROCS, SSTATUS_SIE};
+
+    #[test_case]
+    fn input_wraps_in_order() {
+        print!("console: input wraps in order...");
+
+        let mut input = Input::new();
+        for round in 0..3 {
+            for i in 0..INPUT_MAX {
+                assert!(!input.push((round + i) as u8));
+            }
+            assert!(input.is_full());
+            for i in 0..INPUT_MAX {
+                assert!(input.pop() == Some((round + i) as u8));
+            }
+            assert!(input.pop().is_none());
+            assert!(!input.push(0xff));
+            assert!(input.pop() == Some(0xff));
+        }
+
+        // Overflow drops the oldest bytes
+        for i in 0..INPUT_MAX + 2 {
+            assert!(input.push(i as u8) == (i >= INPUT_MAX));
+        }
+        for i in 2..INPUT_MAX + 2 {
+            assert!(input.pop() == Some(i as u8));
+        }
+        assert!(input.pop().is_none());
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn input_kept_until_read() {
+        print!("console: input kept until read...");
+
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        use_mock_sbi(true);
+
+        // Bytes typed over several ticks with no reader are all kept
+        for &b in b"abc" {
+            *MOCK_SBI.input.lock() = Some(b);
+            poll_console();
+        }
+        let bytes = [read_byte(), read_byte(), read_byte(), read_byte()];
+
+        use_mock_sbi(false);
+        write_csr!("sstatus", sstatus);
+
+        assert!(bytes == [Ok(b'a'), Ok(b'b'), Ok(b'c'), Err(-1)]);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn zero_timeout_polls() {
+        print!("console: zero timeout polls...");
+
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        use_mock_sbi(true);
+        let empty = get_byte_timeout(0);
+        *MOCK_SBI.input.lock() = Some(b'z');
+        let byte = get_byte_timeout(0);
+        use_mock_sbi(false);
+        write_csr!("sstatus", sstatus);
+
+        assert!(empty == Ok(None));
+        assert!(byte == Ok(Some(b'z')));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn blocked_reader_sleeps_until_input() {
+        print!("console: blocked reader sleeps until input...");
+
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        use_mock_sbi(true);
+
+        // Put the last process to sleep as if it were waiting in get_byte
+        let (pid, state, cpu_ticks) = {
+            let mut procs = PROCS.0.lock();
+            let p = procs.last_mut().expect("should have a process");
+            let saved = (p.pid, p.state, p.cpu_ticks);
+            p.state = State::Sleeping;
+            saved
+        };
+
+        // Ticks with no input leave it asleep: never picked to run, so it spends no CPU ticks
+        for _ in 0..3 {
+            poll_console();
+            assert!(PROCS.get_next(pid) != pid);
+        }
+        let asleep = PROCS.0.lock().iter().find(|p| p.pid == pid).map(|p| (p.state, p.cpu_ticks));
+        assert!(asleep == Some((State::Sleeping, cpu_ticks)));
+
+        // A byte arriving wakes it and is buffered for it to read
+        *MOCK_SBI.input.lock() = Some(b'k');
+        poll_console();
+        let woken = PROCS.0.lock().iter().find(|p| p.pid == pid).map(|p| p.state);
+        let byte = read_byte();
+        let empty = read_byte();
+
+        if let Some(p) = PROCS.0.lock().iter_mut().find(|p| p.pid == pid) {
+            p.state = state;
+        }
+        use_mock_sbi(false);
+        write_csr!("sstatus", sstatus);
+
+        assert!(woken == Some(State::Runnable));
+        assert!(byte == Ok(b'k'));
+        assert!(empty == Err(-1));
+
+        common::ok!();
+    }
+}
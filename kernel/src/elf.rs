@@ -0,0 +1,107 @@
+//! ELF executables
+//!
+//! Just enough of the ELF format to load a statically linked 32-bit RISC-V executable: the entry point and
+//! the `PT_LOAD` program headers.
+
+use crate::page::{PAGE_R, PAGE_W, PAGE_X};
+
+pub const SEGMENTS_MAX: usize = 4;     // Loadable segments in an image
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_32: u8 = 1;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_RISCV: u16 = 0xf3;
+
+const HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const PT_LOAD: u32 = 1;
+
+// Segment permissions in `p_flags`
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+/// A part of an image mapped at `vaddr`, from `file_size` bytes of the image at `offset` then zeroes up to
+/// `mem_size`
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Segment {
+    pub vaddr: usize,
+    pub mem_size: usize,
+    pub offset: usize,
+    pub file_size: usize,
+    pub flags: usize,       // `PAGE_R` combined with `PAGE_W` or `PAGE_X`
+}
+
+/// Entry point and loadable segments of an ELF executable
+#[derive(Copy, Clone, Debug)]
+pub struct Elf {
+    pub entry: usize,
+    pub segments: [Segment; SEGMENTS_MAX],
+    pub segment_count: usize,
+}
+
+/// Whether `image` starts with the ELF magic number
+pub fn is_elf(image: &[u8]) -> bool {
+    image.starts_with(MAGIC)
+}
+
+fn read_u16(image: &[u8], off: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(image.get(off..off + 2)?.try_into().ok()?))
+}
+
+fn read_u32(image: &[u8], off: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(image.get(off..off + 4)?.try_into().ok()?))
+}
+
+impl Elf {
+    /// Read the entry point and `PT_LOAD` segments of a 32-bit little-endian RISC-V executable
+    ///
+    /// Returns `None` if the headers are truncated or not for this machine, a segment reaches past the end of
+    /// the image, is not readable or is both writable and executable, or there are more than `SEGMENTS_MAX`.
+    /// Where segments are placed is left to the caller to check.
+    pub fn parse(image: &[u8]) -> Option<Self> {
+        let is_supported = is_elf(image)
+            && image.len() >= HEADER_SIZE
+            && image.get(4) == Some(&CLASS_32)
+            && image.get(5) == Some(&DATA_LITTLE_ENDIAN)
+            && read_u16(image, 16)? == TYPE_EXEC
+            && read_u16(image, 18)? == MACHINE_RISCV
+            && usize::from(read_u16(image, 42)?) == PROGRAM_HEADER_SIZE;
+        if !is_supported {
+            return None;
+        }
+        let entry = read_u32(image, 24)? as usize;
+        let program_headers = read_u32(image, 28)? as usize;
+        let program_header_count = usize::from(read_u16(image, 44)?);
+
+        let mut elf = Self { entry, segments: [Segment::default(); SEGMENTS_MAX], segment_count: 0 };
+        for i in 0..program_header_count {
+            let off = program_headers.checked_add(i * PROGRAM_HEADER_SIZE)?;
+            if read_u32(image, off)? != PT_LOAD {
+                continue;
+            }
+            let p_flags = read_u32(image, off + 24)?;
+            let segment = Segment {
+                vaddr: read_u32(image, off + 8)? as usize,
+                mem_size: read_u32(image, off + 20)? as usize,
+                offset: read_u32(image, off + 4)? as usize,
+                file_size: read_u32(image, off + 16)? as usize,
+                flags: [(PF_R, PAGE_R), (PF_W, PAGE_W), (PF_X, PAGE_X)].iter()
+                    .filter(|&&(pf, _)| p_flags & pf != 0)
+                    .fold(0, |flags, &(_, page_flag)| flags | page_flag),
+            };
+
+            let is_valid = segment.file_size <= segment.mem_size
+                && segment.offset.checked_add(segment.file_size).is_some_and(|end| end <= image.len())
+                && segment.flags & PAGE_R != 0
+                && segment.flags & (PAGE_W | PAGE_X) != PAGE_W | PAGE_X;
+            if !is_valid {
+                return None;
+            }
+            *elf.segments.get_mut(elf.segment_count)? = segment;
+            elf.segment_count += 1;
+        }
+        Some(elf)
+    }
+}
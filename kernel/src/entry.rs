@@ -2,6 +2,8 @@
 
 use core::arch::naked_asm;
 
+use common::trap::{TRAP_FRAME_SIZE, TRAP_FRAME_SP, TRAP_FRAME_SSCRATCH};
+
 use crate::scheduler::SSTATUS_SIE;
 
 #[unsafe(naked)]
@@ -17,7 +19,7 @@ pub unsafe extern "C" fn kernel_entry() {
         "csrr sp, sscratch",            // Get kernel sp back from sscratch
 
         "1:",
-        "addi sp, sp, -4 * 32",
+        "addi sp, sp, -{frame_size}",
         "sw ra,  4 * 0(sp)",
         "sw gp,  4 * 1(sp)",
         "sw tp,  4 * 2(sp)",
@@ -54,15 +56,16 @@ pub unsafe extern "C" fn kernel_entry() {
         "bnez a0, 2f",              // Check if sscratch is non zero (user process)
 
         // Kernel process
-        "sw sp, 4 * 30(sp)",        // Kernel process using already the actual stack pointer
-        "sw zero, 4 * 31(sp)",      // Kernel process sscratch stored as zero
+        "sw sp, {frame_sp}(sp)",        // Kernel process using already the actual stack pointer
+        "sw zero, {frame_sscratch}(sp)", // Kernel process sscratch stored as zero
         "j 3f",
 
         // User process
         "2:",
-        "sw a0, 4 * 30(sp)",        // User process, have just loaded stack pointer into a0
-        "addi a0, sp, 4 * 32",      // a0 = sp + trap frame which is kernel stack top
-        "sw a0, 4 * 31(sp)",
+        "sw a0, {frame_sp}(sp)",        // User process, have just loaded stack pointer into a0
+        "addi a0, sp, {frame_size}",    // a0 = sp + trap frame which is kernel stack top
+        "sw a0, {frame_sscratch}(sp)",
+        "lw tp, (a0)",                  // User code may have changed tp; switch_context left the hart number here
 
         "3:",
         // Now set sscratch to zero for kernel space
@@ -75,12 +78,16 @@ pub unsafe extern "C" fn kernel_entry() {
         // Disable interrupts atomically and check value
         "csrrci t0, sstatus, {sstatus_sie}",
 
-        "lw a0, 31 * 4(sp)",            // Load stored sscratch value into temp register
+        "lw a0, {frame_sscratch}(sp)",  // Load stored sscratch value into temp register
         "csrw sscratch, a0",            // Restore sscratch to before trap
 
+        // Returning to the kernel, tp stays the number of this hart, which may not be the one that trapped
+        "beqz a0, 6f",
+        "lw tp,  4 *  2(sp)",
+        "6:",
+
         "lw ra,  4 *  0(sp)",
         "lw gp,  4 *  1(sp)",
-        "lw tp,  4 *  2(sp)",
         // "lw t0,  4 *  3(sp)",        // t0 temp holding interrupt status
         "lw t1,  4 *  4(sp)",
         "lw t2,  4 *  5(sp)",
@@ -112,17 +119,20 @@ pub unsafe extern "C" fn kernel_entry() {
         // Re-enable interrupts if they were enabled
         "beqz t0, 4f",
         "lw t0,  4 *  3(sp)",        // Restore t0
-        "lw sp,  4 * 30(sp)",
+        "lw sp,  {frame_sp}(sp)",
         "csrsi sstatus, {sstatus_sie}",
         "j 5f",
 
         "4:",
         "lw t0,  4 *  3(sp)",        // Restore t0
-        "lw sp,  4 * 30(sp)",
+        "lw sp,  {frame_sp}(sp)",
 
         "5:",
         "sret",
         sstatus_sie = const SSTATUS_SIE,
+        frame_size = const TRAP_FRAME_SIZE,
+        frame_sp = const TRAP_FRAME_SP,
+        frame_sscratch = const TRAP_FRAME_SSCRATCH,
     );
 }
 
@@ -150,7 +160,37 @@ macro_rules! write_csr {
 
 #[cfg(test)]
 mod test {
-    use crate::{print, println};
+    use core::mem::offset_of;
+
+    use common::trap::TrapFrame;
+
+    use crate::print;
+
+    #[test_case]
+    fn trap_frame_offsets() {
+        print!("entry: trap frame offsets match kernel_entry...");
+
+        // kernel_entry saves register n at 4 * n
+        let offsets = [
+            offset_of!(TrapFrame, ra), offset_of!(TrapFrame, gp), offset_of!(TrapFrame, tp),
+            offset_of!(TrapFrame, t0), offset_of!(TrapFrame, t1), offset_of!(TrapFrame, t2),
+            offset_of!(TrapFrame, t3), offset_of!(TrapFrame, t4), offset_of!(TrapFrame, t5),
+            offset_of!(TrapFrame, t6), offset_of!(TrapFrame, a0), offset_of!(TrapFrame, a1),
+            offset_of!(TrapFrame, a2), offset_of!(TrapFrame, a3), offset_of!(TrapFrame, a4),
+            offset_of!(TrapFrame, a5), offset_of!(TrapFrame, a6), offset_of!(TrapFrame, a7),
+            offset_of!(TrapFrame, s0), offset_of!(TrapFrame, s1), offset_of!(TrapFrame, s2),
+            offset_of!(TrapFrame, s3), offset_of!(TrapFrame, s4), offset_of!(TrapFrame, s5),
+            offset_of!(TrapFrame, s6), offset_of!(TrapFrame, s7), offset_of!(TrapFrame, s8),
+            offset_of!(TrapFrame, s9), offset_of!(TrapFrame, s10), offset_of!(TrapFrame, s11),
+            offset_of!(TrapFrame, sp), offset_of!(TrapFrame, sscratch),
+        ];
+        for (n, &offset) in offsets.iter().enumerate() {
+            assert_eq!(offset, 4 * n);
+        }
+        assert_eq!(size_of::<TrapFrame>(), 4 * offsets.len());
+
+        common::ok!();
+    }
 
     #[test_case]
     fn write_and_read_csr() {
@@ -162,6 +202,6 @@ mod test {
         assert!(sscratch == read_csr!("sscratch"));
         write_csr!("sscratch", tmp);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 }
@@ -0,0 +1,51 @@
+//! Fault injection for testing error paths
+//!
+//! Arming a fault kind with a count makes the next `count` operations of that kind fail. Faults can only be
+//! armed in kernel tests or with the `fault-injection` feature; otherwise nothing ever fails.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use common::FAULT_KINDS;
+
+// Remaining number of operations to fail, per fault kind
+static ARMED: [AtomicUsize; FAULT_KINDS] = [const { AtomicUsize::new(0) }; FAULT_KINDS];
+
+/// Arm the next `count` operations of `kind` to fail
+///
+/// Returns `Err` for an unknown fault kind.
+#[cfg(any(test, feature = "fault-injection"))]
+pub fn inject_fault(kind: usize, count: usize) -> Result<(), ()> {
+    let armed = ARMED.get(kind).ok_or(())?;
+    armed.store(count, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Check whether this operation should fail, consuming one armed fault if so
+pub fn should_fail(kind: usize) -> bool {
+    cfg!(any(test, feature = "fault-injection")) && ARMED[kind]
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+    use common::{FAULT_ALLOC, FAULT_FILE_WRITE};
+
+    #[test_case]
+    fn armed_fault_counts_down() {
+        print!("fault: armed fault counts down...");
+
+        inject_fault(FAULT_FILE_WRITE, 2)
+            .expect("should arm a known fault kind");
+        assert!(should_fail(FAULT_FILE_WRITE));
+        assert!(should_fail(FAULT_FILE_WRITE));
+        assert!(!should_fail(FAULT_FILE_WRITE));
+        assert!(!should_fail(FAULT_ALLOC));
+
+        assert!(inject_fault(FAULT_KINDS, 1).is_err());
+
+        common::ok!();
+    }
+}
@@ -0,0 +1,158 @@
+//! Harts
+//!
+//! Harts are numbered from 0 in the order they start, the boot hart first, and the kernel keeps the number
+//! in `tp`. User code may change `tp`, so the trap entry reloads it from the top of the kernel stack, where
+//! `switch_context` leaves it. Secondary harts are started through the SBI Hart State Management extension
+//! and park until the boot hart starts scheduling, then take processes from the same table.
+
+use core::arch::{asm, naked_asm};
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use crate::entry::kernel_entry;
+use crate::sbi;
+use crate::scheduler::{run_idle, scheduler_init_hart, scheduler_start_hart};
+use crate::spinlock::cpu_relax;
+
+pub const HARTS_MAX: usize = 2;     // Harts the kernel runs on; any others stay stopped
+const HART_IDS_MAX: usize = 8;      // SBI hart IDs tried when looking for secondary harts
+const BOOT_STACK_SIZE: usize = 8192;
+
+/// The number of the hart running this code
+#[inline]
+pub fn hart_id() -> usize {
+    let id: usize;
+    unsafe {
+        // Safety: only reads tp, which the kernel keeps as the hart number
+        asm!("mv {}, tp", out(reg) id, options(nomem, nostack, preserves_flags));
+    }
+    id
+}
+
+/// One `T` for each hart, dereferencing to the running hart's
+pub struct PerHart<T>([T; HARTS_MAX]);
+
+impl<T> PerHart<T> {
+    pub const fn new(values: [T; HARTS_MAX]) -> Self {
+        Self(values)
+    }
+
+    /// The value for hart `hart`
+    pub fn for_hart(&self, hart: usize) -> &T {
+        &self.0[hart]
+    }
+}
+
+impl<T> Deref for PerHart<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.for_hart(hart_id())
+    }
+}
+
+// Harts that have started, counting the boot hart
+static HARTS_STARTED: AtomicUsize = AtomicUsize::new(1);
+
+// Set once the boot hart starts scheduling, releasing the secondary harts
+static SCHEDULING: AtomicBool = AtomicBool::new(false);
+
+/// Number of harts running the kernel
+pub fn harts_started() -> usize {
+    HARTS_STARTED.load(Ordering::Acquire)
+}
+
+// Boot stacks for the secondary harts, used until each first switches to a process
+#[repr(C, align(16))]
+struct BootStacks([[u8; BOOT_STACK_SIZE]; HARTS_MAX - 1]);
+
+static mut BOOT_STACKS: BootStacks = BootStacks([[0; BOOT_STACK_SIZE]; HARTS_MAX - 1]);
+
+/// Start up to `HARTS_MAX - 1` more harts, other than the boot hart `boot_hart_id`
+///
+/// Each is given its own idle process before this returns, then parks until `start_scheduling`.
+pub fn start_secondary_harts(boot_hart_id: usize) {
+    if !sbi::probe_extension(sbi::EID_HART_STATE) {
+        return;
+    }
+    for id in (0..HART_IDS_MAX).filter(|&id| id != boot_hart_id) {
+        let hart = harts_started();
+        if hart == HARTS_MAX {
+            break;
+        }
+        // An ID with no hart behind it is an error
+        if sbi::hart_start(id, secondary_boot as *const () as usize, hart).is_ok() {
+            while harts_started() == hart {
+                cpu_relax();
+            }
+        }
+    }
+}
+
+/// Release the secondary harts to run processes alongside the boot hart
+pub fn start_scheduling() {
+    SCHEDULING.store(true, Ordering::Release);
+}
+
+// Where a secondary hart starts, with its SBI hart ID in a0 and the hart number from `start_secondary_harts`
+// in a1. Paging is off, which works as the kernel is identity mapped.
+#[unsafe(naked)]
+unsafe extern "C" fn secondary_boot() -> ! {
+    naked_asm!(
+        ".align 2",
+        "mv tp, a1",
+        "la sp, {stacks}",          // Hart n has the (n - 1)th stack, so its top is n stacks up
+        "li t0, {stack_size}",
+        "mul t0, t0, a1",
+        "add sp, sp, t0",
+        "j {secondary_main}",
+        stacks = sym BOOT_STACKS,
+        stack_size = const BOOT_STACK_SIZE,
+        secondary_main = sym secondary_main,
+    );
+}
+
+extern "C" fn secondary_main() -> ! {
+    write_csr!("stvec", kernel_entry as *const () as usize);
+    // `kernel_entry` takes a nonzero sscratch as a trap from user mode, and SBI HSM hart_start leaves it unspecified
+    write_csr!("sscratch", 0);
+    scheduler_init_hart();
+    HARTS_STARTED.fetch_add(1, Ordering::Release);
+
+    while !SCHEDULING.load(Ordering::Acquire) {
+        cpu_relax();
+    }
+    scheduler_start_hart();
+    run_idle();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scheduler::{CURRENT_PROC, IDLE_PID, PROCS};
+    use crate::print;
+
+    #[test_case]
+    fn harts_have_own_state() {
+        print!("hart: harts have own state...");
+
+        // Tests run on the boot hart before the others are released
+        assert!(hart_id() == 0);
+        assert!(!SCHEDULING.load(Ordering::Relaxed));
+
+        let counts = PerHart::new([const { AtomicUsize::new(0) }; HARTS_MAX]);
+        counts.fetch_add(1, Ordering::Relaxed);
+        assert!(counts.for_hart(0).load(Ordering::Relaxed) == 1);
+        assert!((1..HARTS_MAX).all(|hart| counts.for_hart(hart).load(Ordering::Relaxed) == 0));
+
+        // Each secondary hart waits in an idle process of its own, not the boot hart's
+        assert!(*CURRENT_PROC.lock() == Some(IDLE_PID));
+        for hart in 1..harts_started() {
+            let pid = CURRENT_PROC.for_hart(hart).lock()
+                .expect("started hart should have a current process");
+            assert!(pid != IDLE_PID);
+            assert!(PROCS.0.lock().iter().any(|p| p.pid == pid && p.is_idle));
+        }
+
+        common::ok!();
+    }
+}
@@ -0,0 +1,95 @@
+//! Kernel log
+//!
+//! `klog!(LEVEL, ...)` prints a line tagged with its severity. Lines less severe than `LOG_LEVEL` are
+//! dropped, and interrupts are disabled while a line is written so a timer interrupt cannot split it.
+
+use core::fmt::{self, Write};
+
+use common::print::DebugConsole;
+
+use crate::scheduler::SSTATUS_SIE;
+
+/// Severity of a log line, most severe first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+pub const ERROR: Level = Level::Error;
+pub const WARN: Level = Level::Warn;
+pub const INFO: Level = Level::Info;
+pub const DEBUG: Level = Level::Debug;
+
+/// Least severe level that is printed
+pub const LOG_LEVEL: Level = INFO;
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+// Write one log line to `out` if `level` is at least as severe as `LOG_LEVEL`
+fn write_line(out: &mut impl Write, level: Level, args: fmt::Arguments) -> fmt::Result {
+    if level > LOG_LEVEL {
+        return Ok(());
+    }
+    writeln!(out, "[{}] {}", level.name(), args)
+}
+
+#[doc(hidden)]
+pub fn log(level: Level, args: fmt::Arguments) {
+    let sstatus = read_csr!("sstatus");
+    write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+
+    let _ = write_line(&mut DebugConsole, level, args);
+
+    if sstatus & SSTATUS_SIE != 0 {
+        write_csr!("sstatus", read_csr!("sstatus") | SSTATUS_SIE);
+    }
+}
+
+/// Print a line to the console at a severity level: `klog!(WARN, "disk {} missing", n)`
+#[macro_export]
+macro_rules! klog {
+    ($level:ident, $($arg:tt)*) => {
+        $crate::log::log($crate::log::$level, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+
+    struct Counter(usize);
+
+    impl Write for Counter {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 += s.len();
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn below_level_is_silent() {
+        print!("log: below level is silent...");
+
+        let mut out = Counter(0);
+        write_line(&mut out, DEBUG, format_args!("hidden")).unwrap();
+        assert_eq!(out.0, 0);
+
+        write_line(&mut out, ERROR, format_args!("shown")).unwrap();
+        assert_eq!(out.0, "[ERROR] shown\n".len());
+
+        common::ok!();
+    }
+}
@@ -17,24 +17,42 @@ use core::ptr::write_bytes;
 
 #[allow(unused_imports)]
 use common::{print, println};
+use common::args::{self, ARGS_SIZE};
 
 mod address;
 mod allocator;
 #[macro_use]
 mod entry;
+mod fault;
+#[macro_use]
+mod log;
+mod console;     // After entry and log, for their macros
+mod elf;
+mod hart;
 mod page;
 mod panic;
+mod pipe;
 mod process;
+mod qemu;
+mod random;
 mod tar;
 mod trap;
 mod sbi;
 mod scheduler;
 mod spinlock;
 mod timer;
+mod uaccess;
 mod virtio;
+mod watchdog;
 
+use crate::allocator::alloc_stats;
 use crate::entry::kernel_entry;
-use crate::process::{create_process,user_entry};
+use crate::hart::{start_scheduling, start_secondary_harts};
+use crate::process::{create_process, find_program, is_loadable, user_entry, FaultPolicy};
+#[cfg(test)]
+use crate::qemu::qemu_exit_fail;
+#[cfg(test)]
+use crate::scheduler::sleep_while;
 use crate::scheduler::{scheduler_init, yield_now};
 use crate::tar::fs_init;
 use crate::virtio::virtio_blk_init;
@@ -46,12 +64,6 @@ unsafe extern "C" {
     static __stack_top: u8;
 }
 
-unsafe extern "C" {
-    // Safety: Symbols created by linker script
-    static _binary_shell_bin_start: u8;
-    static _binary_shell_bin_size: u8;
-}
-
 fn delay() {
     for _ in 0..300_000_000usize {
         unsafe{asm!("nop");} // do nothing
@@ -74,8 +86,32 @@ fn proc_b_entry() {
     }
 }
 
+// Create the shell process, returning its pid
+fn create_shell() -> usize {
+    let shell = find_program("shell").expect("shell should be linked into the kernel");
+    // A shell linked anywhere else would fault as soon as it ran
+    assert!(is_loadable(shell), "shell should be linked at USER_BASE {:#x}", common::USER_BASE);
+    let mut shell_args = [0u8; ARGS_SIZE];
+    let shell_args_len = args::encode(["shell"], &mut shell_args).expect("shell arguments should fit");
+    create_process(user_entry as * const () as usize, shell.as_ptr(), shell.len(), &shell_args[..shell_args_len], FaultPolicy::Kill)
+        .expect("should create the shell process")
+}
+
+// Run the shell, which in test builds runs the user tests and shuts down once they all pass. A failing test
+// panics the shell instead, which exits non-zero and would leave QEMU running with nothing to do.
+#[cfg(test)]
+fn test_shell_entry() {
+    let shell = create_shell();
+    let status = trap::wait(shell);
+    println!("shell exited with {:?}", status);
+    if status != Ok(0) {
+        qemu_exit_fail();
+    }
+    sleep_while(|| true);
+}
+
 #[unsafe(no_mangle)]
-fn kernel_main() -> ! {
+extern "C" fn kernel_main(boot_hart_id: usize) -> ! {
     let bss = &raw const __bss;
     let bss_end = &raw const __bss_end;
     unsafe {
@@ -84,23 +120,44 @@ fn kernel_main() -> ! {
     }
 
     write_csr!("stvec", kernel_entry as *const () as usize);
+    // `kernel_entry` takes a nonzero sscratch as a trap from user mode, and the SBI does not say what it holds
+    write_csr!("sscratch", 0);
 
     common::println!("Hello World!\n🦀 initialising ...");
     virtio_blk_init();
     fs_init();
     scheduler_init();
 
-    let _ = create_process(proc_a_entry as * const () as usize, core::ptr::null(), 0);
-    let _ = create_process(proc_b_entry as * const () as usize, core::ptr::null(), 0);
+    // Kernel processes take only a name as arguments
+    let mut name = [0u8; 32];
+    let name_len = args::encode(["kernel-proc_a"], &mut name).expect("process name should fit");
+    create_process(proc_a_entry as * const () as usize, core::ptr::null(), 0, &name[..name_len], FaultPolicy::Kill)
+        .expect("should create process A");
+    let name_len = args::encode(["kernel-proc_b"], &mut name).expect("process name should fit");
+    create_process(proc_b_entry as * const () as usize, core::ptr::null(), 0, &name[..name_len], FaultPolicy::Kill)
+        .expect("should create process B");
+
+    #[cfg(not(test))]
+    create_shell();
+    #[cfg(test)]
+    {
+        let name_len = args::encode(["kernel-test_shell"], &mut name).expect("process name should fit");
+        create_process(test_shell_entry as * const () as usize, core::ptr::null(), 0, &name[..name_len], FaultPolicy::Kill)
+            .expect("should create the test shell supervisor");
+    }
 
+    // Without alloc_zeroed every zeroed byte would be filled twice: poisoned, then zeroed
+    let stats = alloc_stats();
+    klog!(INFO, "boot: {} allocations filled {} bytes, {} of them zeroed once rather than twice",
+        stats.allocs, stats.bytes_filled, stats.bytes_zeroed);
 
-    let shell_start = &raw const _binary_shell_bin_start as *mut u8;
-    let shell_size = &raw const _binary_shell_bin_size as usize;  // The symbol _address_ is the size of the binary
-    let _ = create_process(user_entry as * const () as usize, shell_start, shell_size);
+    // Other harts wait until the kernel tests are done
+    start_secondary_harts(boot_hart_id);
 
     #[cfg(test)]
     test_main();
 
+    start_scheduling();
     yield_now();
 
     unreachable!("should never reach here!");
@@ -111,8 +168,8 @@ fn kernel_main() -> ! {
 #[unsafe(naked)]
 unsafe extern "C" fn boot() -> ! {
     naked_asm!(
-        "la a0, {stack_top}",
-        "mv sp, a0",
+        "la sp, {stack_top}",
+        "mv tp, zero",          // The boot hart is hart 0; its SBI hart ID stays in a0 for kernel_main
         "j {kernel_main}",
         stack_top = sym __stack_top,
         kernel_main = sym kernel_main,
@@ -130,27 +187,85 @@ mod test {
         print!("trivial assertion... ");
         assert!(1 == 1);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     // In kernel tests
     #[test_case]
     fn test_common_constants() {
-        use common::*;
+        use common::Syscall;
         print!("common: common constants... ");
 
-        assert_eq!(SYS_PUTBYTE, 1);
-        assert_eq!(SYS_GETCHAR, 2);
+        assert_eq!(Syscall::PutByte as usize, 1);
+        assert_eq!(Syscall::GetChar as usize, 2);
+        assert_eq!(Syscall::Stat as usize, 6);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn test_syscall_round_trip() {
+        use common::Syscall;
+        print!("common: syscall round trip... ");
+
+        for sysno in 1..=45 {
+            let syscall = Syscall::try_from(sysno).expect("every number up to 45 should be a syscall");
+            assert_eq!(syscall as usize, sysno);
+        }
+        assert_eq!(Syscall::try_from(3), Ok(Syscall::Exit));
+        assert_eq!(Syscall::try_from(32), Ok(Syscall::Exec));
+        assert_eq!(Syscall::try_from(0), Err(0));
+        assert_eq!(Syscall::try_from(46), Err(46));
+        assert_eq!(Syscall::try_from(usize::MAX), Err(usize::MAX));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn test_error_code_round_trip() {
+        use common::ErrorCode;
+        print!("common: error code round trip... ");
+
+        for code in [
+            ErrorCode::NotFound,
+            ErrorCode::NoSpace,
+            ErrorCode::BadArg,
+            ErrorCode::WouldBlock,
+            ErrorCode::IoError,
+            ErrorCode::PermissionDenied,
+            ErrorCode::NoSys,
+        ] {
+            // The kernel returns a usize in a0 and the user library reads it back as an isize
+            assert_eq!(ErrorCode::try_from(code.as_usize() as isize), Ok(code));
+        }
+        assert_eq!(ErrorCode::NotFound.as_usize(), usize::MAX);
+        assert_eq!(ErrorCode::try_from(0), Err(0));
+        assert_eq!(ErrorCode::try_from(-100), Err(-100));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn test_ansi_constants() {
+        use common::ansi::{GREEN, RED, RESET};
+        print!("common: ansi constants... ");
+
+        assert_eq!(GREEN, "\x1b[32m");
+        assert_eq!(RED, "\x1b[31m");
+        assert_eq!(RESET, "\x1b[0m");
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
 }
 
+// A failing test panics, and the panic handler shuts down QEMU reporting failure.
+// When all kernel tests pass, the user tests in the shell run next and shut down on success.
 #[cfg(test)]
 pub fn test_runner(tests: &[&dyn Fn()]) {
     println!("Running {} tests", tests.len());
     for test in tests {
         test();
     }
+    println!("All {} kernel tests passed", tests.len());
 }
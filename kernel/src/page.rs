@@ -1,19 +1,32 @@
 //! RISC-V Sv32 Page Table
 
 use alloc::boxed::Box;
+use core::arch::asm;
 use core::ops::{Index, IndexMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::address::{is_aligned, PAddr, VAddr};
+use crate::qemu::QEMU_TEST_PADDR;
+use crate::virtio::VIRTIO_BLK_PADDR;
+
+unsafe extern "C" {
+    // Safety: Symbols created by the linker script
+    static __kernel_base: u8;
+    static __free_ram_end: u8;
+}
 
 pub const PAGE_SIZE: usize = 4096;      // Sv32 using 4096 page size
 const ENTRIES_PER_TABLE: usize = 1024;  // Each Page Table Entry is 4 bytes in Sv32
 
 pub const SATP_SV32: usize = 1 << 31;
+pub const SATP_ASID_SHIFT: usize = 22;
+pub const SATP_ASID_MASK: usize = 0x1ff << SATP_ASID_SHIFT;  // Sv32 has up to 9 ASID bits
 pub const PAGE_V: usize = 1 << 0;   // "Valid" bit (entry is enabled)
 pub const PAGE_R: usize = 1 << 1;   // Readable
 pub const PAGE_W: usize = 1 << 2;   // Writable
 pub const PAGE_X: usize = 1 << 3;   // Executable
 pub const PAGE_U: usize = 1 << 4;   // User (accessible in user mode)
+const PTE_FLAGS_MASK: usize = (1 << 10) - 1;    // Flag bits below the PPN in an entry
 
 impl VAddr {
     pub fn vpn0(&self) -> usize {
@@ -61,6 +74,7 @@ impl IndexMut<usize> for PageTable {
 pub fn map_page(table1: &mut PageTable, vaddr: VAddr, paddr: PAddr, flags: usize) {
     assert!(is_aligned(vaddr.as_usize(), PAGE_SIZE), "unaligned vaddr {}", vaddr.as_usize());
     assert!(is_aligned(paddr.as_usize(), PAGE_SIZE), "unaligned paddr {}", paddr.as_usize());
+    debug_assert!(is_mappable(paddr), "paddr {:#x} is neither kernel RAM nor a known device", paddr.as_usize());
 
     let vpn1 = vaddr.vpn1();
 
@@ -79,10 +93,103 @@ pub fn map_page(table1: &mut PageTable, vaddr: VAddr, paddr: PAddr, flags: usize
     table0[vaddr.vpn0()] = paddr.ppn() | flags | PAGE_V;
 }
 
+// Whether `paddr` is in the kernel image or free RAM, or is a device the kernel maps. A page table entry
+// pointing anywhere else is a bug in its caller.
+fn is_mappable(paddr: PAddr) -> bool {
+    let ram = &raw const __kernel_base as usize..&raw const __free_ram_end as usize;
+    let devices = [VIRTIO_BLK_PADDR as usize, QEMU_TEST_PADDR];
+    ram.contains(&paddr.as_usize()) || devices.contains(&paddr.as_usize())
+}
+
+/// Remove the mapping of `vaddr`, returning the physical address it was mapped to
+///
+/// The caller flushes the TLB.
+pub fn unmap_page(table1: &mut PageTable, vaddr: VAddr) -> Option<PAddr> {
+    let pte = lookup_pte(table1, vaddr)?;
+
+    let table0 = unsafe {
+        // Safety: lookup_pte found a valid 1st level entry pointing at a PageTable allocated by map_page
+        &mut *(PAddr::from_ppn(table1[vaddr.vpn1()]).as_ptr() as *mut PageTable)
+    };
+    table0[vaddr.vpn0()] = 0;
+
+    Some(PAddr::from_ppn(pte))
+}
+
+/// Free `table1` and the 2nd level tables `map_page` allocated under it
+///
+/// The pages it maps are left alone. Nothing may translate through it any more.
+pub fn free_page_table(table1: Box<PageTable>) {
+    for &pte in table1.0.iter().filter(|&&pte| pte & PAGE_V != 0) {
+        // Safety: Valid 1st level entries point at a PageTable boxed by map_page, and only this table refers to it
+        drop(unsafe { Box::from_raw(PAddr::from_ppn(pte).as_ptr() as *mut PageTable) });
+    }
+}
+
+// Highest address-space ID the hart implements; 0 if it has none
+static ASID_MAX: AtomicUsize = AtomicUsize::new(0);
+
+/// Find how many address-space ID bits the hart implements
+///
+/// Writes ones to `satp.ASID` and reads back which stuck. Translation is on while probing, so `satp`
+/// must be a valid Sv32 value mapping the running code. Restores the previous `satp` and flushes the TLB.
+pub fn probe_asids(satp: usize) {
+    let previous = read_csr!("satp");
+    write_csr!("satp", satp | SATP_ASID_MASK);
+    let asid_max = (read_csr!("satp") & SATP_ASID_MASK) >> SATP_ASID_SHIFT;
+    write_csr!("satp", previous);
+    flush_tlb(0);
+    ASID_MAX.store(asid_max, Ordering::Relaxed);
+}
+
+/// Address-space ID for a process
+///
+/// TLB entries of a process with its own ASID survive switches to other processes. ASID 0 is shared by
+/// processes that don't fit, or by all when the hart has no ASIDs, and the TLB is flushed on switching to it.
+pub fn asid_for(pid: usize) -> usize {
+    if pid <= ASID_MAX.load(Ordering::Relaxed) { pid } else { 0 }
+}
+
+/// Flush TLB entries for `asid`, or the whole TLB for ASID 0
+pub fn flush_tlb(asid: usize) {
+    // Safety: sfence.vma only orders and invalidates address translation caches
+    unsafe {
+        if asid == 0 {
+            asm!("sfence.vma");
+        } else {
+            asm!("sfence.vma x0, {}", in(reg) asid);
+        }
+    }
+}
+
+/// Look up the leaf page table entry mapping `vaddr`, if it is mapped
+pub fn lookup_pte(table1: &PageTable, vaddr: VAddr) -> Option<usize> {
+    let vpn1 = vaddr.vpn1();
+    if table1[vpn1] & PAGE_V == 0 {
+        return None;
+    }
+
+    let table0 = unsafe {
+        // Safety: valid 1st level entries point at a PageTable allocated by map_page
+        &*(PAddr::from_ppn(table1[vpn1]).as_ptr() as *const PageTable)
+    };
+
+    let pte = table0[vaddr.vpn0()];
+    (pte & PAGE_V != 0).then_some(pte)
+}
+
+/// Translate `vaddr` as the MMU would, giving the physical address and the flag bits of its page table entry
+pub fn translate(table1: &PageTable, vaddr: VAddr) -> Option<(PAddr, usize)> {
+    let pte = lookup_pte(table1, vaddr)?;
+    let paddr = PAddr::from_ppn(pte).as_usize() + vaddr.as_usize() % PAGE_SIZE;
+    Some((PAddr::new(paddr), pte & PTE_FLAGS_MASK))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{print, println};
+    use crate::print;
+    use common::{PAGE_INFO_VALID, PAGE_INFO_READ, PAGE_INFO_WRITE, PAGE_INFO_EXEC, PAGE_INFO_USER};
 
     #[test_case]
     fn vaddr_to_vpn0() {
@@ -92,7 +199,7 @@ mod test {
         let vpn0 = vaddr.vpn0();
         assert!(vpn0 == 8);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -103,7 +210,7 @@ mod test {
         let vpn1 = vaddr.vpn1();
         assert!(vpn1 == 0x40);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -114,7 +221,7 @@ mod test {
         let ppn = paddr.ppn();
         assert!(ppn == 0x21d95000);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -124,7 +231,7 @@ mod test {
         let paddr = PAddr::from_ppn(0x21d95000);
         assert!(paddr.as_usize() == 0x87654000);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -133,7 +240,7 @@ mod test {
 
         let pt = &mut PageTable::new();
         let vaddr = VAddr::new(0x12345000);
-        map_page(pt, vaddr, PAddr::new(0x87654000), 0xF);
+        map_page(pt, vaddr, PAddr::new(0x80654000), 0xF);
         // println!("pt[vaddr.vpn1()] == {:x}", pt[vaddr.vpn1()]);
         // assert!(pt[vaddr.vpn1()] == 0x20094c01);
 
@@ -141,8 +248,90 @@ mod test {
             let mut table0_paddr = PAddr::from_ppn(pt[vaddr.vpn1()]);
             &mut *(table0_paddr.as_ptr_mut() as *mut PageTable)
         };
-        assert!(table0[vaddr.vpn0()] == 0x21d9500f);
+        assert!(table0[vaddr.vpn0()] == 0x2019500f);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn only_ram_and_devices_are_mappable() {
+        print!("page: only ram and devices are mappable...");
+
+        let kernel_base = &raw const __kernel_base as usize;
+        let free_ram_end = &raw const __free_ram_end as usize;
+        assert!(is_mappable(PAddr::new(kernel_base)));
+        assert!(is_mappable(PAddr::new(free_ram_end - PAGE_SIZE)));
+        assert!(is_mappable(PAddr::new(VIRTIO_BLK_PADDR as usize)));
+        assert!(is_mappable(PAddr::new(QEMU_TEST_PADDR)));
+
+        assert!(!is_mappable(PAddr::new(0)));
+        assert!(!is_mappable(PAddr::new(kernel_base - PAGE_SIZE)));
+        assert!(!is_mappable(PAddr::new(free_ram_end)));
+        assert!(!is_mappable(PAddr::new(VIRTIO_BLK_PADDR as usize + PAGE_SIZE)));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn asid_for_pid() {
+        print!("page: asid for pid...");
+
+        // ASID 0 is always shared; pids beyond the hart's ASIDs share it too
+        let asid_max = ASID_MAX.load(Ordering::Relaxed);
+        assert!(asid_for(0) == 0);
+        assert!(asid_for(asid_max + 1) == 0);
+        if asid_max > 0 {
+            assert!(asid_for(1) == 1);
+            assert!(asid_for(asid_max) == asid_max);
+        }
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn look_up_a_page() {
+        print!("page: look up a page...");
+
+        let pt = &mut PageTable::new();
+        let vaddr = VAddr::new(0x12345000);
+        map_page(pt, vaddr, PAddr::new(0x80654000), PAGE_R | PAGE_W);
+        assert!(lookup_pte(pt, vaddr) == Some(0x20195000 | PAGE_R | PAGE_W | PAGE_V));
+        assert!(lookup_pte(pt, VAddr::new(0x12346000)).is_none());
+        assert!(lookup_pte(pt, VAddr::new(0x22345000)).is_none());
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn translate_an_address() {
+        print!("page: translate an address...");
+
+        let pt = &mut PageTable::new();
+        map_page(pt, VAddr::new(0x12345000), PAddr::new(0x80654000), PAGE_R | PAGE_U);
+        let (paddr, flags) = translate(pt, VAddr::new(0x12345678))
+            .expect("page should be mapped");
+        assert!(paddr.as_usize() == 0x80654678);
+        assert!(flags == PAGE_V | PAGE_R | PAGE_U);
+        assert!(translate(pt, VAddr::new(0x12346000)).is_none());
+
+        // User programs decode the flags with the common constants
+        assert!([PAGE_V, PAGE_R, PAGE_W, PAGE_X, PAGE_U]
+            == [PAGE_INFO_VALID, PAGE_INFO_READ, PAGE_INFO_WRITE, PAGE_INFO_EXEC, PAGE_INFO_USER]);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn unmap_a_page() {
+        print!("page: unmap a page...");
+
+        let pt = &mut PageTable::new();
+        let vaddr = VAddr::new(0x12345000);
+        map_page(pt, vaddr, PAddr::new(0x80654000), PAGE_R | PAGE_W);
+        assert!(unmap_page(pt, vaddr).map(|paddr| paddr.as_usize()) == Some(0x80654000));
+        assert!(lookup_pte(pt, vaddr).is_none());
+        assert!(unmap_page(pt, vaddr).is_none());
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 }
@@ -7,9 +7,64 @@ use core::sync::atomic::{AtomicU8, Ordering::SeqCst};
 
 use crate::println;
 
+unsafe extern "C" {
+    // Safety: Symbols created by the linker script
+    static __kernel_base: u8;
+    static __free_ram_end: u8;
+}
+
 // Panic counter. Every time the kernel panics, this counter is incremented.
 static PANIC_COUNTER: AtomicU8 = AtomicU8::new(0);
 
+// Stop the kernel after a panic. Under test, report the failure to QEMU so the test run terminates.
+fn halt() -> ! {
+    #[cfg(test)]
+    crate::qemu::qemu_exit_fail();
+
+    #[cfg(not(test))]
+    loop {
+        spin_loop();
+        unsafe{asm!("wfi", options(readonly, nostack))}
+    }
+}
+
+// Most return addresses printed in a backtrace; stops a corrupted chain looping forever
+const BACKTRACE_MAX: usize = 32;
+
+// Walk the frame pointer chain from the caller, passing each return address to `f`.
+//
+// Built with frame pointers, s0 points just above each frame: the return address is saved at s0 - 4 and the
+// caller's s0 at s0 - 8. The walk stops when the chain leaves kernel memory or stops moving up the stack.
+// Frames in the precompiled core library have no frame pointer and are skipped.
+#[inline(never)]
+fn backtrace(mut f: impl FnMut(usize)) {
+    let kernel = &raw const __kernel_base as usize..&raw const __free_ram_end as usize;
+
+    let mut fp: usize;
+    // Safety: Only reads s0
+    unsafe { asm!("mv {}, s0", out(reg) fp, options(nomem, nostack)) };
+
+    for _ in 0..BACKTRACE_MAX {
+        if !kernel.contains(&fp) || !kernel.contains(&(fp - 8)) || !fp.is_multiple_of(size_of::<usize>()) {
+            break;
+        }
+
+        // Safety: fp is aligned and the saved registers below it are in kernel memory
+        let (ra, prev_fp) = unsafe {
+            (*((fp - 4) as *const usize), *((fp - 8) as *const usize))
+        };
+        if ra == 0 {
+            break;
+        }
+        f(ra);
+
+        if prev_fp <= fp {
+            break;
+        }
+        fp = prev_fp;
+    }
+}
+
 // Kernel panic handler.
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -25,10 +80,12 @@ fn panic(info: &PanicInfo) -> ! {
             // First panic: Try whatever we can do including complicated stuff
             // which may panic again.
             println!("⚠️ Panic: {}", info);
-            loop {
-                spin_loop();
-                unsafe{asm!("wfi", options(readonly, nostack))}
-            }
+
+            // Resolve with `addr2line -e kernel.elf <address>`
+            println!("backtrace:");
+            backtrace(|ra| println!("  {:#010x}", ra));
+
+            halt();
         },
         1 => {
             // Double panics: panicked while handling a panic. Keep it simple and avoid print macros.
@@ -43,10 +100,7 @@ fn panic(info: &PanicInfo) -> ! {
                 let _ = crate::sbi::put_byte(b'!');
             }
 
-            loop {
-                spin_loop();
-                unsafe{asm!("wfi", options(readonly, nostack))}
-            }
+            halt();
         },
         _ => {
             // Triple panics: println! and put_byte seem to be broken. Spin forever.
@@ -65,3 +119,41 @@ fn panic(info: &PanicInfo) -> ! {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+
+    unsafe extern "C" {
+        static __text_end: u8;
+    }
+
+    #[inline(never)]
+    fn nested(depth: usize, addrs: &mut [usize; BACKTRACE_MAX], len: &mut usize) {
+        if depth > 0 {
+            nested(depth - 1, addrs, len);
+        } else {
+            backtrace(|ra| {
+                addrs[*len] = ra;
+                *len += 1;
+            });
+        }
+    }
+
+    #[test_case]
+    fn backtrace_walks_frames() {
+        print!("panic: backtrace walks frames...");
+
+        let mut addrs = [0; BACKTRACE_MAX];
+        let mut len = 0;
+        nested(3, &mut addrs, &mut len);
+
+        // At least the recursive calls, each returning into kernel code
+        let text = &raw const __kernel_base as usize..&raw const __text_end as usize;
+        assert!(len > 3);
+        assert!(addrs[..len].iter().all(|ra| text.contains(ra)));
+
+        common::ok!();
+    }
+}
@@ -0,0 +1,140 @@
+//! Pipes for communication between processes
+//!
+//! A pipe is a fixed-size ring buffer identified by its index in `PIPES`. Any process that knows the id
+//! may read, write or close it.
+
+use crate::spinlock::SpinLock;
+
+pub const PIPES_MAX: usize = 8;
+const PIPE_SIZE: usize = 512;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Pipe {
+    in_use: bool,
+    buf: [u8; PIPE_SIZE],
+    head: usize,    // Index of the next byte to read
+    len: usize,     // Number of bytes buffered
+}
+
+impl Pipe {
+    const fn empty() -> Self {
+        Self {
+            in_use: false,
+            buf: [0; PIPE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Copy buffered bytes into `out`, returning the number of bytes read
+    pub fn read(&mut self, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.len);
+        for byte in out[..n].iter_mut() {
+            *byte = self.buf[self.head];
+            self.head = (self.head + 1) % PIPE_SIZE;
+        }
+        self.len -= n;
+        n
+    }
+
+    /// Whether no bytes are buffered to read
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Buffer as much of `data` as fits, returning the number of bytes written
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(PIPE_SIZE - self.len);
+        for &byte in &data[..n] {
+            self.buf[(self.head + self.len) % PIPE_SIZE] = byte;
+            self.len += 1;
+        }
+        n
+    }
+}
+
+#[derive(Debug)]
+pub struct Pipes(pub SpinLock<[Pipe; PIPES_MAX]>);
+
+impl Pipes {
+    /// Allocate an unused pipe, returning its id
+    pub fn create(&self) -> Option<usize> {
+        let mut pipes = self.0.lock();
+        let (id, pipe) = pipes.iter_mut()
+            .enumerate()
+            .find(|(_, p)| !p.in_use)?;
+        *pipe = Pipe::empty();
+        pipe.in_use = true;
+        Some(id)
+    }
+
+    /// Free the pipe `id` for reuse, discarding anything buffered
+    ///
+    /// Returns `false` if there is no such pipe.
+    pub fn release(&self, id: usize) -> bool {
+        match self.0.lock().get_mut(id) {
+            Some(pipe) if pipe.in_use => {
+                pipe.in_use = false;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Run `f` on the pipe `id`, or return `None` if there is no such pipe
+    pub fn with<R>(&self, id: usize, f: impl FnOnce(&mut Pipe) -> R) -> Option<R> {
+        let mut pipes = self.0.lock();
+        pipes.get_mut(id)
+            .filter(|p| p.in_use)
+            .map(f)
+    }
+}
+
+pub static PIPES: Pipes = Pipes(SpinLock::new([Pipe::empty(); PIPES_MAX]));
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+
+    #[test_case]
+    fn pipe_ring_buffer_wraps() {
+        print!("pipe: ring buffer wraps...");
+
+        let mut pipe = Pipe::empty();
+        let mut out = [0u8; PIPE_SIZE];
+
+        // Move head near the end so the next write wraps around
+        assert!(pipe.write(&[1; PIPE_SIZE - 2]) == PIPE_SIZE - 2);
+        assert!(pipe.read(&mut out) == PIPE_SIZE - 2);
+
+        assert!(pipe.write(b"ping") == 4);
+        assert!(!pipe.is_empty());
+        assert!(pipe.read(&mut out) == 4);
+        assert!(&out[..4] == b"ping");
+        assert!(pipe.is_empty());
+
+        // A full pipe accepts nothing more
+        assert!(pipe.write(&[0; PIPE_SIZE + 1]) == PIPE_SIZE);
+        assert!(pipe.write(b"x") == 0);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn create_pipe() {
+        print!("pipe: create pipe...");
+
+        let id = PIPES.create()
+            .expect("should create a pipe");
+        assert!(PIPES.with(id, |p| p.write(b"ping")) == Some(4));
+        assert!(PIPES.with(PIPES_MAX, |p| p.write(b"ping")).is_none());
+
+        // A released pipe is gone until created again
+        assert!(PIPES.release(id));
+        assert!(PIPES.with(id, |p| p.write(b"ping")).is_none());
+        assert!(!PIPES.release(id));
+
+        common::ok!();
+    }
+}
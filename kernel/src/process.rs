@@ -2,121 +2,717 @@
 
 use alloc::slice;
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 use core::arch::naked_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::address::{align_up, PAddr, VAddr};
-use crate::page::{map_page, PageTable, PAGE_SIZE, SATP_SV32, PAGE_R, PAGE_W, PAGE_X, PAGE_U};
-use crate::scheduler::PROCS;
+use common::{ErrorCode, FAULT_PROCESS_CREATE, OPEN_FILES_MAX, PROC_NAME_MAX, SIGNAL_KINDS, USER_BASE};
+use common::args::{Args, ARGS_SIZE, ARGS_VADDR};
+use common::trap::TrapFrame;
+
+use crate::address::{align_down, align_up, is_aligned, page_range, PAddr, VAddr};
+use crate::allocator::{alloc_frame, free_frame, heap_range};
+use crate::elf::{is_elf, Elf, Segment, SEGMENTS_MAX};
+use crate::fault::should_fail;
+use crate::hart::hart_id;
+use crate::page::{asid_for, flush_tlb, free_page_table, lookup_pte, map_page, unmap_page, PageTable, PAGE_SIZE, SATP_ASID_SHIFT, SATP_SV32, PAGE_R, PAGE_W, PAGE_X, PAGE_U};
+use crate::scheduler::{process_start, CURRENT_PROC, IDLE_PID, PROCS, PROCS_MAX, SSTATUS_SIE};
+use crate::spinlock::SpinLock;
+use crate::timer::get_timer;
+use crate::qemu::QEMU_TEST_PADDR;
 use crate::virtio::VIRTIO_BLK_PADDR;
 
 unsafe extern "C" {
     // Safety: Symbols created by the linker script
     static __kernel_base: u8;
-    static __free_ram_end: u8;
+    static __text_end: u8;
+    static __rodata_end: u8;
+}
+
+unsafe extern "C" {
+    // Safety: Symbols created by objcopy for the linked in shell binary
+    static _binary_shell_bin_start: u8;
+    static _binary_shell_bin_size: u8;
+}
+
+/// Whether `image` is a user program the kernel can load: an ELF executable, or a flat binary linked at
+/// `USER_BASE` starting with its `ImageHeader`. ELF segments may go anywhere from `USER_BASE` up to the
+/// arguments page.
+pub fn is_loadable(image: &'static [u8]) -> bool {
+    UserImage::new(image).is_some()
+}
+
+/// Find the image of a program linked into the kernel by name, or by a path ending in its name
+pub fn find_program(name: &str) -> Option<&'static [u8]> {
+    match name.rsplit_once('/').map_or(name, |(_, base)| base) {
+        "shell" => {
+            let shell_start = &raw const _binary_shell_bin_start;
+            let shell_size = &raw const _binary_shell_bin_size as usize;  // The symbol _address_ is the size of the binary
+            // Safety: objcopy symbols describe the shell binary, which is never mutated
+            Some(unsafe { slice::from_raw_parts(shell_start, shell_size) })
+        },
+        _ => None,
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum State {
     Unused,     // Unused process control structure
     Runnable,   // Runnable process
+    Sleeping,   // Waiting to be woken by wake_sleeping()
     Exited,     // Process exited
 }
 
+/// An entry in a process's open file table
+#[derive(Copy, Clone, Debug)]
+pub struct OpenFile {
+    pub in_use: bool,
+    pub file_i: usize,          // Index into FILES
+    pub offset: usize,          // Where the next read or write starts
+    pub flags: u32,             // OPEN_READ and/or OPEN_WRITE, with OPEN_APPEND
+}
+
+impl OpenFile {
+    pub const CLOSED: Self = Self { in_use: false, file_i: 0, offset: 0, flags: 0 };
+}
+
+/// What happens to a user process that faults on an access demand paging cannot satisfy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultPolicy {
+    Kill,       // The process exits
+    Restart,    // The process starts again from its entry point, up to RESTARTS_MAX times
+}
+
+/// Restarts allowed before a faulting `Restart` process is killed instead. A process that faults every
+/// time it starts would otherwise loop forever.
+pub const RESTARTS_MAX: usize = 3;
+
 #[derive(Clone, Debug)]
 pub struct Process {
     pub pid: usize,             // Process ID
+    pub parent: usize,          // Process that created this one and may wait for it; IDLE_PID once none will
+    pub name: [u8; PROC_NAME_MAX],  // Program name, padded with nul bytes
     pub state: State,           // Process state
     pub sp: VAddr,              // Stack pointer
+    pub runnable_since: u64,    // Timer ticks when the process last became ready to run
+    pub scheduled_at: u64,      // Timer ticks when the process was last switched in
+    pub cpu_ticks: u64,         // Timer ticks spent running, up to the last switch out
+    pub wake_at: u64,           // Timer ticks at which to wake if sleeping, or 0 to wait indefinitely
+    pub is_kernel: bool,        // Runs in supervisor mode with no user image
+    pub is_idle: bool,          // Runs only when its hart has nothing else to run
+    pub running_on: Option<usize>,  // Hart that has switched to the process and not yet away again
+    pub last_hart: Option<usize>,   // Hart the process last ran on, whose TLB may hold its entries
     pub page_table: Option<Box<PageTable>>,
+    image: UserImage,           // Program image that user pages are loaded from on first touch
+    pub files: [OpenFile; OPEN_FILES_MAX],  // Indexed by file descriptor
+    pub signals: Signals,
+    pub on_fault: FaultPolicy,
+    pub restarts: usize,        // Times restarted after a fault
+    pub exit_code: usize,       // Status passed to `SYS_EXIT`
     pub stack: [u8; 8192],      // Kernel stack
 }
 
+/// Signal state of a process
+#[derive(Clone, Debug)]
+pub struct Signals {
+    pub pending: usize,                     // Bit per signal number posted but not yet delivered
+    pub handlers: [usize; SIGNAL_KINDS],    // User handler per signal number; 0 if none is registered
+    pub trampoline: usize,                  // Where handlers return to, to make `SYS_SIGRETURN`
+    pub in_handler: bool,                   // A handler is running; `saved` holds the interrupted state
+    pub saved: TrapFrame,
+    pub saved_pc: usize,
+}
+
+impl Signals {
+    pub const NONE: Self = Self {
+        pending: 0,
+        handlers: [0; SIGNAL_KINDS],
+        trampoline: 0,
+        in_handler: false,
+        saved: TrapFrame::ZERO,
+        saved_pc: 0,
+    };
+}
+
+// Written at the low end of each kernel stack; a stack overflow clobbers it first.
+const STACK_CANARY: [u8; 4] = 0xDEADBEEFu32.to_ne_bytes();
+
+// Kept free at the high end of each kernel stack for the number of the hart running the process, which the
+// trap entry loads on a trap from user mode. A whole 16 bytes keeps the stack aligned.
+const HART_SLOT_SIZE: usize = 16;
+
 impl Process {
-    pub const fn zeroed() -> Self {
+    // Zeroed in place on the heap: a Process is too large to build on a kernel stack.
+    pub fn boxed_zeroed() -> Box<Self> {
         // Safety: All-zero bytes is a valid representation: integers become 0, pointer becomes null, is_kernel bool is false
-        unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
+        unsafe { Box::new_zeroed().assume_init() }
+    }
+
+    /// The process name, up to the first nul byte
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(PROC_NAME_MAX);
+        str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    /// Where `switch_context` saves and restores this process's kernel stack pointer
+    pub fn sp_slot(&mut self) -> &mut usize {
+        // Safety: the pointer is to `self.sp`, which the returned reference keeps borrowed
+        unsafe { &mut *self.sp.field_raw_ptr() }
+    }
+
+    fn set_stack_canary(&mut self) {
+        self.stack[..STACK_CANARY.len()].copy_from_slice(&STACK_CANARY);
+    }
+
+    pub fn is_stack_canary_intact(&self) -> bool {
+        self.stack[..STACK_CANARY.len()] == STACK_CANARY
+    }
+
+    /// The open file table for a child forked from this process
+    ///
+    /// The child has the same descriptors on the same files, each with its own copy of the offset, so reads
+    /// and writes in one process do not move the other's. Unix instead shares the offset between the two.
+    #[cfg_attr(not(test), expect(dead_code))]   // There is no fork yet
+    pub fn clone_fds(&self) -> [OpenFile; OPEN_FILES_MAX] {
+        self.files
+    }
+
+    /// Restart a user process at its entry point after a fault, if its policy allows
+    ///
+    /// Clears the registers in `f` other than `sscratch`, and the signal state, so the process starts as if
+    /// newly created. Its memory is left as it was. Returns the entry point to resume at, or `None` if the
+    /// process should be killed.
+    pub fn restart_after_fault(&mut self, f: &mut TrapFrame) -> Option<usize> {
+        if self.is_kernel || self.on_fault != FaultPolicy::Restart || self.restarts >= RESTARTS_MAX {
+            return None;
+        }
+        self.restarts += 1;
+        *f = TrapFrame { sscratch: f.sscratch, ..TrapFrame::ZERO };
+        self.signals = Signals::NONE;
+        Some(self.image.entry)
+    }
+
+    // Copy the image page containing `vaddr` into a new frame and map it, unless it is already mapped,
+    // outside the image or there is no memory for it. A read-only page is copied only once, and shared
+    // with every other process running the same image. The caller flushes the TLB after a page is loaded.
+    fn load_image_page(&mut self, vaddr: usize) -> bool {
+        let page = align_down(vaddr, PAGE_SIZE);
+        let Some(segment) = self.image.segment_at(page).filter(|_| !self.is_kernel) else {
+            return false;
+        };
+        let page_table = self.page_table.as_mut()
+            .expect("page table must be initialized before mapping user pages");
+        if lookup_pte(page_table, VAddr::new(page)).is_some() {
+            return false;
+        }
+
+        let is_shared = segment.flags & PAGE_W == 0;
+        let mut shared_pages = SHARED_PAGES.lock();
+        if is_shared && let Some(shared) = shared_pages.iter_mut()
+            .find(|shared| shared.image == self.image.addr && shared.vaddr == page)
+        {
+            shared.refs += 1;
+            map_page(page_table, VAddr::new(page), shared.paddr, PAGE_U | segment.flags);
+            return true;
+        }
+
+        // Only part of the page may be in the image; the rest, such as `.bss`, stays zero
+        let Ok(frame) = alloc_frame() else {
+            return false;
+        };
+        let start = page.max(segment.vaddr);
+        let end = (page + PAGE_SIZE).min(segment.vaddr + segment.file_size);
+        if start < end {
+            let offset = segment.offset + (start - segment.vaddr);
+            // Safety: The image is a program linked into the kernel, which is never mutated, and the
+            // segment was checked to lie within it
+            let src = unsafe { slice::from_raw_parts((self.image.addr + offset) as *const u8, end - start) };
+            frame[start - page..end - page].copy_from_slice(src);
+        }
+
+        let paddr = PAddr::new(frame.as_ptr() as usize);
+        // Without room to record it the page is simply not shared
+        if is_shared && shared_pages.try_reserve(1).is_ok() {
+            shared_pages.push(SharedPage { image: self.image.addr, vaddr: page, paddr, refs: 1 });
+        }
+        map_page(page_table, VAddr::new(page), paddr, PAGE_U | segment.flags);
+        true
+    }
+
+    /// Map `len` bytes of zeroed user memory at the lowest free address in the anonymous memory region
+    ///
+    /// `flags` combine `PAGE_R`, `PAGE_W` and `PAGE_X`, and must include `PAGE_R` but not both `PAGE_W`
+    /// and `PAGE_X`. Returns the base address, or `Err(NoSpace)` if there is no room in the region or no
+    /// memory left for the pages, in which case nothing is mapped.
+    pub fn map_anonymous(&mut self, len: usize, flags: usize) -> Result<usize, ErrorCode> {
+        if len == 0 || flags & PAGE_R == 0 || flags & (PAGE_W | PAGE_X) == PAGE_W | PAGE_X {
+            return Err(ErrorCode::BadArg);
+        }
+        if len > MMAP_END - MMAP_BASE {
+            return Err(ErrorCode::NoSpace);
+        }
+        let size = align_up(len, PAGE_SIZE);
+        let page_table = self.page_table.as_mut().ok_or(ErrorCode::BadArg)?;
+
+        // First fit: the first run of unmapped pages long enough
+        let mut start = MMAP_BASE;
+        let base = (MMAP_BASE..MMAP_END).step_by(PAGE_SIZE)
+            .find(|&vaddr| {
+                if lookup_pte(page_table, VAddr::new(vaddr)).is_some() {
+                    start = vaddr + PAGE_SIZE;
+                }
+                vaddr + PAGE_SIZE - start == size
+            })
+            .map(|_| start)
+            .ok_or(ErrorCode::NoSpace)?;
+
+        for vaddr in (base..base + size).step_by(PAGE_SIZE) {
+            let Ok(frame) = alloc_frame() else {
+                // Give back the pages mapped so far
+                if vaddr > base {
+                    self.unmap_anonymous(base, vaddr - base)?;
+                }
+                return Err(ErrorCode::NoSpace);
+            };
+            map_page(page_table, VAddr::new(vaddr), PAddr::new(frame.as_ptr() as usize), PAGE_U | flags);
+        }
+        Ok(base)
+    }
+
+    /// Unmap and free `len` bytes of memory mapped by `map_anonymous` at `vaddr`
+    ///
+    /// Every page in the range must be mapped, otherwise nothing is unmapped.
+    pub fn unmap_anonymous(&mut self, vaddr: usize, len: usize) -> Result<(), ErrorCode> {
+        let end = vaddr.checked_add(align_up(len, PAGE_SIZE)).ok_or(ErrorCode::BadArg)?;
+        if len == 0 || !is_aligned(vaddr, PAGE_SIZE) || vaddr < MMAP_BASE || end > MMAP_END {
+            return Err(ErrorCode::BadArg);
+        }
+        let page_table = self.page_table.as_mut().ok_or(ErrorCode::BadArg)?;
+        if !(vaddr..end).step_by(PAGE_SIZE).all(|page| lookup_pte(page_table, VAddr::new(page)).is_some()) {
+            return Err(ErrorCode::BadArg);
+        }
+
+        for page in (vaddr..end).step_by(PAGE_SIZE) {
+            let paddr = unmap_page(page_table, VAddr::new(page))
+                .expect("page should be mapped");
+            // Safety: map_anonymous allocated this frame, and it is no longer mapped
+            unsafe { free_frame(paddr) };
+        }
+        flush_tlb(asid_for(self.pid));
+        Ok(())
+    }
+
+    /// Replace the user image with `image`, to run afresh from its entry point with `args`
+    ///
+    /// A new page table maps the kernel and the arguments; image pages load on first touch as usual. The old
+    /// image pages, arguments page and anonymous memory are freed. Open files are kept, but signal handlers
+    /// pointed into the old image so they are reset. Returns the entry point, or `Err(BadArg)` if `image`
+    /// is neither a valid ELF executable nor has a valid header, or `args` do not fit.
+    ///
+    /// A process running on this hart is switched to the new page table before the old one is freed, as it is
+    /// still translating through it until then. The caller keeps interrupts disabled across the switch.
+    pub fn exec(&mut self, image: &'static [u8], args: &[u8]) -> Result<usize, ErrorCode> {
+        if self.is_kernel || args.len() > ARGS_SIZE {
+            return Err(ErrorCode::BadArg);
+        }
+        let new_image = UserImage::new(image).ok_or(ErrorCode::BadArg)?;
+
+        // `args` may be in the old image, so copy them before it is freed
+        let args_page = alloc_frame()?;
+        let mut page_table = kernel_page_table();
+        map_args(&mut page_table, args_page, args);
+        let name = process_name(args, false);
+        let mut old = self.page_table.replace(page_table)
+            .expect("user process should have a page table");
+        if self.running_on == Some(hart_id()) {
+            let page_table = self.page_table.as_ref().expect("page table was just replaced");
+            let asid = asid_for(self.pid);
+            write_csr!("satp", satp_for(page_table, asid));
+            flush_tlb(asid);
+        }
+        free_user_pages(&mut old, &self.image);
+        free_page_table(old);
+
+        self.image = new_image;
+        self.name = name;
+        self.signals = Signals::NONE;
+        if EAGER_MAPPING.load(Ordering::Relaxed) {
+            for vaddr in new_image.pages() {
+                self.load_image_page(vaddr);
+            }
+        }
+        Ok(new_image.entry)
+    }
+
+    /// Free the memory of an exited process and make its slot unused
+    ///
+    /// Its user pages, arguments page, anonymous memory and page table are freed. The process must not be
+    /// running on any hart.
+    pub fn release(&mut self) {
+        debug_assert!(self.running_on.is_none(), "process {} is still running", self.pid);
+        if let Some(mut page_table) = self.page_table.take() {
+            if !self.is_kernel {
+                free_user_pages(&mut page_table, &self.image);
+            }
+            free_page_table(page_table);
+        }
+        self.files = [OpenFile::CLOSED; OPEN_FILES_MAX];
+        self.state = State::Unused;
+    }
+}
+
+// Unmap and free every page of `image`, the arguments page and anonymous memory from a page table that is
+// being discarded
+fn free_user_pages(page_table: &mut PageTable, image: &UserImage) {
+    let user_pages = image.pages()
+        .chain((ARGS_VADDR..ARGS_VADDR + PAGE_SIZE).chain(MMAP_BASE..MMAP_END).step_by(PAGE_SIZE));
+    for page in user_pages {
+        if let Some(paddr) = unmap_page(page_table, VAddr::new(page)) {
+            // Safety: Every user page is a frame from alloc_frame, and the page table is being discarded
+            unsafe { put_user_frame(paddr) };
+        }
+    }
+}
+
+// A read-only page of a user image, mapped by every process running the image
+struct SharedPage {
+    image: usize,   // Kernel address of the image
+    vaddr: usize,
+    paddr: PAddr,
+    refs: usize,    // Page tables mapping it
+}
+
+// Read-only image pages in use. Pages are loaded under the PROCS lock, which is always taken first.
+static SHARED_PAGES: SpinLock<Vec<SharedPage>> = SpinLock::new(Vec::new());
+
+// Drop a page table's mapping of a user frame, freeing the frame unless other processes share it
+//
+// Safety: `paddr` must be a frame from alloc_frame that the page table no longer maps.
+unsafe fn put_user_frame(paddr: PAddr) {
+    let mut shared_pages = SHARED_PAGES.lock();
+    if let Some(i) = shared_pages.iter().position(|shared| shared.paddr.as_usize() == paddr.as_usize()) {
+        shared_pages[i].refs -= 1;
+        if shared_pages[i].refs > 0 {
+            return;
+        }
+        shared_pages.swap_remove(i);
+    }
+    drop(shared_pages);
+    // Safety: The caller passes an unmapped frame from alloc_frame, and no other page table maps it
+    unsafe { free_frame(paddr) };
+}
+
+// The first argument, cut short at a character boundary to fit, or a default for a process without one
+fn process_name(args: &[u8], is_kernel: bool) -> [u8; PROC_NAME_MAX] {
+    let name = Args::parse(args).next()
+        .unwrap_or(if is_kernel { "kernel" } else { "user" });
+    let mut len = name.len().min(PROC_NAME_MAX);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    let mut buf = [0; PROC_NAME_MAX];
+    buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+    buf
+}
+
+/// Replace the image of the current process, as `Process::exec`, and switch to its new page table
+///
+/// Returns the entry point to return to user mode at.
+pub fn exec_current(image: &'static [u8], args: &[u8]) -> Result<usize, ErrorCode> {
+    // A context switch between leaving the old page table and freeing it would save and later restore it
+    let sstatus = read_csr!("sstatus");
+    write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+
+    let result = PROCS.with_current(|process| process.exec(image, args));
+
+    write_csr!("sstatus", sstatus);
+    result
+}
+
+/// Load the image page containing `vaddr` into the current process after a page fault
+///
+/// Returns `false` if `vaddr` is not an image page waiting to be loaded, so the fault is a real one.
+pub fn handle_page_fault(vaddr: usize) -> bool {
+    PROCS.with_current(|process| fault_in(process, vaddr))
+}
+
+/// Load the image page containing `vaddr` into `process` if it has not been touched yet
+pub fn fault_in(process: &mut Process, vaddr: usize) -> bool {
+    let loaded = process.load_image_page(vaddr);
+    if loaded {
+        flush_tlb(asid_for(process.pid));
     }
+    loaded
+}
+
+static EAGER_MAPPING: AtomicBool = AtomicBool::new(false);
+
+/// Map whole user images when processes are created, rather than page by page as they are touched
+#[cfg_attr(not(test), expect(dead_code))]
+pub fn set_eager_mapping(eager: bool) {
+    EAGER_MAPPING.store(eager, Ordering::Relaxed);
 }
 
-// The base virtual address of an application image. This needs to match the
-// starting address defined in `user.ld`.
-const USER_BASE: usize = 0x1000000;
 const SSTATUS_SUM: usize = 1 << 18;     // Supervisor read user pages
 
+// Virtual address range of anonymous memory mapped by `SYS_MMAP`, clear of the user image and arguments
+const MMAP_BASE: usize = 0x2000000;
+const MMAP_END: usize = 0x3000000;
+
+// Where a process's user pages are loaded from
+#[derive(Copy, Clone, Debug)]
+struct UserImage {
+    addr: usize,            // Kernel address of the image
+    entry: usize,
+    segments: [Segment; SEGMENTS_MAX],  // No two share a page, so each page has one segment's permissions
+    segment_count: usize,
+}
+
+impl UserImage {
+    // Lay out an ELF executable by its program headers, or else a flat binary by its `ImageHeader`.
+    // Segments must fit between `USER_BASE` and the arguments page, and the entry point must be executable.
+    fn new(image: &'static [u8]) -> Option<Self> {
+        let (entry, segments, segment_count) = if is_elf(image) {
+            let elf = Elf::parse(image)?;
+            (elf.entry, elf.segments, elf.segment_count)
+        } else {
+            let header = ImageHeader::parse(image)?;
+            let (segments, segment_count) = header.segments(image.len());
+            (header.entry, segments, segment_count)
+        };
+        let user_image = Self { addr: image.as_ptr() as usize, entry, segments, segment_count };
+
+        let segments = user_image.segments();
+        let in_user_range = segments.iter().all(|s| {
+            s.vaddr >= USER_BASE && s.vaddr.checked_add(s.mem_size).is_some_and(|end| end <= ARGS_VADDR)
+        });
+        if !in_user_range {
+            return None;
+        }
+        let overlaps = segments.iter().enumerate().any(|(i, a)| {
+            let a = segment_pages(a);
+            segments[i + 1..].iter().map(segment_pages).any(|b| a.start < b.end && b.start < a.end)
+        });
+        let entry_is_executable = segments.iter()
+            .any(|s| s.flags & PAGE_X != 0 && (s.vaddr..s.vaddr + s.mem_size).contains(&entry));
+        (!overlaps && entry_is_executable).then_some(user_image)
+    }
+
+    fn segments(&self) -> &[Segment] {
+        &self.segments[..self.segment_count]
+    }
+
+    // The segment mapped at `vaddr`, counting whole pages
+    fn segment_at(&self, vaddr: usize) -> Option<Segment> {
+        self.segments().iter()
+            .find(|s| segment_pages(s).contains(&vaddr))
+            .copied()
+    }
+
+    // Every page of every segment
+    fn pages(&self) -> impl Iterator<Item = usize> + '_ {
+        self.segments().iter()
+            .flat_map(|s| segment_pages(s).step_by(PAGE_SIZE))
+    }
+}
+
+// The whole pages that `segment` touches
+fn segment_pages(segment: &Segment) -> core::ops::Range<usize> {
+    align_down(segment.vaddr, PAGE_SIZE)..align_up(segment.vaddr + segment.mem_size, PAGE_SIZE)
+}
+
+/// Header at the start of a user image, written by `user.ld`
+///
+/// Section boundaries are page aligned so that each page of the image can be mapped with the
+/// permissions of a single section.
+#[derive(Copy, Clone, Debug)]
+struct ImageHeader {
+    entry: usize,       // Address of the start function
+    text_end: usize,    // End of code, mapped read-execute
+    rodata_end: usize,  // End of read-only data; everything after is mapped read-write
+}
+
+impl ImageHeader {
+    const WORDS: usize = 3;
+
+    // Read the header from the start of an image, checking the boundaries are plausible
+    fn parse(image: &[u8]) -> Option<Self> {
+        let mut words = image.chunks_exact(size_of::<usize>())
+            .take(Self::WORDS)
+            .map(|word| usize::from_le_bytes(word.try_into().expect("chunk should be one word")));
+        let header = Self {
+            entry: words.next()?,
+            text_end: words.next()?,
+            rodata_end: words.next()?,
+        };
+
+        let image_end = USER_BASE + align_up(image.len(), PAGE_SIZE);
+        let is_valid = (USER_BASE..header.text_end).contains(&header.entry)
+            && header.text_end <= header.rodata_end
+            && header.rodata_end <= image_end
+            && is_aligned(header.text_end, PAGE_SIZE)
+            && is_aligned(header.rodata_end, PAGE_SIZE);
+        is_valid.then_some(header)
+    }
+
+    // Code read-execute, read-only data read-only and the rest of an image of `len` bytes read-write.
+    // Empty sections are left out.
+    fn segments(&self, len: usize) -> ([Segment; SEGMENTS_MAX], usize) {
+        let image_end = USER_BASE + align_up(len, PAGE_SIZE);
+        let sections = [
+            (USER_BASE, self.text_end, PAGE_R | PAGE_X),
+            (self.text_end, self.rodata_end, PAGE_R),
+            (self.rodata_end, image_end, PAGE_R | PAGE_W),
+        ];
+        let mut segments = [Segment::default(); SEGMENTS_MAX];
+        let mut count = 0;
+        for (start, end, flags) in sections.into_iter().filter(|(start, end, _)| start < end) {
+            let offset = start - USER_BASE;
+            segments[count] = Segment {
+                vaddr: start,
+                mem_size: end - start,
+                offset,
+                file_size: len.saturating_sub(offset).min(end - start),
+                flags,
+            };
+            count += 1;
+        }
+        (segments, count)
+    }
+}
+
 #[unsafe(naked)]
 pub extern "C" fn user_entry() {
     naked_asm!("sret");
 }
 
-pub fn create_process(entry: usize, image: *const u8, image_size: usize) -> usize {
-    let is_kernel = {image_size == 0 };         // Kernel processes have zero image size
-    let mut procs = PROCS.0.lock();
-
-    // Find an unused process control structure.
-    let (i, process) = procs.iter_mut()
-        .enumerate()
-        .find(|(_, p)| p.state == State::Unused)
-        .expect("no free process slots");
-
-    // Map kernel pages.
+// A new page table mapping the kernel and devices, as every process has.
+// No page is both writable and executable: code is read-execute, read-only data is read-only
+// and everything else read-write. The guard page after the heap stays unmapped.
+fn kernel_page_table() -> Box<PageTable> {
     let mut page_table = Box::new(PageTable::new());
     let kernel_base = &raw const __kernel_base as usize;
-    let free_ram_end = &raw const __free_ram_end as usize;
+    let text_end = &raw const __text_end as usize;
+    let rodata_end = &raw const __rodata_end as usize;
+    let heap = heap_range();
 
-    for paddr in (kernel_base..free_ram_end).step_by(PAGE_SIZE) {
-        map_page(page_table.as_mut(), VAddr::new(paddr), PAddr::new(paddr), PAGE_R | PAGE_W | PAGE_X);
+    for (vaddr, paddr) in page_range(kernel_base, text_end) {
+        map_page(page_table.as_mut(), vaddr, paddr, PAGE_R | PAGE_X);
+    }
+    for (vaddr, paddr) in page_range(text_end, rodata_end) {
+        map_page(page_table.as_mut(), vaddr, paddr, PAGE_R);
+    }
+    for (vaddr, paddr) in page_range(rodata_end, heap.end) {
+        map_page(page_table.as_mut(), vaddr, paddr, PAGE_R | PAGE_W);
     }
 
     map_page(page_table.as_mut(), VAddr::new(VIRTIO_BLK_PADDR as usize), PAddr::new(VIRTIO_BLK_PADDR as usize), PAGE_R | PAGE_W);
+    map_page(page_table.as_mut(), VAddr::new(QEMU_TEST_PADDR), PAddr::new(QEMU_TEST_PADDR), PAGE_R | PAGE_W);
+    page_table
+}
+
+// Copy `args` into `args_page` and map it read-only at ARGS_VADDR
+fn map_args(page_table: &mut PageTable, args_page: &'static mut [u8; PAGE_SIZE], args: &[u8]) {
+    assert!(args.len() <= ARGS_SIZE, "arguments too large");
+    args_page[..args.len()].copy_from_slice(args);
+    map_page(
+        page_table,
+        VAddr::new(ARGS_VADDR),
+        PAddr::new(args_page.as_ptr() as usize),
+        PAGE_U | PAGE_R,
+    );
+}
+
+// The satp value that translates through `page_table`, tagging TLB entries with `asid`
+fn satp_for(page_table: &PageTable, asid: usize) -> usize {
+    SATP_SV32 | (asid << SATP_ASID_SHIFT) | (page_table as *const PageTable as usize / PAGE_SIZE)
+}
+
+/// Create a process
+///
+/// Kernel processes have a zero `image_size`. User processes get `args`, encoded as described in
+/// `common::args`, mapped read-only at `ARGS_VADDR`. The first argument names the process, for kernel
+/// processes too. User image pages are copied in as they are first touched, so the image must stay in
+/// memory for the life of the process.
+///
+/// `on_fault` applies to user processes; a fault in a kernel process is a kernel bug.
+///
+/// Returns the new process ID, or `Err(NoSpace)` if every process slot is in use or there is no memory for
+/// the arguments page.
+pub fn create_process(entry: usize, image: *const u8, image_size: usize, args: &[u8], on_fault: FaultPolicy) -> Result<usize, ErrorCode> {
+    let is_kernel = {image_size == 0 };         // Kernel processes have zero image size
+    let parent = CURRENT_PROC.lock().unwrap_or(IDLE_PID);
+    let args_page = if is_kernel { None } else { Some(alloc_frame()?) };
+    let mut procs = PROCS.0.lock();
+
+    // Find an unused process control structure, growing the table if none is free.
+    // An injected fault behaves as if the table is full.
+    let i = procs.iter()
+        .position(|p| p.state == State::Unused)
+        .or_else(|| (procs.len() < PROCS_MAX).then(|| {
+            procs.push(Process::boxed_zeroed());
+            procs.len() - 1
+        }))
+        .filter(|_| !should_fail(FAULT_PROCESS_CREATE));
+    let Some(i) = i else {
+        if let Some(args_page) = args_page {
+            // Safety: The page was allocated above and never mapped
+            unsafe { free_frame(PAddr::new(args_page.as_ptr() as usize)) };
+        }
+        return Err(ErrorCode::NoSpace);
+    };
+    let process = &mut procs[i];
+
+    // A reused slot still holds the kernel stack of the process that had it
+    process.stack.fill(0);
 
-    process.page_table = Some(page_table);
+    process.page_table = Some(kernel_page_table());
 
-    if !is_kernel {
-        // Map user pages.
-        let aligned_size = align_up(image_size, PAGE_SIZE);
+    let mut user_entry_vaddr = USER_BASE;
+    if let Some(args_page) = args_page {
+        // User pages are mapped when first touched, or all now if eager mapping is on
         let image_slice = unsafe {
             slice::from_raw_parts(image, image_size)
         };
-        let mut image_vec = image_slice.to_vec();
-        image_vec.resize(aligned_size, 0);
-        let image_data = Box::leak(image_vec.into_boxed_slice());
-        let page_table = process.page_table.as_mut()
-        .expect("page table must be initialized before mapping user pages");
+        let user_image = UserImage::new(image_slice)
+            .expect("user image should be an ELF executable or start with a valid header");
+        user_entry_vaddr = user_image.entry;
 
-        for (i, page_chunk) in image_data.chunks_mut(PAGE_SIZE).enumerate() {
-            let vaddr = VAddr::new(USER_BASE + i * PAGE_SIZE);
-            let paddr = PAddr::new(page_chunk.as_mut_ptr() as usize);
-
-            map_page(
-                page_table,
-                vaddr,
-                paddr,
-                PAGE_U | PAGE_R | PAGE_W | PAGE_X,
-            );
+        process.is_kernel = false;
+        process.image = user_image;
+        if EAGER_MAPPING.load(Ordering::Relaxed) {
+            for vaddr in user_image.pages() {
+                process.load_image_page(vaddr);
+            }
         }
+        let page_table = process.page_table.as_mut()
+        .expect("page table must be initialized before mapping user pages");
+        map_args(page_table, args_page, args);
     };
 
     // Create CSRs for new process
     let page_table = process.page_table.as_ref().expect("page_table should exist");
-    // Double deref on page_table for both ref and Box.
-    let page_table_addr = &**page_table as *const PageTable as usize;
-    let satp = SATP_SV32 | (page_table_addr / PAGE_SIZE);
+    // The slot's ASID may have been used by an earlier process with a different page table
+    let asid = asid_for(i + 1);
+    flush_tlb(asid);
+    let satp = satp_for(page_table, asid);
 
+    let stack_top = process.stack.len() - HART_SLOT_SIZE;
     let (sscratch, sepc, sstatus) = if is_kernel {
         (0, 0, read_csr!("sstatus"))                // Kernel CSRs
     } else {                                        // User CSRs
-        (process.stack.as_ptr_range().end as usize,
-         USER_BASE,
+        (&raw const process.stack[stack_top] as usize,
+         user_entry_vaddr,
          read_csr!("sstatus") | SSTATUS_SUM,
         )
     };
 
     // Stack callee-saved registers. These register values will be restored in
-    // the first context switch in switch_context.
+    // the first context switch in switch_context, which returns to process_start to jump to the entry.
     let callee_saved_regs: [usize; 17] = [
-        entry,          // ra
-        0,              // s0
+        process_start as *const () as usize,    // ra
+        entry,          // s0
         0,              // s1
         0,              // s2
         0,              // s3
@@ -134,8 +730,8 @@ pub fn create_process(entry: usize, image: *const u8, image_size: usize) -> usiz
         satp,           // satp
     ];
 
-    // Place the callee-saved registers at the end of the stack
-    let callee_saved_regs_start = process.stack.len() - callee_saved_regs.len() * size_of::<usize>();
+    // Place the callee-saved registers at the top of the stack
+    let callee_saved_regs_start = stack_top - callee_saved_regs.len() * size_of::<usize>();
     let mut offset = callee_saved_regs_start;
     for reg in &callee_saved_regs {
         let bytes = reg.to_ne_bytes(); // native endian
@@ -143,32 +739,49 @@ pub fn create_process(entry: usize, image: *const u8, image_size: usize) -> usiz
         offset += size_of::<usize>();
     }
 
+    process.set_stack_canary();
+
     // Initialise fields.
     process.pid = i + 1;
+    process.parent = parent;
+    process.name = process_name(args, is_kernel);
     process.state = State::Runnable;
+    process.is_kernel = is_kernel;
+    process.is_idle = false;
+    process.running_on = None;
+    process.last_hart = None;
+    process.files = [OpenFile::CLOSED; OPEN_FILES_MAX];
+    process.signals = Signals::NONE;
+    process.on_fault = on_fault;
+    process.restarts = 0;
+    process.exit_code = 0;
+    process.runnable_since = get_timer();
+    process.scheduled_at = 0;
+    process.wake_at = 0;
+    process.cpu_ticks = 0;
     process.sp = VAddr::new(&raw const process.stack[callee_saved_regs_start] as usize);
 
-    process.pid
+    Ok(process.pid)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{print, println};
+    use crate::print;
+    use crate::allocator::bytes_available;
+    use crate::page::lookup_pte;
+    use crate::scheduler::{procs, reap_orphans};
+    use crate::trap::{call_expecting_fault, SCAUSE_INSTRUCTION_PAGE_FAULT};
 
     #[test_case]
     fn create_and_remove_process() {
         print!("process: create and remove user process...");
 
-        unsafe extern "C" {
-            static _binary_shell_bin_start: u8;
-            static _binary_shell_bin_size: u8;
-        }
-
         // Create the user process (will also create idle process)
-        let shell_start = &raw const _binary_shell_bin_start as *mut u8;
-        let shell_size = &raw const _binary_shell_bin_size as usize;  // The symbol _address_ is the size of the binary
-        let shell_pid = create_process(user_entry as *const() as usize, shell_start, shell_size);
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        let shell_pid = create_process(user_entry as *const() as usize, shell.as_ptr(), shell.len(), &[], FaultPolicy::Kill)
+            .expect("should create process");
 
         // Check for existance of user process
         let shell_index = PROCS.try_get_index(shell_pid)
@@ -177,9 +790,579 @@ mod test {
         // Test and set back to unused
         let mut procs = PROCS.0.lock();
         assert!(procs[shell_index].state == State::Runnable);
-        procs[shell_index].state = State::Unused;
+        procs[shell_index].release();
         drop(procs);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
+    }
+
+    #[test_case]
+    fn released_process_frees_its_memory() {
+        print!("process: released process frees its memory...");
+
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        let create_and_release = || {
+            let pid = create_process(user_entry as *const() as usize, shell.as_ptr(), shell.len(), &[], FaultPolicy::Kill)
+                .expect("should create process");
+            let index = PROCS.try_get_index(pid)
+                .expect("should have created user process");
+            let mut procs = PROCS.0.lock();
+            // Loaded already if mapping is eager
+            procs[index].load_image_page(USER_BASE);
+            procs[index].state = State::Exited;
+            reap_orphans(&mut procs);
+            assert!(procs[index].state == State::Unused && procs[index].page_table.is_none());
+        };
+
+        // The first time may grow the process table; the second reuses a slot and must give back every
+        // page it took: the image page, the arguments page and the page tables
+        create_and_release();
+        let available = bytes_available();
+        create_and_release();
+        assert!(bytes_available() == available);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn sp_slot_is_inside_process() {
+        print!("process: sp slot is inside process...");
+
+        let mut process = procs(&[(1, State::Runnable)]).remove(0);
+        let start = &raw const *process as usize;
+        let slot = process.sp_slot();
+        let slot_addr = &raw const *slot as usize;
+        *slot = 0x80201000;
+
+        assert!((start..start + size_of::<Process>()).contains(&slot_addr));
+        assert!(process.sp.as_usize() == 0x80201000);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn process_names_in_dump() {
+        print!("process: process names in dump...");
+
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        let mut args = [0u8; 64];
+        let len = common::args::encode(["shell", "echo"], &mut args)
+            .expect("arguments should fit");
+        let named = create_process(user_entry as *const () as usize, shell.as_ptr(), shell.len(), &args[..len], FaultPolicy::Kill)
+            .expect("should create process");
+        let unnamed = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+
+        let mut procs = PROCS.0.lock();
+        let index = |pid| procs.iter().position(|p| p.pid == pid).unwrap();
+        let (named_i, unnamed_i) = (index(named), index(unnamed));
+        assert!(procs[named_i].name() == "shell");
+        assert!(procs[unnamed_i].name() == "kernel");
+        drop(procs);
+
+        let dump = alloc::format!("{}", PROCS);
+        assert!(dump.lines().any(|line| line.split_whitespace().eq([&*alloc::format!("{named}"), "shell", "Runnable"])));
+
+        // Long names are cut short at a character boundary
+        let len = common::args::encode(["a-very-long-program-🦀"], &mut args)
+            .expect("arguments should fit");
+        let name = process_name(&args[..len], false);
+        assert!(&name == b"a-very-long-prog");
+        let len = common::args::encode(["a-very-long-pr🦀"], &mut args)
+            .expect("arguments should fit");
+        assert!(&process_name(&args[..len], false) == b"a-very-long-pr\0\0");
+
+        let mut procs = PROCS.0.lock();
+        procs[named_i].release();
+        procs[unnamed_i].release();
+        drop(procs);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn heap_is_mapped_without_execute() {
+        print!("process: heap is mapped without execute...");
+
+        let pid = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created kernel process");
+
+        let satp = {
+            let procs = PROCS.0.lock();
+            let page_table = procs[index].page_table.as_ref()
+                .expect("page table should exist");
+
+            let heap_page = align_up(heap_range().start, PAGE_SIZE);
+            let pte = lookup_pte(page_table, VAddr::new(heap_page))
+                .expect("heap should be mapped");
+            assert!(pte & (PAGE_R | PAGE_W) == PAGE_R | PAGE_W);
+            assert!(pte & PAGE_X == 0);
+
+            let code_page = create_process as *const () as usize / PAGE_SIZE * PAGE_SIZE;
+            let pte = lookup_pte(page_table, VAddr::new(code_page))
+                .expect("kernel code should be mapped");
+            assert!(pte & (PAGE_R | PAGE_X) == PAGE_R | PAGE_X);
+            assert!(pte & PAGE_W == 0);
+
+            // The guard page straight after the heap is not mapped at all
+            assert!(lookup_pte(page_table, VAddr::new(heap_range().end)).is_none());
+
+            satp_for(page_table, asid_for(pid))
+        };
+
+        // Fetching an instruction from a heap page raises an instruction page fault. The page holds a `ret`,
+        // so the call comes straight back should the fetch be allowed.
+        let code = alloc_frame()
+            .expect("should allocate a frame");
+        code[..4].copy_from_slice(&0x0000_8067_u32.to_le_bytes());
+        let code_addr = code.as_ptr() as usize;
+        assert!(heap_range().contains(&code_addr));
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        let previous_satp = read_csr!("satp");
+        write_csr!("satp", satp);
+        flush_tlb(asid_for(pid));
+        let cause = call_expecting_fault(code_addr);
+        write_csr!("satp", previous_satp);
+        flush_tlb(0);
+        write_csr!("sstatus", sstatus);
+        // Safety: The frame was allocated above and nothing refers to it now
+        unsafe { free_frame(PAddr::new(code_addr)) };
+        assert!(cause == Some(SCAUSE_INSTRUCTION_PAGE_FAULT));
+
+        PROCS.0.lock()[index].release();
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn mappings_are_never_writable_and_executable() {
+        print!("process: mappings are never writable and executable...");
+
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        let header = ImageHeader::parse(shell)
+            .expect("shell should have a valid image header");
+        let pid = create_process(user_entry as *const () as usize, shell.as_ptr(), shell.len(), &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created user process");
+
+        // The last image page holds the user stack
+        let user_stack_page = USER_BASE + align_up(shell.len(), PAGE_SIZE) - PAGE_SIZE;
+        let mut procs = PROCS.0.lock();
+        assert!(procs[index].load_image_page(USER_BASE));
+        assert!(procs[index].load_image_page(user_stack_page));
+        let page_table = procs[index].page_table.as_ref()
+            .expect("page table should exist");
+
+        // Storing to a page without PAGE_W raises a store page fault
+        let user_code = lookup_pte(page_table, VAddr::new(USER_BASE))
+            .expect("user code should be mapped");
+        assert!(user_code & (PAGE_U | PAGE_R | PAGE_X) == PAGE_U | PAGE_R | PAGE_X);
+        assert!(user_code & PAGE_W == 0);
+
+        assert!(user_stack_page >= header.rodata_end);
+        let user_stack = lookup_pte(page_table, VAddr::new(user_stack_page))
+            .expect("user stack should be mapped");
+        assert!(user_stack & (PAGE_U | PAGE_R | PAGE_W) == PAGE_U | PAGE_R | PAGE_W);
+        assert!(user_stack & PAGE_X == 0);
+
+        let kernel_rodata = lookup_pte(page_table, VAddr::new(&raw const __text_end as usize))
+            .expect("kernel read-only data should be mapped");
+        assert!(kernel_rodata & (PAGE_R | PAGE_W | PAGE_X) == PAGE_R);
+
+        for vaddr in (&raw const __kernel_base as usize..heap_range().end).step_by(PAGE_SIZE) {
+            let pte = lookup_pte(page_table, VAddr::new(vaddr))
+                .expect("kernel memory should be mapped");
+            assert!(pte & (PAGE_W | PAGE_X) != PAGE_W | PAGE_X);
+        }
+
+        procs[index].release();
+        drop(procs);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn restart_after_fault() {
+        print!("process: restart after fault...");
+
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        let header = ImageHeader::parse(shell)
+            .expect("shell should have a valid image header");
+        let restarting = create_process(user_entry as *const () as usize, shell.as_ptr(), shell.len(), &[], FaultPolicy::Restart)
+            .expect("should create process");
+        let killed = create_process(user_entry as *const () as usize, shell.as_ptr(), shell.len(), &[], FaultPolicy::Kill)
+            .expect("should create process");
+
+        let mut procs = PROCS.0.lock();
+        let mut frame = TrapFrame::ZERO;
+        frame.a0 = 42;
+        frame.sscratch = 0x1234;
+
+        // A faulting Restart process comes back at its entry point with fresh registers
+        let p = procs.iter_mut().find(|p| p.pid == restarting).unwrap();
+        assert!(p.restart_after_fault(&mut frame) == Some(header.entry));
+        assert!({ frame.a0 } == 0);
+        assert!({ frame.sscratch } == 0x1234);
+        assert!(p.state == State::Runnable);
+
+        // ...until it has faulted too often
+        for _ in 1..RESTARTS_MAX {
+            assert!(p.restart_after_fault(&mut frame).is_some());
+        }
+        assert!(p.restart_after_fault(&mut frame).is_none());
+
+        let p = procs.iter_mut().find(|p| p.pid == killed).unwrap();
+        assert!(p.restart_after_fault(&mut frame).is_none());
+
+        procs.iter_mut()
+            .filter(|p| p.pid == restarting || p.pid == killed)
+            .for_each(|p| p.release());
+        drop(procs);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn image_pages_load_on_first_touch() {
+        print!("process: image pages load on first touch...");
+
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        let pages = align_up(shell.len(), PAGE_SIZE) / PAGE_SIZE;
+        assert!(pages >= 3, "shell should span at least three pages");
+        let is_mapped = |p: &Process, page: usize| {
+            let page_table = p.page_table.as_ref().expect("page table should exist");
+            lookup_pte(page_table, VAddr::new(USER_BASE + page * PAGE_SIZE)).is_some()
+        };
+
+        // Only the first and third pages are touched
+        let pid = create_process(user_entry as *const () as usize, shell.as_ptr(), shell.len(), &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created user process");
+        let mut procs = PROCS.0.lock();
+        assert!((0..pages).all(|page| !is_mapped(&procs[index], page)));
+        assert!(procs[index].load_image_page(USER_BASE + 4));
+        assert!(procs[index].load_image_page(USER_BASE + 2 * PAGE_SIZE + 8));
+        assert!(!procs[index].load_image_page(USER_BASE), "page should only be loaded once");
+        assert!(!procs[index].load_image_page(USER_BASE + pages * PAGE_SIZE), "outside the image");
+        let mapped: usize = (0..pages).filter(|&page| is_mapped(&procs[index], page)).count();
+        assert!(mapped == 2);
+        assert!(is_mapped(&procs[index], 2) && !is_mapped(&procs[index], 1));
+
+        // The loaded page is a copy of the image
+        let page_table = procs[index].page_table.as_ref().expect("page table should exist");
+        let pte = lookup_pte(page_table, VAddr::new(USER_BASE + 2 * PAGE_SIZE))
+            .expect("third page should be mapped");
+        let frame = (pte >> 10) * PAGE_SIZE;
+        // Safety: The frame was allocated from the identity mapped kernel heap
+        let copy = unsafe { slice::from_raw_parts(frame as *const u8, PAGE_SIZE) };
+        assert!(copy == &shell[2 * PAGE_SIZE..3 * PAGE_SIZE]);
+        procs[index].release();
+        drop(procs);
+
+        // Eager mapping loads every page up front
+        set_eager_mapping(true);
+        let pid = create_process(user_entry as *const () as usize, shell.as_ptr(), shell.len(), &[], FaultPolicy::Kill)
+            .expect("should create process");
+        set_eager_mapping(false);
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created user process");
+        let mut procs = PROCS.0.lock();
+        assert!((0..pages).all(|page| is_mapped(&procs[index], page)));
+        procs[index].release();
+        drop(procs);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn same_image_shares_read_only_pages() {
+        print!("process: same image shares read-only pages...");
+
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        let image = UserImage::new(shell)
+            .expect("shell should be a valid image");
+        let image_bytes = image.pages().count() * PAGE_SIZE;
+        let shared_bytes: usize = image.segments().iter()
+            .filter(|s| s.flags & PAGE_W == 0)
+            .map(|s| segment_pages(s).len())
+            .sum();
+        assert!(shared_bytes > 0);
+
+        let create = |eager| {
+            set_eager_mapping(eager);
+            let before = bytes_available();
+            let pid = create_process(user_entry as *const () as usize, shell.as_ptr(), shell.len(), &[], FaultPolicy::Kill)
+                .expect("should create process");
+            set_eager_mapping(false);
+            (pid, before - bytes_available())
+        };
+        let release = |pid| {
+            let index = PROCS.try_get_index(pid)
+                .expect("should have created user process");
+            PROCS.0.lock()[index].release();
+        };
+
+        // Leave two free slots, so that creating processes below does not grow the table, and measure a
+        // process with nothing of its image loaded
+        let (a, _) = create(false);
+        let (b, _) = create(false);
+        release(a);
+        release(b);
+        let (lazy, lazy_bytes) = create(false);
+        release(lazy);
+
+        // Once one process has loaded the image, another copies only the writable pages, and needs page
+        // tables for them
+        let (first, _) = create(true);
+        let (second, second_bytes) = create(true);
+        let mut tables: Vec<usize> = image.pages().map(|page| VAddr::new(page).vpn1()).collect();
+        tables.dedup();
+        assert!(second_bytes == lazy_bytes + tables.len() * PAGE_SIZE + image_bytes - shared_bytes);
+
+        // The read-only pages are the same frames in both
+        let procs = PROCS.0.lock();
+        let frame = |pid, page| {
+            let p = procs.iter().find(|p| p.pid == pid).expect("process should exist");
+            let page_table = p.page_table.as_ref().expect("page table should exist");
+            lookup_pte(page_table, VAddr::new(page)).expect("image should be mapped") >> 10
+        };
+        for segment in image.segments() {
+            let is_shared = segment.flags & PAGE_W == 0;
+            assert!(segment_pages(segment).step_by(PAGE_SIZE)
+                .all(|page| (frame(first, page) == frame(second, page)) == is_shared));
+        }
+        drop(procs);
+        release(first);
+        release(second);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn create_more_than_eight_processes() {
+        print!("process: create more than eight processes...");
+
+        let pids: [usize; 10] = core::array::from_fn(|_| {
+            create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill).expect("should create process")
+        });
+
+        let mut procs = PROCS.0.lock();
+        assert!(procs.len() >= pids.len());
+        for pid in pids {
+            let p = procs.iter_mut()
+                .find(|p| p.pid == pid)
+                .expect("should have created kernel process");
+            assert!(p.state == State::Runnable);
+            p.state = State::Unused;
+        }
+        drop(procs);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn create_process_fails_when_full() {
+        print!("process: create process fails when full...");
+
+        // No timer tick may switch to the new processes, and they sleep so that nothing else runs them
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        let create_asleep = || -> Result<usize, ErrorCode> {
+            let pid = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)?;
+            PROCS.0.lock().iter_mut()
+                .filter(|p| p.pid == pid)
+                .for_each(|p| p.state = State::Sleeping);
+            Ok(pid)
+        };
+        let release = |pids: &[usize]| PROCS.0.lock().iter_mut()
+            .filter(|p| pids.contains(&p.pid))
+            .for_each(|p| p.release());
+
+        // Fill every free slot, then one more
+        let mut pids = alloc::vec::Vec::new();
+        let full = loop {
+            match create_asleep() {
+                Ok(pid) => pids.push(pid),
+                Err(e) => break e,
+            }
+        };
+        assert!(full == ErrorCode::NoSpace);
+        assert!(PROCS.0.lock().len() == PROCS_MAX);
+        release(&pids);
+
+        // A freed slot can be used again
+        let pid = create_asleep()
+            .expect("should create process in a freed slot");
+        release(&[pid]);
+        write_csr!("sstatus", sstatus);
+
+        common::ok!();
+    }
+
+    // A two-segment ELF executable: a code page, then a data page followed by a page of `.bss`
+    fn tiny_elf(data_flags: u32) -> &'static [u8] {
+        let mut image = alloc::vec![0u8; 3 * PAGE_SIZE];
+        let mut put = |off: usize, bytes: &[u8]| image[off..off + bytes.len()].copy_from_slice(bytes);
+        put(0, b"\x7fELF\x01\x01\x01");
+        put(16, &2u16.to_le_bytes());                   // e_type: executable
+        put(18, &0xf3u16.to_le_bytes());                // e_machine: RISC-V
+        put(24, &(USER_BASE as u32 + 4).to_le_bytes()); // e_entry
+        put(28, &52u32.to_le_bytes());                  // e_phoff
+        put(42, &32u16.to_le_bytes());                  // e_phentsize
+        put(44, &2u16.to_le_bytes());                   // e_phnum
+        let segments = [
+            (PAGE_SIZE, USER_BASE, 8, 8, 0b101),                            // Read-execute
+            (2 * PAGE_SIZE, USER_BASE + PAGE_SIZE, 4, 2 * PAGE_SIZE, data_flags),
+        ];
+        for (i, (offset, vaddr, file_size, mem_size, flags)) in segments.into_iter().enumerate() {
+            let ph = 52 + i * 32;
+            put(ph, &1u32.to_le_bytes());               // p_type: PT_LOAD
+            put(ph + 4, &(offset as u32).to_le_bytes());
+            put(ph + 8, &(vaddr as u32).to_le_bytes());
+            put(ph + 16, &(file_size as u32).to_le_bytes());
+            put(ph + 20, &(mem_size as u32).to_le_bytes());
+            put(ph + 24, &flags.to_le_bytes());
+        }
+        put(PAGE_SIZE, &[0x13, 0, 0, 0, 0x6f, 0, 0, 0]); // nop; j .
+        put(2 * PAGE_SIZE, b"data");
+        alloc::vec::Vec::leak(image)
+    }
+
+    #[test_case]
+    fn load_elf_segments() {
+        print!("process: load ELF segments...");
+
+        // Read-write data; 0b110 is PF_R | PF_W
+        let elf = tiny_elf(0b110);
+        let pid = create_process(user_entry as *const () as usize, elf.as_ptr(), elf.len(), &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let mut procs = PROCS.0.lock();
+        let p = procs.iter_mut().find(|p| p.pid == pid).unwrap();
+        assert!(p.image.entry == USER_BASE + 4);
+        for page in 0..3 {
+            assert!(p.load_image_page(USER_BASE + page * PAGE_SIZE));
+        }
+        assert!(!p.load_image_page(USER_BASE + 3 * PAGE_SIZE), "outside every segment");
+
+        let page_table = p.page_table.as_ref().expect("page table should exist");
+        let flags = |page: usize| lookup_pte(page_table, VAddr::new(USER_BASE + page * PAGE_SIZE))
+            .expect("segment page should be mapped") & (PAGE_U | PAGE_R | PAGE_W | PAGE_X);
+        assert!(flags(0) == PAGE_U | PAGE_R | PAGE_X);
+        assert!(flags(1) == PAGE_U | PAGE_R | PAGE_W);
+        assert!(flags(2) == PAGE_U | PAGE_R | PAGE_W);
+
+        // File bytes are copied and the rest of each segment is zero
+        let frame = |page: usize| {
+            let pte = lookup_pte(page_table, VAddr::new(USER_BASE + page * PAGE_SIZE)).unwrap();
+            // Safety: The frame was allocated from the identity mapped kernel heap
+            unsafe { slice::from_raw_parts(((pte >> 10) * PAGE_SIZE) as *const u8, PAGE_SIZE) }
+        };
+        assert!(frame(0)[..8] == elf[PAGE_SIZE..PAGE_SIZE + 8] && frame(0)[8..].iter().all(|&b| b == 0));
+        assert!(&frame(1)[..4] == b"data" && frame(1)[4..].iter().all(|&b| b == 0));
+        assert!(frame(2).iter().all(|&b| b == 0));
+        p.state = State::Unused;
+        drop(procs);
+
+        // A writable and executable segment is refused, and an image without the magic is a flat binary
+        assert!(UserImage::new(tiny_elf(0b111)).is_none());
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        assert!(UserImage::new(shell).is_some_and(|image| image.segments().iter().all(|s| s.vaddr >= USER_BASE)));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn exec_replaces_image() {
+        print!("process: exec replaces image...");
+
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        let header = ImageHeader::parse(shell)
+            .expect("shell should have a valid image header");
+        let pid = create_process(user_entry as *const () as usize, shell.as_ptr(), shell.len(), b"old", FaultPolicy::Kill)
+            .expect("should create process");
+
+        let mut procs = PROCS.0.lock();
+        let p = procs.iter_mut().find(|p| p.pid == pid).unwrap();
+        assert!(p.load_image_page(USER_BASE));
+        let anonymous = p.map_anonymous(PAGE_SIZE, PAGE_R | PAGE_W)
+            .expect("should map memory");
+        p.signals.handlers[0] = 0x1234;
+
+        assert!(p.exec(shell, b"new") == Ok(header.entry));
+        assert!(p.pid == pid);
+        assert!(p.signals.handlers[0] == 0);
+        let page_table = p.page_table.as_ref().expect("page table should exist");
+        assert!(lookup_pte(page_table, VAddr::new(USER_BASE)).is_none());
+        assert!(lookup_pte(page_table, VAddr::new(anonymous)).is_none());
+        let args_pte = lookup_pte(page_table, VAddr::new(ARGS_VADDR))
+            .expect("arguments should be mapped");
+        let args_page = (args_pte >> 10) * PAGE_SIZE;
+        // Safety: The arguments page is kernel heap memory, identity mapped
+        assert!(unsafe { slice::from_raw_parts(args_page as *const u8, 3) } == b"new");
+
+        // The new image loads on first touch
+        assert!(p.load_image_page(USER_BASE));
+
+        p.release();
+        drop(procs);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn reused_stack_is_zeroed() {
+        print!("process: reused stack is zeroed...");
+
+        let sentinel = *b"secret";
+        let pid = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created kernel process");
+        let mut procs = PROCS.0.lock();
+        procs[index].stack[1024..1024 + sentinel.len()].copy_from_slice(&sentinel);
+        procs[index].state = State::Unused;
+        drop(procs);
+
+        // The freed slot is the first unused one again, so the next process gets it
+        let reused = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process in the freed slot");
+        assert!(reused == pid);
+        let mut procs = PROCS.0.lock();
+        assert!(procs[index].stack[1024..1024 + sentinel.len()] == [0; 6]);
+        procs[index].state = State::Unused;
+        drop(procs);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn encode_and_parse_args() {
+        print!("process: encode and parse args...");
+
+        let mut buf = [0u8; ARGS_SIZE];
+        let len = common::args::encode(["shell", "echo", "hi"], &mut buf)
+            .expect("arguments should fit");
+        let args = common::args::Args::parse(&buf[..len]);
+        assert!(args.is_valid());
+        assert!(args.eq(["shell", "echo", "hi"]));
+
+        // Truncated arguments are rejected
+        assert!(!common::args::Args::parse(&buf[..len - 1]).is_valid());
+        assert!(common::args::encode(["too long"], &mut buf[..8]).is_none());
+
+        common::ok!();
     }
 }
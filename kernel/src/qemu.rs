@@ -0,0 +1,81 @@
+//! QEMU test device
+//!
+//! The `sifive_test` device on the QEMU `virt` machine exits QEMU when a status is written to it, so a test
+//! run can report whether it passed.
+
+use core::hint::spin_loop;
+use core::ptr;
+
+use common::{QEMU_EXIT_FAIL, QEMU_EXIT_PASS};
+
+pub const QEMU_TEST_PADDR: usize = 0x100000;
+
+/// Exit QEMU with `code`: `QEMU_EXIT_PASS`, or `QEMU_EXIT_FAIL` with an exit status in the upper 16 bits
+pub fn qemu_exit(code: u32) -> ! {
+    // Safety: QEMU_TEST_PADDR is the test device, identity mapped in every page table
+    unsafe {
+        ptr::write_volatile(QEMU_TEST_PADDR as *mut u32, code);
+    }
+    // Not reached on QEMU
+    loop {
+        spin_loop();
+    }
+}
+
+/// Exit QEMU with status 0
+pub fn qemu_exit_pass() -> ! {
+    qemu_exit(QEMU_EXIT_PASS)
+}
+
+/// Exit QEMU with status 1
+#[cfg_attr(not(test), expect(dead_code))]
+pub fn qemu_exit_fail() -> ! {
+    qemu_exit(fail_code(1))
+}
+
+// The code that makes QEMU exit with `status`, which must not be 0
+const fn fail_code(status: u16) -> u32 {
+    (status as u32) << 16 | QEMU_EXIT_FAIL
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::address::VAddr;
+    use crate::page::{lookup_pte, PAGE_W};
+    use crate::print;
+    use crate::process::{create_process, FaultPolicy, State};
+    use crate::scheduler::{reap_orphans, PROCS};
+
+    #[test_case]
+    fn exit_codes() {
+        print!("qemu: exit codes...");
+
+        // The status goes in the upper half, above the failure code
+        assert!(fail_code(1) == 0x1_3333);
+        assert!(fail_code(0xabcd) == 0xabcd_3333);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn exit_device_is_mapped_in_processes() {
+        print!("qemu: exit device is mapped in processes...");
+
+        // The test shell supervisor, or a panic in any process, exits QEMU on its own page table
+        let pid = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created process");
+        let mut procs = PROCS.0.lock();
+        let page_table = procs[index].page_table.as_ref()
+            .expect("process should have a page table");
+        let pte = lookup_pte(page_table, VAddr::new(QEMU_TEST_PADDR))
+            .expect("exit device should be mapped");
+        assert!(pte & PAGE_W != 0);
+        procs[index].state = State::Exited;
+        reap_orphans(&mut procs);
+
+        common::ok!();
+    }
+}
@@ -0,0 +1,79 @@
+//! Pseudo-random numbers
+//!
+//! A xorshift generator, seeded from the timer the first time it is used. A few outputs are enough to
+//! predict the rest, so it is not cryptographically secure: fine for games and randomised tests, not keys.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::timer::get_timer;
+
+// Generator state, or 0 until seeded; xorshift never reaches 0 from any other value
+static STATE: AtomicU32 = AtomicU32::new(0);
+
+fn xorshift32(mut x: u32) -> u32 {
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    x
+}
+
+// A nonzero starting state from the timer
+fn seed() -> u32 {
+    let ticks = get_timer();
+    (ticks ^ (ticks >> 32)) as u32 | 1
+}
+
+/// The next pseudo-random number
+pub fn next_u32() -> u32 {
+    let mut state = STATE.load(Ordering::Relaxed);
+    loop {
+        let next = xorshift32(if state == 0 { seed() } else { state });
+        // Another hart may have taken a number meanwhile; each state is used once
+        match STATE.compare_exchange_weak(state, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next,
+            Err(current) => state = current,
+        }
+    }
+}
+
+/// Fill `buf` with pseudo-random bytes
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(size_of::<u32>()) {
+        chunk.copy_from_slice(&next_u32().to_ne_bytes()[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+
+    #[test_case]
+    fn xorshift_known_values() {
+        print!("random: xorshift known values...");
+
+        // The first outputs from a state of 1
+        assert!(xorshift32(1) == 270369);
+        assert!(xorshift32(270369) == 67634689);
+        assert!(xorshift32(u32::MAX) != 0);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn fill_changes_buffer() {
+        print!("random: fill changes buffer...");
+
+        assert!(next_u32() != next_u32());
+
+        // Odd lengths fill every byte; all zero after a fill is vanishingly unlikely
+        let mut buf = [0u8; 13];
+        fill(&mut buf);
+        assert!(buf.iter().any(|&b| b != 0));
+        let mut more = [0u8; 13];
+        fill(&mut more);
+        assert!(more != buf);
+
+        common::ok!();
+    }
+}
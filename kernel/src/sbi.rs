@@ -2,12 +2,110 @@
 
 use core::arch::asm;
 use core::ffi::{c_long, c_int};
+use core::sync::atomic::{AtomicU8, Ordering};
+#[cfg(test)]
+use core::sync::atomic::{AtomicBool, AtomicUsize};
+
+#[cfg(test)]
+use crate::spinlock::SpinLock;
 
 pub const EID_SET_TIMER: c_long = 0;
 pub const EID_CONSOLE_PUTCHAR: c_long = 1;
 pub const EID_CONSOLE_GETCHAR: c_long = 2;
+pub const EID_BASE: c_long = 0x10;
+pub const EID_SYSTEM_RESET: c_long = 0x53525354;   // "SRST"
+pub const EID_DEBUG_CONSOLE: c_long = 0x4442434E;  // "DBCN"
+pub const EID_HART_STATE: c_long = 0x48534D;       // "HSM"
+
+const FID_PROBE_EXTENSION: c_long = 3;
+const FID_CONSOLE_WRITE: c_long = 0;
+
+const FID_HART_START: c_long = 0;
+
+const FID_SYSTEM_RESET: c_long = 0;
+const RESET_TYPE_SHUTDOWN: c_long = 0;
+const RESET_TYPE_COLD_REBOOT: c_long = 1;
+const RESET_REASON_NONE: c_long = 0;
+
+// Bytes passed to the debug console extension per call, copied into a kernel buffer first
+const CONSOLE_WRITE_CHUNK: usize = 64;
+
+// Whether the debug console extension is available, probed on first use
+const DBCN_UNKNOWN: u8 = 0;
+const DBCN_ABSENT: u8 = 1;
+const DBCN_PRESENT: u8 = 2;
+static DBCN: AtomicU8 = AtomicU8::new(DBCN_UNKNOWN);
+
+/// Check whether the SBI implementation provides extension `eid`
+pub fn probe_extension(eid: c_long) -> bool {
+    let error: c_long;
+    let value: c_long;
+    unsafe {
+        asm!(
+            "ecall",
+             inlateout("a0") eid => error,
+             lateout("a1") value,
+             in("a6") FID_PROBE_EXTENSION,
+             in("a7") EID_BASE,
+        );
+    }
+    error == 0 && value != 0
+}
+
+fn has_debug_console() -> bool {
+    match DBCN.load(Ordering::Relaxed) {
+        DBCN_PRESENT => true,
+        DBCN_ABSENT => false,
+        _ => {
+            let present = probe_extension(EID_DEBUG_CONSOLE);
+            DBCN.store(if present { DBCN_PRESENT } else { DBCN_ABSENT }, Ordering::Relaxed);
+            present
+        }
+    }
+}
 
+/// Write `buf` to the debug console, returning the number of bytes written
+///
+/// Uses the Debug Console extension to write many bytes per call, falling back to `put_byte` if it is not
+/// available. The SBI takes a physical address, so `buf`, which may be in user memory, is copied through a
+/// buffer on the kernel stack, which is identity mapped. A console that fails partway returns the bytes
+/// written so far, and the error only if there are none.
 #[unsafe(no_mangle)]
+pub fn console_write(buf: &[u8]) -> Result<usize, isize> {
+    if !has_debug_console() {
+        for (written, &b) in buf.iter().enumerate() {
+            if let Err(e) = put_byte(b) {
+                return if written == 0 { Err(e) } else { Ok(written) };
+            }
+        }
+        return Ok(buf.len());
+    }
+
+    let mut chunk = [0u8; CONSOLE_WRITE_CHUNK];
+    let mut written = 0;
+    while written < buf.len() {
+        let len = (buf.len() - written).min(CONSOLE_WRITE_CHUNK);
+        chunk[..len].copy_from_slice(&buf[written..written + len]);
+        let error: c_long;
+        let count: c_long;
+        unsafe {
+            asm!(
+                "ecall",
+                 inlateout("a0") len => error,
+                 inlateout("a1") chunk.as_ptr() => count,
+                 in("a2") 0,                              // High bits of the physical address
+                 in("a6") FID_CONSOLE_WRITE,
+                 in("a7") EID_DEBUG_CONSOLE,
+            );
+        }
+        if error != 0 {
+            return if written == 0 { Err(error as isize) } else { Ok(written) };
+        }
+        written += count as usize;
+    }
+    Ok(written)
+}
+
 pub fn put_byte(b: u8) -> Result<isize, isize> {
     let result: c_long;
     unsafe {
@@ -33,10 +131,11 @@ pub fn get_char() -> Result<isize, isize> {
              in("a7") EID_CONSOLE_GETCHAR,
         );
     }
-    if result != -1 {
+    // -1 means no byte is ready; other negative values are SBI errors
+    if result >= 0 {
         Ok(result as isize)
     } else {
-        Err(-1)
+        Err(result as isize)
     }
 }
 
@@ -60,6 +159,144 @@ pub fn set_timer(ticks: u64) -> Result<isize, isize> {
     }
 }
 
+/// Start hart `hart_id` in supervisor mode at `start_addr`, with its hart ID in a0 and `opaque` in a1
+///
+/// Returns the SBI error if there is no such hart or it is already running.
+pub fn hart_start(hart_id: usize, start_addr: usize, opaque: usize) -> Result<(), isize> {
+    let error: c_long;
+    unsafe {
+        asm!(
+            "ecall",
+             inlateout("a0") hart_id => error,
+             inlateout("a1") start_addr => _,
+             in("a2") opaque,
+             in("a6") FID_HART_START,
+             in("a7") EID_HART_STATE,
+        );
+    }
+    if error == 0 {
+        Ok(())
+    } else {
+        Err(error as isize)
+    }
+}
+
+fn system_reset(reset_type: c_long, reset_reason: c_long) -> isize {
+    let error: c_long;
+    unsafe {
+        asm!(
+            "ecall",
+             inlateout("a0") reset_type => error,
+             inlateout("a1") reset_reason => _,
+             in("a6") FID_SYSTEM_RESET,
+             in("a7") EID_SYSTEM_RESET,
+        );
+    }
+    error as isize
+}
+
+/// Power off the machine
+pub fn shutdown() -> ! {
+    let error = system_reset(RESET_TYPE_SHUTDOWN, RESET_REASON_NONE);
+    panic!("system shutdown failed error={}", error);
+}
+
+/// Cold reboot the machine
+#[expect(dead_code)]
+pub fn reboot() -> ! {
+    let error = system_reset(RESET_TYPE_COLD_REBOOT, RESET_REASON_NONE);
+    panic!("system reboot failed error={}", error);
+}
+
+/// SBI calls the kernel makes while running, so that tests can replace them
+pub trait Sbi {
+    fn put_byte(&self, b: u8) -> Result<isize, isize>;
+    fn get_char(&self) -> Result<isize, isize>;
+    fn set_timer(&self, ticks: u64) -> Result<isize, isize>;
+}
+
+/// The SBI implementation reached with `ecall`
+pub struct EcallSbi;
+
+impl Sbi for EcallSbi {
+    fn put_byte(&self, b: u8) -> Result<isize, isize> {
+        put_byte(b)
+    }
+
+    fn get_char(&self) -> Result<isize, isize> {
+        get_char()
+    }
+
+    fn set_timer(&self, ticks: u64) -> Result<isize, isize> {
+        set_timer(ticks)
+    }
+}
+
+/// The SBI used by the kernel: `EcallSbi`, or `MOCK_SBI` while a test has it installed
+///
+/// Boot, shutdown and the panic handler always make `ecall`s directly.
+pub fn sbi() -> &'static dyn Sbi {
+    #[cfg(test)]
+    if USE_MOCK_SBI.load(Ordering::Relaxed) {
+        return &MOCK_SBI;
+    }
+    &EcallSbi
+}
+
+/// Records SBI calls instead of making them
+#[cfg(test)]
+pub struct MockSbi {
+    pub bytes_put: AtomicUsize,
+    pub put_calls: AtomicUsize,             // Calls to put_byte, including those refused
+    pub busy_every: AtomicUsize,            // Refuse every nth put_byte as busy, or none if zero
+    pub deadline: SpinLock<Option<u64>>,    // Last ticks passed to set_timer
+    pub input: SpinLock<Option<u8>>,        // Next byte for get_char to return
+}
+
+#[cfg(test)]
+impl Sbi for MockSbi {
+    fn put_byte(&self, _: u8) -> Result<isize, isize> {
+        let calls = self.put_calls.fetch_add(1, Ordering::Relaxed) + 1;
+        let busy_every = self.busy_every.load(Ordering::Relaxed);
+        if busy_every != 0 && calls % busy_every == 0 {
+            return Err(-1);
+        }
+        self.bytes_put.fetch_add(1, Ordering::Relaxed);
+        Ok(0)
+    }
+
+    fn get_char(&self) -> Result<isize, isize> {
+        self.input.lock().take()
+            .map(|b| b as isize)
+            .ok_or(-1)      // No byte ready
+    }
+
+    fn set_timer(&self, ticks: u64) -> Result<isize, isize> {
+        *self.deadline.lock() = Some(ticks);
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+pub static MOCK_SBI: MockSbi = MockSbi {
+    bytes_put: AtomicUsize::new(0),
+    put_calls: AtomicUsize::new(0),
+    busy_every: AtomicUsize::new(0),
+    deadline: SpinLock::new(None),
+    input: SpinLock::new(None),
+};
+
+#[cfg(test)]
+static USE_MOCK_SBI: AtomicBool = AtomicBool::new(false);
+
+/// Route `sbi()` to `MOCK_SBI`, or back to `EcallSbi`
+///
+/// Disable interrupts while the mock is in use: the timer interrupt handler also arms the timer.
+#[cfg(test)]
+pub fn use_mock_sbi(mock: bool) {
+    USE_MOCK_SBI.store(mock, Ordering::Relaxed);
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -69,14 +306,51 @@ mod test {
     fn push_a_byte() {
         print!("sbi: push an 'X'... ");
         let _ = put_byte(b'X');
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
+    }
+
+    #[test_case]
+    fn probe_base_extension() {
+        print!("sbi: probe base extension... ");
+        assert!(probe_extension(EID_BASE));
+        common::ok!();
+    }
+
+    #[test_case]
+    fn write_a_buffer() {
+        print!("sbi: write a buffer... ");
+        assert_eq!(console_write(b"XYZ "), Ok(4));
+        // Longer than one chunk through the kernel buffer
+        assert_eq!(console_write(&[b'.'; CONSOLE_WRITE_CHUNK + 3]), Ok(CONSOLE_WRITE_CHUNK + 3));
+        print!(" ");
+        common::ok!();
+    }
+
+    #[test_case]
+    fn mock_records_calls() {
+        print!("sbi: mock records calls... ");
+
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !crate::scheduler::SSTATUS_SIE);
+        use_mock_sbi(true);
+        let bytes_put = MOCK_SBI.bytes_put.load(Ordering::Relaxed);
+        let put = sbi().put_byte(b'M');
+        let got = sbi().get_char();
+        let set = sbi().set_timer(42);
+        use_mock_sbi(false);
+        write_csr!("sstatus", sstatus);
+
+        assert!(put == Ok(0) && got == Err(-1) && set == Ok(0));
+        assert!(MOCK_SBI.bytes_put.load(Ordering::Relaxed) == bytes_put + 1);
+        assert!(*MOCK_SBI.deadline.lock() == Some(42));
+        common::ok!();
     }
 
     #[test_case]
     fn test_get_char() {
         print!("sbi: get char non-blocking... ");
         let _ = get_char();
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -84,7 +358,7 @@ mod test {
         print!("sbi: making sbi set_timer call... ");
         let ticks: u64 = 1_000_000;
         if let Ok(result) = set_timer(ticks) {
-            println!("[\x1b[32mok\x1b[0m]");
+            common::ok!();
         } else {
             println!("X");
         }
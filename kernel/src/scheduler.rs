@@ -1,18 +1,35 @@
 //! Round-robin scheduler
+//!
+//! Each hart runs its own current process and falls back to its own idle process. A process is claimed by
+//! the hart that switches to it until that hart has switched away again, so no two harts run it at once.
 
-use core::arch::naked_asm;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
-use crate::process::{create_process, Process, State};
+use core::arch::{asm, naked_asm};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use common::SCHED_LATENCY_BUCKETS;
+use common::args;
+
+use crate::hart::{hart_id, PerHart, HARTS_MAX};
+use crate::page::{asid_for, flush_tlb, probe_asids, PageTable, PAGE_SIZE, SATP_ASID_MASK, SATP_ASID_SHIFT, SATP_SV32};
+use crate::process::{create_process, FaultPolicy, Process, State};
 use crate::spinlock::SpinLock;
-use crate::timer::TIMER;
+use crate::tar::fs_sync;
+use crate::timer::{get_timer, TIMER};
+use crate::watchdog;
+
+pub use common::PROCS_MAX;             // Maximum number of processes
 
-pub const PROCS_MAX: usize = 8;         // Maximum number of processes
-pub struct Procs(pub SpinLock<[Process; PROCS_MAX]>);
+// Processes are boxed so they never move as the table grows: saved stack pointers point into them.
+pub struct Procs(pub SpinLock<Vec<Box<Process>>>);
 
 impl Procs {
     const fn new() -> Self {
         Self(
-            SpinLock::new([const { Process::zeroed() }; PROCS_MAX])
+            SpinLock::new(Vec::new())
         )
     }
 
@@ -20,109 +37,423 @@ impl Procs {
         self.0.lock().iter().position(|p| p.pid == pid)
     }
 
+    /// Run `f` on the process running on this hart, with the table locked
+    pub fn with_current<R>(&self, f: impl FnOnce(&mut Process) -> R) -> R {
+        let current = CURRENT_PROC.lock()
+            .expect("current process should be running");
+        let mut procs = self.0.lock();
+        let process = procs.iter_mut()
+            .find(|p| p.pid == current)
+            .expect("current process should exist");
+        f(process)
+    }
+
+    #[cfg_attr(not(test), expect(dead_code))]
     pub fn get_next(&self, current_pid: usize) -> usize {
-        // Search for the next runnable process; return IDLE_PID if none found
-        {
-            let current_index = PROCS.try_get_index(current_pid)
+        // Search for the next runnable process; return this hart's idle process if none found
+        next_pid(&self.0.lock(), current_pid, sched_order())
+    }
+}
+
+/// Order in which runnable processes take turns
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SchedOrder {
+    RoundRobin,     // The process that was switched in longest ago, then the next slot after the current process
+    PidOrder,       // The next higher pid, wrapping round to the lowest; makes the run sequence reproducible in tests
+}
+
+static PID_ORDER: AtomicBool = AtomicBool::new(false);
+
+pub fn sched_order() -> SchedOrder {
+    if PID_ORDER.load(Ordering::Relaxed) { SchedOrder::PidOrder } else { SchedOrder::RoundRobin }
+}
+
+#[cfg_attr(not(test), expect(dead_code))]
+pub fn set_sched_order(order: SchedOrder) {
+    PID_ORDER.store(order == SchedOrder::PidOrder, Ordering::Relaxed);
+}
+
+// The next process for this hart to run after `current_pid`, or its idle process if there is none.
+// Processes running on other harts and every hart's idle process are passed over.
+fn next_pid(procs: &[Box<Process>], current_pid: usize, order: SchedOrder) -> usize {
+    let is_runnable = |p: &Process| p.state == State::Runnable
+        && p.pid != IDLE_PID
+        && !p.is_idle
+        && (p.pid == current_pid || p.running_on.is_none());
+    match order {
+        SchedOrder::RoundRobin => {
+            let current_index = procs.iter()
+                .position(|p| p.pid == current_pid)
                 .expect("current process PID should have an index");
-            PROCS.0.lock().iter()
+            // The current process is running now, so it goes last whenever it last switched in
+            procs.iter()
                 .cycle()
                 .skip(current_index + 1)
-                .take(PROCS_MAX)
-                .find(|p| p.state == State::Runnable && p.pid != IDLE_PID)
+                .take(procs.len())
+                .filter(|p| is_runnable(p))
+                .min_by_key(|p| (p.pid == current_pid, p.scheduled_at))
                 .map(|p| p.pid)
-                .unwrap_or(IDLE_PID)
-        }
+        },
+        SchedOrder::PidOrder => {
+            let runnable_pids = || procs.iter().filter(|p| is_runnable(p)).map(|p| p.pid);
+            runnable_pids()
+                .filter(|&pid| pid > current_pid)
+                .min()
+                .or_else(|| runnable_pids().min())
+        },
     }
+    .unwrap_or_else(idle_pid)
 }
 
 pub static PROCS: Procs = Procs::new();  // All process control structures.
 
-// Optional - but vital for debugging if you want to print the contents of PROCS.
-// impl alloc::fmt::Display for Procs {
-//     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-//         let procs = PROCS.0.lock();
-//         for (i, process) in procs.iter().enumerate() {
-//             write!(f, "Addr: {:x?} ", &raw const *process as usize)?;
-//             writeln!(f, "PROC[{i}]")?;
-//             write!(f, "PID: {} ", process.pid)?;
-//             write!(f, "SP: {:x?} ", process.sp)?;
-//             writeln!(f, "STATE: {:?} ", process.state)?;
-//             writeln!(f, "IS_KERNEL: {:?} ", process.is_kernel)?;
-//             writeln!(f, "STACK:  ... {:x?}", &process.stack[process.stack.len()-128..process.stack.len()])? // Remember range top is _exclusive_ hence no panic
-//         }
-//         Ok(())
-//     }
-// }
-
-pub static CURRENT_PROC: SpinLock<Option<usize>> = SpinLock::new(Some(IDLE_PID)); // Currently running process set to idle at start
-
-pub const IDLE_PID: usize = 0;      // idle
-const SIE_STIE: usize = 1 << 5;     // Enable supervisor timer interrupt
+/// Free each exited process that nothing will wait for, making its slot unused
+///
+/// Only a live parent other than an idle process may still wait; otherwise the process is freed as soon as
+/// it has exited and switched away for the last time. The children of a freed process pass to the idle
+/// process, so they are freed in turn once they exit.
+pub fn reap_orphans(procs: &mut [Box<Process>]) {
+    let may_wait = |procs: &[Box<Process>], pid: usize| procs.iter()
+        .any(|p| p.pid == pid && !p.is_idle && matches!(p.state, State::Runnable | State::Sleeping));
+    for i in 0..procs.len() {
+        let p = &procs[i];
+        if p.state != State::Exited || p.running_on.is_some() || may_wait(procs, p.parent) {
+            continue;
+        }
+        let pid = p.pid;
+        procs[i].release();
+        for child in procs.iter_mut().filter(|child| child.parent == pid) {
+            child.parent = IDLE_PID;
+        }
+    }
+}
+
+/// A process table holding zeroed processes with the given pids and states, for testing scheduling decisions
+#[cfg(test)]
+pub fn procs(spec: &[(usize, State)]) -> Vec<Box<Process>> {
+    spec.iter()
+        .map(|&(pid, state)| {
+            let mut p = Process::boxed_zeroed();
+            p.pid = pid;
+            p.state = state;
+            p
+        })
+        .collect()
+}
+
+// Lists each process in use with its name and state, for debugging
+impl fmt::Display for Procs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "  PID NAME             STATE")?;
+        for process in self.0.lock().iter().filter(|p| p.state != State::Unused) {
+            writeln!(f, "{:>5} {:<16} {:?}", process.pid, process.name(), process.state)?;
+        }
+        Ok(())
+    }
+}
+
+// Currently running process on each hart, set to idle at start
+pub static CURRENT_PROC: PerHart<SpinLock<Option<usize>>> = PerHart::new([const { SpinLock::new(Some(IDLE_PID)) }; HARTS_MAX]);
+
+pub const IDLE_PID: usize = 0;      // idle process of the boot hart
+
+// Idle process of each hart
+static IDLE_PIDS: PerHart<AtomicUsize> = PerHart::new([const { AtomicUsize::new(IDLE_PID) }; HARTS_MAX]);
+
+/// The pid of this hart's idle process
+pub fn idle_pid() -> usize {
+    IDLE_PIDS.load(Ordering::Relaxed)
+}
+pub const SIE_STIE: usize = 1 << 5; // Enable supervisor timer interrupt
 pub const SSTATUS_SIE: usize = 1 << 1;  // Enable supervisor interrupts
 // const SSTATUS_SPIE: usize =  1 << 5;    // Supervisor previous interrupt state (enables interrupts on `sret`)
-// const SSTATUS_SPP: usize = 1 << 8;      // Supervisor previous priv. level (user = 0, supervisor = 1)
+pub const SSTATUS_SPP: usize = 1 << 8;  // Supervisor previous priv. level (user = 0, supervisor = 1)
+
+// Runs when no other process is runnable. Each interrupt wakes the hart, and the timer interrupt
+// switches to any process that has become runnable since. With the machine otherwise quiet, it also
+// writes back changed files.
+fn idle_process() -> ! {
+    run_idle();
+}
+
+/// Do what the idle process does, from a hart's boot stack until it first switches to a process
+pub fn run_idle() -> ! {
+    loop {
+        let _ = fs_sync();
+        wait_for_interrupt();
+    }
+}
 
-fn idle_process() {
-    panic!("reached idle process");
+// Enable interrupts and sleep the hart until one arrives
+fn wait_for_interrupt() {
+    write_csr!("sstatus", read_csr!("sstatus") | SSTATUS_SIE);
+    unsafe {
+        // Safety: wfi only pauses the hart; the pending interrupt is taken once it resumes
+        asm!("wfi", options(nomem, nostack));
+    }
+}
+
+// Create an idle process, returning its pid
+fn create_idle_process() -> usize {
+    let mut idle_args = [0u8; 32];
+    let idle_args_len = args::encode(["kernel-idle"], &mut idle_args).expect("idle name should fit");
+    let idle_pid = create_process(idle_process as *const() as usize, core::ptr::null(), 0, &idle_args[..idle_args_len], FaultPolicy::Kill)
+        .expect("should create the idle process");
+    if let Some(p) = PROCS.0.lock().iter_mut()
+        .find(|p| p.pid == idle_pid) {
+            p.is_idle = true;
+        }
+    idle_pid
 }
 
 pub fn scheduler_init() {
     // Initialise idle process
-    let idle_pid = create_process(idle_process as *const() as usize, core::ptr::null(), 0);
+    let idle_pid = create_idle_process();
+    let mut idle_satp = 0;
     if let Some(p) = PROCS.0.lock().iter_mut()
         .find(|p| p.pid == idle_pid) {
             p.pid = IDLE_PID;
+            let page_table = p.page_table.as_ref().expect("idle process should have a page table");
+            idle_satp = SATP_SV32 | (&**page_table as *const PageTable as usize / PAGE_SIZE);
         }
 
+    // The idle process maps the kernel, so its page table can be used to probe for ASIDs.
+    // It was created before probing and shares ASID 0.
+    probe_asids(idle_satp);
+
+    scheduler_start_hart();
+}
+
+/// Give a secondary hart an idle process of its own, which it runs as until it first switches
+pub fn scheduler_init_hart() {
+    let idle_pid = create_idle_process();
+    IDLE_PIDS.store(idle_pid, Ordering::Relaxed);
+    *CURRENT_PROC.lock() = Some(idle_pid);
+}
+
+/// Start the timer interrupts that drive scheduling on this hart
+pub fn scheduler_start_hart() {
     // Enable timer interrupt in supervisor mode
     write_csr!("sie", SIE_STIE);                                    // Enable timer interrupt
     write_csr!("sstatus", read_csr!("sstatus") | SSTATUS_SIE);      // Enable all supervisor interrupts
 
-    TIMER.set(500);                                                 // Scheduler interrupts at 500 ms
+    TIMER.set_time_slice()                                          // Scheduler interrupts each time slice
+        .expect("could not set timer");
+}
+
+// Histogram of scheduling delays: ticks between a process becoming ready and being switched in.
+pub static SCHED_LATENCY_HIST: [AtomicUsize; SCHED_LATENCY_BUCKETS] = [const { AtomicUsize::new(0) }; SCHED_LATENCY_BUCKETS];
+
+fn latency_bucket(delay_ticks: u64) -> usize {
+    // Bucket by the number of significant bits in the delay, i.e. floor(log2) + 1
+    ((u64::BITS - delay_ticks.leading_zeros()) as usize).min(SCHED_LATENCY_BUCKETS - 1)
+}
+
+fn record_sched_latency(delay_ticks: u64) {
+    SCHED_LATENCY_HIST[latency_bucket(delay_ticks)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Put the current process to sleep for as long as `should_sleep` returns true
+///
+/// `should_sleep` is checked with interrupts disabled, so a `wake_sleeping` between the check and the
+/// process sleeping cannot be missed.
+pub fn sleep_while(should_sleep: impl FnMut() -> bool) {
+    sleep_until(0, should_sleep);
+}
+
+/// Put the current process to sleep for as long as `should_sleep` returns true, but if `deadline` is
+/// non-zero, only until the timer interrupt after that many timer ticks
+///
+/// `should_sleep` should check the deadline itself, as the process may be woken before it.
+pub fn sleep_until(deadline: u64, mut should_sleep: impl FnMut() -> bool) {
+    let sstatus = read_csr!("sstatus");
+    loop {
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        let wakes = WAKES.load(Ordering::SeqCst);
+        if !should_sleep() {
+            break;
+        }
+        let current_pid = CURRENT_PROC.lock()
+            .expect("CURRENT_PROC initialised before use");
+        if let Some(p) = PROCS.0.lock().iter_mut()
+            .find(|p| p.pid == current_pid) {
+                // Another hart's wake since the check would have found the process still runnable, and
+                // another hart may have killed it, which must not be undone
+                if WAKES.load(Ordering::SeqCst) == wakes && p.state == State::Runnable {
+                    p.state = State::Sleeping;
+                    p.wake_at = deadline;
+                }
+            }
+        yield_now();
+    }
+    write_csr!("sstatus", sstatus);
+}
+
+/// Make runnable each sleeping process whose wake deadline has passed at timer ticks `now`
+///
+/// Only sleeping processes are woken; one that was killed stays exited.
+pub fn wake_expired(now: u64) {
+    PROCS.0.lock().iter_mut()
+        .filter(|p| p.state == State::Sleeping && p.wake_at != 0 && p.wake_at <= now)
+        .for_each(|p| {
+            p.state = State::Runnable;
+            p.runnable_since = now;
+            p.wake_at = 0;
+        });
+}
+
+// Calls to `wake_sleeping`, so a process about to sleep can tell it may have missed one
+static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+/// Make every sleeping process runnable, so each can recheck what it is waiting for
+///
+/// Only sleeping processes are woken; one that was killed stays exited.
+pub fn wake_sleeping() {
+    WAKES.fetch_add(1, Ordering::SeqCst);
+    let now = get_timer();
+    PROCS.0.lock().iter_mut()
+        .filter(|p| p.state == State::Sleeping)
+        .for_each(|p| {
+            p.state = State::Runnable;
+            p.runnable_since = now;
+        });
 }
 
-static FIRST_SWITCH: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(true);
+/// Context switches since boot, not counting a yield that finds no other process to run
+pub static SWITCH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// Each hart's first switch leaves its boot stack, which has no process to save it in
+static FIRST_SWITCH: PerHart<AtomicBool> = PerHart::new([const { AtomicBool::new(true) }; HARTS_MAX]);
+
+// Process each hart last switched away from, still claimed until the switch finishes
+static SWITCHED_FROM: PerHart<SpinLock<Option<usize>>> = PerHart::new([const { SpinLock::new(None) }; HARTS_MAX]);
 
 pub fn yield_now() {
+    // Keep interrupts disabled while PROCS is locked and across the context switch: a timer interrupt
+    // here would re-enter the scheduler and find PROCS already locked.
+    let sstatus = read_csr!("sstatus");
+    write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+
+    switch_to_next();
+
+    // Back in this process: restore its interrupt state from before it yielded
+    if sstatus & SSTATUS_SIE != 0 {
+        write_csr!("sstatus", read_csr!("sstatus") | SSTATUS_SIE);
+    }
+}
+
+// The pid of `current`, being switched away from, if it overflowed its kernel stack while it ran
+fn overflowed_stack(current: &Process) -> Option<usize> {
+    (!current.is_stack_canary_intact()).then_some(current.pid)
+}
+
+// Account for switching from `current` to `next` at `now`: how long `next` waited since it became ready
+// to run, and the time `current` ran since it was switched in. `current` is ready again from now, if it is
+// still runnable. CPU time is charged only here, so preemption by the timer, which switches through
+// `yield_now`, and a voluntary yield are charged alike.
+fn account_switch(current: &mut Process, next: &mut Process, now: u64) {
+    record_sched_latency(now.saturating_sub(next.runnable_since));
+    current.runnable_since = now;
+    current.cpu_ticks += now.saturating_sub(current.scheduled_at);
+    next.scheduled_at = now;
+}
+
+// Switch to the next runnable process, if any. Must be called with interrupts disabled.
+fn switch_to_next() {
+    let hart = hart_id();
     let current_pid = CURRENT_PROC.lock()
         .expect("CURRENT_PROC initialised before use");
 
-    // Search for a runnable process
-    let next_pid = PROCS.get_next(current_pid);
+    // Search for a runnable process and claim it, under one lock so that no other hart claims it too
+    let mut procs = PROCS.0.lock();
+    let next_pid = next_pid(&procs, current_pid, sched_order());
 
     // If there's no runnable process other than the current one, return and continue processing
     if next_pid == current_pid {
         return;
     }
 
+    // The first switch leaves the boot stack, whose stack pointer is saved here and never used again
+    let mut boot_sp: usize = 0;
+
+    // Each process is boxed and never removed from PROCS, and both are claimed by this hart through
+    // `running_on`, so the stack pointer slots stay valid and untouched by other harts after the lock is
+    // dropped, until `finish_switch` releases the previous process.
     let (next_sp_ptr, current_sp_ptr) = {
-        let next_index = PROCS.try_get_index(next_pid)
+        let next_index = procs.iter().position(|p| p.pid == next_pid)
             .expect("should find next by pid");
-        let current_index = PROCS.try_get_index(current_pid)
+        let current_index = procs.iter().position(|p| p.pid == current_pid)
             .expect("should find current by pid");
-        let mut procs = PROCS.0.lock();
         let [next, current] = procs.get_disjoint_mut([next_index, current_index])
             .expect("indices should be valid and distinct");
 
-        let next_sp_ptr = next.sp.field_raw_ptr();
+        if let Some(pid) = overflowed_stack(current) {
+            panic!("kernel stack overflow in process {}", pid);
+        }
+
+        account_switch(current, next, get_timer());
+        next.running_on = Some(hart);
+
+        // This hart's TLB may hold entries for the ASID from before the process last ran here
+        if next.last_hart != Some(hart) {
+            flush_tlb(asid_for(next.pid));
+            next.last_hart = Some(hart);
+        }
 
-        let current_sp_ptr = if FIRST_SWITCH.swap(false, core::sync::atomic::Ordering::Relaxed) {
-            // First switch - provide a valid but ultimately discarded pointer
-            &raw const next_sp_ptr as *mut usize  // Let's just reuse next_sp_ptr's address as we need any valid address and this will do
+        let next_sp_ptr: *mut usize = next.sp_slot();
+        let current_sp_ptr: *mut usize = if FIRST_SWITCH.swap(false, Ordering::Relaxed) {
+            &raw mut boot_sp
         } else {
-            current.sp.field_raw_ptr()
+            current.sp_slot()
         };
 
         (next_sp_ptr, current_sp_ptr)
     };
+    drop(procs);
 
     // Context switch
+    SWITCH_COUNT.fetch_add(1, Ordering::Relaxed);
+    watchdog::pet();
+    *SWITCHED_FROM.lock() = Some(current_pid);
     *CURRENT_PROC.lock() = Some(next_pid);
     unsafe {
         // Safety: Both stack pointers are valid pointers to runnable processes
         switch_context(current_sp_ptr, next_sp_ptr);
     }
+    finish_switch();
+}
+
+// Release the process this hart switched away from, now that its stack is saved.
+// Called with interrupts disabled by whichever process the switch resumed.
+extern "C" fn finish_switch() {
+    let Some(previous) = SWITCHED_FROM.lock().take() else {
+        return;
+    };
+    let mut procs = PROCS.0.lock();
+    if let Some(p) = procs.iter_mut()
+        .find(|p| p.pid == previous) {
+            p.running_on = None;
+            // Only now is nothing using an exited process's stack and page table
+            if p.state == State::Exited {
+                reap_orphans(&mut procs);
+            }
+        }
+}
+
+/// Where a new process first resumes from `switch_context`, which restores its entry point into `s0`
+#[unsafe(naked)]
+pub unsafe extern "C" fn process_start() {
+    naked_asm!(
+        ".align 2",
+        // An interrupt before the switch finishes could switch away and lose the process to release
+        "csrrci s1, sstatus, {sstatus_sie}",
+        "andi s1, s1, {sstatus_sie}",
+        "call {finish_switch}",
+        "csrs sstatus, s1",
+        "jr s0",
+        sstatus_sie = const SSTATUS_SIE,
+        finish_switch = sym finish_switch,
+    );
 }
 
 #[unsafe(naked)]
@@ -161,11 +492,15 @@ pub unsafe extern "C" fn switch_context(prev_sp: *mut usize, next_sp: *mut usize
         "sw sp, (a0)",              // *prev_sp = sp;
         "lw sp, (a1)",              // Switch stack pointer (sp) here
 
-        // Switch satp to next stack if different to current
+        // Switch satp to next stack if different to current.
+        // TLB entries tagged with the next process's own ASID are still valid; the shared ASID 0 needs a flush.
         "lw s0, 16 * 4(sp)",
         "csrr s1, satp",
         "beq s0, s1, 1f",
         "csrw satp, s0",
+        "srli s1, s0, {asid_shift}",
+        "andi s1, s1, {asid_bits}",
+        "bnez s1, 1f",
         "sfence.vma",
         "1:",
 
@@ -192,12 +527,294 @@ pub unsafe extern "C" fn switch_context(prev_sp: *mut usize, next_sp: *mut usize
         "lw s10, 11 * 4(sp)",
         "lw s11, 12 * 4(sp)",
         "addi sp, sp, 17 * 4",              // We've popped 17 4-byte registers from the stack
+
+        // A user process finds the hart number at its kernel stack top when it next traps
+        "csrr t1, sscratch",
+        "beqz t1, 3f",
+        "sw tp, (t1)",
+        "3:",
+
         "beqz t0, 2f",                      // t0 = 0 means interrupts were disabled
         "csrsi sstatus, {sstatus_sie}",     // Reenable interrupts last thing
 
         "2:",
         "ret",
         sstatus_sie = const SSTATUS_SIE,
+        asid_shift = const SATP_ASID_SHIFT,
+        asid_bits = const SATP_ASID_MASK >> SATP_ASID_SHIFT,
     );
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+
+    #[test_case]
+    fn sched_latency_buckets() {
+        print!("scheduler: sched latency buckets...");
+
+        assert!(latency_bucket(0) == 0);
+        assert!(latency_bucket(1) == 1);
+        assert!(latency_bucket(1023) == 10);
+        assert!(latency_bucket(1024) == 11);
+        assert!(latency_bucket(u64::MAX) == SCHED_LATENCY_BUCKETS - 1);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn sched_pid_order_is_deterministic() {
+        print!("scheduler: sched pid order is deterministic...");
+
+        // Three processes in table slots out of pid order, next to the idle process
+        let procs = procs(&[(IDLE_PID, State::Runnable), (7, State::Runnable), (3, State::Runnable), (5, State::Runnable)]);
+
+        let run_sequence = |order| {
+            let mut pid = IDLE_PID;
+            core::array::from_fn::<usize, 6, _>(|_| {
+                pid = next_pid(&procs, pid, order);
+                pid
+            })
+        };
+        assert!(run_sequence(SchedOrder::PidOrder) == [3, 5, 7, 3, 5, 7]);
+        assert!(run_sequence(SchedOrder::RoundRobin) == [7, 3, 5, 7, 3, 5]);
+
+        set_sched_order(SchedOrder::PidOrder);
+        assert!(sched_order() == SchedOrder::PidOrder);
+        set_sched_order(SchedOrder::RoundRobin);
+        assert!(sched_order() == SchedOrder::RoundRobin);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn sched_round_robin_takes_turns() {
+        print!("scheduler: sched round robin takes turns...");
+
+        // Switch-in times disagree with slot order: pid 2 ran longest ago, then 3, then 1
+        let mut procs = procs(&[(IDLE_PID, State::Runnable), (1, State::Runnable), (2, State::Runnable), (3, State::Runnable)]);
+        for (p, scheduled_at) in procs.iter_mut().zip([0, 30, 10, 20]) {
+            p.scheduled_at = scheduled_at;
+        }
+
+        let mut pid = IDLE_PID;
+        let run_sequence: [usize; 6] = core::array::from_fn(|tick| {
+            pid = next_pid(&procs, pid, SchedOrder::RoundRobin);
+            procs.iter_mut().find(|p| p.pid == pid).unwrap().scheduled_at = 100 + tick as u64;
+            pid
+        });
+        assert!(run_sequence == [2, 3, 1, 2, 3, 1]);
+
+        // A lone runnable process keeps running; the idle process runs only when nothing else can
+        for p in procs.iter_mut().filter(|p| p.pid != 3) {
+            p.state = State::Sleeping;
+        }
+        assert!(next_pid(&procs, 3, SchedOrder::RoundRobin) == 3);
+        procs[3].state = State::Sleeping;
+        assert!(next_pid(&procs, 3, SchedOrder::RoundRobin) == IDLE_PID);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn sched_skips_other_harts() {
+        print!("scheduler: sched skips other harts...");
+
+        // Pid 4 runs on this hart, pid 2 on another, and pid 3 is the other hart's idle process
+        let mut procs = procs(&[(IDLE_PID, State::Runnable), (1, State::Runnable), (2, State::Runnable), (3, State::Runnable), (4, State::Runnable)]);
+        procs[2].running_on = Some(1);
+        procs[3].is_idle = true;
+        procs[4].running_on = Some(0);
+
+        // The other hart's processes are passed over, and the current process can carry on
+        for order in [SchedOrder::RoundRobin, SchedOrder::PidOrder] {
+            assert!(next_pid(&procs, 4, order) == 1);
+        }
+        procs[1].state = State::Sleeping;
+        for order in [SchedOrder::RoundRobin, SchedOrder::PidOrder] {
+            assert!(next_pid(&procs, 4, order) == 4);
+        }
+        procs[4].state = State::Sleeping;
+        for order in [SchedOrder::RoundRobin, SchedOrder::PidOrder] {
+            assert!(next_pid(&procs, 4, order) == IDLE_PID);
+        }
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn sched_wakes_expired_sleepers() {
+        print!("scheduler: sched wakes expired sleepers...");
+
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+
+        // Put the last process to sleep as if in sleep_until(100, ..)
+        let (pid, state) = {
+            let mut procs = PROCS.0.lock();
+            let p = procs.last_mut().expect("should have a process");
+            let saved = (p.pid, p.state);
+            p.state = State::Sleeping;
+            p.wake_at = 100;
+            saved
+        };
+        let state_at = |now| {
+            wake_expired(now);
+            PROCS.0.lock().iter().find(|p| p.pid == pid).map(|p| (p.state, p.wake_at))
+        };
+        let early = state_at(99);
+        let due = state_at(100);
+
+        // A process killed while asleep stays exited whatever wakes up
+        if let Some(p) = PROCS.0.lock().iter_mut().find(|p| p.pid == pid) {
+            p.state = State::Exited;
+            p.wake_at = 100;
+        }
+        wake_sleeping();
+        let killed = state_at(200);
+
+        if let Some(p) = PROCS.0.lock().iter_mut().find(|p| p.pid == pid) {
+            p.state = state;
+            p.wake_at = 0;
+        }
+        write_csr!("sstatus", sstatus);
+
+        assert!(early == Some((State::Sleeping, 100)));
+        assert!(due == Some((State::Runnable, 0)));
+        assert!(killed == Some((State::Exited, 100)));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn next_pid_is_idle_when_all_exit() {
+        print!("scheduler: next pid is idle when all exit...");
+
+        // The idle process is created by scheduler_init and can always run
+        let idle_state = PROCS.0.lock().iter()
+            .find(|p| p.pid == IDLE_PID)
+            .map(|p| p.state);
+        assert!(idle_state == Some(State::Runnable));
+
+        // Once the only other process exits, the scheduler picks the idle process rather than none
+        let mut procs = procs(&[(IDLE_PID, State::Runnable), (1, State::Runnable)]);
+        assert!(next_pid(&procs, IDLE_PID, SchedOrder::RoundRobin) == 1);
+        procs[1].state = State::Exited;
+        for order in [SchedOrder::RoundRobin, SchedOrder::PidOrder] {
+            assert!(next_pid(&procs, 1, order) == IDLE_PID);
+            assert!(next_pid(&procs, IDLE_PID, order) == IDLE_PID);
+        }
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn switch_detects_stack_overflow() {
+        print!("scheduler: switch detects stack overflow...");
+
+        let pid = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created kernel process");
+        let mut procs = PROCS.0.lock();
+        let p = &mut procs[index];
+
+        // A call chain that pushes frames from the saved stack pointer down to just above the canary is deep,
+        // but still fits
+        let stack_base = p.stack.as_ptr() as usize;
+        let used_from = p.sp.as_usize() - stack_base;
+        p.stack[4..used_from].fill(0x55);
+        assert!(overflowed_stack(p).is_none());
+
+        // One that goes further writes over the canary, and is caught before the process is switched away
+        p.stack[..used_from].fill(0x55);
+        assert!(overflowed_stack(p) == Some(pid));
+
+        p.release();
+
+        common::ok!();
+    }
+
+    // Run `count` processes round robin for `switches` timeslices of `timeslice` ticks on a made-up clock,
+    // returning how many scheduling delays landed in each latency bucket
+    fn run_round_robin(count: usize, switches: u64, timeslice: u64) -> [usize; SCHED_LATENCY_BUCKETS] {
+        let hist = || SCHED_LATENCY_HIST.each_ref().map(|bucket| bucket.load(Ordering::Relaxed));
+        let before = hist();
+
+        let spec: Vec<_> = (1..=count).map(|pid| (pid, State::Runnable)).collect();
+        let mut procs = procs(&spec);
+        let mut current = 1;
+        for switch in 1..=switches {
+            let next = next_pid(&procs, current, SchedOrder::RoundRobin);
+            let index = |pid| procs.iter().position(|p| p.pid == pid).expect("pid should be in the table");
+            let indices = [index(next), index(current)];
+            let [next_p, current_p] = procs.get_disjoint_mut(indices)
+                .expect("round robin should switch to another process");
+            account_switch(current_p, next_p, switch * timeslice);
+            current = next;
+        }
+
+        let after = hist();
+        core::array::from_fn(|i| after[i] - before[i])
+    }
+
+    #[test_case]
+    fn sched_latency_shifts_with_load() {
+        print!("scheduler: sched latency shifts with load...");
+
+        // Each of two processes waits one timeslice for the other; each of five waits for four others
+        let timeslice = 1000;
+        let busiest = |hist: [usize; SCHED_LATENCY_BUCKETS]| (0..SCHED_LATENCY_BUCKETS)
+            .max_by_key(|&i| hist[i])
+            .expect("there are buckets");
+        let light = run_round_robin(2, 20, timeslice);
+        let heavy = run_round_robin(5, 20, timeslice);
+        assert!(busiest(light) == latency_bucket(timeslice));
+        assert!(busiest(heavy) == latency_bucket(4 * timeslice));
+        assert!(light.iter().sum::<usize>() == 20 && heavy.iter().sum::<usize>() == 20);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn cpu_time_charged_at_switch_out() {
+        print!("scheduler: cpu time charged at switch out...");
+
+        // A process is charged the time it ran when it is switched away, whether it was preempted at the end
+        // of its timeslice or yielded early, and nothing while it waits
+        let mut procs = procs(&[(1, State::Runnable), (2, State::Runnable)]);
+        let [first, second] = procs.get_disjoint_mut([0, 1])
+            .expect("indices should be valid and distinct");
+        account_switch(first, second, 30);      // Preempted after 30 ticks
+        account_switch(second, first, 35);      // Yielded after 5 ticks
+        assert!(first.cpu_ticks == 30 && second.cpu_ticks == 5);
+        account_switch(first, second, 65);
+        assert!(first.cpu_ticks == 60 && second.cpu_ticks == 5);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn woken_process_waits_from_its_wake() {
+        print!("scheduler: woken process waits from its wake...");
+
+        // Time spent asleep is not scheduling delay
+        let pid = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created process");
+        {
+            let mut procs = PROCS.0.lock();
+            procs[index].state = State::Sleeping;
+            procs[index].runnable_since = 0;
+        }
+        let before = get_timer();
+        wake_sleeping();
+        let mut procs = PROCS.0.lock();
+        assert!(procs[index].state == State::Runnable && procs[index].runnable_since >= before);
+        procs[index].release();
+
+        common::ok!();
+    }
+}
@@ -1,12 +1,44 @@
 //! Spinlock for os1k
 
+use core::arch::asm;
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, Ordering::{Acquire, Release}};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering::{Acquire, Relaxed, Release}};
 
+use crate::hart::hart_id;
+use crate::scheduler::SSTATUS_SIE;
+
+/// Hint to the hart that it is spinning in a busy-wait loop
+///
+/// Emits `pause` from the Zihintpause extension. It is encoded as a `fence` that orders nothing, so a hart
+/// without the extension runs it as a no-op.
+#[inline]
+pub fn cpu_relax() {
+    // Safety: `pause` has no architectural effect
+    unsafe {
+        asm!(".insn i 0x0f, 0, x0, x0, 0x010", options(nomem, nostack, preserves_flags));
+    }
+}
+
+// Disable interrupts on this hart, returning whether they were enabled
+fn disable_interrupts() -> bool {
+    let sstatus: usize;
+    // Safety: Clearing `sstatus.SIE` only defers interrupts; reading and clearing it in one instruction leaves
+    // no window for an interrupt between the two
+    unsafe {
+        asm!("csrrci {}, sstatus, {sie}", out(reg) sstatus, sie = const SSTATUS_SIE, options(nomem, nostack));
+    }
+    sstatus & SSTATUS_SIE != 0
+}
+
+/// Lock for data shared between harts
+///
+/// Interrupts are disabled on the holding hart until the guard drops: a trap handler taken while the lock is
+/// held, such as the timer interrupt, could otherwise try to lock it again on the same hart.
 #[derive(Debug)]
 pub struct SpinLock<T> {
     locked: AtomicBool,
+    owner: AtomicUsize,     // Hart number plus one of the holder, or 0 while free or being taken
     value: UnsafeCell<T>,
 }
 
@@ -16,24 +48,44 @@ impl<T> SpinLock<T> {
     pub const fn new(value: T) -> Self {
         Self {
             locked: AtomicBool::new(false),
+            owner: AtomicUsize::new(0),
             value: UnsafeCell::new(value),
         }
     }
 
-    #[allow(clippy::never_loop)]
+    /// Lock, waiting for another hart to release it
+    ///
+    /// Panics if this hart already holds it, as waiting would never end.
     pub fn lock(&self) -> Guard<'_, T> {
+        let interrupts_enabled = disable_interrupts();
         while self.locked.swap(true, Acquire) {
-            core::hint::spin_loop();
-            // crate::print!(".");
-            panic!("locked");   // For single-threaded keep as panic, but need to remove on multitasking
+            if self.owner.load(Relaxed) == hart_id() + 1 {
+                panic!("locked");
+            }
+            cpu_relax();
+        }
+        self.owner.store(hart_id() + 1, Relaxed);
+        Guard { lock: self, interrupts_enabled }
+    }
+
+    /// Lock if free, without panicking when already held
+    pub fn try_lock(&self) -> Option<Guard<'_, T>> {
+        let interrupts_enabled = disable_interrupts();
+        if self.locked.swap(true, Acquire) {
+            if interrupts_enabled {
+                write_csr!("sstatus", read_csr!("sstatus") | SSTATUS_SIE);
+            }
+            return None;
         }
-        Guard { lock: self }
+        self.owner.store(hart_id() + 1, Relaxed);
+        Some(Guard { lock: self, interrupts_enabled })
     }
 }
 
 #[derive(Debug)]
 pub struct Guard<'a, T> {
     lock: &'a SpinLock<T>,
+    interrupts_enabled: bool,   // Whether interrupts were enabled when locked, to restore when the guard drops
 }
 
 impl<T> Deref for Guard<'_, T> {
@@ -53,7 +105,68 @@ impl<T> DerefMut for Guard<'_, T> {
 
 impl<T> Drop for Guard<'_, T> {
     fn drop(&mut self) {
+        self.lock.owner.store(0, Relaxed);
         self.lock.locked.store(false, Release);
+        if self.interrupts_enabled {
+            write_csr!("sstatus", read_csr!("sstatus") | SSTATUS_SIE);
+        }
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+
+    #[test_case]
+    fn lock_and_release() {
+        print!("spinlock: lock and release...");
+
+        let lock = SpinLock::new(0);
+        for i in 1..=100 {
+            *lock.lock() += 1;
+            cpu_relax();
+            assert!(*lock.lock() == i);
+        }
+
+        // A held lock cannot be taken again until its guard drops
+        let guard = lock.lock();
+        assert!(lock.try_lock().is_none());
+        cpu_relax();
+        drop(guard);
+        assert!(lock.try_lock().is_some_and(|value| *value == 100));
+
+        // The holder is recorded so that only this hart locking it again panics
+        let guard = lock.lock();
+        assert!(lock.owner.load(Relaxed) == hart_id() + 1);
+        drop(guard);
+        assert!(lock.owner.load(Relaxed) == 0);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn lock_disables_interrupts() {
+        print!("spinlock: lock disables interrupts...");
+
+        // Interrupts are off while either guard is held, and back on only once the outer one drops
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus | SSTATUS_SIE);
+        let outer = SpinLock::new(0);
+        let inner = SpinLock::new(0);
+        let outer_guard = outer.lock();
+        assert!(read_csr!("sstatus") & SSTATUS_SIE == 0);
+        let inner_guard = inner.try_lock()
+            .expect("inner lock should be free");
+        drop(inner_guard);
+        assert!(read_csr!("sstatus") & SSTATUS_SIE == 0);
+        assert!(outer.try_lock().is_none());
+        assert!(read_csr!("sstatus") & SSTATUS_SIE == 0);
+        drop(outer_guard);
+        assert!(read_csr!("sstatus") & SSTATUS_SIE != 0);
+        write_csr!("sstatus", sstatus);
+
+        common::ok!();
+    }
+}
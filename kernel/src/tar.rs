@@ -2,15 +2,31 @@
 
 use core::ffi::CStr;
 use core::fmt::Debug;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use common::println;
+#[cfg(test)]
+use core::sync::atomic::AtomicBool;
+
+use common::{println, ErrorCode, FsInfo};
 
 use crate::address::align_up;
+use crate::scheduler::SSTATUS_SIE;
 use crate::spinlock::SpinLock;
 use crate::virtio::{read_write_disk, SECTOR_SIZE};
 
-pub const FILES_MAX: usize = 2;
-const DISK_MAX_SIZE: usize = align_up(size_of::<File>() * FILES_MAX, SECTOR_SIZE);
+// Slots in the file table: 3, or OS1K_FILES_MAX set when building
+include!(concat!(env!("OUT_DIR"), "/files_max.rs"));
+const FILE_DATA_MAX: usize = 1024;
+// Room for every file at its largest, each a header and data in whole blocks, and the two end blocks
+const DISK_MAX_SIZE: usize = FILES_MAX * (size_of::<TarHeader>() + align_up(FILE_DATA_MAX, SECTOR_SIZE)) + 2 * SECTOR_SIZE;
+
+// The disk holds two copies of the tar image followed by a sector naming the active one. A flush writes
+// the inactive copy before switching, so a reset part way through leaves the old image to load.
+const COPY_SECTORS: usize = DISK_MAX_SIZE / SECTOR_SIZE;
+const ACTIVE_SECTOR: u64 = 2 * COPY_SECTORS as u64;
+const ACTIVE_MAGIC: &[u8] = b"os1k-active";
+
+static ACTIVE_COPY: AtomicUsize = AtomicUsize::new(0);
 
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone)]
@@ -42,6 +58,15 @@ impl TarHeader {
         unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
     }
 
+    // Sum of the header bytes, counting the checksum field as spaces
+    fn checksum(&self) -> usize {
+        let mut header = *self;
+        header.checksum.fill(b' ');
+        // Safety: `header` is a copy that is not mutated while its bytes are borrowed
+        let buf = unsafe { header.as_bytes() };
+        buf.iter().fold(0, |checksum, byte| checksum + *byte as usize)
+    }
+
     /// SAFETY: It is UB to mutate the underlying memory while this byte array exists
     unsafe fn as_bytes(&self) -> &[u8] {
         // Safety:
@@ -67,8 +92,9 @@ impl TarHeader {
 #[derive(Copy, Clone, Debug)]
 pub struct File {
     in_use: bool,
+    pub dirty: bool,        // Changed since the last flush to disk
     pub name: [u8; 100],
-    pub data: [u8; 1024],
+    pub data: [u8; FILE_DATA_MAX],
     pub size: usize,
 }
 
@@ -78,6 +104,37 @@ impl File {
         // All-zero bytes is a valid representation: integers become 0, pointer becomes null.
         unsafe { core::mem::MaybeUninit::zeroed().assume_init() }
     }
+
+    /// The file name, up to the first nul byte
+    pub fn name(&self) -> &str {
+        CStr::from_bytes_until_nul(&self.name).ok()
+            .and_then(|cstr| cstr.to_str().ok())
+            .unwrap_or("?")
+    }
+
+    /// Set the file size, zero filling when it grows and discarding the tail when it shrinks
+    ///
+    /// Bytes past the new size are zeroed either way, so a later write past the end leaves no stale data.
+    /// Returns `Err` if `size` is beyond the file's capacity.
+    pub fn truncate(&mut self, size: usize) -> Result<(), ErrorCode> {
+        if size > self.data.len() {
+            return Err(ErrorCode::NoSpace);
+        }
+        let (start, end) = (size.min(self.size), size.max(self.size));
+        self.data[start..end].fill(0);
+        self.size = size;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// An empty file named `name`, for tests to put in the file table in place of a file on the disk
+    #[cfg(test)]
+    pub fn scratch(name: &str) -> Self {
+        let mut file = Self::zeroed();
+        file.in_use = true;
+        file.name[..name.len()].copy_from_slice(name.as_bytes());
+        file
+    }
 }
 
 #[derive(Debug)]
@@ -87,7 +144,7 @@ impl Files {
     pub fn fs_lookup(&self, name: &str) -> Option<usize> {
         let files = self.0.lock();
 
-        println!("looking up filename {}", name);
+        klog!(DEBUG, "looking up filename {}", name);
 
         files.iter()
         .position(|f| {  // `position` returns the index based on the closure result being true
@@ -97,6 +154,67 @@ impl Files {
             .is_some_and(|s| s == name) // Evaluates closure if receiving Some
         })
     }
+
+    /// Report how full the file table and files are
+    pub fn info(&self) -> FsInfo {
+        fs_info(&*self.0.lock())
+    }
+
+    /// List directory `dir` into `buf` as `list_dir` does, over the names of every file
+    pub fn list_dir(&self, dir: &str, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+        let files = self.0.lock();
+        let names = files.iter()
+            .filter(|f| f.in_use)
+            .filter_map(|f| CStr::from_bytes_until_nul(&f.name).ok()?.to_str().ok());
+        list_dir(names, dir, buf)
+    }
+}
+
+fn fs_info(files: &[File]) -> FsInfo {
+    let in_use = || files.iter().filter(|f| f.in_use);
+    let bytes_used = in_use().map(|f| f.size).sum();
+    FsInfo {
+        files_used: in_use().count(),
+        files_max: files.len(),
+        bytes_used,
+        bytes_free: files.len() * FILE_DATA_MAX - bytes_used,
+        file_size_max: FILE_DATA_MAX,
+    }
+}
+
+// The entry for `name` in directory `dir`, or `None` if it is not in there. A name further down collapses
+// to the subdirectory of `dir` holding it, with a trailing `/`.
+fn dir_entry<'a>(name: &'a str, dir: &str) -> Option<&'a str> {
+    let dir = dir.trim_end_matches('/');
+    let rest = if dir.is_empty() {
+        name
+    } else {
+        name.strip_prefix(dir)?.strip_prefix('/')?
+    };
+    match rest.find('/') {
+        Some(i) => Some(&rest[..=i]),
+        None if rest.is_empty() => None,
+        None => Some(rest),
+    }
+}
+
+/// Write the entries of directory `dir`, the root being `""`, into `buf`, each followed by a newline
+///
+/// Tar entries are flat names that may contain `/`. Entries keep the order of `names`, and each
+/// subdirectory is listed once however many names are under it. Returns the length of the listing, or
+/// `Err(NoSpace)` if it does not fit in `buf`.
+pub fn list_dir<'a>(names: impl IntoIterator<Item = &'a str>, dir: &str, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+    let mut len = 0;
+    for entry in names.into_iter().filter_map(|name| dir_entry(name, dir)) {
+        if buf[..len].split(|&b| b == b'\n').any(|listed| listed == entry.as_bytes()) {
+            continue;
+        }
+        let line = buf.get_mut(len..len + entry.len() + 1).ok_or(ErrorCode::NoSpace)?;
+        line[..entry.len()].copy_from_slice(entry.as_bytes());
+        line[entry.len()] = b'\n';
+        len += line.len();
+    }
+    Ok(len)
 }
 
 pub static FILES: Files = Files(SpinLock::new([File::zeroed(); FILES_MAX]));
@@ -139,72 +257,162 @@ fn int2oct(dec: usize, oct: &mut [u8]) {
         });
 }
 
-pub fn fs_init() {
-    // Load into DISK by sector
-    for sector in 0..(size_of::<[u8; DISK_MAX_SIZE]>() / SECTOR_SIZE) {
-        let mut disk = DISK.0.lock();
-        // let ptr = &raw mut disk[sector * SECTOR_SIZE];
-        let offset = sector * SECTOR_SIZE;
-        read_write_disk(&mut disk[offset..offset + SECTOR_SIZE], sector as u64, false);
+// Move one copy of the image between `image` and the disk, a sector at a time, stopping at the first error
+fn transfer_copy(image: &mut [u8], copy: usize, is_write: bool, io: &mut impl FnMut(&mut [u8], u64, bool) -> Result<(), ErrorCode>) -> Result<(), ErrorCode> {
+    for (i, sector) in image.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+        io(sector, (copy * COPY_SECTORS + i) as u64, is_write)?;
     }
+    Ok(())
+}
+
+// Read the active copy of the image, as named by the active sector. Returns which copy it was.
+fn read_image(image: &mut [u8], io: &mut impl FnMut(&mut [u8], u64, bool) -> Result<(), ErrorCode>) -> Result<usize, ErrorCode> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    io(&mut sector, ACTIVE_SECTOR, false)?;
+    // A plain tar image has no active sector, so anything unrecognised means the first copy
+    let copy = if sector.starts_with(ACTIVE_MAGIC) && sector[ACTIVE_MAGIC.len()] == 1 { 1 } else { 0 };
+    transfer_copy(image, copy, false, io)?;
+    Ok(copy)
+}
+
+// Write the image over the copy that is not `active`, then name it in the active sector. Returns the new
+// active copy. Until the single sector write lands, the active sector still names the old, intact copy.
+fn write_image(image: &mut [u8], active: usize, io: &mut impl FnMut(&mut [u8], u64, bool) -> Result<(), ErrorCode>) -> Result<usize, ErrorCode> {
+    let copy = 1 - active;
+    transfer_copy(image, copy, true, io)?;
+
+    let mut sector = [0u8; SECTOR_SIZE];
+    sector[..ACTIVE_MAGIC.len()].copy_from_slice(ACTIVE_MAGIC);
+    sector[ACTIVE_MAGIC.len()] = copy as u8;
+    io(&mut sector, ACTIVE_SECTOR, true)?;
+    Ok(copy)
+}
+
+// The block device: virtio-blk, or a count of sectors written while a test has the mock installed
+fn disk_io(buf: &mut [u8], sector: u64, is_write: bool) -> Result<(), ErrorCode> {
+    #[cfg(test)]
+    if USE_MOCK_DISK.load(Ordering::Relaxed) {
+        if is_write {
+            MOCK_DISK_WRITES.fetch_add(1, Ordering::Relaxed);
+        }
+        return Ok(());
+    }
+    read_write_disk(buf, sector, is_write)
+}
+
+#[cfg(test)]
+static USE_MOCK_DISK: AtomicBool = AtomicBool::new(false);
+
+/// Sectors written while the mock disk is in use
+#[cfg(test)]
+pub static MOCK_DISK_WRITES: AtomicUsize = AtomicUsize::new(0);
+
+/// Sectors written by one flush
+#[cfg(test)]
+pub const FLUSH_SECTORS: usize = COPY_SECTORS + 1;
+
+/// Route disk reads and writes to the mock, or back to virtio-blk
+#[cfg(test)]
+pub fn use_mock_disk(mock: bool) {
+    USE_MOCK_DISK.store(mock, Ordering::Relaxed);
+}
+
+/// Load `FILES` from the tar archive `image` in memory, rather than from the disk as `fs_init` does
+#[cfg(test)]
+fn fs_init_from_bytes(image: &[u8]) {
+    let mut files = FILES.0.lock();
+    files.fill(File::zeroed());
+    parse(image, &mut *files);
+}
+
+pub fn fs_init() {
+    // Load the active copy into DISK, or start with no files if it cannot be read
+    let mut disk = DISK.0.lock();
+    let copy = read_image(&mut *disk, &mut disk_io).unwrap_or_else(|e| {
+        klog!(ERROR, "could not read the file system error={:?}, starting empty", e);
+        disk.fill(0);
+        0
+    });
+    drop(disk);
+    ACTIVE_COPY.store(copy, Ordering::Relaxed);
 
     // Load into FILES from DISK
-    let mut off = 0;
     let mut files = FILES.0.lock();
+    let count = parse(&*DISK.0.lock(), &mut *files);
+
+    for file in &files[..count] {
+        let file_name_str = str::from_utf8(&file.name)
+        .expect("file name text should be valid UTF8")
+        .trim_end_matches('\0');
+        crate::println!("file: {}, size={}", file_name_str, file.size);
+    }
+}
+
+// Load the tar archive in `image` into `files`, returning how many were found. Stops at the end of the
+// archive or of `image`, or at the first malformed entry, keeping the files before it.
+fn parse(image: &[u8], files: &mut [File]) -> usize {
+    let mut off = 0;
+    let mut count = 0;
 
     for file in files.iter_mut() {
-        let disk = DISK.0.lock();
-        assert!(disk.len() >= off + size_of::<TarHeader>());
+        if image.len() < off + size_of::<TarHeader>() {
+            break;
+        }
         // Safety:
         // * data is aligned to single byte alignment - not using larger types
-        // * disk is initialised and valid for reading
+        // * image is initialised and valid for reading
         let header = unsafe {
-            &*(disk.as_ptr().add(off) as *const TarHeader)
+            &*(image.as_ptr().add(off) as *const TarHeader)
         };
 
         if header.name[0] == b'\0' { // name is a c string with nul terminator
             break;
         }
 
-        match core::ffi::CStr::from_bytes_with_nul(&header.magic) {
-            Ok(magic) if magic == c"ustar" => {},
-            Ok(magic) => panic!("invalid tar header: magic={:?}", magic),
-            Err(_) => panic!("invalid tar header: magic is not a valid c string"),
+        if header.magic != *b"ustar\0" {
+            klog!(ERROR, "tar: invalid header at {}: magic={:x?}", off, header.magic);
+            break;
         }
 
-        let filesz = oct2int(&header.size)
-        .expect("file size should be valid");
+        if oct2int(&header.checksum) != Ok(header.checksum()) {
+            klog!(ERROR, "tar: invalid header at {}: checksum mismatch", off);
+            break;
+        }
+
+        let data_offset = off + header.size();
+        let filesz = match oct2int(&header.size) {
+            Ok(filesz) if filesz <= FILE_DATA_MAX && data_offset + filesz <= image.len() => filesz,
+            Ok(filesz) => {
+                klog!(ERROR, "tar: file at {} does not fit: size={}", off, filesz);
+                break;
+            },
+            Err(()) => {
+                klog!(ERROR, "tar: invalid header at {}: size={:x?}", off, header.size);
+                break;
+            },
+        };
 
         file.in_use = true;
+        file.dirty = false;
         file.name = header.name;
         file.size = filesz;
 
-        let data_offset = off + header.size();
-
-        file.data[..filesz].copy_from_slice(&disk[data_offset..data_offset + filesz]);
-
-        let file_name_str = str::from_utf8(&file.name)
-        .expect("file name text should be valid UTF8")
-        .trim();
-        crate::println!("file: {}, size={}", file_name_str, filesz);
+        file.data.fill(0);
+        file.data[..filesz].copy_from_slice(&image[data_offset..data_offset + filesz]);
 
-        off += align_up( header.size() + filesz, SECTOR_SIZE );
+        count += 1;
+        off += header.size() + align_up(filesz, SECTOR_SIZE);
     }
+    count
 }
 
-pub fn fs_flush() {
-    // Copy all file contents into `disk` buffer.
-    let mut disk = DISK.0.lock();
-    disk.fill(0);
-
-    let files = FILES.0.lock();
+// Write the files in use to `image` as a tar archive. Each is a header then its data padded to whole
+// blocks; the zeroed rest of the image includes the two blocks that end the archive.
+fn serialize(files: &[File], image: &mut [u8]) {
+    image.fill(0);
 
     let mut off = 0;
-    for file in files.iter() {
-        if !file.in_use {
-            break;
-        }
-
+    for file in files.iter().filter(|file| file.in_use) {
         // Create header
         let mut header = TarHeader::zeroed();
         header.name.copy_from_slice(&file.name);
@@ -213,42 +421,67 @@ pub fn fs_flush() {
         header.version.copy_from_slice("00".as_bytes());
         header.typeflag = b'0'; // Regular file
         int2oct(file.size, &mut header.size);
-        header.checksum.fill(b' '); // Checksum is calculated with checksum field set to spaces
-
-        // Calculate the checksum
-        let checksum = {
-            // Safety: We drop buf on checksum creation to avoid mutating underlying data
-            let buf = unsafe { header.as_bytes() };
-            buf.iter().fold(0, | checksum, byte | checksum + *byte as usize )
-        };
+        let checksum = header.checksum();
         int2oct(checksum, &mut header.checksum);
 
         // Safety: We do not mutate header in the remainder of this loop
         let buf = unsafe { header.as_bytes() };
-        disk[off..off + header.size()].copy_from_slice(buf);
+        image[off..off + header.size()].copy_from_slice(buf);
 
         // Copy file data immediately after the header.
         let data_offset = off + header.size();
-        let data_size = size_of_val(&file.data);
-        disk[data_offset..data_offset + data_size].copy_from_slice(&file.data);
+        image[data_offset..data_offset + file.size].copy_from_slice(&file.data[..file.size]);
 
-        off += align_up(header.size() + file.size, SECTOR_SIZE);
+        off += header.size() + align_up(file.size, SECTOR_SIZE);
     }
+    debug_assert!(off + 2 * SECTOR_SIZE <= image.len(), "tar image should end with two zero blocks");
+}
 
-    // Write `disk` buffer into the vitio-blk.
-    for sector in 0..(DISK_MAX_SIZE / SECTOR_SIZE) {
-        let offset = sector * SECTOR_SIZE;
-        read_write_disk(&mut disk[offset..offset + SECTOR_SIZE], sector as u64, true);
-    }
+/// Write every file to disk if any has changed since the last flush
+///
+/// File system calls only change `FILES`; this runs on `SYS_SYNC`, process exit and in the idle process,
+/// which each timer interrupt wakes while nothing else runs. Interrupts are disabled throughout so no other process can use the files meanwhile.
+/// Returns whether the disk was written, or the error writing it. After an error the files are still
+/// changed, so the next sync tries again.
+pub fn fs_sync() -> Result<bool, ErrorCode> {
+    let sstatus = read_csr!("sstatus");
+    write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+    let dirty = FILES.0.lock().iter().any(|file| file.dirty);
+    let result = if dirty { fs_flush() } else { Ok(()) };
+    write_csr!("sstatus", sstatus);
+    result.map(|()| dirty)
+}
 
-    println!("wrote {} bytes to disk", DISK_MAX_SIZE);
+fn fs_flush() -> Result<(), ErrorCode> {
+    // Copy all file contents into `disk` buffer.
+    let mut disk = DISK.0.lock();
+    let mut files = FILES.0.lock();
+    serialize(&*files, &mut *disk);
+    files.iter_mut().for_each(|file| file.dirty = false);
+    drop(files);
+
+    // Write `disk` buffer into the virtio-blk, leaving the current copy intact until the new one is whole
+    match write_image(&mut *disk, ACTIVE_COPY.load(Ordering::Relaxed), &mut disk_io) {
+        Ok(copy) => {
+            ACTIVE_COPY.store(copy, Ordering::Relaxed);
+            println!("wrote {} bytes to disk", DISK_MAX_SIZE);
+            Ok(())
+        },
+        Err(e) => {
+            klog!(ERROR, "could not write the file system error={:?}", e);
+            FILES.0.lock().iter_mut().filter(|file| file.in_use).for_each(|file| file.dirty = true);
+            Err(e)
+        },
+    }
 }
 
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{print, println};
+    use alloc::vec;
+    use core::mem::offset_of;
+    use crate::print;
 
     #[test_case]
     fn check_oct2int_and_int2_oct() {
@@ -261,7 +494,7 @@ mod test {
             .expect("should be able to convert back to octal");
         assert!(i == i_again);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -273,6 +506,231 @@ mod test {
             panic!("file not found {:x?}", filename);
         };
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
+    }
+
+    #[test_case]
+    fn init_from_bytes() {
+        print!("tar: init from bytes...");
+
+        // A one file archive, built in memory with no disk behind it
+        let mut hello = File::zeroed();
+        hello.in_use = true;
+        hello.name[..9].copy_from_slice(b"hello.txt");
+        hello.data[..6].copy_from_slice(b"howdy\n");
+        hello.size = 6;
+        let mut image = vec![0u8; 2 * size_of::<TarHeader>() + 2 * SECTOR_SIZE];
+        serialize(&[hello], &mut image);
+
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        let saved = FILES.0.lock().to_vec();
+
+        fs_init_from_bytes(&image);
+        let hello_i = FILES.fs_lookup("hello.txt");
+        let meow_i = FILES.fs_lookup("meow.txt");
+        let loaded = hello_i.map(|i| FILES.0.lock()[i]);
+        let info = FILES.info();
+
+        FILES.0.lock().copy_from_slice(&saved);
+        write_csr!("sstatus", sstatus);
+
+        assert!(hello_i == Some(0));
+        assert!(meow_i.is_none());
+        let loaded = loaded.expect("hello.txt should be loaded");
+        assert!(loaded.size == 6 && loaded.data[..6] == *b"howdy\n" && !loaded.dirty);
+        assert!(info.files_used == 1);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn flush_interrupted_before_switch() {
+        print!("tar: flush interrupted before switch...");
+
+        let mut sectors = vec![[0u8; SECTOR_SIZE]; COPY_SECTORS * 2 + 1];
+        let mut disk = |buf: &mut [u8], sector: u64, is_write: bool| {
+            let stored = &mut sectors[sector as usize];
+            if is_write { stored.copy_from_slice(buf) } else { buf.copy_from_slice(stored) }
+            Ok(())
+        };
+        let mut image = vec![0u8; DISK_MAX_SIZE];
+
+        // A plain tar image is the first copy
+        image.fill(b'a');
+        assert!(transfer_copy(&mut image, 0, true, &mut disk).is_ok());
+        image.fill(0);
+        assert!(read_image(&mut image, &mut disk) == Ok(0) && image.iter().all(|&b| b == b'a'));
+
+        // Reset after writing the new copy but before the active sector: the old image still loads
+        image.fill(b'b');
+        let mut interrupted = |buf: &mut [u8], sector: u64, is_write: bool| {
+            if sector == ACTIVE_SECTOR { Err(ErrorCode::IoError) } else { disk(buf, sector, is_write) }
+        };
+        assert!(write_image(&mut image, 0, &mut interrupted) == Err(ErrorCode::IoError));
+        assert!(read_image(&mut image, &mut disk) == Ok(0) && image.iter().all(|&b| b == b'a'));
+
+        // Completed flushes alternate between the copies
+        image.fill(b'b');
+        assert!(write_image(&mut image, 0, &mut disk) == Ok(1));
+        assert!(read_image(&mut image, &mut disk) == Ok(1) && image.iter().all(|&b| b == b'b'));
+        image.fill(b'c');
+        assert!(write_image(&mut image, 1, &mut disk) == Ok(0));
+        assert!(read_image(&mut image, &mut disk) == Ok(0) && image.iter().all(|&b| b == b'c'));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn list_dir_groups_subdirectories() {
+        print!("tar: list dir groups subdirectories...");
+
+        let names = ["a.txt", "dir/b.txt", "dir/c.txt", "dir/sub/d.txt", "directory.txt"];
+        let mut buf = [0u8; 64];
+        let mut listing = |dir| list_dir(names, dir, &mut buf).map(|len| buf[..len].to_vec());
+        assert!(listing("") == Ok(b"a.txt\ndir/\ndirectory.txt\n".to_vec()));
+        assert!(listing("dir") == Ok(b"b.txt\nc.txt\nsub/\n".to_vec()));
+        assert!(listing("dir/") == Ok(b"b.txt\nc.txt\nsub/\n".to_vec()));
+        assert!(listing("dir/sub") == Ok(b"d.txt\n".to_vec()));
+        assert!(listing("di") == Ok(vec![]));
+        assert!(listing("a.txt") == Ok(vec![]));
+
+        // A listing that does not fit is refused, even by the last newline
+        assert!(list_dir(names, "", &mut buf[..25]) == Err(ErrorCode::NoSpace));
+        assert!(list_dir(names, "", &mut buf[..26]) == Ok(26));
+
+        // The disk holds only top level files
+        let len = FILES.list_dir("", &mut buf)
+            .expect("listing should fit");
+        assert!(&buf[..len] == b"hello.txt\nlog.txt\nmeow.txt\n");
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn serialize_and_parse_new_file() {
+        print!("tar: serialize and parse new file...");
+
+        // The boot files, a deleted slot and a file created since boot
+        let mut files = vec![File::zeroed(); FILES_MAX];
+        files[..FILES_MAX - 1].copy_from_slice(&FILES.0.lock()[..FILES_MAX - 1]);
+        files[1].in_use = false;
+        let new = &mut files[FILES_MAX - 1];
+        new.in_use = true;
+        new.name[..7].copy_from_slice(b"new.txt");
+        new.data[..FILE_DATA_MAX].fill(b'n');
+        new.size = FILE_DATA_MAX;
+
+        let mut image = vec![0xffu8; DISK_MAX_SIZE];
+        serialize(&files, &mut image);
+
+        // Headers carry valid checksums and the archive ends in two zero blocks
+        let end = 2 * size_of::<TarHeader>() + align_up(files[0].size, SECTOR_SIZE) + FILE_DATA_MAX;
+        assert!(image[end..end + 2 * SECTOR_SIZE].iter().all(|&b| b == 0));
+
+        let mut loaded = vec![File::zeroed(); FILES_MAX];
+        assert!(parse(&image, &mut loaded) == 2);
+        assert!(loaded[0].name == files[0].name && loaded[0].size == files[0].size);
+        assert!(loaded[0].data[..loaded[0].size] == files[0].data[..files[0].size]);
+        assert!(loaded[1].name[..8] == *b"new.txt\0");
+        assert!(loaded[1].size == FILE_DATA_MAX && loaded[1].data.iter().all(|&b| b == b'n'));
+        assert!(!loaded[2].in_use);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn parse_stops_at_malformed_entry() {
+        print!("tar: parse stops at malformed entry...");
+
+        let mut files = vec![File::zeroed(); FILES_MAX];
+        files.copy_from_slice(&FILES.0.lock()[..]);
+        files[1].data[..10].fill(b'l');
+        files[1].size = 10;
+        let mut image = vec![0u8; DISK_MAX_SIZE];
+        serialize(&files, &mut image);
+        let second = size_of::<TarHeader>() + align_up(files[0].size, SECTOR_SIZE);
+        let mut loaded = vec![File::zeroed(); FILES_MAX];
+
+        // Cut off part way through the second header, then part way through its data
+        assert!(parse(&image[..second + 100], &mut loaded) == 1);
+        assert!(loaded[0].name == files[0].name && !loaded[1].in_use);
+        assert!(parse(&image[..second + size_of::<TarHeader>() + files[1].size - 1], &mut vec![File::zeroed(); FILES_MAX]) == 1);
+
+        // A size beyond the file table, a bad size field, bad magic and a bad checksum
+        let size_field = second + offset_of!(TarHeader, size);
+        let magic_field = second + offset_of!(TarHeader, magic);
+        let corruptions: [(usize, &[u8]); 4] = [
+            (size_field, b"00000100000\0"),
+            (size_field, b"0000000009x\0"),
+            (magic_field, b"tar\0\0\0"),
+            (size_field, b"00000000001\0"),
+        ];
+        for (i, (at, bytes)) in corruptions.into_iter().enumerate() {
+            let mut bad = image.clone();
+            bad[at..at + bytes.len()].copy_from_slice(bytes);
+            if i < 3 {
+                // Keep the checksum right so only the field itself is wrong
+                // Safety: the header lies within `bad` and has byte alignment
+                let header = unsafe { &mut *(bad.as_mut_ptr().add(second) as *mut TarHeader) };
+                let checksum = header.checksum();
+                int2oct(checksum, &mut header.checksum);
+            }
+            assert!(parse(&bad, &mut vec![File::zeroed(); FILES_MAX]) == 1);
+        }
+        assert!(parse(&image, &mut loaded) == 3);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn report_fs_info() {
+        print!("tar: report fs info...");
+
+        let mut files = vec![File::zeroed(); FILES_MAX];
+        let empty = fs_info(&files);
+        assert!(empty == FsInfo {
+            files_used: 0,
+            files_max: FILES_MAX,
+            bytes_used: 0,
+            bytes_free: FILES_MAX * FILE_DATA_MAX,
+            file_size_max: FILE_DATA_MAX,
+        });
+
+        files[0].in_use = true;
+        files[0].size = 100;
+        let one = fs_info(&files);
+        assert!(one.files_used == 1 && one.bytes_used == 100);
+        assert!(one.bytes_free == FILES_MAX * FILE_DATA_MAX - 100);
+
+        // The disk's files fit in the table, however large it was built
+        let disk = FILES.info();
+        assert!(disk.files_used <= disk.files_max && disk.files_max == FILES_MAX);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn truncate_file() {
+        print!("tar: truncate file...");
+
+        let mut file = File::zeroed();
+        file.data[..5].copy_from_slice(b"hello");
+        file.size = 5;
+
+        // Shrinking discards the tail and growing zero fills
+        assert!(file.truncate(2).is_ok());
+        assert!(file.size == 2);
+        assert!(file.truncate(4).is_ok());
+        assert!(file.size == 4 && file.data[..5] == *b"he\0\0\0");
+        assert!(file.truncate(0).is_ok());
+        assert!(file.size == 0 && file.data[..2] == [0, 0]);
+
+        // Up to but not past the capacity
+        assert!(file.truncate(file.data.len()).is_ok());
+        assert!(file.truncate(file.data.len() + 1) == Err(ErrorCode::NoSpace));
+        assert!(file.size == file.data.len());
+
+        common::ok!();
     }
 }
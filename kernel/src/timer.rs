@@ -1,27 +1,76 @@
 //! Timers
 
 use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sbi::sbi;
+
+const DEFAULT_TIME_SLICE_MS: usize = 500;
+const SET_ATTEMPTS: usize = 3;      // Tries at arming the timer before giving up
+
+// Scheduler time slice (quantum) in milliseconds
+static TIME_SLICE_MS: AtomicUsize = AtomicUsize::new(DEFAULT_TIME_SLICE_MS);
 
 pub struct Timer;
 
 impl Timer {
-    pub fn set(&self, millisecs: u64) {
+    /// Arm the timer to interrupt in `millisecs`
+    ///
+    /// Returns the SBI error if the timer could not be armed after `SET_ATTEMPTS` tries.
+    pub fn set(&self, millisecs: u64) -> Result<(), isize> {
         let ticks = millisecs_to_ticks(millisecs);
         let current_ticks = get_timer();
-        crate::sbi::set_timer(current_ticks + ticks)
-        .expect("could not set timer");
+        set_with_retry(current_ticks.saturating_add(ticks), |deadline| sbi().set_timer(deadline))
+    }
+
+    /// Arm the timer to interrupt at the end of the scheduler time slice
+    pub fn set_time_slice(&self) -> Result<(), isize> {
+        self.set(self.time_slice_ms() as u64)
+    }
+
+    pub fn time_slice_ms(&self) -> usize {
+        TIME_SLICE_MS.load(Ordering::Relaxed)
+    }
+
+    /// Change the scheduler time slice, taking effect when the timer is next armed
+    ///
+    /// Returns the time slice it replaces, or `Err` for a zero time slice.
+    pub fn set_time_slice_ms(&self, millisecs: usize) -> Result<usize, ()> {
+        if millisecs == 0 {
+            return Err(());
+        }
+        Ok(TIME_SLICE_MS.swap(millisecs, Ordering::Relaxed))
     }
 }
 
 pub static TIMER: Timer = Timer;
 
-fn millisecs_to_ticks(millisecs: u64) -> u64 {
-    const FREQ: u64 = 10_000_000; // QEMU counter runs at 10 MHz ticks / second
-    millisecs * FREQ / 1_000
+// Call `set_timer` until it succeeds, up to `SET_ATTEMPTS` times in all
+fn set_with_retry(deadline: u64, mut set_timer: impl FnMut(u64) -> Result<isize, isize>) -> Result<(), isize> {
+    (1..SET_ATTEMPTS)
+        .fold(set_timer(deadline), |result, _| result.or_else(|_| set_timer(deadline)))
+        .map(|_| ())
+}
+
+const FREQ: u64 = 10_000_000; // QEMU counter runs at 10 MHz ticks / second
+
+// Whole seconds and the remainder convert separately so large values saturate rather than wrap
+pub fn millisecs_to_ticks(millisecs: u64) -> u64 {
+    (millisecs / 1_000).saturating_mul(FREQ)
+        .saturating_add(millisecs % 1_000 * FREQ / 1_000)
+}
+
+fn ticks_to_millisecs(ticks: u64) -> u64 {
+    ticks / FREQ * 1_000 + ticks % FREQ * 1_000 / FREQ
+}
+
+/// Milliseconds since the machine started
+pub fn uptime_ms() -> u64 {
+    ticks_to_millisecs(get_timer())
 }
 
 #[inline]
-fn get_timer() -> u64 {
+pub fn get_timer() -> u64 {
     let mut ticksl: u32;
     let mut ticksh: u32;
     let mut ticksh_check: u32;
@@ -37,3 +86,127 @@ fn get_timer() -> u64 {
     }
     ((ticksh as u64) << 32) | (ticksl as u64)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+
+    #[test_case]
+    fn convert_ticks_and_millisecs() {
+        print!("timer: convert ticks and millisecs...");
+
+        assert!(millisecs_to_ticks(500) == 5_000_000);
+        assert!(ticks_to_millisecs(millisecs_to_ticks(1234)) == 1234);
+
+        // Large values convert exactly up to the counter's range, then saturate
+        assert!(millisecs_to_ticks(10_000_000_000_001) == 100_000_000_000_010_000);
+        assert!(millisecs_to_ticks(2_000_000_000_000) > millisecs_to_ticks(1_000_000_000_000));
+        assert!(millisecs_to_ticks(u64::MAX) == u64::MAX);
+        assert!(ticks_to_millisecs(u64::MAX) == u64::MAX / FREQ * 1_000 + u64::MAX % FREQ * 1_000 / FREQ);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn change_time_slice() {
+        print!("timer: change time slice...");
+
+        assert!(TIMER.time_slice_ms() == DEFAULT_TIME_SLICE_MS);
+        assert!(TIMER.set_time_slice_ms(0).is_err());
+        assert!(TIMER.set_time_slice_ms(10) == Ok(DEFAULT_TIME_SLICE_MS));
+        assert!(TIMER.time_slice_ms() == 10);
+        assert!(TIMER.set_time_slice_ms(DEFAULT_TIME_SLICE_MS) == Ok(10));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn set_arms_after_millisecs() {
+        print!("timer: set arms after millisecs...");
+
+        use crate::sbi::{use_mock_sbi, MOCK_SBI};
+        use crate::scheduler::SSTATUS_SIE;
+
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        use_mock_sbi(true);
+        let before = get_timer();
+        let result = TIMER.set(7);
+        let after = get_timer();
+        use_mock_sbi(false);
+        write_csr!("sstatus", sstatus);
+
+        assert!(result == Ok(()));
+        let deadline = MOCK_SBI.deadline.lock()
+            .expect("timer should be armed");
+        assert!((before + millisecs_to_ticks(7)..=after + millisecs_to_ticks(7)).contains(&deadline));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn set_far_future_does_not_wrap() {
+        print!("timer: set far future does not wrap...");
+
+        use crate::sbi::{use_mock_sbi, MOCK_SBI};
+        use crate::scheduler::SSTATUS_SIE;
+
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        use_mock_sbi(true);
+        let before = get_timer();
+        let results = [TIMER.set(3_000_000_000_000), TIMER.set(u64::MAX)];
+        use_mock_sbi(false);
+        write_csr!("sstatus", sstatus);
+
+        // The deadline stays in the future, at the end of the counter's range
+        assert!(results == [Ok(()), Ok(())]);
+        let deadline = MOCK_SBI.deadline.lock()
+            .expect("timer should be armed");
+        assert!(deadline == u64::MAX && deadline > before);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn set_retries_failures() {
+        print!("timer: set retries failures...");
+
+        // A single failure is retried and the timer is still armed
+        let mut calls = 0;
+        let result = set_with_retry(1234, |deadline| {
+            assert!(deadline == 1234);
+            calls += 1;
+            if calls == 1 { Err(-1) } else { Ok(0) }
+        });
+        assert!(result == Ok(()));
+        assert!(calls == 2);
+
+        // Persistent failures give up with the error
+        let mut calls = 0;
+        let result = set_with_retry(1234, |_| {
+            calls += 1;
+            Err(-2)
+        });
+        assert!(result == Err(-2));
+        assert!(calls == SET_ATTEMPTS);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn uptime_advances() {
+        print!("timer: uptime advances...");
+
+        let start = uptime_ms();
+        let deadline = get_timer() + millisecs_to_ticks(20);
+        while get_timer() < deadline {
+            core::hint::spin_loop();
+        }
+        let elapsed = uptime_ms() - start;
+        assert!((20..100).contains(&elapsed));
+
+        common::ok!();
+    }
+}
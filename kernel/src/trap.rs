@@ -1,178 +1,1882 @@
 //! Trap handler
 
-use alloc::slice;
+use core::ffi::CStr;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(test)]
+use core::sync::atomic::AtomicUsize;
 
 use common::{
-    SYS_PUTBYTE,
-    SYS_GETCHAR,
-    SYS_EXIT,
-    SYS_READFILE,
-    SYS_WRITEFILE,
+    FileStat,
+    PageInfo,
+    ProcStat,
+    PROC_STATE_RUNNABLE,
+    PROC_STATE_SLEEPING,
+    PROC_STATE_EXITED,
+    PROC_HART_NONE,
+    FAULT_FILE_WRITE,
+    FAULT_CONSOLE_WRITE,
+    SIGNAL_KINDS,
+    EXIT_SIGNALLED,
+    EXIT_KILLED,
+    EXIT_FAULTED,
+    OPEN_READ,
+    OPEN_WRITE,
+    OPEN_APPEND,
+    SPAWN_RESTART_ON_FAULT,
+    SELECT_CONSOLE,
+    SELECT_FOREVER,
+    SELECT_MAX,
+    MAP_READ,
+    MAP_WRITE,
+    MAP_EXEC,
 };
+use common::args::{Args, ARGS_SIZE};
+use common::trap::TrapFrame;
+use common::{ErrorCode, Syscall};
 
-use crate::process::State;
-use crate::sbi::{put_byte, get_char};
-use crate::scheduler::{yield_now, PROCS, CURRENT_PROC, SSTATUS_SIE};
-use crate::tar::{FILES, fs_flush};
-use crate::timer::TIMER;
+use crate::address::VAddr;
+use crate::allocator::bytes_available;
+use crate::console::{get_byte, get_byte_timeout, has_input, poll_console, read_byte};
+#[cfg(any(test, feature = "fault-injection"))]
+use crate::fault::inject_fault;
+use crate::fault::should_fail;
+use crate::page::{translate, PAGE_R, PAGE_W, PAGE_X};
+use crate::pipe::{Pipe, PIPES};
+use crate::process::{create_process, exec_current, find_program, handle_page_fault, user_entry, FaultPolicy, OpenFile, State};
+use crate::qemu::qemu_exit_pass;
+use crate::random;
+use crate::sbi::{console_write, sbi, shutdown};
+use crate::scheduler::{idle_pid, reap_orphans, sleep_until, sleep_while, wake_expired, wake_sleeping, yield_now, PROCS, CURRENT_PROC, IDLE_PID, SCHED_LATENCY_HIST, SWITCH_COUNT, SIE_STIE, SSTATUS_SIE, SSTATUS_SPP};
+use crate::tar::{FILES, fs_sync};
+use crate::timer::{get_timer, millisecs_to_ticks, uptime_ms, TIMER};
+use crate::uaccess::{copy_from_user, copy_to_user, put_user, user_filename, user_slice, user_slice_mut};
+use crate::watchdog;
 use crate::println;
 
+const CONSOLE_RETRIES: usize = 16;      // Yields while the console is busy before a write reports an error
+
 const SCAUSE_ECALL: usize = 8;
 const SCAUSE_TIMER_INTERRUPT: usize = 0x80000005;
+const SCAUSE_ILLEGAL_INSTRUCTION: usize = 2;
+const SCAUSE_LOAD_FAULT: usize = 5;
+const SCAUSE_STORE_FAULT: usize = 7;
+pub const SCAUSE_INSTRUCTION_PAGE_FAULT: usize = 12;
+const SCAUSE_LOAD_PAGE_FAULT: usize = 13;
+const SCAUSE_STORE_PAGE_FAULT: usize = 15;
 
-#[derive(Debug)]
-#[repr(C, packed)]
-pub struct TrapFrame{
-  ra: usize,      // 0
-  gp: usize,
-  tp: usize,
-  t0: usize,
-  t1: usize,
-  t2: usize,
-  t3: usize,
-  t4: usize,
-  t5: usize,
-  t6: usize,
-  a0: usize,
-  a1: usize,
-  a2: usize,
-  a3: usize,
-  a4: usize,
-  a5: usize,
-  a6: usize,
-  a7: usize,
-  s0: usize,
-  s1: usize,
-  s2: usize,
-  s3: usize,
-  s4: usize,
-  s5: usize,
-  s6: usize,
-  s7: usize,
-  s8: usize,
-  s9: usize,
-  s10: usize,
-  s11: usize,
-  sp: usize,          // 30
-  sscratch: usize,    // 31
-}
-
+/// Handle a trap from `kernel_entry`
+///
+/// On entry interrupts are disabled (`sstatus.SIE` clear) by the trap, with `sstatus.SPIE` holding the
+/// interrupted state and `sstatus.SPP` the privilege level trapped from.
+/// - System calls re-enable interrupts while they run so that blocking calls can be preempted. Interrupts are
+///   disabled again while a `SpinLock` is held, so a timer interrupt never finds this hart holding a lock.
+/// - Timer interrupts keep interrupts disabled through `yield_now` and the context switch.
+///
+/// On exit interrupts are disabled again by `kernel_entry` and `sret` restores `sstatus.SIE` from `sstatus.SPIE`.
+/// A process returning to user mode with a signal pending is sent to its handler instead.
 #[unsafe(no_mangle)]
 pub extern "C" fn handle_trap(f: &mut TrapFrame) {
     let scause = read_csr!("scause");
     if scause == SCAUSE_ECALL {
-        let mut user_pc = read_csr!("sepc");
+        // Only user mode may make system calls; an ecall from supervisor mode is a kernel bug
+        if read_csr!("sstatus") & SSTATUS_SPP != 0 {
+            panic!("unexpected ecall from supervisor mode a7={:x}, sepc=0x{:x}", { f.a7 }, read_csr!("sepc"));
+        }
+        // Return past the ecall; SYS_SIGRETURN replaces this with where the signal interrupted the process
+        write_csr!("sepc", read_csr!("sepc") + 4);
         write_csr!("sstatus", read_csr!("sstatus") | SSTATUS_SIE);  // Re-enable interrupts
         handle_syscall(f);
-        user_pc += 4;
-        write_csr!("sepc", user_pc);
     } else if scause == SCAUSE_TIMER_INTERRUPT {
-        TIMER.set(500);
-        write_csr!("sstatus", read_csr!("sstatus") | SSTATUS_SIE);  // Re-enable interrupts
+        if let Err(error) = TIMER.set_time_slice() {
+            // The deadline that raised this interrupt has passed, so it would be taken again straight after sret.
+            // Turn timer interrupts off and carry on without preemption: processes still switch when they
+            // yield or block.
+            write_csr!("sie", read_csr!("sie") & !SIE_STIE);
+            klog!(ERROR, "could not arm timer error={}, preemption stopped", error);
+        }
+        // Only time spent in the kernel outside the idle process counts towards the watchdog. It is checked
+        // before anything here locks the process table, as the stuck code may hold that or CURRENT_PROC.
+        let is_busy_in_kernel = read_csr!("sstatus") & SSTATUS_SPP != 0
+            && CURRENT_PROC.try_lock().is_none_or(|current| *current != Some(idle_pid()));
+        if is_busy_in_kernel {
+            watchdog::check();
+        } else {
+            watchdog::pet();
+        }
+        poll_console();
+        wake_expired(get_timer());
         yield_now();
+    } else if is_page_fault(scause) && read_csr!("sstatus") & SSTATUS_SPP == 0 && handle_page_fault(read_csr!("stval")) {
+        // A user image page was loaded on first touch; return to retry the faulting instruction
+    } else if read_csr!("sstatus") & SSTATUS_SPP == 0 {
+        handle_user_fault(f, scause);
     } else {
+        #[cfg(test)]
+        if EXPECTING_FAULT.swap(false, Ordering::Relaxed) {
+            // Return from the call that faulted, as if it had returned
+            FAULT_CAUSE.store(scause, Ordering::Relaxed);
+            write_csr!("sepc", { f.ra });
+            return;
+        }
+        dump_trap_state(f, scause);
         panic!("unexpected trap scause=0x{:x}, stval=0x{:x}, sepc=0x{:x}", scause, read_csr!("stval"), read_csr!("sepc"));
     }
+
+    if read_csr!("sstatus") & SSTATUS_SPP == 0 {
+        deliver_signal(f);
+    }
+}
+
+// While set, a fault in the kernel returns from the call that raised it and records its cause, rather than
+// panicking, so that a test can see the fault
+#[cfg(test)]
+static EXPECTING_FAULT: AtomicBool = AtomicBool::new(false);
+#[cfg(test)]
+static FAULT_CAUSE: AtomicUsize = AtomicUsize::new(0);
+
+/// Call the code at `addr`, returning the `scause` of the fault it raises, or `None` if it returns normally
+///
+/// The code is called with no arguments and must return if it does not fault.
+#[cfg(test)]
+pub fn call_expecting_fault(addr: usize) -> Option<usize> {
+    EXPECTING_FAULT.store(true, Ordering::Relaxed);
+    // Safety: The caller passes code that returns, and a fault returns here too
+    unsafe {
+        core::arch::asm!("jalr {}", in(reg) addr, clobber_abi("C"));
+    }
+    // Still set if nothing faulted
+    (!EXPECTING_FAULT.swap(false, Ordering::Relaxed)).then(|| FAULT_CAUSE.load(Ordering::Relaxed))
+}
+
+// Send a process about to return to user mode to the handler for its lowest pending signal.
+// The handler is called with the signal number and returns to the trampoline, which makes SYS_SIGRETURN.
+fn deliver_signal(f: &mut TrapFrame) {
+    // Interrupts stay disabled until sret
+    write_csr!("sstatus", read_csr!("sstatus") & !SSTATUS_SIE);
+
+    PROCS.with_current(|process| {
+        let signals = &mut process.signals;
+        if signals.in_handler || signals.pending == 0 {
+            return;
+        }
+        let signo = signals.pending.trailing_zeros() as usize;
+        signals.pending &= !(1 << signo);
+
+        signals.in_handler = true;
+        signals.saved = *f;
+        signals.saved_pc = read_csr!("sepc");
+
+        f.a0 = signo;
+        f.ra = signals.trampoline;
+        f.sp &= !0xf;      // The handler's stack frame goes below the interrupted one, 16 byte aligned
+        write_csr!("sepc", signals.handlers[signo]);
+    });
+}
+
+// A user process trapped on something other than a system call or a page demand paging could load.
+// It is restarted if its fault policy allows, otherwise killed.
+fn handle_user_fault(f: &mut TrapFrame, scause: usize) {
+    let (stval, sepc) = (read_csr!("stval"), read_csr!("sepc"));
+    let (pid, restart_pc) = PROCS.with_current(|process| (process.pid, process.restart_after_fault(f)));
+    klog!(WARN, "process {} {} stval=0x{:x}, sepc=0x{:x}", pid, scause_name(scause), stval, sepc);
+
+    match restart_pc {
+        Some(pc) => {
+            println!("process {} restarted", pid);
+            write_csr!("sepc", pc);
+        },
+        None => {
+            let _ = kill(pid, EXIT_FAULTED + scause);
+        },
+    }
+}
+
+fn is_page_fault(scause: usize) -> bool {
+    matches!(scause, SCAUSE_INSTRUCTION_PAGE_FAULT | SCAUSE_LOAD_PAGE_FAULT | SCAUSE_STORE_PAGE_FAULT)
+}
+
+// Describe the common exception causes
+fn scause_name(scause: usize) -> &'static str {
+    match scause {
+        SCAUSE_ILLEGAL_INSTRUCTION => "illegal instruction",
+        SCAUSE_LOAD_FAULT => "load access fault",
+        SCAUSE_STORE_FAULT => "store access fault",
+        SCAUSE_INSTRUCTION_PAGE_FAULT => "instruction page fault",
+        SCAUSE_LOAD_PAGE_FAULT => "load page fault",
+        SCAUSE_STORE_PAGE_FAULT => "store page fault",
+        _ => "unknown",
+    }
+}
+
+// Print the registers and process of an unexpected trap before panicking
+fn dump_trap_state(f: &TrapFrame, scause: usize) {
+    // The trap may have interrupted code holding the lock, so don't wait on it
+    match CURRENT_PROC.try_lock().as_deref() {
+        Some(Some(pid)) => println!("trap in pid {}: {}", pid, scause_name(scause)),
+        _ => println!("trap in unknown pid: {}", scause_name(scause)),
+    }
+    println!("{:#x?}", f);
+}
+
+// Log each system call with its arguments and result, set with `SYS_SET_TRACE`
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+// A traced system call, printed as `pid 2: GetPid(0x0, 0x0, 0x0, 0x0) = 2`. Only the register values are
+// shown: pointer arguments are not followed into user memory.
+struct SyscallTrace {
+    pid: usize,
+    syscall: Syscall,
+    args: [usize; 4],
+    result: usize,
+}
+
+impl fmt::Display for SyscallTrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a0, a1, a2, a3] = self.args;
+        write!(f, "pid {}: {:?}({:#x}, {:#x}, {:#x}, {:#x}) = {}", self.pid, self.syscall, a0, a1, a2, a3, self.result as isize)
+    }
 }
 
 fn handle_syscall(f: &mut TrapFrame) {
-    let sysno = f.a7;
-    match sysno {
-        SYS_PUTBYTE => {  // Match what user code sends
-            match put_byte(f.a0 as u8) {
+    let Ok(syscall) = Syscall::try_from(f.a7) else {
+        println!("unknown syscall sysno={:x}", { f.a7 });
+        f.a0 = ErrorCode::NoSys.as_usize();
+        return;
+    };
+    let args = [f.a0, f.a1, f.a2, f.a3];
+
+    run_syscall(f, syscall);
+
+    if TRACE.load(Ordering::Relaxed) {
+        let pid = CURRENT_PROC.lock().unwrap_or(0);
+        klog!(INFO, "{}", SyscallTrace { pid, syscall, args, result: f.a0 });
+    }
+}
+
+fn run_syscall(f: &mut TrapFrame, syscall: Syscall) {
+    match syscall {
+        Syscall::PutByte => {  // Match what user code sends
+            match retry_console(|| sbi().put_byte(f.a0 as u8)) {
                 Ok(_) => f.a0 = 0,     // Set return value to 0 (success)
-                Err(e) => f.a0 = e as usize,    // Set return value to error code
+                Err(_) => f.a0 = ErrorCode::IoError.as_usize(),
             }
         },
-        SYS_GETCHAR => {
-            loop {
-                if let Ok(ch) = get_char() {
-                    f.a0 = ch as usize;
-                    break;
+        Syscall::PutBytes => 'putbytes: {
+            let buf = match user_slice(f.a0, f.a1) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    f.a0 = e.as_usize();
+                    break 'putbytes;
+                },
+            };
+            // An injected fault cuts the write short, as a console that stops taking bytes partway would
+            let len = if should_fail(FAULT_CONSOLE_WRITE) { buf.len() / 2 } else { buf.len() };
+            let mut written = 0;
+            while written < len {
+                match retry_console(|| console_write(&buf[written..len])) {
+                    Ok(count) => written += count,
+                    Err(_) => break,
                 }
-                yield_now();
             }
+            f.a0 = if written == 0 && !buf.is_empty() { ErrorCode::IoError.as_usize() } else { written };
+        },
+        Syscall::GetChar => {
+            f.a0 = get_byte() as usize;
         },
-        SYS_EXIT => {
+        Syscall::GetCharTimeout => {
+            let millisecs = (f.a1 as u64) << 32 | f.a0 as u64;
+            f.a0 = match get_byte_timeout(millisecs) {
+                Ok(Some(b)) => b as usize,
+                Ok(None) => ErrorCode::WouldBlock.as_usize(),
+                Err(_) => ErrorCode::IoError.as_usize(),
+            };
+        },
+        Syscall::SleepUntil => {
+            // A deadline already passed, including zero, returns at once
+            let deadline = millisecs_to_ticks((f.a1 as u64) << 32 | f.a0 as u64);
+            sleep_until(deadline, || get_timer() < deadline);
+            f.a0 = 0;
+        },
+        Syscall::PollChar => {
+            f.a0 = match read_byte() {
+                Ok(b) => b as usize,
+                Err(-1) => ErrorCode::WouldBlock.as_usize(),   // SBI returns -1 if no byte is ready
+                Err(_) => ErrorCode::IoError.as_usize(),
+            };
+        },
+        Syscall::Spawn => 'spawn: {
+            let flags = f.a2 as u32;
+            if flags & !SPAWN_RESTART_ON_FAULT != 0 {
+                f.a0 = ErrorCode::BadArg.as_usize();
+                break 'spawn;
+            }
+
+            let (image, args) = match program_args(f.a0, f.a1) {
+                Ok(program) => program,
+                Err(e) => {
+                    f.a0 = e.as_usize();
+                    break 'spawn;
+                },
+            };
+
+            let on_fault = if flags & SPAWN_RESTART_ON_FAULT != 0 { FaultPolicy::Restart } else { FaultPolicy::Kill };
+            f.a0 = match create_process(user_entry as *const () as usize, image.as_ptr(), image.len(), args, on_fault) {
+                Ok(pid) => pid,
+                Err(e) => {
+                    println!("spawn: no free process slot or memory");
+                    e.as_usize()
+                },
+            };
+        },
+        Syscall::Exec => {
+            let result = program_args(f.a0, f.a1)
+                .and_then(|(image, args)| exec_current(image, args));
+            match result {
+                Ok(entry) => {
+                    // Start the new image with clean registers, keeping the kernel stack in sscratch
+                    *f = TrapFrame { sscratch: f.sscratch, ..TrapFrame::ZERO };
+                    write_csr!("sepc", entry);
+                },
+                Err(e) => f.a0 = e.as_usize(),
+            }
+        },
+        Syscall::Yield => {
+            yield_now();
+            f.a0 = 0;
+        },
+        Syscall::Uptime => {
+            // The result is 64 bits wide, so it is written to the caller's buffer rather than returned in a0
+            f.a0 = match put_user(f.a0, uptime_ms()) {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::Exit => {
+            let _ = fs_sync();
             let current = CURRENT_PROC.lock()
             .expect("current process should be running");
-            crate::println!("process {} exited", current);
+            crate::println!("process {} exited with {}", current, f.a0);
             if let Some(p) = PROCS.0.lock().iter_mut()
                 .find(|p| p.pid == current) {
+                    p.exit_code = f.a0;
                     p.state = State::Exited
                 }
+                // Processes waiting for this one recheck
+                wake_sleeping();
                 yield_now();
-            unreachable!("unreachable after SYS_EXIT");
+            unreachable!("unreachable after Syscall::Exit");
+        },
+        Syscall::Shutdown => {
+            println!("shutting down");
+            let _ = fs_sync();
+            // The user test runner shuts down once every test has passed
+            if cfg!(test) {
+                qemu_exit_pass();
+            }
+            shutdown();
         },
-        SYS_READFILE | SYS_WRITEFILE => 'readorwritefile: {
-            let filename_ptr = f.a0 as *const u8;
-            let filename_len = f.a1;
+        Syscall::GetPid => {
+            f.a0 = CURRENT_PROC.lock()
+                .expect("current process should be running");
+        },
+        Syscall::Kill => {
+            f.a0 = match kill(f.a0, EXIT_KILLED) {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::SigAction => {
+            let (signo, handler, trampoline) = (f.a0, f.a1, f.a2);
+            f.a0 = if signo == 0 || signo >= SIGNAL_KINDS {
+                ErrorCode::BadArg.as_usize()
+            } else {
+                PROCS.with_current(|process| {
+                    process.signals.handlers[signo] = handler;
+                    process.signals.trampoline = trampoline;
+                    // With no handler left to run, a signal still pending would be delivered to address 0
+                    if handler == 0 {
+                        process.signals.pending &= !(1 << signo);
+                    }
+                });
+                0
+            };
+        },
+        Syscall::Signal => 'signal: {
+            let (pid, signo) = (f.a0, f.a1);
+            if signo == 0 || signo >= SIGNAL_KINDS {
+                f.a0 = ErrorCode::BadArg.as_usize();
+                break 'signal;
+            }
 
-            // Safety: Caller guarantees that filename_ptr points to valid memory
-            // of length filename_len that remains valid for the lifetime of this reference
-            let filename = unsafe {
-                str::from_utf8(slice::from_raw_parts(filename_ptr, filename_len))
-            }.expect("filename must be valid UTF-8");
+            let mut procs = PROCS.0.lock();
+            let Some(p) = procs.iter_mut()
+                .find(|p| p.pid == pid && pid != IDLE_PID && !p.is_idle && matches!(p.state, State::Runnable | State::Sleeping)) else {
+                    f.a0 = ErrorCode::NotFound.as_usize();
+                    break 'signal;
+                };
+            if p.signals.handlers[signo] != 0 {
+                p.signals.pending |= 1 << signo;
+                drop(procs);
+                // A sleeping process wakes to recheck what it waits for, and takes the signal on its way
+                // back to user mode
+                wake_sleeping();
+                f.a0 = 0;
+                break 'signal;
+            }
+            drop(procs);
 
-            let buf_ptr = f.a2 as *mut u8;
-            let buf_len = f.a3;
+            // Without a handler, the signal terminates the process
+            f.a0 = match kill(pid, EXIT_SIGNALLED + signo) {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::SigReturn => {
+            PROCS.with_current(|process| {
+                let signals = &mut process.signals;
+                if signals.in_handler {
+                    signals.in_handler = false;
+                    *f = signals.saved;
+                    write_csr!("sepc", signals.saved_pc);
+                } else {
+                    f.a0 = ErrorCode::BadArg.as_usize();
+                }
+            });
+        },
+        Syscall::Select => 'select: {
+            let (fds_ptr, count) = (f.a0, f.a1);
+            let millisecs = (f.a3 as u64) << 32 | f.a2 as u64;
+            if count > SELECT_MAX {
+                f.a0 = ErrorCode::BadArg.as_usize();
+                break 'select;
+            }
+            let mut bytes = [0u8; SELECT_MAX * size_of::<usize>()];
+            let bytes = &mut bytes[..count * size_of::<usize>()];
+            if let Err(e) = copy_from_user(bytes, fds_ptr) {
+                f.a0 = e.as_usize();
+                break 'select;
+            }
+            let mut fds = [0usize; SELECT_MAX];
+            for (fd, fd_bytes) in fds.iter_mut().zip(bytes.chunks_exact(size_of::<usize>())) {
+                *fd = usize::from_ne_bytes(fd_bytes.try_into().expect("chunk is one usize"));
+            }
+
+            f.a0 = match select(&fds[..count], millisecs) {
+                Ok(Some(index)) => index,
+                Ok(None) => ErrorCode::WouldBlock.as_usize(),
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::PipeCreate => {
+            f.a0 = PIPES.create().unwrap_or(ErrorCode::NoSpace.as_usize());
+        },
+        Syscall::PipeWrite | Syscall::PipeRead => {
+            let id = f.a0;
+            let buf_ptr = f.a1;
+            let buf_len = f.a2;
+
+            let result = match syscall {
+                Syscall::PipeWrite => user_slice(buf_ptr, buf_len)
+                    .map(|buf| pipe_transfer(id, buf.is_empty(), |pipe| pipe.write(buf))),
+                _ => user_slice_mut(buf_ptr, buf_len)
+                    .map(|buf| pipe_transfer(id, buf.is_empty(), |pipe| pipe.read(buf))),
+            };
 
-            // Safety: Caller guarantees that buf_ptr points to valid memory
-            // of length buf_len that remains valid for the lifetime of this reference
-            let buf = unsafe {
-                slice::from_raw_parts_mut(buf_ptr, buf_len)
+            f.a0 = match result {
+                Ok(Some(len)) => len,
+                Ok(None) => ErrorCode::NotFound.as_usize(),
+                Err(e) => e.as_usize(),
             };
+        },
+        Syscall::PipeClose => {
+            f.a0 = if PIPES.release(f.a0) {
+                // Processes blocked on the pipe find it gone
+                wake_sleeping();
+                0
+            } else {
+                ErrorCode::NotFound.as_usize()
+            };
+        },
+        Syscall::ProcStat => 'procstat: {
+            let pid = f.a0;
+            let stat_ptr = f.a1;
+            let current = CURRENT_PROC.lock()
+            .expect("current process should be running");
+
+            let procs = PROCS.0.lock();
+            let Some(p) = procs.iter()
+                .find(|p| p.pid == pid && p.state != State::Unused) else {
+                    f.a0 = ErrorCode::NotFound.as_usize();
+                    break 'procstat;
+                };
 
-            let Some(file_i) = FILES.fs_lookup(filename) else {
-                println!("file not found {:x?}", filename);
-                f.a0 = usize::MAX; // 2's complement is -1
-                break 'readorwritefile;
+            // A running process, here or on another hart, has also used the time since it was switched in
+            let running_ticks = if pid == current || p.running_on.is_some() { get_timer().saturating_sub(p.scheduled_at) } else { 0 };
+            let stat = ProcStat {
+                pid,
+                state: match p.state {
+                    State::Runnable => PROC_STATE_RUNNABLE,
+                    State::Sleeping => PROC_STATE_SLEEPING,
+                    State::Exited | State::Unused => PROC_STATE_EXITED,
+                },
+                cpu_ticks: p.cpu_ticks + running_ticks,
+                exit_code: p.exit_code,
+                name: p.name,
+                hart: p.running_on.unwrap_or(PROC_HART_NONE),
             };
+            drop(procs);
 
-            match sysno {
-                SYS_WRITEFILE => {
+            f.a0 = match put_user(stat_ptr, stat) {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::SetQuantum => {
+            f.a0 = match TIMER.set_time_slice_ms(f.a0) {
+                Ok(previous) => previous,
+                Err(()) => ErrorCode::BadArg.as_usize(),
+            };
+        },
+        Syscall::ReadFile | Syscall::WriteFile => 'readorwritefile: {
+            let file_i = match user_file_index(f.a0, f.a1) {
+                Ok(file_i) => file_i,
+                Err(e) => {
+                    f.a0 = e.as_usize();
+                    break 'readorwritefile;
+                },
+            };
+
+            let buf_ptr = f.a2;
+            let buf_len = f.a3;
+
+            match syscall {
+                Syscall::WriteFile => {
+                    if should_fail(FAULT_FILE_WRITE) {
+                        println!("injected fault writing file {}", FILES.0.lock()[file_i].name());
+                        f.a0 = ErrorCode::IoError.as_usize();
+                        break 'readorwritefile;
+                    }
+
+                    // The lock is held from the check through the size update, and `copy_from_user` checks the
+                    // whole buffer before copying any of it. A write replaces the file in full or not at
+                    // all, so of several writers the last one wins. `fs_flush` serializes under the same lock.
                     let mut files = FILES.0.lock();
-                    // try_borrow_mut()
-                    // .expect("should be able to borrow FILES mutably to handle SYS_WRITEFILE");
 
-                    files[file_i].data[..buf.len()].copy_from_slice(buf);
-                    files[file_i].size = buf.len();
-                    drop(files);
-                    fs_flush();
+                    if buf_len > files[file_i].data.len() {
+                        println!("file too large {}", files[file_i].name());
+                        f.a0 = ErrorCode::NoSpace.as_usize();
+                        break 'readorwritefile;
+                    }
+
+                    if let Err(e) = copy_from_user(&mut files[file_i].data[..buf_len], buf_ptr) {
+                        f.a0 = e.as_usize();
+                        break 'readorwritefile;
+                    }
+                    files[file_i].size = buf_len;
+                    files[file_i].dirty = true;
                 },
-                SYS_READFILE => {
+                Syscall::ReadFile => {
                     let files = FILES.0.lock();
                     // try_borrow()
                     // .expect("should be able to borrow FILES to handle SYS_READFILE");
 
-                    buf.copy_from_slice(&files[file_i].data[..buf.len()]);
+                    let Some(data) = files[file_i].data.get(..buf_len) else {
+                        f.a0 = ErrorCode::BadArg.as_usize();
+                        break 'readorwritefile;
+                    };
+
+                    if let Err(e) = copy_to_user(buf_ptr, data) {
+                        f.a0 = e.as_usize();
+                        break 'readorwritefile;
+                    }
                 },
-                _ => unreachable!("sysno must be SYS_READFILE or SYS_WRITEFILE"),
+                _ => unreachable!("syscall must be ReadFile or WriteFile"),
             }
 
             f.a0 = buf_len;
         },
-        _ => {panic!("unexpected syscall sysno={:x}", sysno);},
+        Syscall::Truncate => {
+            let size = f.a2;
+            f.a0 = match user_file_index(f.a0, f.a1).and_then(|file_i| FILES.0.lock()[file_i].truncate(size)) {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::Sync => {
+            f.a0 = match fs_sync() {
+                Ok(_) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::ListDir => 'listdir: {
+            let dir_bytes = match user_filename(f.a0, f.a1) {
+                Ok(dir_bytes) => dir_bytes,
+                Err(e) => {
+                    f.a0 = e.as_usize();
+                    break 'listdir;
+                },
+            };
+
+            let buf = match user_slice_mut(f.a2, f.a3) {
+                Ok(buf) => buf,
+                Err(e) => {
+                    f.a0 = e.as_usize();
+                    break 'listdir;
+                },
+            };
+
+            // File names are stored as UTF-8, so any other directory is empty.
+            // The listing is written straight into the user's buffer.
+            let result = match str::from_utf8(dir_bytes) {
+                Ok(dir) => FILES.list_dir(dir, buf),
+                Err(_) => Ok(0),
+            };
+            f.a0 = result.unwrap_or_else(|e| e.as_usize());
+        },
+        Syscall::FsInfo => {
+            f.a0 = match put_user(f.a0, FILES.info()) {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::V2P => {
+            f.a0 = if cfg!(any(test, feature = "debug-syscalls")) {
+                let (vaddr, info_ptr) = (f.a0, f.a1);
+                let mapping = PROCS.with_current(|process| {
+                    translate(process.page_table.as_ref()?, VAddr::new(vaddr))
+                });
+                match mapping {
+                    Some((paddr, flags)) => match put_user(info_ptr, PageInfo { paddr: paddr.as_usize(), flags }) {
+                        Ok(()) => 0,
+                        Err(e) => e.as_usize(),
+                    },
+                    None => ErrorCode::NotFound.as_usize(),
+                }
+            } else {
+                ErrorCode::NoSys.as_usize()
+            };
+        },
+        Syscall::SetTrace => {
+            f.a0 = if cfg!(any(test, feature = "debug-syscalls")) {
+                TRACE.store(f.a0 != 0, Ordering::Relaxed);
+                0
+            } else {
+                ErrorCode::NoSys.as_usize()
+            };
+        },
+        Syscall::Stat => 'stat: {
+            let file_i = match user_file_index(f.a0, f.a1) {
+                Ok(file_i) => file_i,
+                Err(e) => {
+                    f.a0 = e.as_usize();
+                    break 'stat;
+                },
+            };
+
+            let stat_ptr = f.a2;
+
+            let files = FILES.0.lock();
+            let file = &files[file_i];
+            let stat = FileStat {
+                size: file.size,
+                name_len: CStr::from_bytes_until_nul(&file.name)
+                    .map_or(file.name.len(), |cstr| cstr.count_bytes()),
+            };
+            drop(files);
+
+            f.a0 = match put_user(stat_ptr, stat) {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::Open => 'open: {
+            let flags = f.a2 as u32;
+
+            let append_without_write = flags & OPEN_APPEND != 0 && flags & OPEN_WRITE == 0;
+            if flags == 0 || flags & !(OPEN_READ | OPEN_WRITE | OPEN_APPEND) != 0 || append_without_write {
+                f.a0 = ErrorCode::BadArg.as_usize();
+                break 'open;
+            }
+
+            let file_i = match user_file_index(f.a0, f.a1) {
+                Ok(file_i) => file_i,
+                Err(e) => {
+                    f.a0 = e.as_usize();
+                    break 'open;
+                },
+            };
+
+            // Use the lowest free file descriptor
+            let opened = PROCS.with_current(|process| {
+                let fd = process.files.iter().position(|file| !file.in_use)?;
+                process.files[fd] = OpenFile { in_use: true, file_i, offset: 0, flags };
+                Some(fd)
+            });
+            f.a0 = opened.unwrap_or_else(|| {
+                println!("too many open files");
+                ErrorCode::NoSpace.as_usize()
+            });
+        },
+        Syscall::Close => {
+            f.a0 = with_open_file(f.a0, |file| *file = OpenFile::CLOSED)
+                .map_or(ErrorCode::BadArg.as_usize(), |()| 0);
+        },
+        Syscall::Read | Syscall::Write => 'readorwrite: {
+            let fd = f.a0;
+            let buf_ptr = f.a1;
+            let buf_len = f.a2;
+
+            let Some(open_file) = with_open_file(fd, |file| *file) else {
+                f.a0 = ErrorCode::BadArg.as_usize();
+                break 'readorwrite;
+            };
+
+            let mut files = FILES.0.lock();
+            let file = &mut files[open_file.file_i];
+            let offset = if syscall == Syscall::Write && open_file.flags & OPEN_APPEND != 0 {
+                file.size
+            } else {
+                open_file.offset
+            };
+            let len = match syscall {
+                Syscall::Read if open_file.flags & OPEN_READ != 0 => {
+                    let len = buf_len.min(file.size.saturating_sub(offset));
+                    if let Err(e) = copy_to_user(buf_ptr, &file.data[offset..offset + len]) {
+                        f.a0 = e.as_usize();
+                        break 'readorwrite;
+                    }
+                    len
+                },
+                Syscall::Write if open_file.flags & OPEN_WRITE != 0 => {
+                    if should_fail(FAULT_FILE_WRITE) {
+                        println!("injected fault writing file descriptor {}", fd);
+                        f.a0 = ErrorCode::IoError.as_usize();
+                        break 'readorwrite;
+                    }
+
+                    let len = buf_len.min(file.data.len().saturating_sub(offset));
+                    if let Err(e) = copy_from_user(&mut file.data[offset..offset + len], buf_ptr) {
+                        f.a0 = e.as_usize();
+                        break 'readorwrite;
+                    }
+                    file.size = file.size.max(offset + len);
+                    file.dirty = true;
+                    len
+                },
+                _ => {
+                    println!("file descriptor {} not opened for this access", fd);
+                    f.a0 = ErrorCode::PermissionDenied.as_usize();
+                    break 'readorwrite;
+                },
+            };
+            drop(files);
+
+            // Advance the offset so the next read or write carries on from here
+            let _ = with_open_file(fd, |file| file.offset = offset + len);
+            f.a0 = len;
+        },
+        Syscall::Mmap => {
+            let (len, prot) = (f.a0, f.a1 as u32);
+            let result = if prot & !(MAP_READ | MAP_WRITE | MAP_EXEC) != 0 {
+                Err(ErrorCode::BadArg)
+            } else {
+                let flags = [(MAP_READ, PAGE_R), (MAP_WRITE, PAGE_W), (MAP_EXEC, PAGE_X)].into_iter()
+                    .filter(|&(map, _)| prot & map != 0)
+                    .fold(0, |flags, (_, page)| flags | page);
+                PROCS.with_current(|process| process.map_anonymous(len, flags))
+            };
+            f.a0 = result.unwrap_or_else(|e| e.as_usize());
+        },
+        Syscall::Munmap => {
+            let (vaddr, len) = (f.a0, f.a1);
+            f.a0 = match PROCS.with_current(|process| process.unmap_anonymous(vaddr, len)) {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::MemInfo => {
+            f.a0 = bytes_available();
+        },
+        Syscall::Random => {
+            f.a0 = match user_slice_mut(f.a0, f.a1) {
+                Ok(buf) => {
+                    random::fill(buf);
+                    0
+                },
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::SchedStat => {
+            f.a0 = SWITCH_COUNT.load(Ordering::Relaxed);
+        },
+        Syscall::SchedLatencyHist => {
+            let buf_ptr = f.a0;
+            let buf_len = f.a1.min(SCHED_LATENCY_HIST.len());
+
+            let result = SCHED_LATENCY_HIST.iter()
+                .take(buf_len)
+                .enumerate()
+                .try_for_each(|(i, bucket)| put_user(buf_ptr + i * size_of::<usize>(), bucket.load(Ordering::Relaxed)));
+
+            f.a0 = match result {
+                Ok(()) => buf_len,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::Wait => {
+            let (pid, exit_code_ptr) = (f.a0, f.a1);
+            f.a0 = match wait(pid).and_then(|exit_code| put_user(exit_code_ptr, exit_code)) {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+        Syscall::InjectFault => {
+            #[cfg(any(test, feature = "fault-injection"))]
+            let result = inject_fault(f.a0, f.a1).map_err(|()| ErrorCode::BadArg);
+            #[cfg(not(any(test, feature = "fault-injection")))]
+            let result = Err(ErrorCode::NoSys);
+            f.a0 = match result {
+                Ok(()) => 0,
+                Err(e) => e.as_usize(),
+            };
+        },
+    }
+}
+
+// Retry `write` while the console refuses it, yielding between tries so that fast output is slowed down rather
+// than lost. Gives up with the console's error after `CONSOLE_RETRIES` yields.
+fn retry_console<T>(mut write: impl FnMut() -> Result<T, isize>) -> Result<T, isize> {
+    let mut retries = 0;
+    loop {
+        match write() {
+            Err(_) if retries < CONSOLE_RETRIES => {
+                retries += 1;
+                yield_now();
+            },
+            result => return result,
+        }
+    }
+}
+
+// Sleep while the pipe is full (write) or empty (read), then wake any process waiting on the other end.
+// Returns the bytes transferred by `transfer`, or `None` if there is no pipe `id`.
+fn pipe_transfer(id: usize, is_empty: bool, mut transfer: impl FnMut(&mut Pipe) -> usize) -> Option<usize> {
+    let mut result = None;
+    sleep_while(|| {
+        result = PIPES.with(id, &mut transfer);
+        result == Some(0) && !is_empty
+    });
+    wake_sleeping();
+    result
+}
+
+// Sleep until one of `fds` is readable, returning the index of the first that is, or `None` once `millisecs`
+// have passed.
+// A pipe is readable while it has bytes buffered and the console while it has input waiting. Pipe writes and
+// console input wake sleeping processes, so each is rechecked as it happens.
+fn select(fds: &[usize], millisecs: u64) -> Result<Option<usize>, ErrorCode> {
+    let is_ready = |fd| match fd {
+        SELECT_CONSOLE => Some(has_input()),
+        id => PIPES.with(id, |pipe| !pipe.is_empty()),
+    };
+    if fds.iter().any(|&fd| is_ready(fd).is_none()) {
+        return Err(ErrorCode::NotFound);
+    }
+    // Nothing to wait for and no timeout would sleep for ever
+    if fds.is_empty() && millisecs == SELECT_FOREVER {
+        return Err(ErrorCode::BadArg);
+    }
+
+    let deadline = match millisecs {
+        SELECT_FOREVER => 0,
+        _ => get_timer().saturating_add(millisecs_to_ticks(millisecs)),
+    };
+    let mut ready = None;
+    sleep_until(deadline, || {
+        ready = fds.iter().position(|&fd| is_ready(fd) == Some(true));
+        ready.is_none() && (deadline == 0 || get_timer() < deadline)
+    });
+    Ok(ready)
+}
+
+// Terminate process `pid` with `exit_code`; does not return if it is the current process
+fn kill(pid: usize, exit_code: usize) -> Result<(), ErrorCode> {
+    let current = CURRENT_PROC.lock()
+        .expect("current process should be running");
+
+    let mut procs = PROCS.0.lock();
+    if pid == IDLE_PID || procs.iter().any(|p| p.pid == pid && p.is_idle) {
+        println!("cannot kill the idle process");
+        return Err(ErrorCode::PermissionDenied);
     }
+
+    let Some(p) = procs.iter_mut()
+        .find(|p| p.pid == pid && matches!(p.state, State::Runnable | State::Sleeping)) else {
+            println!("no process {} to kill", pid);
+            return Err(ErrorCode::NotFound);
+        };
+
+    p.state = State::Exited;
+    p.exit_code = exit_code;
+    // A running process, here or on another hart, is freed once it switches away
+    reap_orphans(&mut procs);
+    drop(procs);
+
+    crate::println!("process {} killed", pid);
+    wake_sleeping();
+    if pid == current {
+        yield_now();
+        unreachable!("unreachable after killing the current process");
+    }
+    Ok(())
+}
+
+// Sleep until process `pid` has exited, returning its exit code and freeing it. Exits and kills wake
+// sleeping processes, so each is rechecked as it happens. Any process may wait, but only a parent is sure
+// to find its child's exit code, as no other process keeps an exited process from being freed.
+pub fn wait(pid: usize) -> Result<usize, ErrorCode> {
+    let current = CURRENT_PROC.lock()
+        .expect("current process should be running");
+    if pid == current {
+        return Err(ErrorCode::BadArg);
+    }
+
+    let mut result = Err(ErrorCode::NotFound);
+    sleep_while(|| {
+        let mut procs = PROCS.0.lock();
+        match procs.iter_mut().find(|p| p.pid == pid && p.state != State::Unused) {
+            Some(p) if p.state != State::Exited => true,
+            p => {
+                result = p.map(|p| {
+                    // Collected, so nothing waits for it any more
+                    p.parent = IDLE_PID;
+                    p.exit_code
+                }).ok_or(ErrorCode::NotFound);
+                false
+            },
+        }
+    });
+    reap_orphans(&mut PROCS.0.lock());
+    result
+}
+
+// Look up the file named by `len` bytes of user memory at `ptr`, returning its index in FILES
+fn user_file_index(ptr: usize, len: usize) -> Result<usize, ErrorCode> {
+    let filename_bytes = user_filename(ptr, len)?;
+    // File names are stored as UTF-8, so any other name cannot exist
+    str::from_utf8(filename_bytes).ok()
+        .and_then(|filename| FILES.fs_lookup(filename))
+        .ok_or_else(|| {
+            println!("file not found {:x?}", filename_bytes);
+            ErrorCode::NotFound
+        })
+}
+
+// Find the program named by encoded arguments `len` bytes long at user address `ptr`, for SYS_SPAWN and SYS_EXEC.
+// Returns the program image and the arguments.
+fn program_args<'a>(ptr: usize, len: usize) -> Result<(&'static [u8], &'a [u8]), ErrorCode> {
+    if len > ARGS_SIZE {
+        return Err(ErrorCode::BadArg);
+    }
+    let args = user_slice(ptr, len)?;
+
+    let parsed = Args::parse(args);
+    let Some(name) = parsed.clone().next().filter(|_| parsed.is_valid()) else {
+        println!("invalid program arguments");
+        return Err(ErrorCode::BadArg);
+    };
+    let Some(image) = find_program(name) else {
+        println!("program not found {:x?}", name);
+        return Err(ErrorCode::NotFound);
+    };
+    Ok((image, args))
+}
+
+// Run `f` on the current process's open file `fd`, or return `None` if it is not open
+fn with_open_file<R>(fd: usize, f: impl FnOnce(&mut OpenFile) -> R) -> Option<R> {
+    PROCS.with_current(|process| {
+        process.files.get_mut(fd)
+            .filter(|file| file.in_use)
+            .map(f)
+    })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{print, println};
-    use common::SYS_PUTBYTE;
+    use alloc::vec::Vec;
+    use crate::print;
+    use common::{FAULT_ALLOC, SIGNAL_TERM};
+    use crate::pipe::PIPES_MAX;
+    use crate::uaccess::FILENAME_MAX;
+
+    #[test_case]
+    fn decode_scause() {
+        print!("trap: decode scause...");
+
+        assert_eq!(scause_name(SCAUSE_STORE_PAGE_FAULT), "store page fault");
+        assert_eq!(scause_name(SCAUSE_ILLEGAL_INSTRUCTION), "illegal instruction");
+        assert_eq!(scause_name(SCAUSE_ECALL), "unknown");
+
+        common::ok!();
+    }
 
     #[test_case]
     fn handle_syscall_put_byte() {
         print!("entry: handle syscall put byte...");
 
-        let f = &mut TrapFrame { ra: 0, gp: 0, tp: 0, t0: 0, t1: 0, t2: 0, t3: 0, t4: 0, t5: 0, t6: 0, a0: 0, a1: 0, a2: 0, a3: 0, a4: 0, a5: 0, a6: 0, a7: 0, s0: 0, s1: 0, s2: 0, s3: 0, s4: 0, s5: 0, s6: 0, s7: 0, s8: 0, s9: 0, s10: 0, s11: 0, sp: 0, sscratch: 0 };
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
 
         f.a0 = 'T' as usize;
-        f.a7 = SYS_PUTBYTE;
+        f.a7 = Syscall::PutByte as usize;
+
+        handle_syscall(f);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_dispatches_from_a7() {
+        print!("entry: handle syscall dispatches from a7...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+
+        // a4 holds an invalid syscall number; only a7 must be used for dispatch
+        f.a0 = 'T' as usize;
+        f.a4 = usize::MAX;
+        f.a7 = Syscall::PutByte as usize;
+
+        handle_syscall(f);
+
+        assert!({ f.a0 } == 0);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_put_byte_retries_busy() {
+        use crate::sbi::{use_mock_sbi, MOCK_SBI};
+        print!("entry: handle syscall put byte retries busy...");
+
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        use_mock_sbi(true);
+
+        // Put the other processes to sleep so that each retry's yield comes straight back
+        let current = CURRENT_PROC.lock()
+            .expect("current process should be running");
+        let asleep: Vec<usize> = PROCS.0.lock().iter_mut()
+            .filter(|p| p.pid != current && !p.is_idle && p.state == State::Runnable)
+            .map(|p| {
+                p.state = State::Sleeping;
+                p.pid
+            })
+            .collect();
+
+        // A burst with every third byte refused comes through whole
+        let mut f = TrapFrame::ZERO;
+        let bytes_put = MOCK_SBI.bytes_put.load(Ordering::Relaxed);
+        MOCK_SBI.busy_every.store(3, Ordering::Relaxed);
+        let all_ok = (0..200).all(|i| {
+            f.a0 = b'a' as usize + i % 26;
+            f.a7 = Syscall::PutByte as usize;
+            handle_syscall(&mut f);
+            { f.a0 } == 0
+        });
+        let burst = MOCK_SBI.bytes_put.load(Ordering::Relaxed) - bytes_put;
+
+        // A console that stays busy is an error once the retries run out
+        let put_calls = MOCK_SBI.put_calls.load(Ordering::Relaxed);
+        MOCK_SBI.busy_every.store(1, Ordering::Relaxed);
+        f.a0 = b'!' as usize;
+        f.a7 = Syscall::PutByte as usize;
+        handle_syscall(&mut f);
+        let stuck = f.a0;
+        let tries = MOCK_SBI.put_calls.load(Ordering::Relaxed) - put_calls;
+        MOCK_SBI.busy_every.store(0, Ordering::Relaxed);
+
+        PROCS.0.lock().iter_mut()
+            .filter(|p| asleep.contains(&p.pid))
+            .for_each(|p| p.state = State::Runnable);
+        use_mock_sbi(false);
+        write_csr!("sstatus", sstatus);
+
+        assert!(all_ok);
+        assert!(burst == 200);
+        assert!(stuck == ErrorCode::IoError.as_usize());
+        assert!(tries == CONSOLE_RETRIES + 1);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_traces() {
+        print!("entry: handle syscall traces...");
+
+        // Arguments are shown as hex and results as signed, so that errors read as negative
+        let trace = SyscallTrace { pid: 2, syscall: Syscall::GetPid, args: [0, 0x80000000, 0, 0], result: 2 };
+        assert!(alloc::format!("{}", trace) == "pid 2: GetPid(0x0, 0x80000000, 0x0, 0x0) = 2");
+        let trace = SyscallTrace { pid: 3, syscall: Syscall::Open, args: [0x1000, 9, 1, 0], result: ErrorCode::NotFound.as_usize() };
+        assert!(alloc::format!("{}", trace) == "pid 3: Open(0x1000, 0x9, 0x1, 0x0) = -1");
+
+        // Turning tracing on logs this getpid with no change to its result
+        let mut f = TrapFrame::ZERO;
+        let current = CURRENT_PROC.lock()
+            .expect("current process should be running");
+        f.a0 = 1;
+        f.a7 = Syscall::SetTrace as usize;
+        handle_syscall(&mut f);
+        let traced = TRACE.load(Ordering::Relaxed);
+        f.a7 = Syscall::GetPid as usize;
+        handle_syscall(&mut f);
+        let pid = f.a0;
+        f.a0 = 0;
+        f.a7 = Syscall::SetTrace as usize;
+        handle_syscall(&mut f);
+
+        assert!(traced);
+        assert!(pid == current);
+        assert!(!TRACE.load(Ordering::Relaxed));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_unknown_sysno() {
+        print!("entry: handle syscall unknown sysno...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+
+        for sysno in [0, 1000, usize::MAX] {
+            f.a0 = 0;
+            f.a7 = sysno;
+
+            handle_syscall(f);
+
+            assert!({ f.a0 } == ErrorCode::NoSys.as_usize());
+        }
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_getpid_from_a7() {
+        print!("entry: handle syscall getpid from a7...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+
+        let current = CURRENT_PROC.lock()
+            .expect("current process should be running");
+
+        f.a0 = usize::MAX;
+        f.a4 = Syscall::PutByte as usize;
+        f.a7 = Syscall::GetPid as usize;
+
+        handle_syscall(f);
+
+        assert!({ f.a0 } == current);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_v2p() {
+        print!("entry: handle syscall v2p...");
+
+        use crate::page::PAGE_U;
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let mut info = PageInfo::default();
+
+        // The kernel is mapped at its physical addresses in every page table
+        f.a0 = &raw const info as usize;
+        f.a1 = &raw mut info as usize;
+        f.a7 = Syscall::V2P as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+        assert!(info.paddr == &raw const info as usize);
+        assert!(info.flags & (PAGE_R | PAGE_W | PAGE_U) == PAGE_R | PAGE_W);
+
+        // Nothing is mapped at the null page
+        f.a0 = 0;
+        f.a1 = &raw mut info as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == ErrorCode::NotFound.as_usize());
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_stat() {
+        print!("entry: handle syscall stat...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let filename = "meow.txt";
+        let mut stat = FileStat::default();
+
+        f.a0 = filename.as_ptr() as usize;
+        f.a1 = filename.len();
+        f.a2 = &raw mut stat as usize;
+        f.a7 = Syscall::Stat as usize;
+
+        handle_syscall(f);
+
+        assert!({ f.a0 } == 0);
+        assert!(stat.name_len == filename.len());
+
+        let missing = "missing.txt";
+        f.a0 = missing.as_ptr() as usize;
+        f.a1 = missing.len();
+        f.a7 = Syscall::Stat as usize;
+
+        handle_syscall(f);
+
+        assert!({ f.a0 } == ErrorCode::NotFound.as_usize());
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_non_utf8_filename() {
+        print!("entry: handle syscall non-UTF-8 filename...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let filename = b"\xffmeow\xfe.txt";
+        let mut buf = [0u8; 16];
+        let mut stat = FileStat::default();
+
+        for syscall in [Syscall::ReadFile, Syscall::WriteFile] {
+            f.a0 = filename.as_ptr() as usize;
+            f.a1 = filename.len();
+            f.a2 = buf.as_mut_ptr() as usize;
+            f.a3 = buf.len();
+            f.a7 = syscall as usize;
+            handle_syscall(f);
+            assert!({ f.a0 } == ErrorCode::NotFound.as_usize());
+        }
+
+        f.a0 = filename.as_ptr() as usize;
+        f.a1 = filename.len();
+        f.a2 = &raw mut stat as usize;
+        f.a7 = Syscall::Stat as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == ErrorCode::NotFound.as_usize());
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_filename_too_long() {
+        print!("entry: handle syscall filename too long...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let filename = "meow.txt";
+        let mut buf = [0u8; 16];
+        let mut stat = FileStat::default();
+
+        for filename_len in [FILENAME_MAX + 1, usize::MAX] {
+            for syscall in [Syscall::ReadFile, Syscall::Stat, Syscall::Open] {
+                f.a0 = filename.as_ptr() as usize;
+                f.a1 = filename_len;
+                f.a2 = if syscall == Syscall::Stat { &raw mut stat as usize } else { buf.as_mut_ptr() as usize };
+                f.a3 = buf.len();
+                f.a7 = syscall as usize;
+                handle_syscall(f);
+                assert!({ f.a0 } == ErrorCode::BadArg.as_usize());
+            }
+        }
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_open_read_close() {
+        print!("entry: handle syscall open read close...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let filename = "hello.txt";
+
+        f.a0 = filename.as_ptr() as usize;
+        f.a1 = filename.len();
+        f.a2 = OPEN_READ as usize;
+        f.a7 = Syscall::Open as usize;
+        handle_syscall(f);
+        let fd = f.a0;
+        assert!((fd as isize) >= 0);
+
+        // Read in small chunks; each read carries on from the last
+        let file_i = FILES.fs_lookup(filename)
+            .expect("hello.txt should exist");
+        let (size, data) = {
+            let files = FILES.0.lock();
+            (files[file_i].size, files[file_i].data)
+        };
+        let mut contents = [0u8; 1024];
+        let mut total = 0;
+        loop {
+            let mut chunk = [0u8; 5];
+            f.a0 = fd;
+            f.a1 = chunk.as_mut_ptr() as usize;
+            f.a2 = chunk.len();
+            f.a7 = Syscall::Read as usize;
+            handle_syscall(f);
+            let len = f.a0;
+            assert!(len <= chunk.len());
+            if len == 0 {
+                break;
+            }
+            contents[total..total + len].copy_from_slice(&chunk[..len]);
+            total += len;
+        }
+        assert!(total == size);
+        assert!(contents[..total] == data[..size]);
+
+        // Read-only descriptors refuse writes
+        f.a0 = fd;
+        f.a1 = filename.as_ptr() as usize;
+        f.a2 = filename.len();
+        f.a7 = Syscall::Write as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == ErrorCode::PermissionDenied.as_usize());
+
+        f.a0 = fd;
+        f.a7 = Syscall::Close as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+
+        // Closed descriptors can't be used or closed again
+        for syscall in [Syscall::Read, Syscall::Close] {
+            f.a0 = fd;
+            f.a1 = contents.as_mut_ptr() as usize;
+            f.a2 = contents.len();
+            f.a7 = syscall as usize;
+            handle_syscall(f);
+            assert!({ f.a0 } == ErrorCode::BadArg.as_usize());
+        }
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_rejects_kernel_pointers() {
+        print!("entry: handle syscall rejects kernel pointers...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let shell = find_program("shell")
+            .expect("shell should be linked into the kernel");
+        let pid = create_process(user_entry as *const () as usize, shell.as_ptr(), shell.len(), &[], FaultPolicy::Kill)
+            .expect("should create process");
+
+        // Make system calls as the user process, whose page table has no user mappings for kernel memory.
+        // Keep the timer from switching processes while it is current.
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        let previous = CURRENT_PROC.lock().replace(pid);
+
+        let filename = "meow.txt";
+        let file_i = FILES.fs_lookup(filename)
+            .expect("meow.txt should exist");
+        let before = FILES.0.lock()[file_i].data;
+        let mut buf = [0x55u8; 16];
+        for syscall in [Syscall::ReadFile, Syscall::WriteFile] {
+            f.a0 = filename.as_ptr() as usize;
+            f.a1 = filename.len();
+            f.a2 = buf.as_mut_ptr() as usize;
+            f.a3 = buf.len();
+            f.a7 = syscall as usize;
+            handle_syscall(f);
+            assert!({ f.a0 } == ErrorCode::BadArg.as_usize());
+        }
+        assert!(buf.iter().all(|&b| b == 0x55));
+        assert!(FILES.0.lock()[file_i].data == before);
+
+        let mut uptime = 0u64;
+        f.a0 = &raw mut uptime as usize;
+        f.a7 = Syscall::Uptime as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == ErrorCode::BadArg.as_usize());
+        assert!(uptime == 0);
+
+        *CURRENT_PROC.lock() = previous;
+        write_csr!("sstatus", sstatus);
+
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created user process");
+        PROCS.0.lock()[index].release();
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_inject_file_write_fault() {
+        print!("entry: handle syscall inject file write fault...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+
+        f.a0 = FAULT_FILE_WRITE;
+        f.a1 = 1;
+        f.a7 = Syscall::InjectFault as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+
+        let filename = "meow.txt";
+        let buf = b"not written";
+        f.a0 = filename.as_ptr() as usize;
+        f.a1 = filename.len();
+        f.a2 = buf.as_ptr() as usize;
+        f.a3 = buf.len();
+        f.a7 = Syscall::WriteFile as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == ErrorCode::IoError.as_usize());
+        assert!(!should_fail(FAULT_FILE_WRITE));
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_spawn_fails_without_memory() {
+        print!("entry: handle syscall spawn fails without memory...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let mut args = [0u8; 16];
+        let len = common::args::encode(["shell"], &mut args)
+            .expect("arguments should fit");
+        let spawn = |f: &mut TrapFrame| {
+            f.a0 = args.as_ptr() as usize;
+            f.a1 = len;
+            f.a2 = 0;
+            f.a7 = Syscall::Spawn as usize;
+            handle_syscall(f);
+        };
+
+        // Arming an allocation failure makes the next spawn fail gracefully
+        f.a0 = FAULT_ALLOC;
+        f.a1 = 1;
+        f.a7 = Syscall::InjectFault as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+        let slots = PROCS.0.lock().iter().filter(|p| p.state != State::Unused).count();
+        spawn(f);
+        assert!({ f.a0 } == ErrorCode::NoSpace.as_usize());
+        assert!(PROCS.0.lock().iter().filter(|p| p.state != State::Unused).count() == slots);
+
+        // The fault is used up, so the next spawn succeeds
+        spawn(f);
+        let pid = f.a0;
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created user process");
+        PROCS.0.lock()[index].state = State::Unused;
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_sync_batches_writes() {
+        print!("entry: handle syscall sync batches writes...");
+
+        use crate::tar::{use_mock_disk, File, FILES_MAX, FLUSH_SECTORS, MOCK_DISK_WRITES};
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+
+        // Write to a scratch file in place of the last file on the disk, which is put back afterwards
+        use_mock_disk(true);
+        let _ = fs_sync();     // Start clean
+        let filename = "scratch.txt";
+        let saved = core::mem::replace(&mut FILES.0.lock()[FILES_MAX - 1], File::scratch(filename));
+        let writes = MOCK_DISK_WRITES.load(Ordering::Relaxed);
+
+        // Writes only change the files in memory, where reads see them
+        for i in 0..100u8 {
+            let buf = [b'a' + i % 26];
+            f.a0 = filename.as_ptr() as usize;
+            f.a1 = filename.len();
+            f.a2 = buf.as_ptr() as usize;
+            f.a3 = buf.len();
+            f.a7 = Syscall::WriteFile as usize;
+            handle_syscall(f);
+            assert!({ f.a0 } == 1);
+        }
+        let mut buf = [0u8; 1];
+        f.a0 = filename.as_ptr() as usize;
+        f.a1 = filename.len();
+        f.a2 = buf.as_mut_ptr() as usize;
+        f.a3 = buf.len();
+        f.a7 = Syscall::ReadFile as usize;
+        handle_syscall(f);
+        let unflushed = MOCK_DISK_WRITES.load(Ordering::Relaxed) - writes;
+
+        // One sync writes them all in a single flush, and a second has nothing to write
+        f.a7 = Syscall::Sync as usize;
+        handle_syscall(f);
+        let synced = MOCK_DISK_WRITES.load(Ordering::Relaxed) - writes;
+        handle_syscall(f);
+        let resynced = MOCK_DISK_WRITES.load(Ordering::Relaxed) - writes;
+        FILES.0.lock()[FILES_MAX - 1] = saved;
+        use_mock_disk(false);
+
+        assert!(buf == [b'a' + 99 % 26]);
+        assert!(unflushed == 0);
+        assert!(synced == FLUSH_SECTORS);
+        assert!(resynced == FLUSH_SECTORS);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_read_inherited_descriptor() {
+        use crate::scheduler::procs;
+        print!("entry: handle syscall read inherited descriptor...");
+
+        let file_i = FILES.fs_lookup("hello.txt")
+            .expect("hello.txt should exist");
+        let data = FILES.0.lock()[file_i].data;
+
+        // A parent has read part way into a file, then forks this process as its child
+        let mut parent = procs(&[(1, State::Sleeping)]).remove(0);
+        parent.files[3] = OpenFile { in_use: true, file_i, offset: 2, flags: OPEN_READ };
+        let saved = PROCS.with_current(|child| core::mem::replace(&mut child.files, parent.clone_fds()));
+
+        // The child reads on from where the parent was, through the same descriptor
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let mut buf = [0u8; 3];
+        f.a0 = 3;
+        f.a1 = buf.as_mut_ptr() as usize;
+        f.a2 = buf.len();
+        f.a7 = Syscall::Read as usize;
+        handle_syscall(f);
+        let read = f.a0;
+        let child_offset = PROCS.with_current(|child| child.files[3].offset);
+        PROCS.with_current(|child| child.files = saved);
+
+        assert!(read == buf.len() && buf == data[2..5]);
+        // Offsets are copied, not shared
+        assert!(child_offset == 5 && parent.files[3].offset == 2);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_poll_char() {
+        print!("entry: handle syscall poll char...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+
+        f.a7 = Syscall::PollChar as usize;
+
+        // Returns immediately with a byte, or WouldBlock when the console is idle
+        handle_syscall(f);
+        assert!({ f.a0 } <= u8::MAX as usize || { f.a0 } == ErrorCode::WouldBlock.as_usize());
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_kill() {
+        print!("entry: handle syscall kill...");
+
+        fn looping() {
+            loop {
+                core::hint::spin_loop();
+            }
+        }
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let pid = create_process(looping as *const () as usize, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+
+        f.a0 = pid;
+        f.a7 = Syscall::Kill as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+
+        // Its parent is the idle process, which never waits, so it is freed at once
+        let index = PROCS.try_get_index(pid)
+            .expect("killed process should keep its slot");
+        let procs = PROCS.0.lock();
+        assert!(procs[index].state == State::Unused);
+        assert!(procs[index].exit_code == EXIT_KILLED);
+        assert!(procs[index].page_table.is_none());
+        drop(procs);
+
+        // A sleeping process can be killed too, and is not woken again
+        let sleeper = create_process(looping as *const () as usize, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let sleeper_index = PROCS.try_get_index(sleeper)
+            .expect("should have created process");
+        PROCS.0.lock()[sleeper_index].state = State::Sleeping;
+        f.a0 = sleeper;
+        f.a7 = Syscall::Kill as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+        wake_sleeping();
+        assert!(PROCS.0.lock()[sleeper_index].state == State::Unused);
+
+        // Killing it again, killing the idle process, and killing a missing pid all fail
+        for (pid, error) in [
+            (pid, ErrorCode::NotFound),
+            (IDLE_PID, ErrorCode::PermissionDenied),
+            (usize::MAX, ErrorCode::NotFound),
+        ] {
+            f.a0 = pid;
+            f.a7 = Syscall::Kill as usize;
+            handle_syscall(f);
+            assert!({ f.a0 } == error.as_usize());
+        }
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_wait() {
+        print!("entry: handle syscall wait...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let parent = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let pid = create_process(0, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let parent_index = PROCS.try_get_index(parent)
+            .expect("should have created process");
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created process");
+
+        // An exited process whose parent is live is kept until it is waited for
+        {
+            let mut procs = PROCS.0.lock();
+            procs[parent_index].state = State::Sleeping;
+            procs[index].parent = parent;
+            procs[index].state = State::Exited;
+            procs[index].exit_code = 42;
+            reap_orphans(&mut procs);
+            assert!(procs[index].state == State::Exited);
+        }
+
+        // Then it is collected at once, with its exit code, and freed
+        let mut exit_code = 0usize;
+        f.a0 = pid;
+        f.a1 = &raw mut exit_code as usize;
+        f.a7 = Syscall::Wait as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+        assert!(exit_code == 42);
+        {
+            let mut procs = PROCS.0.lock();
+            assert!(procs[index].state == State::Unused);
+            procs[parent_index].state = State::Exited;
+            reap_orphans(&mut procs);
+        }
+
+        // A missing process, and the caller itself, cannot be waited for
+        let current = CURRENT_PROC.lock()
+            .expect("current process should be running");
+        for (pid, error) in [(pid, ErrorCode::NotFound), (current, ErrorCode::BadArg)] {
+            f.a0 = pid;
+            f.a1 = &raw mut exit_code as usize;
+            f.a7 = Syscall::Wait as usize;
+            handle_syscall(f);
+            assert!({ f.a0 } == error.as_usize());
+        }
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_signal() {
+        print!("entry: handle syscall signal...");
+
+        fn looping() {
+            loop {
+                core::hint::spin_loop();
+            }
+        }
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let pid = create_process(looping as *const () as usize, core::ptr::null(), 0, &[], FaultPolicy::Kill)
+            .expect("should create process");
+        let index = PROCS.try_get_index(pid)
+            .expect("should have created process");
+
+        // A registered handler leaves the signal pending
+        PROCS.0.lock()[index].signals.handlers[SIGNAL_TERM] = looping as *const () as usize;
+        f.a0 = pid;
+        f.a1 = SIGNAL_TERM;
+        f.a7 = Syscall::Signal as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+        assert!(PROCS.0.lock()[index].signals.pending == 1 << SIGNAL_TERM);
+
+        // A sleeping process is woken to take the signal
+        PROCS.0.lock()[index].signals.pending = 0;
+        PROCS.0.lock()[index].state = State::Sleeping;
+        f.a0 = pid;
+        f.a1 = SIGNAL_TERM;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+        let (state, pending) = {
+            let procs = PROCS.0.lock();
+            (procs[index].state, procs[index].signals.pending)
+        };
+        assert!(state == State::Runnable && pending == 1 << SIGNAL_TERM);
+
+        // Without one the process is terminated
+        PROCS.0.lock()[index].signals.handlers[SIGNAL_TERM] = 0;
+        f.a0 = pid;
+        f.a1 = SIGNAL_TERM;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+        let (state, exit_code) = {
+            let procs = PROCS.0.lock();
+            (procs[index].state, procs[index].exit_code)
+        };
+        assert!(state == State::Unused && exit_code == EXIT_SIGNALLED + SIGNAL_TERM);
+
+        for (pid, signo, error) in [
+            (pid, SIGNAL_TERM, ErrorCode::NotFound),
+            (IDLE_PID, SIGNAL_TERM, ErrorCode::NotFound),
+            (pid, 0, ErrorCode::BadArg),
+            (pid, SIGNAL_KINDS, ErrorCode::BadArg),
+        ] {
+            f.a0 = pid;
+            f.a1 = signo;
+            handle_syscall(f);
+            assert!({ f.a0 } == error.as_usize());
+        }
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_sigaction_clears_pending() {
+        print!("entry: handle syscall sigaction clears pending...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let saved = PROCS.with_current(|process| process.signals.clone());
+
+        // Removing the handler of a pending signal drops the signal rather than delivering it to address 0
+        f.a0 = SIGNAL_TERM;
+        f.a1 = 0x1234;
+        f.a2 = 0x5678;
+        f.a7 = Syscall::SigAction as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == 0);
+        PROCS.with_current(|process| process.signals.pending |= 1 << SIGNAL_TERM);
+        f.a0 = SIGNAL_TERM;
+        f.a1 = 0;
+        handle_syscall(f);
+        let pending = PROCS.with_current(|process| {
+            let pending = process.signals.pending;
+            process.signals = saved;
+            pending
+        });
+
+        assert!({ f.a0 } == 0);
+        assert!(pending & 1 << SIGNAL_TERM == 0);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_select() {
+        print!("entry: handle syscall select...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let empty = PIPES.create().expect("should create a pipe");
+        let full = PIPES.create().expect("should create a pipe");
+        PIPES.with(full, |pipe| pipe.write(b"ready"));
+
+        let mut select_with = |fds: &[usize], millisecs: u64| {
+            f.a0 = fds.as_ptr() as usize;
+            f.a1 = fds.len();
+            f.a2 = millisecs as usize;
+            f.a3 = (millisecs >> 32) as usize;
+            f.a7 = Syscall::Select as usize;
+            handle_syscall(f);
+            f.a0
+        };
+        // A ready pipe returns at once, even with no timeout; an empty one waits out the timeout
+        assert!(select_with(&[empty, full], SELECT_FOREVER) == 1);
+        assert!(select_with(&[empty], 0) == ErrorCode::WouldBlock.as_usize());
+        assert!(select_with(&[], 1) == ErrorCode::WouldBlock.as_usize());
+        // Waiting on nothing for ever, an unknown pipe or too many descriptors fail
+        assert!(select_with(&[], SELECT_FOREVER) == ErrorCode::BadArg.as_usize());
+        assert!(select_with(&[empty, PIPES_MAX], 0) == ErrorCode::NotFound.as_usize());
+        assert!(select_with(&[full; SELECT_MAX + 1], 0) == ErrorCode::BadArg.as_usize());
+
+        PIPES.release(empty);
+        PIPES.release(full);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_pipe_close() {
+        print!("entry: handle syscall pipe close...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        f.a7 = Syscall::PipeCreate as usize;
+        handle_syscall(f);
+        let id = f.a0;
+        assert!(id < PIPES_MAX);
+
+        // Closing frees the pipe, so it can be closed only once and is the next one created
+        for result in [0, ErrorCode::NotFound.as_usize()] {
+            f.a0 = id;
+            f.a7 = Syscall::PipeClose as usize;
+            handle_syscall(f);
+            assert!({ f.a0 } == result);
+        }
+        f.a7 = Syscall::PipeCreate as usize;
+        handle_syscall(f);
+        assert!({ f.a0 } == id);
+        PIPES.release(id);
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn handle_syscall_proc_stat() {
+        print!("entry: handle syscall proc stat...");
+
+        let mut frame = TrapFrame::ZERO;
+        let f = &mut frame;
+        let mut stat = ProcStat::default();
+
+        f.a0 = IDLE_PID;
+        f.a1 = &raw mut stat as usize;
+        f.a7 = Syscall::ProcStat as usize;
+        handle_syscall(f);
+
+        assert!({ f.a0 } == 0);
+        assert!(stat.pid == IDLE_PID);
+        assert!(stat.state == PROC_STATE_RUNNABLE);
 
+        f.a0 = usize::MAX;
+        f.a7 = Syscall::ProcStat as usize;
         handle_syscall(f);
+        assert!({ f.a0 } == ErrorCode::NotFound.as_usize());
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 }
@@ -0,0 +1,99 @@
+//! Access user memory from system calls
+//!
+//! User processes pass pointers in system call arguments. Before the kernel touches that memory it checks
+//! every page is mapped into the current process as a user page, so a bad pointer cannot make the kernel
+//! read or write its own memory.
+
+use core::slice;
+
+use common::ErrorCode;
+
+use crate::address::VAddr;
+use crate::page::{lookup_pte, PAGE_R, PAGE_SIZE, PAGE_U, PAGE_W};
+use crate::process::fault_in;
+use crate::scheduler::PROCS;
+
+/// Longest file name, in bytes, that a system call accepts
+pub const FILENAME_MAX: usize = 256;
+
+// Check `len` bytes from `addr` are mapped as user pages with `flags` in the current process.
+// Kernel processes make system calls with kernel memory, so their pointers are trusted.
+fn check_user_range(addr: usize, len: usize, flags: usize) -> Result<(), ErrorCode> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(ErrorCode::BadArg)?;
+
+    PROCS.with_current(|process| {
+        if process.is_kernel {
+            return Ok(());
+        }
+
+        let first_page = addr / PAGE_SIZE * PAGE_SIZE;
+        for page in (first_page..end).step_by(PAGE_SIZE) {
+            // Image pages the process has not touched yet are loaded as if it had faulted on them
+            fault_in(process, page);
+            let page_table = process.page_table.as_ref().ok_or(ErrorCode::BadArg)?;
+            let pte = lookup_pte(page_table, VAddr::new(page)).ok_or(ErrorCode::BadArg)?;
+            if pte & (PAGE_U | flags) != PAGE_U | flags {
+                return Err(ErrorCode::BadArg);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Borrow `len` bytes of user memory from `addr` for reading
+pub fn user_slice<'a>(addr: usize, len: usize) -> Result<&'a [u8], ErrorCode> {
+    check_user_range(addr, len, PAGE_R)?;
+    if len == 0 {
+        return Ok(&[]);
+    }
+    // Safety: The range is mapped readable in the current process, and sstatus.SUM lets the kernel access it
+    Ok(unsafe { slice::from_raw_parts(addr as *const u8, len) })
+}
+
+/// Borrow a file name of `len` bytes from user memory at `addr`
+///
+/// Names longer than `FILENAME_MAX` are rejected before any user memory is checked.
+pub fn user_filename<'a>(addr: usize, len: usize) -> Result<&'a [u8], ErrorCode> {
+    if len > FILENAME_MAX {
+        return Err(ErrorCode::BadArg);
+    }
+    user_slice(addr, len)
+}
+
+/// Borrow `len` bytes of user memory from `addr` for writing
+pub fn user_slice_mut<'a>(addr: usize, len: usize) -> Result<&'a mut [u8], ErrorCode> {
+    check_user_range(addr, len, PAGE_R | PAGE_W)?;
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    // Safety: The range is mapped writable in the current process, and sstatus.SUM lets the kernel access it
+    Ok(unsafe { slice::from_raw_parts_mut(addr as *mut u8, len) })
+}
+
+/// Copy `dst.len()` bytes from user memory at `src`
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> Result<(), ErrorCode> {
+    dst.copy_from_slice(user_slice(src, dst.len())?);
+    Ok(())
+}
+
+/// Copy `src` to user memory at `dst`
+pub fn copy_to_user(dst: usize, src: &[u8]) -> Result<(), ErrorCode> {
+    user_slice_mut(dst, src.len())?.copy_from_slice(src);
+    Ok(())
+}
+
+/// Write a value to user memory at `dst`, which must be aligned for `T`
+pub fn put_user<T: Copy>(dst: usize, value: T) -> Result<(), ErrorCode> {
+    if !dst.is_multiple_of(align_of::<T>()) {
+        return Err(ErrorCode::BadArg);
+    }
+    check_user_range(dst, size_of::<T>(), PAGE_R | PAGE_W)?;
+    // Safety: The range is mapped writable in the current process and aligned for T
+    unsafe {
+        (dst as *mut T).write(value);
+    }
+    Ok(())
+}
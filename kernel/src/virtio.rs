@@ -6,6 +6,9 @@ use core::ptr;
 
 use alloc::boxed::Box;
 
+use common::{ErrorCode, FAULT_VIRTIO};
+
+use crate::fault::should_fail;
 use crate::println;
 use crate::spinlock::SpinLock;
 
@@ -251,13 +254,20 @@ fn virtq_is_busy(vq: &VirtioVirtq) -> bool {
     }
 }
 
-// Reads/writes from/to virtio-blk device.
-pub fn read_write_disk(buf: &mut [u8], sector: u64, is_write: bool) {
+/// Read or write one sector of the virtio-blk device
+///
+/// Returns `Err(IoError)` if the sector is past the end of the disk or the device reports an error.
+pub fn read_write_disk(buf: &mut [u8], sector: u64, is_write: bool) -> Result<(), ErrorCode> {
     let blk_capacity = BLK_CAPACITY.lock()
         .expect("block capacity should be initialised before read_write_disk call.");
     if sector >= (blk_capacity / SECTOR_SIZE as u64) {
-        println!("virtio: tried to read/write sector={}, but capacity is {}", sector, blk_capacity / SECTOR_SIZE as u64);
-        return;
+        klog!(ERROR, "virtio: tried to read/write sector={}, but capacity is {}", sector, blk_capacity / SECTOR_SIZE as u64);
+        return Err(ErrorCode::IoError);
+    }
+
+    if should_fail(FAULT_VIRTIO) {
+        klog!(WARN, "virtio: injected fault on read/write sector={}", sector);
+        return Err(ErrorCode::IoError);
     }
 
     let mut br_guard = BLK_REQ.lock();
@@ -312,20 +322,21 @@ pub fn read_write_disk(buf: &mut [u8], sector: u64, is_write: bool) {
 
     // virtio-blk: If a non-zero value is returned, it's an error.
     if br.status != 0 {
-        println!("virtio: warn: failed to read/write sector={} status={}", sector, br.status);
-        return;
+        klog!(WARN, "virtio: failed to read/write sector={} status={}", sector, br.status);
+        return Err(ErrorCode::IoError);
     }
 
     // For read operations, copy the data into the buffer.
     if !is_write {
         buf.copy_from_slice(&br.data);
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{print, println};
+    use crate::print;
 
     #[test_case]
     fn fetch_and_or_reg() {
@@ -339,7 +350,7 @@ mod test {
         print!(" and updated device status is {device_status}");
         assert!(device_status == 5);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -349,7 +360,7 @@ mod test {
         virtio_reg_write32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER_OK);
         let virtio_status = virtio_reg_read32(VIRTIO_REG_DEVICE_STATUS);
         assert!(virtio_status == VIRTIO_STATUS_DRIVER_OK);
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -358,9 +369,9 @@ mod test {
 
         let block_capacity = virtio_reg_read64(VIRTIO_REG_DEVICE_CONFIG + 0);
         print!("block capacity is {block_capacity}");
-        assert!(block_capacity == 20);
+        assert!(block_capacity == 32);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
     }
 
     #[test_case]
@@ -370,15 +381,30 @@ mod test {
         let s = "hello from kernel!!!";
         let mut buf: [u8; SECTOR_SIZE] = [0u8; SECTOR_SIZE];
         buf[..s.len()].copy_from_slice(s.as_bytes());
-        read_write_disk(&mut buf, 1, true /* write to the disk */);
+        read_write_disk(&mut buf, 1, true /* write to the disk */)
+            .expect("should write the sector");
         // Now read back
-        read_write_disk(&mut buf, 1, false);
+        read_write_disk(&mut buf, 1, false)
+            .expect("should read the sector");
         let read_str = str::from_utf8(&buf)
         .expect("should be valid UTF8")
         .trim_end_matches('\0')
         .trim();
         assert!(s == read_str);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        common::ok!();
+    }
+
+    #[test_case]
+    fn injected_fault_is_reported() {
+        print!("virtio: injected fault is reported... ");
+
+        let mut buf = [0u8; SECTOR_SIZE];
+        crate::fault::inject_fault(FAULT_VIRTIO, 1)
+            .expect("should arm a known fault kind");
+        assert!(read_write_disk(&mut buf, 1, false) == Err(ErrorCode::IoError));
+        assert!(read_write_disk(&mut buf, 1, false).is_ok());
+
+        common::ok!();
     }
 }
@@ -0,0 +1,135 @@
+//! Watchdog for a kernel stuck in a loop
+//!
+//! Context switches, and timer interrupts that find the hart in user mode or idle, are signs of life. A timer
+//! interrupt that finds the kernel busy with no sign of life on its hart for the watchdog interval dumps the
+//! processes and panics. Each hart keeps its own signs of life, so one busy hart cannot hide another stuck one.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(test)]
+use core::sync::atomic::AtomicBool;
+
+use crate::hart::{PerHart, HARTS_MAX};
+use crate::println;
+use crate::scheduler::{CURRENT_PROC, PROCS};
+use crate::timer::uptime_ms;
+
+const DEFAULT_INTERVAL_MS: usize = 10_000;
+
+// Uptime in milliseconds at each hart's last sign of life; wraps after about 49 days, which the comparison
+// allows for
+static LAST_ALIVE_MS: PerHart<AtomicUsize> = PerHart::new([const { AtomicUsize::new(0) }; HARTS_MAX]);
+
+// How long the kernel may go without a sign of life, in milliseconds; zero turns the watchdog off
+static INTERVAL_MS: AtomicUsize = AtomicUsize::new(DEFAULT_INTERVAL_MS);
+
+// While set, an expired watchdog is counted in `EXPIRIES` rather than panicking, so a test can see it fire
+#[cfg(test)]
+static RECORD_EXPIRIES: AtomicBool = AtomicBool::new(false);
+#[cfg(test)]
+static EXPIRIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Record a sign of life on this hart
+pub fn pet() {
+    LAST_ALIVE_MS.store(uptime_ms() as usize, Ordering::Relaxed);
+}
+
+/// Change how long the kernel may go without a sign of life; zero turns the watchdog off
+#[cfg_attr(not(test), expect(dead_code))]
+pub fn set_interval_ms(millisecs: usize) {
+    INTERVAL_MS.store(millisecs, Ordering::Relaxed);
+}
+
+// Whether the last sign of life is more than the watchdog interval before `now_ms`
+fn is_overdue(now_ms: usize) -> bool {
+    let interval = INTERVAL_MS.load(Ordering::Relaxed);
+    interval != 0 && now_ms.wrapping_sub(LAST_ALIVE_MS.load(Ordering::Relaxed)) > interval
+}
+
+/// Panic with a dump of the processes if the kernel has gone too long without a sign of life
+///
+/// Called from the timer interrupt when it interrupted the kernel, before the interrupt takes any lock.
+pub fn check() {
+    let now = uptime_ms() as usize;
+    if !is_overdue(now) {
+        return;
+    }
+
+    // The stuck code may hold either lock, and locking it again would panic with less to go on
+    let current = CURRENT_PROC.try_lock().and_then(|current| *current);
+    let silent_ms = now.wrapping_sub(LAST_ALIVE_MS.load(Ordering::Relaxed));
+    println!("watchdog: no sign of life for {} ms, current process {:?}", silent_ms, current);
+    if PROCS.0.try_lock().is_some() {
+        println!("{}", PROCS);
+    } else {
+        println!("watchdog: process table is locked");
+    }
+    #[cfg(test)]
+    if RECORD_EXPIRIES.load(Ordering::Relaxed) {
+        EXPIRIES.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    panic!("watchdog expired");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::print;
+    use crate::scheduler::SSTATUS_SIE;
+
+    #[test_case]
+    fn watchdog_expires_without_pet() {
+        print!("watchdog: expires without pet...");
+
+        // A process stuck in the kernel stops the pets; the watchdog fires once the interval has passed
+        set_interval_ms(100);
+        pet();
+        let alive = LAST_ALIVE_MS.load(Ordering::Relaxed);
+        assert!(!is_overdue(alive + 100));
+        assert!(is_overdue(alive + 101));
+
+        // The last pet counts, even across the uptime wrapping
+        LAST_ALIVE_MS.store(usize::MAX - 10, Ordering::Relaxed);
+        assert!(!is_overdue(50));
+        assert!(is_overdue(200));
+
+        set_interval_ms(0);
+        assert!(!is_overdue(alive + 1_000_000));
+
+        set_interval_ms(DEFAULT_INTERVAL_MS);
+        pet();
+
+        common::ok!();
+    }
+
+    #[test_case]
+    fn watchdog_fires_when_stuck() {
+        print!("watchdog: fires when stuck...");
+
+        // Spin in the kernel with interrupts off, as a stuck system call would, checking as the timer
+        // interrupt would
+        let sstatus = read_csr!("sstatus");
+        write_csr!("sstatus", sstatus & !SSTATUS_SIE);
+        RECORD_EXPIRIES.store(true, Ordering::Relaxed);
+        let expiries = EXPIRIES.load(Ordering::Relaxed);
+        set_interval_ms(50);
+        pet();
+        check();
+        let early = EXPIRIES.load(Ordering::Relaxed) - expiries;
+
+        let stuck_until = uptime_ms() + 60;
+        while uptime_ms() <= stuck_until {}
+        check();
+        let stuck = EXPIRIES.load(Ordering::Relaxed) - expiries;
+
+        RECORD_EXPIRIES.store(false, Ordering::Relaxed);
+        set_interval_ms(DEFAULT_INTERVAL_MS);
+        pet();
+        write_csr!("sstatus", sstatus);
+        assert!(early == 0, "the watchdog should wait out its interval");
+        assert!(stuck == 1, "the watchdog should fire once the interval has passed");
+
+        common::ok!();
+    }
+}
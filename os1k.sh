@@ -27,9 +27,9 @@ fi
 if [ "$COMMAND" == "test" ]; then
     cargo clean;
     # Build with test for easy debugging
-    cargo test --no-run -p user --bin shell;
+    cargo test --no-run -p user --bin shell --features fault-injection;
     # Build with json output to find binary name
-    TEST_BINARY=$(cargo test --no-run -p user --bin shell --message-format=json 2>/dev/null | \
+    TEST_BINARY=$(cargo test --no-run -p user --bin shell --features fault-injection --message-format=json 2>/dev/null | \
     sed -n 's/.*"executable":"\([^"]*\)".*/\1/p' | \
     head -n 1);
     cd $TARGET_DIR;
@@ -77,11 +77,12 @@ if [ "$COMMAND" == "build" ]; then
 fi
 
 if [ "$COMMAND" == "run" ]; then
+    # Debugging system calls such as v2p are for interactive runs only
     if [ -f $TARGET/shell.bin.o ]; then
-        cargo run;
+        cargo run --features kernel/debug-syscalls;
     else
         "./$0" build;
-        cargo run;
+        cargo run --features kernel/debug-syscalls;
     fi
 fi
 
@@ -11,11 +11,15 @@ cd "$CARGO_WORKSPACE_DIR"
 cp $1 kernel.elf
 
 (cd disk && tar cf ../disk.tar --format=ustar *.txt)
+# Room for two copies of the file system and the sector choosing between them. Each copy holds a header
+# and two data sectors per file, and two end sectors.
+truncate -s '>16K' disk.tar
+truncate -s ">$(( (2 * (3 * ${OS1K_FILES_MAX:-3} + 2) + 1) * 512 ))" disk.tar
 
 #     -d unimp,guest_errors,int,cpu_reset -D qemu.log \
 
 #Start QEMU
-$QEMU -machine virt -bios default -nographic -serial mon:stdio --no-reboot \
+$QEMU -machine virt -smp 2 -bios default -nographic -serial mon:stdio --no-reboot \
     -drive id=drive0,file=disk.tar,format=raw,if=none \
     -device virtio-blk-device,drive=drive0,bus=virtio-mmio-bus.0 \
     -kernel kernel.elf
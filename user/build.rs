@@ -1,4 +1,23 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// USER_BASE and ARGS_VADDR, shared with common
+include!("../common/src/layout.rs");
+
 fn main() {
     println!("cargo:rustc-link-arg=--Map=user/user.map");
     println!("cargo:rustc-link-arg=--script=user/user.ld");
+
+    // Give user.ld the addresses the kernel loads programs at, so they are only set in common
+    let out_dir = env::var("OUT_DIR").expect("cargo should set OUT_DIR");
+    fs::write(
+        Path::new(&out_dir).join("layout.ld"),
+        format!("USER_BASE = {:#x};\nARGS_VADDR = {:#x};\n", USER_BASE, ARGS_VADDR),
+    ).expect("should write layout.ld");
+    println!("cargo:rustc-link-search=native={}", out_dir);
+
+    // Tell cargo to rerun if the linker script or the addresses change
+    println!("cargo:rerun-if-changed=user.ld");
+    println!("cargo:rerun-if-changed=../common/src/layout.rs");
 }
@@ -2,9 +2,34 @@
 //!
 //! Very simple shell supporting these commands:
 //! - `hello` - Prints a welcome message
-//! - `readfile` - Reads the first 128 bytes of the file "hello.txt" and prints these to the debug console
+//! - `echo <text>` - Prints the text
+//! - `spawn <program> [args]` - Runs a program in a new process; `spawn shell <command>` runs a shell command
+//! - `exec <program> [args]` - Replaces the shell with a program, keeping its process ID
+//! - `readfile` - Reads the file "hello.txt" and prints its bytes to the debug console
+//! - `cat <file>` - Prints the file, however large
 //! - `writefile` - Writes the text "Hello from the shell!" to the file "meow.txt"
+//! - `log <text>` - Appends the text to the file "log.txt", after the time since the machine started
+//! - `source <file>` - Runs each line of the file as a command; lines starting with `#` are comments
+//! - `cd <dir>` - Changes the current directory; `cd ..` goes up and `cd /` to the root
+//! - `pwd` - Prints the current directory
+//! - `pipewrite <id> <text>` - Writes the text to pipe `id`
+//! - `top` - Lists processes with their state and CPU time
+//! - `ps` - Lists processes with their name and state
+//! - `kill <pid>` - Kills the process with the given process ID
+//! - `clear` - Clears the screen
+//! - `uptime` - Prints the time since the machine started
+//! - `free` - Prints how much kernel memory is left
+//! - `sched` - Prints the number of context switches and the rate since the machine started
+//! - `spin <ms>` - Keeps the processor busy for the given milliseconds, without yielding
+//! - `strace on|off` - Turns logging of every system call on or off
+//! - `panic <text>` - Panics with the text, exiting the shell with status 101
 //! - `exit` - Exits the shell
+//! - `shutdown` - Powers off the machine
+//!
+//! File names not starting with `/` are relative to the current directory. The file system is flat, so a
+//! directory is just a prefix of file names up to a `/`.
+//!
+//! The up and down arrows recall recent commands and Tab completes command names. `spawn shell source <file>` runs a script in a new shell.
 
 #![no_std]
 #![no_main]
@@ -13,89 +38,622 @@
 #![cfg_attr(test, test_runner(crate::test_runner))]
 #![cfg_attr(test, reexport_test_harness_main = "test_main")]
 
-
-use core::ffi::CStr;
+use core::fmt::{self, Write};
 
 use user::{
+    append_file,
+    console,
     exit,
+    free_memory,
+    kill,
+    pipe_write,
     print,
-    println,
-    get_char,
+    print_file,
     put_byte,
+    println,
+    proc_stat,
+    read_line_with_completion,
+    Completion,
+    Recall,
     readfile,
+    shutdown,
+    spawn,
+    stat,
+    switch_count,
+    uptime_ms,
     writefile,
 };
 
+// What Tab completes after a command's name
+#[derive(Clone, Copy, PartialEq)]
+enum Arg {
+    Text,   // Nothing
+    File,   // A file or directory name
+}
+
+// Commands that `execute_command` runs, by name, each given the rest of its command line
+static COMMANDS: &[(&str, Arg, fn(&str, &mut Cwd))] = &[
+    ("hello", Arg::Text, |_, _| {
+        println!("Hello world from the shell! 🐚");
+    }),
+    ("echo", Arg::Text, |rest, _| {
+        println!("{}", rest.trim());
+    }),
+    ("spawn", Arg::Text, |rest, _| {
+        match spawn(rest.split_whitespace()) {
+            Ok(pid) => println!("spawned process {}", pid),
+            Err(_) => println!("could not spawn: {}", rest.trim()),
+        }
+    }),
+    ("exec", Arg::Text, |rest, _| {
+        let error = user::exec(rest.split_whitespace());
+        println!("could not exec {}: {:?}", rest.trim(), error);
+    }),
+    ("pipewrite", Arg::Text, |rest, _| {
+        let (id, text) = rest.split_once(' ')
+            .unwrap_or((rest, ""));
+        match id.parse() {
+            Ok(id) => {
+                if pipe_write(user::PipeId(id), text.as_bytes()).is_err() {
+                    println!("could not write to pipe {}", id);
+                }
+            },
+            Err(_) => println!("usage: pipewrite <id> <text>"),
+        }
+    }),
+    ("name", Arg::Text, |rest, _| {
+        // The name this program was started as, printed or written to a pipe
+        let mut name = [0u8; PATH_MAX];
+        let len = user::program_name(&mut name);
+        let name = &name[..len];
+        match rest.trim() {
+            "" => println!("{}", str::from_utf8(name).unwrap_or("?")),
+            id => match id.parse() {
+                Ok(id) => {
+                    if pipe_write(user::PipeId(id), name).is_err() {
+                        println!("could not write to pipe {}", id);
+                    }
+                },
+                Err(_) => println!("usage: name [<pipe id>]"),
+            },
+        }
+    }),
+    ("top", Arg::Text, |_, _| {
+        println!("  PID STATE     CPU TICKS");
+        for stat in (0..=user::PROCS_MAX).filter_map(|pid| proc_stat(pid).ok()) {
+            let state = match stat.state {
+                user::PROC_STATE_RUNNABLE => "runnable",
+                user::PROC_STATE_SLEEPING => "sleeping",
+                _ => "exited",
+            };
+            println!("{:>5} {:<9} {:>9}", stat.pid, state, stat.cpu_ticks);
+        }
+    }),
+    ("ps", Arg::Text, |_, _| {
+        println!("  PID NAME             STATE");
+        for stat in (0..=user::PROCS_MAX).filter_map(|pid| proc_stat(pid).ok()) {
+            let len = stat.name.iter().position(|&b| b == 0).unwrap_or(stat.name.len());
+            let name = str::from_utf8(&stat.name[..len]).unwrap_or("?");
+            let state = match stat.state {
+                user::PROC_STATE_RUNNABLE => "runnable",
+                user::PROC_STATE_SLEEPING => "sleeping",
+                _ => "exited",
+            };
+            println!("{:>5} {:<16} {}", stat.pid, name, state);
+        }
+    }),
+    ("kill", Arg::Text, |rest, _| {
+        match rest.trim().parse() {
+            Ok(pid) => {
+                if kill(pid).is_err() {
+                    println!("could not kill process {}", pid);
+                }
+            },
+            Err(_) => println!("usage: kill <pid>"),
+        }
+    }),
+    ("clear", Arg::Text, |_, _| {
+        console::clear_screen();
+    }),
+    ("uptime", Arg::Text, |_, _| {
+        let uptime = uptime_ms();
+        println!("up {}.{:03} s", uptime / 1000, uptime % 1000);
+    }),
+    ("df", Arg::Text, |_, _| {
+        match user::fs_info() {
+            Ok(info) => println!("{}/{} files, {} bytes used, {} bytes free",
+                info.files_used, info.files_max, info.bytes_used, info.bytes_free),
+            Err(e) => println!("df failed: {:?}", e),
+        }
+    }),
+    ("free", Arg::Text, |_, _| {
+        println!("{} KiB free", free_memory() / 1024);
+    }),
+    ("sched", Arg::Text, |_, _| {
+        let switches = switch_count();
+        let uptime = uptime_ms().max(1);
+        println!("{} context switches, {} per second", switches, switches as u64 * 1000 / uptime);
+    }),
+    ("spin", Arg::Text, |rest, _| {
+        let Ok(millisecs) = rest.trim().parse::<u64>() else {
+            println!("usage: spin <ms>");
+            return;
+        };
+        let end = uptime_ms() + millisecs;
+        while uptime_ms() < end {}
+    }),
+    ("strace", Arg::Text, |rest, _| {
+        let on = match rest.trim() {
+            "on" => true,
+            "off" => false,
+            _ => {
+                println!("usage: strace on|off");
+                return;
+            },
+        };
+        if let Err(e) = user::set_trace(on) {
+            println!("strace failed: {:?}", e);
+        }
+    }),
+    ("v2p", Arg::Text, |rest, _| {
+        let hex = rest.trim();
+        let Ok(vaddr) = usize::from_str_radix(hex.trim_start_matches("0x"), 16) else {
+            println!("usage: v2p <hex address>");
+            return;
+        };
+        match user::virt_to_phys(vaddr) {
+            Ok(info) => {
+                let flag = |bit, c| if info.flags & bit != 0 { c } else { '-' };
+                println!("{:#010x} -> {:#010x} {}{}{}{}", vaddr, info.paddr,
+                    flag(user::PAGE_INFO_READ, 'r'), flag(user::PAGE_INFO_WRITE, 'w'),
+                    flag(user::PAGE_INFO_EXEC, 'x'), flag(user::PAGE_INFO_USER, 'u'));
+            },
+            Err(user::ErrorCode::NotFound) => println!("{:#010x} is not mapped", vaddr),
+            Err(e) => println!("v2p failed: {:?}", e),
+        }
+    }),
+    ("panic", Arg::Text, |rest, _| {
+        panic!("{}", rest.trim());
+    }),
+    ("exit", Arg::Text, |_, _| {
+        exit();
+    }),
+    ("shutdown", Arg::Text, |_, _| {
+        shutdown();
+    }),
+    ("readfile", Arg::Text, |_, cwd| {
+        let mut path = [0u8; PATH_MAX];
+        let Some(filename) = cwd.resolve("hello.txt", &mut path) else {
+            return;
+        };
+        let Ok(file_stat) = stat(filename) else {
+            println!("file not found: {}", filename);
+            return;
+        };
+        let mut buf = [0u8; FILE_READ_MAX];
+        let Some(contents) = buf.get_mut(..file_stat.size) else {
+            println!("file too large: {} is {} bytes, at most {} can be read", filename, file_stat.size, FILE_READ_MAX);
+            return;
+        };
+        readfile(filename, contents);
+        // Contents are raw bytes, so pass them to the console unchanged
+        for &byte in contents.trim_ascii_end() {
+            let _ = put_byte(byte);
+        }
+        println!();
+    }),
+    ("cat", Arg::File, |rest, cwd| {
+        let mut path = [0u8; PATH_MAX];
+        let Some(filename) = cwd.resolve(rest.trim(), &mut path) else {
+            return;
+        };
+        match print_file(filename) {
+            Ok(_) => println!(),
+            Err(user::ErrorCode::NotFound) => println!("file not found: {}", filename),
+            Err(e) => println!("cat failed: {:?}", e),
+        }
+    }),
+    ("writefile", Arg::Text, |_, cwd| {
+        let mut path = [0u8; PATH_MAX];
+        let Some(filename) = cwd.resolve("meow.txt", &mut path) else {
+            return;
+        };
+        writefile(
+            filename,
+            b"Hello from the shell!");
+    }),
+    ("log", Arg::Text, |rest, cwd| {
+        let mut path = [0u8; PATH_MAX];
+        let Some(filename) = cwd.resolve("log.txt", &mut path) else {
+            return;
+        };
+        let uptime = uptime_ms();
+        let mut line = LineBuf::new();
+        if writeln!(line, "[{}.{:03}] {}", uptime / 1000, uptime % 1000, rest.trim()).is_err() {
+            println!("log line too long");
+            return;
+        }
+        match append_file(filename, line.as_bytes()) {
+            Ok(len) if len == line.as_bytes().len() => {},
+            Ok(_) => println!("log file full: {}", filename),
+            Err(_) => println!("could not append to {}", filename),
+        }
+    }),
+    ("cd", Arg::File, |rest, cwd| {
+        if cwd.change(rest.trim()).is_err() {
+            println!("path too long: {}", rest.trim());
+        }
+    }),
+    ("ls", Arg::File, |rest, cwd| {
+        let mut dir = Cwd { path: cwd.path, len: cwd.len };
+        if dir.change(rest.trim()).is_err() {
+            println!("path too long: {}", rest.trim());
+            return;
+        }
+        let mut buf = [0u8; LISTING_MAX];
+        match user::list_dir(dir.as_str(), &mut buf) {
+            Ok(len) => {
+                // Entries are file names, which are UTF-8
+                for entry in buf[..len].split(|&b| b == b'\n').filter(|entry| !entry.is_empty()) {
+                    println!("{}", str::from_utf8(entry).unwrap_or("?"));
+                }
+            },
+            Err(e) => println!("could not list {}: {:?}", rest.trim(), e),
+        }
+    }),
+    ("pwd", Arg::Text, |_, cwd| {
+        println!("/{}", cwd.as_str().trim_end_matches('/'));
+    }),
+    ("source", Arg::File, |rest, cwd| {
+        let mut path = [0u8; PATH_MAX];
+        let Some(filename) = cwd.resolve(rest.trim(), &mut path) else {
+            return;
+        };
+        let Ok(file_stat) = stat(filename) else {
+            println!("file not found: {}", filename);
+            return;
+        };
+        let mut buf = [0u8; FILE_READ_MAX];
+        let Some(script) = buf.get_mut(..file_stat.size) else {
+            println!("file too large: {} is {} bytes, at most {} can be read", filename, file_stat.size, FILE_READ_MAX);
+            return;
+        };
+        readfile(filename, script);
+        for line in script_lines(script) {
+            match line {
+                Ok(line) if line.split_whitespace().next() == Some("source") => {
+                    println!("nested source not supported: {}", line);
+                },
+                Ok(line) => execute_command(line, cwd),
+                Err(_) => println!("skipping script line that is not valid UTF-8"),
+            }
+        }
+    }),
+];
+
 #[doc(hidden)]
-fn execute_command(cmdline_str: &str) {
-    match cmdline_str {
-        "hello" => {
-            println!("Hello world from the shell! 🐚");
+fn execute_command(cmdline_str: &str, cwd: &mut Cwd) {
+    let (command, rest) = cmdline_str.split_once(' ')
+        .unwrap_or((cmdline_str, ""));
+    match COMMANDS.iter().find(|&&(name, _, _)| name == command) {
+        Some((_, _, run)) => run(rest, cwd),
+        None => println!("unknown command: {}", cmdline_str),
+    }
+}
+
+// Complete the line being edited for `read_line_with_completion`: the command name at the start of the
+// line, or the file name being typed after a command that takes one
+fn complete_line(buf: &mut [u8], len: usize, cwd: &Cwd) -> Completion {
+    let Ok(typed) = str::from_utf8(&buf[..len]) else {
+        return Completion::Unchanged;
+    };
+    let mut candidates = [""; CANDIDATES_MAX];
+    let mut listing = [0u8; LISTING_MAX];
+    let (start, count) = match typed.split_once(' ') {
+        None => {
+            let names = COMMANDS.iter()
+                .map(|&(name, _, _)| name)
+                .filter(|name| name.starts_with(typed));
+            (0, fill_candidates(&mut candidates, names))
         },
-        "exit" => {
-            exit();
+        Some((command, arg)) => {
+            let takes_file = COMMANDS.iter().any(|&(name, kind, _)| name == command && kind == Arg::File);
+            if !takes_file || arg.contains(' ') {
+                return Completion::Unchanged;
+            }
+
+            // Complete the last component of the name from a listing of the directory it is in
+            let (dir, prefix) = arg.split_at(arg.rfind('/').map_or(0, |i| i + 1));
+            let mut in_dir = Cwd { path: cwd.path, len: cwd.len };
+            if in_dir.change(dir).is_err() {
+                return Completion::Unchanged;
+            }
+            let Ok(listed) = user::list_dir(in_dir.as_str(), &mut listing) else {
+                return Completion::Unchanged;
+            };
+            // Entries are file names, which are UTF-8
+            let names = listing[..listed].split(|&b| b == b'\n')
+                .filter_map(|entry| str::from_utf8(entry).ok())
+                .filter(|entry| !entry.is_empty() && entry.starts_with(prefix));
+            (len - prefix.len(), fill_candidates(&mut candidates, names))
         },
-        "readfile" => {
-            let mut buf = [0u8; 128];
-            readfile("hello.txt", &mut buf);
-            CStr::from_bytes_until_nul(&buf)
-            .ok()
-            .and_then(|cstr| cstr.to_str().ok())
-            .map(|s| println!("{}", s.trim_end()))
-            .unwrap_or_else(|| println!("could not read file contents"));
-        }
-        "writefile" => {
-            writefile(
-                "meow.txt",
-                b"Hello from the shell!");
+    };
+    complete_word(buf, start, &candidates[..count])
+}
+
+// Put the first of `names` in `candidates`, as many as fit, returning how many
+fn fill_candidates<'a>(candidates: &mut [&'a str], names: impl Iterator<Item = &'a str>) -> usize {
+    let mut count = 0;
+    for (candidate, name) in candidates.iter_mut().zip(names) {
+        *candidate = name;
+        count += 1;
+    }
+    count
+}
+
+// Complete the word starting at `start` in `buf` from the names it could be. Just one fills in the rest of the
+// word and a space to start the next, unless it is a directory; several are listed.
+fn complete_word(buf: &mut [u8], start: usize, candidates: &[&str]) -> Completion {
+    match candidates {
+        [] => Completion::Unchanged,
+        [word] => {
+            let separator = if word.ends_with('/') { "" } else { " " };
+            let completed = start + word.len() + separator.len();
+            let Some(dest) = buf.get_mut(start..completed) else {
+                return Completion::Unchanged;
+            };
+            dest[..word.len()].copy_from_slice(word.as_bytes());
+            dest[word.len()..].copy_from_slice(separator.as_bytes());
+            Completion::Replaced(completed)
         },
-        _ => {
-            println!("unknown command: {}", cmdline_str);
+        many => {
+            println!();
+            for word in many {
+                print!("{}  ", word);
+            }
+            println!();
+            print!("> ");
+            Completion::Listed
         },
     }
 }
 
-#[unsafe(no_mangle)]
-#[doc(hidden)]
-fn main() {
+// Commands in a newline separated script, skipping blank lines and `#` comments
+fn script_lines(script: &[u8]) -> impl Iterator<Item = Result<&str, core::str::Utf8Error>> {
+    script.split(|&byte| byte == b'\n')
+        .map(|line| line.trim_ascii())
+        .filter(|line| !line.is_empty() && !line.starts_with(b"#"))
+        .map(str::from_utf8)
+}
 
-    #[cfg(test)]
-    test_main();
+const LINE_MAX: usize = 128;        // Longest command line
+const ARGS_TOO_LONG: usize = 2;     // Exit status when spawned with arguments longer than a command line
+const CANDIDATES_MAX: usize = 32;   // Most completions listed for Tab
+const PATH_MAX: usize = 100;        // Longest file name, as held in a tar header
+const HISTORY_LEN: usize = 8;       // Number of commands remembered for recall
+const LOG_LINE_MAX: usize = LINE_MAX + 32;  // Longest `log` line, with its timestamp
+const LISTING_MAX: usize = 512;    // Longest directory listing `ls` prints
+const FILE_READ_MAX: usize = 1024;  // Largest file `readfile` and `source` read whole
 
-    loop {
-        print!("> ");
-        let mut cmdline = [b'\n'; 128];
-        let mut pos = 0;
-        loop {
-            let Some(ch) = get_char() else {
-                break;
-            };
-            let byte = ch as u8;
-            match byte {
-                b'\r' => { // On the debug console the newline is \r
-                    println!();
-                    break;
+/// Formatted text held in a fixed buffer; writes fail once it is full
+struct LineBuf {
+    buf: [u8; LOG_LINE_MAX],
+    len: usize,
+}
+
+impl LineBuf {
+    const fn new() -> Self {
+        Self {
+            buf: [0; LOG_LINE_MAX],
+            len: 0,
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let dest = self.buf.get_mut(self.len..self.len + s.len()).ok_or(fmt::Error)?;
+        dest.copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+/// Current directory, the prefix added to relative file names
+///
+/// Held without a leading `/` and with a trailing `/`, so the root is empty.
+struct Cwd {
+    path: [u8; PATH_MAX],
+    len: usize,
+}
+
+impl Cwd {
+    const fn new() -> Self {
+        Self {
+            path: [0; PATH_MAX],
+            len: 0,
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        str::from_utf8(&self.path[..self.len])
+            .expect("directory is built from UTF-8 names")
+    }
+
+    // Change directory, component by component: `..` goes up, `.` and empty components are skipped and
+    // a leading `/` starts from the root. Leaves the directory unchanged if the result is too long.
+    fn change(&mut self, dir: &str) -> Result<(), ()> {
+        let mut new = Self { path: self.path, len: if dir.starts_with('/') { 0 } else { self.len } };
+        for component in dir.split('/') {
+            match component {
+                "" | "." => {},
+                ".." => {
+                    // Trim the last component, keeping the `/` before it
+                    let parent = new.as_str().trim_end_matches('/');
+                    new.len = parent.rfind('/').map_or(0, |i| i + 1);
                 },
                 _ => {
-                    let _ = put_byte(byte);
-                    cmdline[pos] = byte;
-                    pos += 1;
-                }
+                    let dest = new.path.get_mut(new.len..new.len + component.len() + 1).ok_or(())?;
+                    dest[..component.len()].copy_from_slice(component.as_bytes());
+                    dest[component.len()] = b'/';
+                    new.len += component.len() + 1;
+                },
             }
         }
+        *self = new;
+        Ok(())
+    }
+
+    // The complete name of file `name` in `buf`, or `None` after printing an error if it is too long
+    fn resolve<'a>(&self, name: &str, buf: &'a mut [u8; PATH_MAX]) -> Option<&'a str> {
+        let (prefix, name) = match name.strip_prefix('/') {
+            Some(absolute) => ("", absolute),
+            None => (self.as_str(), name),
+        };
+        let len = prefix.len() + name.len();
+        if len > PATH_MAX {
+            println!("path too long: {}{}", prefix, name);
+            return None;
+        }
+        buf[..prefix.len()].copy_from_slice(prefix.as_bytes());
+        buf[prefix.len()..len].copy_from_slice(name.as_bytes());
+        Some(str::from_utf8(&buf[..len]).expect("joined from UTF-8 names"))
+    }
+}
+
+/// Ring buffer of recent command lines
+struct History {
+    lines: [[u8; LINE_MAX]; HISTORY_LEN],
+    lens: [usize; HISTORY_LEN],
+    next: usize,        // Slot for the next line
+    count: usize,       // Number of lines held, at most HISTORY_LEN
+    cursor: usize,      // How many lines back recall has reached; 0 is the new line being edited
+    draft: [u8; LINE_MAX],  // The new line being edited, saved while older lines are recalled
+    draft_len: usize,
+}
+
+impl History {
+    const fn new() -> Self {
+        Self {
+            lines: [[0; LINE_MAX]; HISTORY_LEN],
+            lens: [0; HISTORY_LEN],
+            next: 0,
+            count: 0,
+            cursor: 0,
+            draft: [0; LINE_MAX],
+            draft_len: 0,
+        }
+    }
+
+    // Remember a line, skipping blank lines and repeats of the newest line
+    fn push(&mut self, line: &[u8]) {
+        self.cursor = 0;
+        let line = line.trim_ascii();
+        if line.is_empty() || self.get(1) == Some(line) {
+            return;
+        }
+
+        let len = line.len().min(LINE_MAX);
+        self.lines[self.next][..len].copy_from_slice(&line[..len]);
+        self.lens[self.next] = len;
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.count = (self.count + 1).min(HISTORY_LEN);
+    }
+
+    // The line `back` lines ago, counting the newest as 1
+    fn get(&self, back: usize) -> Option<&[u8]> {
+        if back == 0 || back > self.count {
+            return None;
+        }
+        let i = (self.next + HISTORY_LEN - back) % HISTORY_LEN;
+        Some(&self.lines[i][..self.lens[i]])
+    }
+
+    // Step through the history for `read_line_with_recall`, filling `buf` with the recalled line in place of
+    // the `len` bytes being edited
+    fn recall(&mut self, direction: Recall, buf: &mut [u8], len: usize) -> Option<usize> {
+        match direction {
+            Recall::Older if self.cursor < self.count => self.cursor += 1,
+            Recall::Newer if self.cursor > 0 => self.cursor -= 1,
+            _ => return None,
+        }
+
+        // Keep the new line when leaving it, and give it back when stepping forward past the newest line
+        if self.cursor == 1 && direction == Recall::Older {
+            self.draft_len = len.min(LINE_MAX);
+            self.draft[..self.draft_len].copy_from_slice(&buf[..self.draft_len]);
+        }
+        let line = self.get(self.cursor).unwrap_or(&self.draft[..self.draft_len]);
+        let len = line.len().min(buf.len());
+        buf[..len].copy_from_slice(&line[..len]);
+        Some(len)
+    }
+}
+
+#[unsafe(no_mangle)]
+#[doc(hidden)]
+extern "C" fn main(argc: usize, argv: *const u8) {
 
-        let cmdline_str = str::from_utf8(&cmdline)
-        .expect("command line text valid UTF8")
-        .trim();
+    // Say which shell panicked, as several may be running
+    user::set_panic_hook(|_| print!("shell {}: ", user::getpid()));
 
-        execute_command(cmdline_str);
+    // When spawned with arguments, run them as a single command and exit
+    let mut cmdline = [0u8; LINE_MAX];
+    let mut len = 0;
+    // Safety: `start` passes the count and first argument of this process's arguments page
+    let main_args = unsafe { user::Args::from_argv(argc, argv) };
+    for arg in main_args.skip(1) {
+        let sep = usize::from(len > 0);
+        let Some(dest) = cmdline.get_mut(len..len + sep + arg.len()) else {
+            user::die!(ARGS_TOO_LONG, "arguments too long");
+        };
+        dest[..sep].fill(b' ');
+        dest[sep..].copy_from_slice(arg.as_bytes());
+        len += sep + arg.len();
+    }
+    if len > 0 {
+        let cmdline_str = str::from_utf8(&cmdline[..len])
+        .expect("arguments are valid UTF8");
+        execute_command(cmdline_str, &mut Cwd::new());
+        exit();
+    }
+
+    #[cfg(test)]
+    test_main();
+
+    let mut history = History::new();
+    let mut cwd = Cwd::new();
+    loop {
+        print!("> ");
+        let mut cmdline = [0u8; LINE_MAX];
+        let len = read_line_with_completion(
+            &mut cmdline,
+            |direction, buf, len| history.recall(direction, buf, len),
+            |buf, len| complete_line(buf, len, &cwd),
+        );
+        history.push(&cmdline[..len]);
+        execute_line(&cmdline[..len], &mut cwd);
    }
 }
 
+// Run a line typed at the prompt. A stray byte can make it invalid UTF-8, which is reported rather than run.
+fn execute_line(line: &[u8], cwd: &mut Cwd) {
+    match str::from_utf8(line) {
+        Ok(cmdline_str) => execute_command(cmdline_str.trim(), cwd),
+        Err(_) => println!("invalid input"),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::{print, println};
+    use user::console::print_ok;
+    use user::{args, PROC_HART_NONE};
 
     #[test_case]
     fn shell_trivial_test() {
@@ -103,7 +661,7 @@ mod test {
 
         assert!(1 == 1);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        print_ok();
     }
 
     #[test_case]
@@ -112,24 +670,1010 @@ mod test {
 
         println!("assertion is {}", 1 == 1);
 
-        println!("[\x1b[32mok\x1b[0m]");
+        print_ok();
     }
 
     #[test_case]
     fn shell_read_write_file_test() {
         print!("shell: read write file test...");
 
-        execute_command("hello");
-        execute_command("readfile");
+        execute_command("hello", &mut Cwd::new());
+        execute_command("readfile", &mut Cwd::new());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_cwd_test() {
+        print!("shell: cwd test...");
+
+        let mut cwd = Cwd::new();
+        let mut path = [0u8; PATH_MAX];
+        assert!(cwd.resolve("hello.txt", &mut path) == Some("hello.txt"));
+
+        assert!(cwd.change("docs/notes") == Ok(()));
+        assert!(cwd.as_str() == "docs/notes/");
+        assert!(cwd.resolve("a.txt", &mut path) == Some("docs/notes/a.txt"));
+        assert!(cwd.resolve("/hello.txt", &mut path) == Some("hello.txt"));
+
+        assert!(cwd.change("..") == Ok(()));
+        assert!(cwd.as_str() == "docs/");
+        assert!(cwd.change("../..") == Ok(()));
+        assert!(cwd.as_str() == "");
+        assert!(cwd.change("/docs/./x") == Ok(()));
+        assert!(cwd.as_str() == "docs/x/");
+
+        // Too long a path leaves the directory alone
+        let long = [b'd'; PATH_MAX];
+        assert!(cwd.change(str::from_utf8(&long).unwrap()).is_err());
+        assert!(cwd.as_str() == "docs/x/");
+
+        // Commands find files relative to the directory
+        execute_command("cd /", &mut cwd);
+        execute_command("pwd", &mut cwd);
+        execute_command("readfile", &mut cwd);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_args_test() {
+        print!("shell: args test...");
+
+        assert!(args().next() == Some("shell"));
+        execute_command("echo hello from the args test", &mut Cwd::new());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_user_base_test() {
+        print!("shell: user base test...");
+
+        unsafe extern "C" {
+            // Safety: Symbol created by the linker script
+            static __user_base: u8;
+        }
+        // The kernel loads programs at the common constant, so user.ld must link them there
+        assert!(&raw const __user_base as usize == user::USER_BASE);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_program_name_test() {
+        print!("shell: program name test...");
+
+        // The kernel started the test shell by name, and a short buffer gets the start of it
+        let mut buf = [0u8; 16];
+        let len = user::program_name(&mut buf);
+        assert!(&buf[..len] == b"shell");
+        assert!(user::program_name(&mut buf[..3]) == 3 && &buf[..3] == b"she");
+
+        // A shell spawned by a path reports the path it was started as
+        let pipe = user::pipe()
+            .expect("should create a pipe");
+        let id = [b'0' + pipe.0 as u8]; // Pipe ids are single digits
+        let id = str::from_utf8(&id)
+            .expect("pipe id is ASCII");
+        let pid = spawn(["/bin/shell", "name", id])
+            .expect("should spawn a shell by path");
+        let len = user::pipe_read(pipe, &mut buf)
+            .expect("should read from the pipe");
+        assert!(&buf[..len] == b"/bin/shell");
+        assert!(user::wait(pid) == Ok(0));
+        assert!(user::pipe_close(pipe) == Ok(()));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_random_test() {
+        print!("shell: random test...");
+
+        // Two numbers in a row match with a chance of one in 2^32
+        assert!(user::random_u32() != user::random_u32());
+
+        let mut buf = [0u8; 37];
+        user::random(&mut buf);
+        assert!(buf.iter().any(|&b| b != 0));
+        user::random(&mut []);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_yield_ping_pong_test() {
+        print!("shell: yield ping pong test...");
+
+        // Write "ping", then yield until a spawned shell overwrites the file
+        writefile("meow.txt", b"ping");
+        spawn(["shell", "writefile"])
+            .expect("should spawn a shell");
+
+        let mut buf = [0u8; 4];
+        let ponged = (0..100).any(|_| {
+            user::yield_now();
+            readfile("meow.txt", &mut buf);
+            &buf != b"ping"
+        });
+        assert!(ponged);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_concurrent_writefile_test() {
+        print!("shell: concurrent writefile test...");
+
+        // Alternate writes with spawned shells writing the same file; each read sees one write whole
+        const SHELL_TEXT: &[u8] = b"Hello from the shell!";
+        const TEST_TEXT: &[u8] = b"Written by the tests.";
+        let quantum = user::set_quantum(1)
+            .expect("quantum should be valid");
+        for _ in 0..10 {
+            let pid = spawn(["shell", "writefile"])
+                .expect("should spawn a shell");
+            writefile("meow.txt", TEST_TEXT);
+            user::yield_now();
+            let mut buf = [0u8; SHELL_TEXT.len()];
+            readfile("meow.txt", &mut buf);
+            assert!(buf == SHELL_TEXT || buf == TEST_TEXT);
+            assert!(user::wait(pid) == Ok(0));
+        }
+        user::set_quantum(quantum)
+            .expect("previous quantum should be valid");
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_buffered_println_test() {
+        print!("shell: buffered println test...");
+        println!();
+
+        // Each line is written with one system call rather than one per byte
+        let before = user::console_syscalls();
+        println!("  first line\n  second line\n  third line");
+        let after = user::console_syscalls();
+        assert!(after - before <= 3);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_flush_test() {
+        print!("shell: flush test...");
+
+        // Text without a newline waits until flushed
+        let before = user::console_syscalls();
+        print!("waiting...");
+        assert!(user::console_syscalls() == before);
+        user::flush();
+        assert!(user::console_syscalls() == before + 1);
+        user::flush();
+        assert!(user::console_syscalls() == before + 1, "an empty buffer needs no system call");
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_yield_cost_test() {
+        print!("shell: yield cost test...");
+
+        // Time a tight yield loop. Each yield runs every other process for up to a quantum, so shorten it
+        // to make the context switches themselves show.
+        let quantum = user::set_quantum(1)
+            .expect("quantum should be valid");
+        let yields = 200;
+        let start = uptime_ms();
+        for _ in 0..yields {
+            user::yield_now();
+        }
+        let elapsed = uptime_ms() - start;
+        user::set_quantum(quantum)
+            .expect("previous quantum should be valid");
+
+        // A loose bound, so as not to fail on a slow host: with every other process giving way within its 1 ms
+        // quantum, a yield should come back within a few milliseconds
+        print!("{} yields in {} ms...", yields, elapsed);
+        assert!(elapsed < yields * 5, "yields should not take more than 5 ms each");
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_smp_test() {
+        print!("shell: smp test...");
+
+        // With a second hart a busy shell runs alongside this one, rather than taking turns with it
+        let quantum = user::set_quantum(10)
+            .expect("quantum should be valid");
+        let pid = spawn(["shell", "spin", "1000"])
+            .expect("should spawn a shell");
+        let me = user::getpid();
+        let end = uptime_ms() + 1000;
+        let mut harts = (PROC_HART_NONE, PROC_HART_NONE);
+        while uptime_ms() < end && (harts.1 == PROC_HART_NONE || harts.0 == harts.1) {
+            let hart = |pid| proc_stat(pid).map_or(PROC_HART_NONE, |stat| stat.hart);
+            harts = (hart(me), hart(pid));
+        }
+        user::set_quantum(quantum)
+            .expect("previous quantum should be valid");
+        assert!(harts.0 != PROC_HART_NONE, "this shell is running");
+        assert!(harts.1 != PROC_HART_NONE && harts.1 != harts.0, "spun on another hart");
+
+        // Both carry on through their system calls on either hart
+        assert!(user::wait(pid) == Ok(0));
+        assert!(proc_stat(me).is_ok_and(|stat| stat.pid == me));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_uptime_test() {
+        print!("shell: uptime test...");
+
+        let start = uptime_ms();
+        while uptime_ms() < start + 20 {
+            user::yield_now();
+        }
+        assert!(uptime_ms() - start >= 20);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_pipe_ping_test() {
+        print!("shell: pipe ping test...");
+
+        let pipe = user::pipe()
+            .expect("should create a pipe");
+        let id = [b'0' + pipe.0 as u8]; // Pipe ids are single digits
+        let id = str::from_utf8(&id)
+            .expect("pipe id is ASCII");
+        spawn(["shell", "pipewrite", id, "ping"])
+            .expect("should spawn a shell");
+
+        // Blocks until the spawned shell writes
+        let mut buf = [0u8; 4];
+        let len = user::pipe_read(pipe, &mut buf)
+            .expect("should read from the pipe");
+        assert!(&buf[..len] == b"ping");
+
+        // Closing frees the pipe for reuse, so it cannot be used or closed again
+        assert!(user::pipe_close(pipe) == Ok(()));
+        assert!(user::pipe_write(pipe, b"ping") == Err(user::ErrorCode::NotFound));
+        assert!(user::pipe_close(pipe) == Err(user::ErrorCode::NotFound));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_spawn_args_test() {
+        print!("shell: spawn args test...");
+
+        // The child gets its arguments and writes them back joined with spaces
+        let pipe = user::pipe()
+            .expect("should create a pipe");
+        let id = [b'0' + pipe.0 as u8]; // Pipe ids are single digits
+        let id = str::from_utf8(&id)
+            .expect("pipe id is ASCII");
+        let pid = spawn(["shell", "pipewrite", id, "first", "second", "🐚"])
+            .expect("should spawn a shell");
+
+        let mut buf = [0u8; 32];
+        let len = user::pipe_read(pipe, &mut buf)
+            .expect("should read from the pipe");
+        assert!(&buf[..len] == "first second 🐚".as_bytes());
+        assert!(user::wait(pid) == Ok(0));
+        assert!(user::pipe_close(pipe) == Ok(()));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_select_test() {
+        print!("shell: select test...");
+
+        let quiet = user::pipe()
+            .expect("should create a pipe");
+        let pipe = user::pipe()
+            .expect("should create a pipe");
+        let id = [b'0' + pipe.0 as u8]; // Pipe ids are single digits
+        let id = str::from_utf8(&id)
+            .expect("pipe id is ASCII");
+
+        // Nothing written yet
+        assert!(user::select(&[quiet.0, pipe.0], Some(0)) == Ok(None));
+
+        // Blocks until the spawned shell writes to the second pipe
+        let pid = spawn(["shell", "pipewrite", id, "pong"])
+            .expect("should spawn a shell");
+        assert!(user::select(&[quiet.0, pipe.0], None) == Ok(Some(1)));
+        let mut buf = [0u8; 4];
+        let len = user::pipe_read(pipe, &mut buf)
+            .expect("should read from the pipe");
+        assert!(&buf[..len] == b"pong");
+        assert!(user::wait(pid) == Ok(0));
+        assert!(user::pipe_close(quiet) == Ok(()));
+        assert!(user::pipe_close(pipe) == Ok(()));
+
+        assert!(user::select(&[], None) == Err(user::ErrorCode::BadArg));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_exec_test() {
+        print!("shell: exec test...");
+
+        // The spawned shell replaces itself with another that writes to the pipe
+        let pipe = user::pipe()
+            .expect("should create a pipe");
+        let id = [b'0' + pipe.0 as u8];
+        let id = str::from_utf8(&id)
+            .expect("pipe id is ASCII");
+        let pid = spawn(["shell", "exec", "shell", "pipewrite", id, "exec"])
+            .expect("should spawn a shell");
+
+        let mut buf = [0u8; 4];
+        let len = user::pipe_read(pipe, &mut buf)
+            .expect("should read from the pipe");
+        assert!(&buf[..len] == b"exec");
+        let stat = proc_stat(pid)
+            .expect("should stat the exec'd shell");
+        assert!(stat.pid == pid);
+        assert!(user::pipe_close(pipe) == Ok(()));
+
+        assert!(user::exec(["missing"]) == user::ErrorCode::NotFound);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_panic_exit_code_test() {
+        print!("shell: panic exit code test...");
+
+        let pid = spawn(["shell", "panic", "on purpose"])
+            .expect("should spawn a shell");
+        assert!(user::wait(pid) == Ok(user::PANIC_EXIT_CODE));
+
+        let pid = spawn(["shell", "echo", "no panic"])
+            .expect("should spawn a shell");
+        assert!(user::wait(pid) == Ok(0));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_die_exit_code_test() {
+        print!("shell: die exit code test...");
+
+        // A shell spawned with too long a command line dies with an error
+        let long = [b'x'; LINE_MAX + 1];
+        let long = str::from_utf8(&long)
+            .expect("arguments are ASCII");
+        let pid = spawn(["shell", "echo", long])
+            .expect("should spawn a shell");
+        assert!(user::wait(pid) == Ok(ARGS_TOO_LONG));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_proc_stat_test() {
+        print!("shell: proc stat test...");
+
+        let stat = proc_stat(user::getpid())
+            .expect("should stat the running shell");
+        assert!(stat.state == user::PROC_STATE_RUNNABLE);
+        execute_command("top", &mut Cwd::new());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_proc_name_test() {
+        print!("shell: proc name test...");
+
+        let stat = proc_stat(user::getpid())
+            .expect("should stat the running shell");
+        assert!(stat.name.starts_with(b"shell\0"));
+
+        // A spawned process is named after its program
+        let pid = spawn(["shell", "echo", "named"])
+            .expect("should spawn a shell");
+        let stat = proc_stat(pid)
+            .expect("should stat the spawned shell");
+        assert!(stat.name.starts_with(b"shell\0"));
+        assert!(user::wait(pid) == Ok(0));
+        execute_command("ps", &mut Cwd::new());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_quantum_test() {
+        print!("shell: quantum test...");
+
+        // Every context switch records one scheduling latency sample
+        fn switches_over_window() -> usize {
+            let mut before = [0usize; user::SCHED_LATENCY_BUCKETS];
+            let mut after = [0usize; user::SCHED_LATENCY_BUCKETS];
+            user::sched_latency_hist(&mut before);
+            let start = uptime_ms();
+            while uptime_ms() < start + 200 {}
+            user::sched_latency_hist(&mut after);
+            after.iter().sum::<usize>() - before.iter().sum::<usize>()
+        }
+
+        assert!(user::set_quantum(0) == Err(user::ErrorCode::BadArg));
+        let quantum = user::set_quantum(10)
+            .expect("quantum should be valid");
+        let short = switches_over_window();
+        assert!(user::set_quantum(100) == Ok(10), "setting a quantum should return the one it replaces");
+        let long = switches_over_window();
+        user::set_quantum(quantum)
+            .expect("previous quantum should be valid");
+        assert!(short > long);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_short_quantum_stress_test() {
+        print!("shell: short quantum stress test...");
+
+        // Preempt as often as possible while also yielding and making syscalls that lock the process table
+        // and check user pointers, so timer interrupts land inside the scheduler and syscall paths
+        let quantum = user::set_quantum(1)
+            .expect("quantum should be valid");
+        let spin = || spawn(["shell", "spin", "1000"])
+            .expect("should spawn a shell");
+        let mut child = spin();
+        let mut buf = [0u8; 64];
+        let start = uptime_ms();
+        let mut next_kill = start + 50;
+        while uptime_ms() < start + 200 {
+            user::yield_now();
+            let _ = user::getpid();
+            assert!(user::readfile_bytes(b"hello.txt", &mut buf).is_ok());
+            assert!(user::stat("hello.txt").is_ok());
+            assert!(proc_stat(child).is_ok_and(|stat| stat.pid == child));
+            if uptime_ms() >= next_kill {
+                assert!(user::kill(child).is_ok());
+                assert!(user::wait(child).is_ok());
+                child = spin();
+                next_kill += 50;
+            }
+        }
+        assert!(user::kill(child).is_ok());
+        assert!(user::wait(child).is_ok());
+        user::set_quantum(quantum)
+            .expect("previous quantum should be valid");
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_utf8_char_test() {
+        print!("shell: UTF-8 char test...");
+
+        let decode = |bytes: &[u8]| {
+            let mut rest = bytes[1..].iter().copied();
+            user::utf8_char_from(bytes[0], || rest.next().expect("sequence should be complete"))
+        };
+        assert!(decode(b"a") == Some('a'));
+        assert!(decode("é".as_bytes()) == Some('é'));
+        assert!(decode("😬".as_bytes()) == Some('😬'));
+
+        // A stray continuation byte, a bad continuation byte and an overlong encoding
+        assert!(decode(b"\x80") == None);
+        assert!(decode(b"\xc3\x28") == None);
+        assert!(decode(b"\xe0\x80\x80") == None);
+
+        print_ok();
+    }
+
+    #[test_case]
+    #[cfg(feature = "fault-injection")]
+    fn shell_put_bytes_test() {
+        use user::FAULT_CONSOLE_WRITE;
+        print!("shell: put bytes test...");
+        user::flush();
+
+        assert!(user::put_bytes(b"ok ") == Ok(3));
+        assert!(user::put_bytes(b"") == Ok(0));
+
+        // A console that stops partway reports how much it took, and one that takes nothing the error
+        user::inject_fault(FAULT_CONSOLE_WRITE, 1)
+            .expect("should arm a console fault");
+        assert!(user::put_bytes(b"ha") == Ok(1));
+        assert!(user::put_bytes(b"a ") == Ok(2));
+        user::inject_fault(FAULT_CONSOLE_WRITE, 1)
+            .expect("should arm a console fault");
+        assert!(user::put_bytes(b"!") == Err(user::ErrorCode::IoError));
+
+        // print! writes the rest of a line cut short
+        user::inject_fault(FAULT_CONSOLE_WRITE, 1)
+            .expect("should arm a console fault");
+        let before = user::console_syscalls();
+        print!("retried ");
+        user::flush();
+        assert!(user::console_syscalls() == before + 2);
+
+        // So does read_line echoing a completed line, before the newline that ends it
+        user::inject_fault(FAULT_CONSOLE_WRITE, 1)
+            .expect("should arm a console fault");
+        let mut input = b"\t\r".iter().copied();
+        let mut line = [0u8; 8];
+        let before = user::console_syscalls();
+        let len = user::read_line_from(
+            || input.next().unwrap_or(b'\r'),
+            &mut line,
+            |_, _, _| None,
+            |buf, _| {
+                buf[..5].copy_from_slice(b"hello");
+                Completion::Replaced(5)
+            },
+        );
+        assert!(&line[..len] == b"hello");
+        assert!(user::console_syscalls() == before + 3);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_mmap_test() {
+        print!("shell: mmap test...");
+
+        let addr = user::mmap(100, user::MAP_READ | user::MAP_WRITE)
+            .expect("should map a page");
+        // Safety: The page was just mapped readable and writable
+        let page = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, 4096) };
+        assert!(page.iter().all(|&b| b == 0));
+        page.fill(0xa5);
+        assert!(page[4095] == 0xa5);
+
+        // The next mapping does not overlap
+        let next = user::mmap(4096, user::MAP_READ).expect("should map a second page");
+        assert!(next != addr);
+
+        assert!(user::munmap(addr, 100) == Ok(()));
+        assert!(user::munmap(addr, 100) == Err(user::ErrorCode::BadArg));
+        assert!(user::munmap(next, 4096) == Ok(()));
+        assert!(user::mmap(4096, user::MAP_WRITE | user::MAP_EXEC) == Err(user::ErrorCode::BadArg));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_free_memory_test() {
+        print!("shell: free memory test...");
+
+        // Mapping memory takes pages from the kernel heap
+        let before = free_memory();
+        let addr = user::mmap(4 * 4096, user::MAP_READ).expect("should map memory");
+        assert!(free_memory() < before);
+        assert!(user::munmap(addr, 4 * 4096) == Ok(()));
+        execute_command("free", &mut Cwd::new());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_switch_count_test() {
+        print!("shell: switch count test...");
+
+        // The demo processes are runnable, so yielding switches away and back again
+        let before = switch_count();
+        user::yield_now();
+        assert!(switch_count() >= before + 2);
+        execute_command("sched", &mut Cwd::new());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_signal_test() {
+        print!("shell: signal test...");
+
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        static HANDLED: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn on_term(signo: usize) {
+            print!("cleaning up after signal {}...", signo);
+            HANDLED.store(signo, Ordering::Relaxed);
+        }
+
+        assert!(user::sigaction(user::SIGNAL_TERM, Some(on_term)) == Ok(()));
+        assert!(user::signal(user::getpid(), user::SIGNAL_TERM) == Ok(()));
+        // The handler has run by the time the system call returns
+        assert!(HANDLED.load(Ordering::Relaxed) == user::SIGNAL_TERM);
+
+        assert!(user::sigaction(0, None) == Err(user::ErrorCode::BadArg));
+        assert!(user::signal(user::getpid(), 7) == Err(user::ErrorCode::BadArg));
+        assert!(user::sigaction(user::SIGNAL_TERM, None) == Ok(()));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_poll_char_test() {
+        print!("shell: poll char test...");
+
+        assert!(user::poll_char().is_ok());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_stat_file_test() {
+        print!("shell: stat file test...");
+
+        let file_stat = stat("hello.txt")
+            .expect("hello.txt should exist");
+        assert!(file_stat.name_len == "hello.txt".len());
+        assert!(stat("missing.txt") == Err(user::ErrorCode::NotFound));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_script_lines_test() {
+        print!("shell: script lines test...");
+
+        let script = b"# greet\nhello\r\n\n  echo hi  \n\xff\nreadfile";
+        let mut lines = script_lines(script);
+        assert!(lines.next() == Some(Ok("hello")));
+        assert!(lines.next() == Some(Ok("echo hi")));
+        assert!(lines.next().is_some_and(|line| line.is_err()));
+        assert!(lines.next() == Some(Ok("readfile")));
+        assert!(lines.next().is_none());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_source_script_test() {
+        print!("shell: source script test...");
+
+        // The script overwrites itself, but runs from the copy read beforehand
+        let script = b"# demo script\nhello\nwritefile\nreadfile\n";
+        writefile("meow.txt", script);
+        execute_command("source meow.txt", &mut Cwd::new());
+
+        let expected = b"Hello from the shell!";
+        let mut contents = [0u8; 21];
+        readfile("meow.txt", &mut contents);
+        assert!(contents == *expected);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_history_recall_test() {
+        print!("shell: history recall test...");
+
+        // Type two commands, then press up twice to recall the first. Pressing up then down while typing
+        // gives back the line being typed, and a lone ESC drops only itself.
+        let mut history = History::new();
+        let mut input = b"hello\recho hi\r\x1b[A\x1b[A\rcat\x1b[A\x1b[B me\r\x1bhello\r".iter().copied();
+        let mut next_byte = || input.next().expect("input should end with a line ending");
+        let mut line = [0u8; LINE_MAX];
+        for expected in [b"hello".as_slice(), b"echo hi", b"hello", b"cat me", b"hello"] {
+            let len = user::read_line_from(
+                &mut next_byte,
+                &mut line,
+                |direction, buf, len| history.recall(direction, buf, len),
+                |_, _| Completion::Unchanged,
+            );
+            assert!(&line[..len] == expected);
+            history.push(&line[..len]);
+        }
+
+        // Repeats of the newest line are not stored twice, and recall stops at the oldest line
+        assert!(history.get(1) == Some(b"hello".as_slice()));
+        assert!(history.get(2) == Some(b"cat me".as_slice()));
+        assert!(history.get(3) == Some(b"hello".as_slice()));
+        assert!(history.get(4) == Some(b"echo hi".as_slice()));
+        assert!(history.get(5) == Some(b"hello".as_slice()));
+        assert!(history.get(6).is_none());
+        history.push(b"hello");
+        assert!(history.get(2) == Some(b"cat me".as_slice()));
+        assert!(history.recall(Recall::Newer, &mut line, 0).is_none());
+
+        // Older lines are forgotten once the buffer is full
+        for i in 0..HISTORY_LEN {
+            history.push(&[b'a' + i as u8]);
+        }
+        assert!(history.get(HISTORY_LEN) == Some(b"a".as_slice()));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_tab_completion_test() {
+        print!("shell: tab completion test...");
+
+        // A unique prefix completes, an ambiguous one lists and keeps the line, and only file names are
+        // completed in arguments
+        let mut input = b"hel\t\rs\t\recho he\t\rcat hel\t\rls /lo\t\rcat \t\r".iter().copied();
+        let mut next_byte = || input.next().expect("input should end with a line ending");
+        let mut line = [0u8; LINE_MAX];
+        let cwd = Cwd::new();
+        let expected = [b"hello ".as_slice(), b"s", b"echo he", b"cat hello.txt ", b"ls /log.txt ", b"cat "];
+        for expected in expected {
+            let len = user::read_line_from(&mut next_byte, &mut line, |_, _, _| None, |buf, len| complete_line(buf, len, &cwd));
+            assert!(&line[..len] == expected);
+        }
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_long_line_test() {
+        print!("shell: long line test...");
+
+        // Bytes past the end of the buffer are dropped, leaving the bytes after it untouched
+        let mut input = [b'x'; 200].into_iter()
+            .chain(*b"\x7fy\r")
+            .chain(*b"hello\r")
+            .chain(*b"caf\xc3\xa9\x7fe\r");
+        let mut next_byte = || input.next().expect("input should end with a line ending");
+        let mut buf = [0u8; LINE_MAX + 8];
+        let (line, after) = buf.split_at_mut(LINE_MAX);
+        let len = user::read_line_from(&mut next_byte, line, |_, _, _| None, |_, _| Completion::Unchanged);
+        assert!(len == LINE_MAX);
+        assert!(line[..LINE_MAX - 1].iter().all(|&b| b == b'x') && line[LINE_MAX - 1] == b'y');
+        assert!(after.iter().all(|&b| b == 0));
+        assert!(str::from_utf8(&line[..len]).is_ok());
+
+        // The next line starts afresh, and backspace erases a whole character however many bytes it takes
+        let len = user::read_line_from(&mut next_byte, line, |_, _, _| None, |_, _| Completion::Unchanged);
+        assert!(&line[..len] == b"hello");
+        let len = user::read_line_from(&mut next_byte, line, |_, _, _| None, |_, _| Completion::Unchanged);
+        assert!(&line[..len] == b"cafe");
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_invalid_utf8_test() {
+        print!("shell: invalid utf8 test...");
+
+        // A 0xff byte typed at the prompt is reported, and the shell carries on to the next line
+        let mut input = b"ec\xffho hi\rhello\r".iter().copied();
+        let mut next_byte = || input.next().expect("input should end with a line ending");
+        let mut cwd = Cwd::new();
+        let mut line = [0u8; LINE_MAX];
+        for expected in [b"ec\xffho hi".as_slice(), b"hello"] {
+            let len = user::read_line_from(&mut next_byte, &mut line, |_, _, _| None, |_, _| Completion::Unchanged);
+            assert!(&line[..len] == expected);
+            execute_line(&line[..len], &mut cwd);
+        }
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_open_read_close_test() {
+        print!("shell: open read close test...");
+
+        let file_stat = stat("hello.txt")
+            .expect("hello.txt should exist");
+        let mut expected = [0u8; 1024];
+        let expected = &mut expected[..file_stat.size.min(1024)];
+        readfile("hello.txt", expected);
+
+        // Read in chunks, relying on the offset advancing after each read
+        let fd = user::open("hello.txt", user::OPEN_READ)
+            .expect("hello.txt should open");
+        let mut contents = [0u8; 1024];
+        let mut total = 0;
+        loop {
+            let mut chunk = [0u8; 7];
+            let len = user::read(&fd, &mut chunk)
+                .expect("read should succeed");
+            if len == 0 {
+                break;
+            }
+            contents[total..total + len].copy_from_slice(&chunk[..len]);
+            total += len;
+        }
+        assert!(contents[..total] == *expected);
+        assert!(user::write(&fd, b"meow") == Err(user::ErrorCode::PermissionDenied));
+        user::close(fd)
+            .expect("close should succeed");
+
+        assert!(user::open("missing.txt", user::OPEN_READ).is_err());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_print_file_test() {
+        print!("shell: print file test...");
+
+        // Several chunks and a partial one
+        let contents = [b'c'; 200];
+        assert!(user::writefile_bytes(b"meow.txt", &contents) == Ok(contents.len()));
+        assert!(print_file("meow.txt") == Ok(contents.len()));
+        println!();
+
+        assert!(print_file("missing.txt") == Err(user::ErrorCode::NotFound));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_append_file_test() {
+        print!("shell: append file test...");
+
+        assert!(user::writefile_bytes(b"log.txt", b"") == Ok(0));
+        assert!(user::append_file("log.txt", b"one\n") == Ok(4));
+        assert!(user::append_file("log.txt", b"two\n") == Ok(4));
+        let mut contents = [0u8; 8];
+        readfile("log.txt", &mut contents);
+        assert!(contents == *b"one\ntwo\n");
+
+        // The log command adds a timestamped line after them
+        execute_command("log meow", &mut Cwd::new());
+        let file_stat = stat("log.txt")
+            .expect("log.txt should exist");
+        let mut buf = [0u8; 64];
+        let contents = &mut buf[..file_stat.size];
+        readfile("log.txt", contents);
+        assert!(contents.starts_with(b"one\ntwo\n["));
+        assert!(contents.ends_with(b"] meow\n"));
+
+        // Append needs write access
+        assert!(user::open("log.txt", user::OPEN_APPEND) == Err(user::ErrorCode::BadArg));
+        assert!(user::append_file("missing.txt", b"meow") == Err(user::ErrorCode::NotFound));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_truncate_test() {
+        print!("shell: truncate test...");
+
+        assert!(user::writefile_bytes(b"log.txt", b"meow") == Ok(4));
+        assert!(user::truncate("log.txt", 0) == Ok(()));
+        assert!(stat("log.txt").map(|file_stat| file_stat.size) == Ok(0));
+        let fd = user::open("log.txt", user::OPEN_READ)
+            .expect("log.txt should open");
+        let mut buf = [0u8; 4];
+        assert!(user::read(&fd, &mut buf) == Ok(0));
+        assert!(user::close(fd).is_ok());
+
+        // Growing zero fills, but not past the file's capacity
+        assert!(user::truncate("log.txt", 2) == Ok(()));
+        buf.fill(0xff);
+        readfile("log.txt", &mut buf[..2]);
+        assert!(buf[..2] == [0, 0]);
+        assert!(user::truncate("log.txt", 0) == Ok(()));
+        assert!(user::truncate("log.txt", usize::MAX >> 1) == Err(user::ErrorCode::NoSpace));
+        assert!(user::truncate("missing.txt", 0) == Err(user::ErrorCode::NotFound));
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_list_dir_test() {
+        print!("shell: list dir test...");
+
+        let mut buf = [0u8; LISTING_MAX];
+        let len = user::list_dir("", &mut buf)
+            .expect("root should list");
+        assert!(buf[..len] == *b"hello.txt\nlog.txt\nmeow.txt\n");
+        assert!(user::list_dir("dir", &mut buf) == Ok(0));
+        assert!(user::list_dir("", &mut buf[..4]) == Err(user::ErrorCode::NoSpace));
+
+        execute_command("ls", &mut Cwd::new());
+        execute_command("ls /", &mut Cwd::new());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_get_char_timeout_test() {
+        print!("shell: get char timeout test...");
+
+        // No input arrives while the tests run
+        assert!(user::get_char_timeout(0) == Ok(None));
+        let start = uptime_ms();
+        assert!(user::get_char_timeout(100) == Ok(None));
+        assert!(uptime_ms() - start >= 100);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_sleep_until_test() {
+        print!("shell: sleep until test...");
+
+        // A short time slice bounds how late each wake can be
+        let quantum = user::set_quantum(5)
+            .expect("quantum should be valid");
+        let period = 50;
+        let start = uptime_ms();
+        for tick in 1..=4 {
+            // Work for a varying time, then sleep to the next period boundary
+            let work_end = uptime_ms() + tick * 5;
+            while uptime_ms() < work_end {}
+            let deadline = start + tick * period;
+            user::sleep_until(deadline);
+            let woke = uptime_ms();
+            assert!(woke >= deadline && woke < deadline + period / 2);
+        }
+        user::set_quantum(quantum)
+            .expect("previous quantum should be valid");
+
+        // A past deadline returns at once
+        let before = uptime_ms();
+        user::sleep_until(0);
+        assert!(uptime_ms() - before < period);
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_virt_to_phys_test() {
+        print!("shell: virt to phys test...");
+
+        // The stack is a user page, readable and writable, at the same offset in its physical page
+        let local = 0u32;
+        let vaddr = &raw const local as usize;
+        let info = user::virt_to_phys(vaddr)
+            .expect("the stack should be mapped");
+        let expected = user::PAGE_INFO_VALID | user::PAGE_INFO_READ | user::PAGE_INFO_WRITE | user::PAGE_INFO_USER;
+        assert!(info.flags & expected == expected);
+        assert!(info.paddr % 4096 == vaddr % 4096);
+
+        assert!(user::virt_to_phys(0) == Err(user::ErrorCode::NotFound));
+        execute_command("v2p 0x0", &mut Cwd::new());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_fs_info_test() {
+        print!("shell: fs info test...");
+
+        // The disk's files fit in the file table, whatever size it was built with
+        let info = user::fs_info()
+            .expect("should get file system info");
+        assert!(info.files_used > 0 && info.files_used <= info.files_max);
+        let log_size = stat("log.txt").map(|file_stat| file_stat.size).unwrap_or(0);
+        assert!(info.bytes_used >= log_size);
+        assert!(info.bytes_used + info.bytes_free == info.files_max * info.file_size_max);
+        execute_command("df", &mut Cwd::new());
+
+        print_ok();
+    }
+
+    #[test_case]
+    fn shell_non_utf8_filename_test() {
+        print!("shell: non-UTF-8 filename test...");
+
+        let mut buf = [0u8; 16];
+        assert!(user::readfile_bytes(b"\xffhello.txt", &mut buf).is_err());
+        assert!(user::writefile_bytes(b"meow\xfe.txt", b"meow").is_err());
+
+        // The kernel survived and still serves valid names
+        assert!(user::readfile_bytes(b"hello.txt", &mut buf).is_ok());
 
-        println!("[\x1b[32mok\x1b[0m]");
+        print_ok();
     }
 }
 
+// User tests run last, so shut down QEMU once they all pass.
 #[cfg(test)]
 pub fn test_runner(tests: &[&dyn Fn()]) {
     println!("Running {} user tests", tests.len());
     for test in tests {
         test();
     }
+    println!("All {} user tests passed", tests.len());
+    shutdown();
 }
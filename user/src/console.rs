@@ -0,0 +1,74 @@
+//! ANSI console helpers
+//!
+//! Colour and cursor control using ANSI escape sequences. Turn them off with `set_ansi(false)` on consoles
+//! that do not understand escape sequences; text is then printed plain.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use common::ansi::{GREEN, RED, RESET};
+
+use crate::{print, println};
+
+static ANSI: AtomicBool = AtomicBool::new(true);
+
+/// Enable or disable ANSI escape sequences
+pub fn set_ansi(enabled: bool) {
+    ANSI.store(enabled, Ordering::Relaxed);
+}
+
+fn ansi() -> bool {
+    ANSI.load(Ordering::Relaxed)
+}
+
+fn colored(colour: &str, s: &str) {
+    if ansi() {
+        print!("{}{}{}", colour, s, RESET);
+    } else {
+        print!("{}", s);
+    }
+}
+
+/// Print text in green
+pub fn green(s: &str) {
+    colored(GREEN, s);
+}
+
+/// Print text in red
+pub fn red(s: &str) {
+    colored(RED, s);
+}
+
+/// Print a green `[ok]` and end the line, as used by the test runners
+pub fn print_ok() {
+    print!("[");
+    green("ok");
+    println!("]");
+}
+
+/// Clear the screen and move the cursor to the top left
+pub fn clear_screen() {
+    if ansi() {
+        print!("\x1b[2J\x1b[H");
+    }
+}
+
+/// Move the cursor to `row` and `col`, both counted from 1
+pub fn move_cursor(row: usize, col: usize) {
+    if ansi() {
+        print!("\x1b[{};{}H", row, col);
+    }
+}
+
+/// Erase the `count` characters before the cursor, leaving the cursor where the first of them was
+pub fn erase_back(count: usize) {
+    if count == 0 {
+        return;
+    }
+    if ansi() {
+        print!("\x1b[{}D\x1b[K", count);
+    } else {
+        for _ in 0..count {
+            print!("\x08 \x08");
+        }
+    }
+}
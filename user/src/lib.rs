@@ -4,34 +4,73 @@
 
 #![no_std]
 
+pub mod console;
+
 use core::arch::{asm, naked_asm};
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+
+pub use common::{print, println, ErrorCode, FileStat, ProcStat, SCHED_LATENCY_BUCKETS};
+pub use common::{SELECT_CONSOLE, SELECT_MAX};
+pub use common::{PROCS_MAX, PROC_HART_NONE, PROC_STATE_RUNNABLE, PROC_STATE_SLEEPING, PROC_STATE_EXITED};
+pub use common::{FAULT_ALLOC, FAULT_FILE_WRITE, FAULT_VIRTIO, FAULT_PROCESS_CREATE, FAULT_CONSOLE_WRITE};
+pub use common::{OPEN_READ, OPEN_WRITE, OPEN_APPEND, OPEN_FILES_MAX};
+pub use common::{MAP_READ, MAP_WRITE, MAP_EXEC};
+pub use common::SIGNAL_TERM;
+pub use common::{EXIT_SIGNALLED, EXIT_KILLED, EXIT_FAULTED};
+pub use common::FsInfo;
+pub use common::{PageInfo, PAGE_INFO_VALID, PAGE_INFO_READ, PAGE_INFO_WRITE, PAGE_INFO_EXEC, PAGE_INFO_USER};
+pub use common::SPAWN_RESTART_ON_FAULT;
+pub use common::USER_BASE;
+
+use common::{Syscall, SELECT_FOREVER};
+use common::args::{self as args_abi, ARGS_SIZE, ARGS_VADDR};
 
-pub use common::{print, println};
+pub use common::args::Args;
+pub use common::ansi;
 
-use common::{
-    SYS_PUTBYTE,
-    SYS_GETCHAR,
-    SYS_EXIT,
-    SYS_READFILE,
-    SYS_WRITEFILE,
-};
+/// Exit status of a process that panicked, as for a Rust program that panics
+pub const PANIC_EXIT_CODE: usize = 101;
+
+// Function called by the panic handler before its message, or null for none
+static PANIC_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Install a function for the panic handler to call before printing its message
+///
+/// The hook can print a prefix or record state; the handler still prints the panic message and exits. It is
+/// removed before it runs, so a hook that panics does not recurse.
+pub fn set_panic_hook(hook: fn(&PanicInfo)) {
+    PANIC_HOOK.store(hook as *mut (), Ordering::Relaxed);
+}
 
 /// User panic handler
 ///
-/// Prints a panic message and exits the process.
+/// Calls the panic hook if one is installed, prints a panic message and exits the process with
+/// `PANIC_EXIT_CODE`.
 #[panic_handler]
 pub fn panic(info: &PanicInfo) -> ! {
+    let hook = PANIC_HOOK.swap(core::ptr::null_mut(), Ordering::Relaxed);
+    if !hook.is_null() {
+        // Safety: Only `set_panic_hook` stores a non-null pointer, and it stores a `fn(&PanicInfo)`
+        let hook = unsafe { core::mem::transmute::<*mut (), fn(&PanicInfo)>(hook) };
+        hook(info);
+    }
     println!("😬 User Panic! {}", info);
-    exit();
+    exit_with(PANIC_EXIT_CODE);
 }
 
 unsafe extern "C" {
     static __user_stack_top: u8;
 }
 
+// Decode a negative system call result
+fn error(result: isize) -> ErrorCode {
+    // Unknown codes only come from a mismatched kernel; report them as I/O errors
+    ErrorCode::try_from(result).unwrap_or(ErrorCode::IoError)
+}
+
 #[doc(hidden)]
-pub fn sys_call(arg0: isize, arg1: isize, arg2: isize, arg3: isize, sysno: usize)  -> isize {
+pub fn sys_call(arg0: isize, arg1: isize, arg2: isize, arg3: isize, syscall: Syscall)  -> isize {
     let a0: isize;
     unsafe{asm!(
         "ecall",
@@ -39,7 +78,7 @@ pub fn sys_call(arg0: isize, arg1: isize, arg2: isize, arg3: isize, sysno: usize
         in("a1") arg1,
         in("a2") arg2,
         in("a3") arg3,
-        in("a7") sysno,
+        in("a7") syscall as usize,
     )}
     a0
 }
@@ -48,56 +87,952 @@ pub fn sys_call(arg0: isize, arg1: isize, arg2: isize, arg3: isize, sysno: usize
 ///
 /// Returns `Err` if the function fails.
 /// Must be called repeatedly for each byte of a multibyte character.
-#[unsafe(no_mangle)]
-pub fn put_byte(b: u8) -> Result<(), isize> {
-    let result = sys_call(b as isize, 0, 0, 0, SYS_PUTBYTE);
+pub fn put_byte(b: u8) -> Result<(), ErrorCode> {
+    flush();    // Buffered `print!` output goes first
+    let result = sys_call(b as isize, 0, 0, 0, Syscall::PutByte);
     if result == 0 {
         Ok(())
     } else {
-        Err(result)
+        Err(error(result))
+    }
+}
+
+// System calls made to write to the console, for tests of buffering
+static CONSOLE_SYSCALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of system calls made so far to write bytes to the console
+#[doc(hidden)]
+pub fn console_syscalls() -> usize {
+    CONSOLE_SYSCALLS.load(Ordering::Relaxed)
+}
+
+/// Put bytes onto the debug console with a single system call
+///
+/// Stops at the first bytes the console fails to take and returns how many bytes were written, so the
+/// caller can retry the rest. Returns `Err` only if no bytes were written.
+pub fn put_bytes(buf: &[u8]) -> Result<usize, ErrorCode> {
+    flush();    // Buffered `print!` output goes first; does nothing when called to flush it
+    CONSOLE_SYSCALLS.fetch_add(1, Ordering::Relaxed);
+    let result = sys_call(buf.as_ptr() as isize, buf.len() as isize, 0, 0, Syscall::PutBytes);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+// Put all of `buf` onto the debug console, retrying after a partial write until the console takes nothing
+fn put_all_bytes(mut buf: &[u8]) -> Result<(), ErrorCode> {
+    while !buf.is_empty() {
+        let written = put_bytes(buf)?;
+        buf = &buf[written..];
+    }
+    Ok(())
+}
+
+const CONSOLE_BUFFER_SIZE: usize = 128;
+
+// Console output not yet written, and its length
+static mut CONSOLE_BUFFER: ([u8; CONSOLE_BUFFER_SIZE], usize) = ([0; CONSOLE_BUFFER_SIZE], 0);
+// Set while `CONSOLE_BUFFER` is in use, so a signal handler printing part way through cannot touch it
+static CONSOLE_BUSY: AtomicBool = AtomicBool::new(false);
+
+// Run `f` on the console buffer, or return `None` if it is already in use further up the stack
+fn with_console_buffer<R>(f: impl FnOnce(&mut [u8; CONSOLE_BUFFER_SIZE], &mut usize) -> R) -> Option<R> {
+    if CONSOLE_BUSY.swap(true, Ordering::Acquire) {
+        return None;
+    }
+    // Safety: The process has a single thread, and CONSOLE_BUSY keeps out a signal handler that interrupts it
+    let (bytes, len) = unsafe { &mut *&raw mut CONSOLE_BUFFER };
+    let result = f(bytes, len);
+    CONSOLE_BUSY.store(false, Ordering::Release);
+    Some(result)
+}
+
+// Write out the console buffer. Written bytes are dropped even if the console fails, so output cannot
+// pile up behind a broken console.
+fn flush_console_buffer(bytes: &[u8; CONSOLE_BUFFER_SIZE], len: &mut usize) -> Result<(), ErrorCode> {
+    let result = put_all_bytes(&bytes[..*len]);
+    *len = 0;
+    result
+}
+
+/// Write out console output buffered by `print!`
+///
+/// `print!` output without a newline waits in a buffer until `println!`, `flush`, reading console input or
+/// the process exiting. Call this to show it sooner, such as before a long computation.
+pub fn flush() {
+    let _ = with_console_buffer(|bytes, len| flush_console_buffer(bytes, len));
+}
+
+/// Write bytes to the debug console for `print!`, returning the number of bytes written
+///
+/// Output is buffered and written a line at a time, or when the buffer fills; see `flush`. A signal handler
+/// printing while the buffer is in use writes straight through.
+#[doc(hidden)]
+#[unsafe(no_mangle)]
+pub fn console_write(buf: &[u8]) -> Result<usize, isize> {
+    let buffered = with_console_buffer(|bytes, len| {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let n = rest.len().min(CONSOLE_BUFFER_SIZE - *len);
+            bytes[*len..*len + n].copy_from_slice(&rest[..n]);
+            *len += n;
+            let has_newline = rest[..n].contains(&b'\n');
+            rest = &rest[n..];
+            if has_newline || *len == CONSOLE_BUFFER_SIZE {
+                flush_console_buffer(bytes, len)?;
+            }
+        }
+        Ok(())
+    });
+    buffered.unwrap_or_else(|| put_all_bytes(buf)).map_err(|e| e as isize)?;
+    Ok(buf.len())
+}
+
+/// Poll the debug console for a byte
+///
+/// Returns `Ok(None)` if no byte is ready, `Ok(Some(byte))` if a byte was read, or `Err` if the console failed.
+///
+/// Does not block.
+pub fn poll_char() -> Result<Option<u8>, ErrorCode> {
+    flush();
+    match sys_call(0, 0, 0, 0, Syscall::PollChar) {
+        ch if ch >= 0 => Ok(Some(ch as u8)),
+        e => match error(e) {
+            ErrorCode::WouldBlock => Ok(None),
+            e => Err(e),
+        },
+    }
+}
+
+/// Wait up to `millisecs` for a byte from the debug console
+///
+/// Returns `Ok(None)` if no byte arrives in time, `Ok(Some(byte))` if one does, or `Err` if the console failed.
+///
+/// A zero timeout only polls. Otherwise blocks, yielding to other processes while waiting; the wait may run
+/// over by up to a scheduler time slice.
+pub fn get_char_timeout(millisecs: u64) -> Result<Option<u8>, ErrorCode> {
+    flush();
+    match sys_call(millisecs as u32 as isize, (millisecs >> 32) as isize, 0, 0, Syscall::GetCharTimeout) {
+        ch if ch >= 0 => Ok(Some(ch as u8)),
+        e => match error(e) {
+            ErrorCode::WouldBlock => Ok(None),
+            e => Err(e),
+        },
     }
 }
 
 /// Get character (or more accurately a byte) from the debug console
 ///
-/// If no character is read, returns `None`.
+/// If no character is read, or the console reports an error, returns `None`.
 ///
 /// Characters are returned as `usize` values. For multibyte characters, the function must be called for each byte.
 ///
 /// Does not block.
 pub fn get_char() -> Option<usize> {
-    let ch = sys_call(0, 0, 0, 0, SYS_GETCHAR);
-    if ch == -1 {
-        None
+    poll_char().ok().flatten().map(usize::from)
+}
+
+/// Get character (or more accurately a byte) from the debug console, waiting until one is available
+///
+/// Characters are returned as `usize` values. For multibyte characters, the function must be called for each byte.
+///
+/// Blocks, yielding to other processes while waiting.
+pub fn get_char_blocking() -> usize {
+    flush();
+    sys_call(0, 0, 0, 0, Syscall::GetChar) as usize
+}
+
+/// Get a whole UTF-8 character from the debug console
+///
+/// Returns `None` if no byte is ready or the bytes are not a valid UTF-8 sequence. Once the leading byte has
+/// arrived, blocks until the rest of the character does.
+pub fn get_utf8_char() -> Option<char> {
+    let lead = get_char()? as u8;
+    utf8_char_from(lead, || get_char_blocking() as u8)
+}
+
+/// Decode the UTF-8 character starting with `lead`, taking any continuation bytes from `next_byte`
+///
+/// Returns `None` for an invalid sequence, after taking as many bytes as `lead` says the character has.
+pub fn utf8_char_from(lead: u8, mut next_byte: impl FnMut() -> u8) -> Option<char> {
+    let len = match lead {
+        0x00..=0x7f => 1,
+        0xc2..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf4 => 4,
+        _ => return None,   // A continuation byte, or a lead byte no valid character starts with
+    };
+    let mut buf = [lead, 0, 0, 0];
+    for byte in &mut buf[1..len] {
+        *byte = next_byte();
+    }
+    // Rejects bad continuation bytes, overlong encodings and surrogates
+    str::from_utf8(&buf[..len]).ok()?.chars().next()
+}
+
+// UTF-8 continuation bytes are 0b10xxxxxx; every other byte starts a character
+fn is_continuation(byte: u8) -> bool {
+    byte & 0xc0 == 0x80
+}
+
+// Number of characters, and so console columns, taken by the UTF-8 in `bytes`
+fn char_count(bytes: &[u8]) -> usize {
+    bytes.iter().filter(|&&byte| !is_continuation(byte)).count()
+}
+
+/// Read a line from the debug console with basic editing
+///
+/// - `buf`: Byte buffer to receive the line, without the line ending
+///
+/// Echoes each byte as it is typed. Backspace (`0x7f` or `0x08`) erases the last character.
+/// Once `buf` is full further bytes are ignored and not echoed. Returns the number of bytes read.
+pub fn read_line(buf: &mut [u8]) -> usize {
+    read_line_with_recall(buf, |_, _, _| None)
+}
+
+/// Line recall requested by an arrow key while reading a line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recall {
+    Older,  // Up arrow, `\x1b[A`
+    Newer,  // Down arrow, `\x1b[B`
+}
+
+/// Read a line from the console, recalling earlier lines with the up and down arrows
+///
+/// As `read_line`, but an arrow key calls `recall` with `buf` and the length of the line being edited, which
+/// it may save before filling `buf` with the recalled line. It returns the length of that line, which replaces
+/// the one being edited, or `None` to leave the line alone. Other escape sequences are ignored.
+pub fn read_line_with_recall(buf: &mut [u8], recall: impl FnMut(Recall, &mut [u8], usize) -> Option<usize>) -> usize {
+    read_line_with_completion(buf, recall, |_, _| Completion::Unchanged)
+}
+
+/// Outcome of completing the line being edited when Tab is pressed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Completion {
+    Unchanged,          // Nothing to complete
+    Replaced(usize),    // `buf` holds a completed line of this length, which replaces the one being edited
+    Listed,             // Candidates were printed and the prompt redrawn; the line is printed again after it
+}
+
+/// Read a line from the console with line recall and completion
+///
+/// As `read_line_with_recall`, but Tab calls `complete` with `buf` and the length of the line so far.
+pub fn read_line_with_completion(
+    buf: &mut [u8],
+    recall: impl FnMut(Recall, &mut [u8], usize) -> Option<usize>,
+    complete: impl FnMut(&mut [u8], usize) -> Completion,
+) -> usize {
+    read_line_from(|| get_char_blocking() as u8, buf, recall, complete)
+}
+
+/// As `read_line_with_completion`, taking input bytes from `next_byte` rather than the console
+pub fn read_line_from(
+    mut next_byte: impl FnMut() -> u8,
+    buf: &mut [u8],
+    mut recall: impl FnMut(Recall, &mut [u8], usize) -> Option<usize>,
+    mut complete: impl FnMut(&mut [u8], usize) -> Completion,
+) -> usize {
+    let mut len = 0;
+    let mut pending = None;     // A byte read after ESC that did not start a control sequence
+    loop {
+        let byte = pending.take().unwrap_or_else(&mut next_byte);
+        match byte {
+            b'\r' => { // On the debug console the newline is \r
+                println!();
+                return len;
+            },
+            0x7f | 0x08 => {
+                // Erase the whole of the last character, lead byte and any continuation bytes
+                if len > 0 {
+                    len = buf[..len].iter().rposition(|&b| !is_continuation(b)).unwrap_or(0);
+                    console::erase_back(1);
+                }
+            },
+            b'\t' => {
+                let shown = char_count(&buf[..len]);
+                match complete(buf, len) {
+                    Completion::Unchanged => {},
+                    Completion::Replaced(completed_len) => {
+                        console::erase_back(shown);
+                        len = completed_len.min(buf.len());
+                        let _ = put_all_bytes(&buf[..len]);
+                    },
+                    Completion::Listed => {
+                        let _ = put_all_bytes(&buf[..len]);
+                    },
+                }
+            },
+            0x1b => {
+                // Control sequences are ESC [ then parameter bytes, ending with a byte in 0x40..=0x7e
+                // A lone ESC is dropped, but the byte after it is read as input
+                let byte = next_byte();
+                if byte != b'[' {
+                    pending = Some(byte);
+                    continue;
+                }
+                let final_byte = loop {
+                    let byte = next_byte();
+                    if (0x40..=0x7e).contains(&byte) {
+                        break byte;
+                    }
+                };
+                let direction = match final_byte {
+                    b'A' => Recall::Older,
+                    b'B' => Recall::Newer,
+                    _ => continue,
+                };
+                let shown = char_count(&buf[..len]);
+                if let Some(recalled_len) = recall(direction, buf, len) {
+                    console::erase_back(shown);
+                    len = recalled_len.min(buf.len());
+                    let _ = put_all_bytes(&buf[..len]);
+                }
+            },
+            _ if len < buf.len() => {
+                let _ = put_all_bytes(&[byte]);
+                buf[len] = byte;
+                len += 1;
+            },
+            _ => {}, // Buffer full
+        }
+    }
+}
+
+/// Get the process ID of the calling process
+pub fn getpid() -> usize {
+    sys_call(0, 0, 0, 0, Syscall::GetPid) as usize
+}
+
+/// Give up the CPU to other runnable processes
+///
+/// Returns when the scheduler next runs this process.
+pub fn yield_now() {
+    let _ = sys_call(0, 0, 0, 0, Syscall::Yield);
+}
+
+/// Milliseconds since the machine started
+pub fn uptime_ms() -> u64 {
+    let mut uptime: u64 = 0;
+    let _ = sys_call(&raw mut uptime as isize, 0, 0, 0, Syscall::Uptime);
+    uptime
+}
+
+/// Sleep until `deadline_ms` milliseconds after the machine started, as `uptime_ms` counts
+///
+/// Returns at once if the deadline has passed. Sleeping to deadlines rather than for durations keeps a
+/// periodic task from drifting by however long its work takes. The wake may run over by up to a scheduler
+/// time slice.
+pub fn sleep_until(deadline_ms: u64) {
+    let _ = sys_call(deadline_ms as u32 as isize, (deadline_ms >> 32) as isize, 0, 0, Syscall::SleepUntil);
+}
+
+/// Kill a process
+///
+/// - `pid`: Process ID of the process to kill; may be the calling process
+///
+/// Returns `Err` if there is no running process `pid` or it is the idle process.
+pub fn kill(pid: usize) -> Result<(), ErrorCode> {
+    let result = sys_call(pid as isize, 0, 0, 0, Syscall::Kill);
+    if result == 0 {
+        Ok(())
     } else {
-        Some(ch as usize)
+        Err(error(result))
     }
 }
 
+/// Register a handler for a signal
+///
+/// - `signo`: Signal number, such as `SIGNAL_TERM`
+/// - `handler`: Called with the signal number when the signal arrives; `None` restores the default action of
+///   terminating the process
+///
+/// When the handler returns the process carries on where the signal interrupted it.
+/// Returns `Err` if `signo` is not a signal number.
+pub fn sigaction(signo: usize, handler: Option<extern "C" fn(usize)>) -> Result<(), ErrorCode> {
+    let handler = handler.map_or(0, |handler| handler as usize);
+    let result = sys_call(signo as isize, handler as isize, signal_return as *const () as isize, 0, Syscall::SigAction);
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Send a signal to a process
+///
+/// - `pid`: Process ID of the process; may be the calling process
+/// - `signo`: Signal number, such as `SIGNAL_TERM`
+///
+/// The process runs its handler next time it returns to user mode, or is terminated if it has none.
+/// Returns `Err` if there is no running process `pid` or `signo` is not a signal number.
+pub fn signal(pid: usize, signo: usize) -> Result<(), ErrorCode> {
+    let result = sys_call(pid as isize, signo as isize, 0, 0, Syscall::Signal);
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(error(result))
+    }
+}
+
+// Signal handlers return here; the kernel restores the state from before the signal
+#[unsafe(naked)]
+extern "C" fn signal_return() {
+    naked_asm!(
+        "li a7, {sysno}",
+        "ecall",
+        sysno = const Syscall::SigReturn as usize,
+    )
+}
+
+/// Get information about a process
+///
+/// - `pid`: Process ID of the process
+///
+/// Returns the process state and CPU time, or `Err` if there is no process `pid`.
+pub fn proc_stat(pid: usize) -> Result<ProcStat, ErrorCode> {
+    let mut stat = ProcStat::default();
+    let result = sys_call(pid as isize, &raw mut stat as isize, 0, 0, Syscall::ProcStat);
+    if result == 0 {
+        Ok(stat)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Set the scheduler time slice
+///
+/// - `millisecs`: How long each process runs before it is preempted; takes effect from the next time slice
+///
+/// Returns the time slice it replaces, so that it can be restored, or `Err` for a zero time slice.
+pub fn set_quantum(millisecs: usize) -> Result<usize, ErrorCode> {
+    let result = sys_call(millisecs as isize, 0, 0, 0, Syscall::SetQuantum);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
 
 /// Exit the process
 ///
-/// System call to exit the process immediately.
+/// System call to exit the process immediately, with status 0. Buffered console output is written first.
 #[unsafe(no_mangle)]
 pub fn exit() -> ! {
-    let _ = sys_call(0, 0, 0, 0, SYS_EXIT);
+    exit_with(0);
+}
+
+/// Exit the process with a status that `wait` returns to other processes
+///
+/// Buffered console output is written first.
+pub fn exit_with(code: usize) -> ! {
+    flush();
+    let _ = sys_call(code as isize, 0, 0, 0, Syscall::Exit);
     unreachable!("just in case!");
 }
 
-/// Read a text file from the file system
+/// Print an error to the debug console, after a red `error:` prefix
+#[macro_export]
+macro_rules! eprintln {
+    () => { $crate::println!("{}error:{}", $crate::ansi::RED, $crate::ansi::RESET) };
+    ($($arg:tt)*) => {
+        $crate::println!("{}error:{} {}", $crate::ansi::RED, $crate::ansi::RESET, format_args!($($arg)*))
+    };
+}
+
+/// Print an error as `eprintln!` does, then exit the process with status `code`
+#[macro_export]
+macro_rules! die {
+    ($code:expr, $($arg:tt)*) => {
+        {
+            $crate::eprintln!($($arg)*);
+            $crate::exit_with($code)
+        }
+    };
+}
+
+/// Wait for a process to exit
+///
+/// - `pid`: Process ID of the process
+///
+/// Sleeps until the process has exited, then returns its exit status. A process the kernel ended exits with
+/// `EXIT_KILLED`, `EXIT_SIGNALLED` plus the signal number, or `EXIT_FAULTED` plus the cause of its fault.
+/// The process is then freed, so it can be waited for only once. A process that no live parent can wait for
+/// is freed as soon as it exits. Returns `Err` if there is no process `pid`, or it is the calling process.
+pub fn wait(pid: usize) -> Result<usize, ErrorCode> {
+    let mut exit_code: usize = 0;
+    let result = sys_call(pid as isize, &raw mut exit_code as isize, 0, 0, Syscall::Wait);
+    if result == 0 {
+        Ok(exit_code)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Power off the machine
+///
+/// System call to shut down the whole system immediately.
+pub fn shutdown() -> ! {
+    let _ = sys_call(0, 0, 0, 0, Syscall::Shutdown);
+    unreachable!("system should have shut down");
+}
+
+/// Identifies a pipe; any process knowing the id can read or write it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PipeId(pub usize);
+
+/// Create a pipe for communicating between processes
+///
+/// Returns `Err` if all pipes are in use.
+pub fn pipe() -> Result<PipeId, ErrorCode> {
+    let result = sys_call(0, 0, 0, 0, Syscall::PipeCreate);
+    if result >= 0 {
+        Ok(PipeId(result as usize))
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Write bytes to a pipe
+///
+/// Blocks while the pipe is full. Returns the number of bytes written, which may be fewer than `buf.len()`.
+pub fn pipe_write(pipe: PipeId, buf: &[u8]) -> Result<usize, ErrorCode> {
+    let result = sys_call(pipe.0 as isize, buf.as_ptr() as isize, buf.len() as isize, 0, Syscall::PipeWrite);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Read bytes from a pipe
+///
+/// Blocks while the pipe is empty. Returns the number of bytes read.
+pub fn pipe_read(pipe: PipeId, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+    let result = sys_call(pipe.0 as isize, buf.as_mut_ptr() as isize, buf.len() as isize, 0, Syscall::PipeRead);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Close a pipe, freeing it for reuse
+///
+/// Anything still buffered is discarded, and processes blocked reading or writing it get `Err(NotFound)`.
+/// Returns `Err(NotFound)` if there is no such pipe.
+pub fn pipe_close(pipe: PipeId) -> Result<(), ErrorCode> {
+    let result = sys_call(pipe.0 as isize, 0, 0, 0, Syscall::PipeClose);
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Wait until one of `fds` can be read, returning its index
+///
+/// Each of `fds` is a pipe id or `SELECT_CONSOLE` for console input. Returns `Ok(None)` if none is ready
+/// within `timeout_ms`, or at once for a zero timeout; `None` waits as long as it takes. The wait may run
+/// over by up to a scheduler time slice. Returns `Err(NotFound)` for a pipe that does not exist, and
+/// `Err(BadArg)` for more than `SELECT_MAX` descriptors or none with no timeout.
+pub fn select(fds: &[usize], timeout_ms: Option<u64>) -> Result<Option<usize>, ErrorCode> {
+    if fds.contains(&SELECT_CONSOLE) {
+        flush();
+    }
+    let millisecs = timeout_ms.unwrap_or(SELECT_FOREVER);
+    match sys_call(fds.as_ptr() as isize, fds.len() as isize, millisecs as u32 as isize, (millisecs >> 32) as isize, Syscall::Select) {
+        index if index >= 0 => Ok(Some(index as usize)),
+        e => match error(e) {
+            ErrorCode::WouldBlock => Ok(None),
+            e => Err(e),
+        },
+    }
+}
+
+/// Read a file from the file system
 ///
 /// - `filename`: Complete file name as a Rust string slice
 /// - `buf`: Byte buffer to receive the file contents
+///
+/// File contents are raw bytes and need not be valid UTF-8.
 pub fn readfile(filename: &str, buf: &mut [u8]) {
-    let _ = sys_call(filename.as_ptr() as isize, filename.len() as isize, buf.as_mut_ptr() as isize, buf.len() as isize, SYS_READFILE);
+    let _ = readfile_bytes(filename.as_bytes(), buf);
 }
 
-/// Write text to file
+/// Read a file from the file system, naming it by raw bytes
+///
+/// - `filename`: Complete file name as a byte slice
+/// - `buf`: Byte buffer to receive the file contents
+///
+/// Returns the number of bytes requested, or `Err` if the file is not found.
+/// File names that are not valid UTF-8 are never found.
+pub fn readfile_bytes(filename: &[u8], buf: &mut [u8]) -> Result<usize, ErrorCode> {
+    let result = sys_call(filename.as_ptr() as isize, filename.len() as isize, buf.as_mut_ptr() as isize, buf.len() as isize, Syscall::ReadFile);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Write bytes to a file
 ///
 /// - `filename`: Complete file name as a Rust string slice
 /// - `buf`: Byte buffer which will be written to the file
 pub fn writefile(filename: &str, buf: &[u8]) {
-    let _ = sys_call(filename.as_ptr() as isize, filename.len() as isize,  buf.as_ptr() as isize, buf.len() as isize, SYS_WRITEFILE);
+    let _ = writefile_bytes(filename.as_bytes(), buf);
+}
+
+/// Write bytes to a file, naming it by raw bytes
+///
+/// - `filename`: Complete file name as a byte slice
+/// - `buf`: Byte buffer which will be written to the file
+///
+/// Returns the number of bytes written, or `Err` if the file is not found or the write fails.
+/// File names that are not valid UTF-8 are never found.
+///
+/// Each write replaces the file contents at once. When processes write the same file, it holds the last
+/// write in full, never a mix of two.
+pub fn writefile_bytes(filename: &[u8], buf: &[u8]) -> Result<usize, ErrorCode> {
+    let result = sys_call(filename.as_ptr() as isize, filename.len() as isize, buf.as_ptr() as isize, buf.len() as isize, Syscall::WriteFile);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Identifies a file opened by `open` in this process
+#[derive(Debug, PartialEq)]
+pub struct Fd(pub usize);
+
+/// Open a file
+///
+/// - `filename`: Complete file name as a Rust string slice
+/// - `flags`: `OPEN_READ` and/or `OPEN_WRITE`, optionally with `OPEN_APPEND`
+///
+/// Reads and writes start at the beginning of the file and each carries on where the last left off.
+/// With `OPEN_APPEND` every write goes to the end of the file instead.
+/// Returns `Err` if the file is not found, the flags are invalid or too many files are open.
+pub fn open(filename: &str, flags: u32) -> Result<Fd, ErrorCode> {
+    let result = sys_call(filename.as_ptr() as isize, filename.len() as isize, flags as isize, 0, Syscall::Open);
+    if result >= 0 {
+        Ok(Fd(result as usize))
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Close a file opened by `open`
+pub fn close(fd: Fd) -> Result<(), ErrorCode> {
+    let result = sys_call(fd.0 as isize, 0, 0, 0, Syscall::Close);
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Read bytes from an open file
+///
+/// Returns the number of bytes read, which is 0 at the end of the file.
+pub fn read(fd: &Fd, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+    let result = sys_call(fd.0 as isize, buf.as_mut_ptr() as isize, buf.len() as isize, 0, Syscall::Read);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Write bytes to an open file
+///
+/// Returns the number of bytes written, which may be fewer than `buf.len()` when the file is full.
+pub fn write(fd: &Fd, buf: &[u8]) -> Result<usize, ErrorCode> {
+    let result = sys_call(fd.0 as isize, buf.as_ptr() as isize, buf.len() as isize, 0, Syscall::Write);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Append bytes to the end of a file
+///
+/// - `filename`: Complete file name as a Rust string slice
+///
+/// Returns the number of bytes appended, which may be fewer than `buf.len()` when the file is full,
+/// or `Err` if the file is not found.
+pub fn append_file(filename: &str, buf: &[u8]) -> Result<usize, ErrorCode> {
+    let fd = open(filename, OPEN_WRITE | OPEN_APPEND)?;
+    let result = write(&fd, buf);
+    close(fd)?;
+    result
+}
+
+/// Set the size of a file
+///
+/// - `filename`: Complete file name as a Rust string slice
+/// - `size`: New size in bytes; the file is zero filled if it grows
+///
+/// Returns `Err` if the file is not found or `size` is beyond its capacity.
+pub fn truncate(filename: &str, size: usize) -> Result<(), ErrorCode> {
+    let result = sys_call(filename.as_ptr() as isize, filename.len() as isize, size as isize, 0, Syscall::Truncate);
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Write changed files to disk
+///
+/// File writes only reach the disk on a sync. The kernel also syncs when a process exits and when it is idle.
+pub fn sync() {
+    let _ = sys_call(0, 0, 0, 0, Syscall::Sync);
+}
+
+/// List a directory
+///
+/// - `dir`: Directory name, or `""` for the root
+/// - `buf`: Receives the entries, each followed by a newline; subdirectories end in `/`
+///
+/// Returns the number of bytes written, `Err(NoSpace)` if the listing does not fit in `buf`.
+/// A directory with no files in it lists as empty.
+pub fn list_dir(dir: &str, buf: &mut [u8]) -> Result<usize, ErrorCode> {
+    let result = sys_call(dir.as_ptr() as isize, dir.len() as isize, buf.as_mut_ptr() as isize, buf.len() as isize, Syscall::ListDir);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Print a whole file to the debug console
+///
+/// - `filename`: Complete file name as a Rust string slice
+///
+/// Reads the file in chunks of `PRINT_CHUNK` bytes, so files of any size print without a large buffer.
+/// Returns the number of bytes printed, or `Err` if the file is not found or a read fails.
+pub fn print_file(filename: &str) -> Result<usize, ErrorCode> {
+    let size = stat(filename)?.size;
+    let fd = open(filename, OPEN_READ)?;
+    let mut chunk = [0u8; PRINT_CHUNK];
+    let mut printed = 0;
+    let result = loop {
+        if printed >= size {
+            break Ok(printed);
+        }
+        match read(&fd, &mut chunk) {
+            Ok(0) => break Ok(printed),     // The file shrank since `stat`
+            Ok(len) => {
+                if let Err(e) = put_all_bytes(&chunk[..len]) {
+                    break Err(e);
+                }
+                printed += len;
+            },
+            Err(e) => break Err(e),
+        }
+    };
+    close(fd)?;
+    result
+}
+
+// Bytes read at a time by `print_file`
+const PRINT_CHUNK: usize = 64;
+
+/// Map zeroed memory into this process
+///
+/// - `len`: Number of bytes, rounded up to whole pages
+/// - `prot`: `MAP_READ`, optionally with one of `MAP_WRITE` or `MAP_EXEC`
+///
+/// Returns the page aligned address of the memory, or `Err` if the arguments are invalid or there is no room.
+pub fn mmap(len: usize, prot: u32) -> Result<usize, ErrorCode> {
+    let result = sys_call(len as isize, prot as isize, 0, 0, Syscall::Mmap);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Unmap and free memory mapped by `mmap`
+///
+/// Returns `Err` if any page from `addr` to `addr + len` is not mapped.
+pub fn munmap(addr: usize, len: usize) -> Result<(), ErrorCode> {
+    let result = sys_call(addr as isize, len as isize, 0, 0, Syscall::Munmap);
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Bytes of kernel memory not yet allocated
+pub fn free_memory() -> usize {
+    sys_call(0, 0, 0, 0, Syscall::MemInfo) as usize
+}
+
+/// Fill `buf` with pseudo-random bytes
+///
+/// The kernel's generator is seeded from the timer and is not cryptographically secure: use it for games,
+/// demos and randomised tests, never for keys.
+pub fn random(buf: &mut [u8]) {
+    // A buffer borrowed from this process is always writable, so the call cannot fail
+    let _ = sys_call(buf.as_mut_ptr() as isize, buf.len() as isize, 0, 0, Syscall::Random);
+}
+
+/// A pseudo-random number, as from `random`
+pub fn random_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    random(&mut bytes);
+    u32::from_ne_bytes(bytes)
+}
+
+/// Context switches the scheduler has made since boot
+pub fn switch_count() -> usize {
+    sys_call(0, 0, 0, 0, Syscall::SchedStat) as usize
+}
+
+/// Get file system usage: files in the table and its capacity, and bytes used and free
+pub fn fs_info() -> Result<FsInfo, ErrorCode> {
+    let mut info = FsInfo::default();
+    let result = sys_call(&raw mut info as isize, 0, 0, 0, Syscall::FsInfo);
+    if result == 0 {
+        Ok(info)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Look up the physical page behind a virtual address in this process, for debugging
+///
+/// Returns the physical address and page flags, `Err(NotFound)` if the address is not mapped, or
+/// `Err(NoSys)` if the kernel was built without debug system calls.
+pub fn virt_to_phys(vaddr: usize) -> Result<PageInfo, ErrorCode> {
+    let mut info = PageInfo::default();
+    let result = sys_call(vaddr as isize, &raw mut info as isize, 0, 0, Syscall::V2P);
+    if result == 0 {
+        Ok(info)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Turn logging of every system call, with its arguments and result, on or off, for debugging
+///
+/// Returns `Err(NoSys)` if the kernel was built without debug system calls.
+pub fn set_trace(on: bool) -> Result<(), ErrorCode> {
+    let result = sys_call(on as isize, 0, 0, 0, Syscall::SetTrace);
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Get file metadata
+///
+/// - `filename`: Complete file name as a Rust string slice
+///
+/// Returns the file size and name length, or `Err` if the file is not found.
+pub fn stat(filename: &str) -> Result<FileStat, ErrorCode> {
+    let mut stat = FileStat::default();
+    let result = sys_call(filename.as_ptr() as isize, filename.len() as isize, &raw mut stat as isize, 0, Syscall::Stat);
+    if result == 0 {
+        Ok(stat)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Read the scheduling latency histogram
+///
+/// - `buf`: Receives one count per bucket, up to `SCHED_LATENCY_BUCKETS` entries
+///
+/// Bucket 0 counts zero-tick delays and bucket `i` counts delays in `[2^(i-1), 2^i)` timer ticks.
+/// Returns the number of buckets copied.
+pub fn sched_latency_hist(buf: &mut [usize]) -> usize {
+    sys_call(buf.as_mut_ptr() as isize, buf.len() as isize, 0, 0, Syscall::SchedLatencyHist) as usize
+}
+
+/// Arm the kernel to fail the next `count` operations of a given kind
+///
+/// - `kind`: One of `FAULT_ALLOC`, `FAULT_FILE_WRITE`, `FAULT_VIRTIO`, `FAULT_PROCESS_CREATE` or
+///   `FAULT_CONSOLE_WRITE`
+/// - `count`: Number of operations to fail; zero disarms
+///
+/// Intended for tests of error handling, with the kernel and this crate built with the `fault-injection`
+/// feature. Returns `Err` for an unknown fault kind, or `Err(NoSys)` if the kernel was built without it.
+#[cfg(feature = "fault-injection")]
+pub fn inject_fault(kind: usize, count: usize) -> Result<(), ErrorCode> {
+    let result = sys_call(kind as isize, count as isize, 0, 0, Syscall::InjectFault);
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Spawn a new process running a program linked into the kernel
+///
+/// - `args`: Program name followed by its arguments
+///
+/// Returns the process ID of the new process, or `Err` if the program is not found, the arguments do not fit or
+/// every process slot is in use.
+pub fn spawn<'s>(args: impl IntoIterator<Item = &'s str>) -> Result<usize, ErrorCode> {
+    spawn_with_flags(args, 0)
+}
+
+/// Spawn a new process as `spawn`, with `SPAWN_*` flags
+///
+/// With `SPAWN_RESTART_ON_FAULT` a process that faults starts again from its entry point, keeping its memory,
+/// rather than being killed. A process still faulting after a few restarts is killed.
+pub fn spawn_with_flags<'s>(args: impl IntoIterator<Item = &'s str>, flags: u32) -> Result<usize, ErrorCode> {
+    let mut buf = [0u8; ARGS_SIZE];
+    let len = args_abi::encode(args, &mut buf).ok_or(ErrorCode::BadArg)?;
+    let result = sys_call(buf.as_ptr() as isize, len as isize, flags as isize, 0, Syscall::Spawn);
+    if result >= 0 {
+        Ok(result as usize)
+    } else {
+        Err(error(result))
+    }
+}
+
+/// Replace this process's program with another linked into the kernel, keeping the process ID
+///
+/// - `args`: Program name followed by its arguments
+///
+/// The new program starts from its entry point with fresh memory; open files are kept and signal handlers
+/// are reset. Only returns if the program is not found or the arguments do not fit.
+pub fn exec<'s>(args: impl IntoIterator<Item = &'s str>) -> ErrorCode {
+    let mut buf = [0u8; ARGS_SIZE];
+    let Some(len) = args_abi::encode(args, &mut buf) else {
+        return ErrorCode::BadArg;
+    };
+    error(sys_call(buf.as_ptr() as isize, len as isize, 0, 0, Syscall::Exec))
+}
+
+/// Arguments of this process, starting with the program name
+pub fn args() -> Args<'static> {
+    // Safety: The kernel maps the read-only arguments page at ARGS_VADDR for every user process
+    let page = unsafe {
+        core::slice::from_raw_parts(ARGS_VADDR as *const u8, ARGS_SIZE)
+    };
+    Args::parse(page)
+}
+
+/// Copy the name this program was started as, its first argument, into `buf`
+///
+/// Returns the number of bytes copied, which is less than the name's length if `buf` is too small. The name
+/// is read from the arguments page, so no system call is needed.
+pub fn program_name(buf: &mut [u8]) -> usize {
+    let name = args().next().unwrap_or("");
+    let len = name.len().min(buf.len());
+    buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+    len
 }
 
 #[unsafe(link_section = ".text.start")]
@@ -106,9 +1041,14 @@ pub fn writefile(filename: &str, buf: &[u8]) {
 unsafe extern "C" fn start() {
     naked_asm!(
         "la sp, {stack_top}",
+        "li a1, {args}",
+        "lw a0, 0(a1)",             // argc
+        "addi a1, a1, {word}",      // argv: the first length-prefixed argument
         "call main",
         "call exit",
-        stack_top = sym __user_stack_top
+        stack_top = sym __user_stack_top,
+        args = const ARGS_VADDR,
+        word = const size_of::<usize>(),
     )
 }
 